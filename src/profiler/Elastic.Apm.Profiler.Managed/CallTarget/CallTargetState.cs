@@ -22,6 +22,7 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget
 		private readonly IExecutionSegment _segment;
 		private readonly object _state;
 		private readonly DateTimeOffset? _startTime;
+		private readonly bool _skipMethodBody;
 
 		/// <summary>
 		/// Initializes a new instance of the <see cref="CallTargetState"/> struct.
@@ -33,6 +34,7 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget
 			_segment = segment;
 			_state = null;
 			_startTime = null;
+			_skipMethodBody = false;
 		}
 
 		/// <summary>
@@ -46,6 +48,7 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget
 			_segment = segment;
 			_state = state;
 			_startTime = null;
+			_skipMethodBody = false;
 		}
 
 		/// <summary>
@@ -60,6 +63,23 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget
 			_segment = segment;
 			_state = state;
 			_startTime = startTime;
+			_skipMethodBody = false;
+		}
+
+		/// <summary>
+		/// Initializes a new instance of the <see cref="CallTargetState"/> struct.
+		/// </summary>
+		/// <param name="segment">Scope instance</param>
+		/// <param name="state">Object state instance</param>
+		/// <param name="startTime">The intended start time of the scope, intended for scopes created in the OnMethodEnd handler</param>
+		/// <param name="skipMethodBody">Whether the body of the instrumented method is skipped</param>
+		public CallTargetState(IExecutionSegment segment, object state, DateTimeOffset? startTime, bool skipMethodBody)
+		{
+			_previousSegment = null;
+			_segment = segment;
+			_state = state;
+			_startTime = startTime;
+			_skipMethodBody = skipMethodBody;
 		}
 
 		internal CallTargetState(IExecutionSegment previousSegment, CallTargetState state)
@@ -68,6 +88,7 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget
 			_segment = state._segment;
 			_state = state._state;
 			_startTime = state._startTime;
+			_skipMethodBody = state._skipMethodBody;
 		}
 
 		/// <summary>
@@ -85,6 +106,14 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget
 		/// </summary>
 		public DateTimeOffset? StartTime => _startTime;
 
+		/// <summary>
+		/// Gets whether the body of the instrumented method is skipped. OnMethodEnd is still called,
+		/// with the default return value, and the return value it returns is returned from the method.
+		/// Only honored when the wrapper of the integration sets skip_method_body in the integrations file,
+		/// and when the instrumented method itself is rewritten, not its call sites.
+		/// </summary>
+		public bool SkipMethodBody => _skipMethodBody;
+
 		internal IExecutionSegment PreviousSegment => _previousSegment;
 
 		/// <summary>
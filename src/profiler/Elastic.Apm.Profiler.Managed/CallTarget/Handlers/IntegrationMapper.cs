@@ -166,7 +166,10 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget.Handlers
 			 *      - CallTargetState OnMethodBegin<TTarget, TArg1>(TArg1 arg1);
 			 *      - CallTargetState OnMethodBegin<TTarget, TArg1, TArg2>(TArg1 arg1, TArg2);
 			 *      - CallTargetState OnMethodBegin<TTarget, TArg1, TArg2, ...>(TArg1 arg1, TArg2, ...);
+			 *      - CallTargetState OnMethodBegin<TTarget, TArg1, TArg2, ...>(TTarget instance, ref TArg1 arg1, ref TArg2, ...);
 			 *
+			 * A ref parameter is passed a local holding the argument, which is copied back into the arguments array
+			 * after the call, so that the rewritten method can copy the changed argument back.
 			 */
 
 			Logger.Debug($"Creating SlowBeginMethod Dynamic Method for '{integrationType.FullName}' integration. [Target={targetType.FullName}]");
@@ -228,9 +231,14 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget.Handlers
 			}
 
 			// Load arguments
+			var byRefArguments = new List<KeyValuePair<int, LocalBuilder>>();
 			for (var i = mustLoadInstance ? 1 : 0; i < onMethodBeginParameters.Length; i++)
 			{
 				var targetParameterType = onMethodBeginParameters[i].ParameterType;
+				var isByRef = targetParameterType.IsByRef;
+				if (isByRef)
+					targetParameterType = targetParameterType.GetElementType();
+
 				Type targetParameterTypeConstraint = null;
 
 				if (targetParameterType.IsGenericParameter)
@@ -253,14 +261,44 @@ namespace Elastic.Apm.Profiler.Managed.CallTarget.Handlers
 				ilWriter.Emit(OpCodes.Ldelem_Ref);
 
 				if (targetParameterTypeConstraint != null)
+				{
+					if (isByRef)
+					{
+						throw new ArgumentException(
+							$"The ref parameter: {onMethodBeginParameters[i].Name} of the method: {BeginMethodName} in type: {integrationType.FullName} can't be duck typed.");
+					}
+
 					ilWriter.EmitCall(OpCodes.Call, ConvertTypeMethodInfo.MakeGenericMethod(targetParameterTypeConstraint), null);
+				}
 				else if (targetParameterType.IsValueType)
 					ilWriter.Emit(OpCodes.Unbox_Any, targetParameterType);
+				else if (isByRef && !targetParameterType.IsGenericParameter)
+					ilWriter.Emit(OpCodes.Castclass, targetParameterType);
+
+				if (isByRef)
+				{
+					var local = ilWriter.DeclareLocal(targetParameterType.IsGenericParameter ? typeof(object) : targetParameterType);
+					ilWriter.Emit(OpCodes.Stloc, local);
+					ilWriter.Emit(OpCodes.Ldloca, local);
+					byRefArguments.Add(new KeyValuePair<int, LocalBuilder>(i - (mustLoadInstance ? 1 : 0), local));
+				}
 			}
 
 			// Call method
 			onMethodBeginMethodInfo = onMethodBeginMethodInfo.MakeGenericMethod(callGenericTypes.ToArray());
 			ilWriter.EmitCall(OpCodes.Call, onMethodBeginMethodInfo, null);
+
+			// Copy the ref arguments back into the arguments array, leaving the returned state on the stack
+			foreach (var byRefArgument in byRefArguments)
+			{
+				ilWriter.Emit(OpCodes.Ldarg_1);
+				WriteIntValue(ilWriter, byRefArgument.Key);
+				ilWriter.Emit(OpCodes.Ldloc, byRefArgument.Value);
+				if (byRefArgument.Value.LocalType.IsValueType)
+					ilWriter.Emit(OpCodes.Box, byRefArgument.Value.LocalType);
+				ilWriter.Emit(OpCodes.Stelem_Ref);
+			}
+
 			ilWriter.Emit(OpCodes.Ret);
 
 			Logger.Debug($"Created SlowBeginMethod Dynamic Method for '{integrationType.FullName}' integration. [Target={targetType.FullName}]");
@@ -0,0 +1,894 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{
+        CorExceptionFlag, FatSectionClause, FatSectionHeader, FieldToken, HandleToken, Instruction,
+        Method, MethodHeader, MethodToken, Opcode, Operand, OperandParams, Section, SingleByte,
+        TinyMethodHeader, TypeToken, UNALIGNED,
+    },
+    error::Error,
+    ffi::{mdToken, mdTokenNil},
+};
+use std::{collections::HashMap, convert::TryFrom};
+
+/// Resolves the names used in IL source text to metadata tokens.
+///
+/// All methods default to `None`, in which case only raw tokens
+/// such as `0x0a000001` can be used as operands.
+pub trait TokenProvider {
+    /// Gets the TypeDef, TypeRef or TypeSpec token for a type name
+    fn type_token(&self, name: &str) -> Option<mdToken> {
+        None
+    }
+
+    /// Gets the MethodDef, MemberRef or MethodSpec token for a method reference
+    fn method_token(&self, name: &str) -> Option<mdToken> {
+        None
+    }
+
+    /// Gets the FieldDef or MemberRef token for a field reference
+    fn field_token(&self, name: &str) -> Option<mdToken> {
+        None
+    }
+
+    /// Gets the user string token for a literal value
+    fn string_token(&self, value: &str) -> Option<mdToken> {
+        None
+    }
+
+    /// Gets the StandAloneSig token for a `calli` method signature
+    fn signature_token(&self, signature: &str) -> Option<mdToken> {
+        None
+    }
+
+    /// Gets the StandAloneSig token for the contents of a `.locals` declaration
+    fn locals_token(&self, locals: &str) -> Option<mdToken> {
+        None
+    }
+}
+
+/// A protected or handler block that has been opened with `{`
+enum Block {
+    Try {
+        start: usize,
+    },
+    Handler {
+        flag: CorExceptionFlag,
+        class_token: mdToken,
+        try_range: (usize, usize),
+        start: usize,
+    },
+    Filter {
+        try_range: (usize, usize),
+        start: usize,
+    },
+    FilterHandler {
+        try_range: (usize, usize),
+        filter_start: usize,
+        start: usize,
+    },
+}
+
+/// A block keyword that expects to be followed by `{`
+enum Pending {
+    Try,
+    Handler(CorExceptionFlag, mdToken),
+    Filter,
+}
+
+/// An exception clause in terms of instruction indexes
+struct Clause {
+    flag: CorExceptionFlag,
+    try_range: (usize, usize),
+    handler_range: (usize, usize),
+    class_token_or_filter_start: u32,
+}
+
+/// Assembles ILAsm-like source text into a [Method].
+///
+/// The source consists of an optional `.maxstack` and `.locals` declaration
+/// followed by instructions, labels, and `.try`/`catch`/`filter`/`finally`/`fault`
+/// blocks. Comments start with `//`. The output of [crate::cil::Disassembler]
+/// can be assembled again.
+///
+/// ```text
+/// .maxstack 2
+/// .locals init (int32 V_0)
+/// .try
+/// {
+///   ldarg.0
+///   brfalse.s  EXIT
+///   call       void Foo::Bar()
+/// EXIT:
+///   leave.s    END
+/// }
+/// catch [System.Runtime]System.Exception
+/// {
+///   pop
+///   leave.s    END
+/// }
+/// END:
+///   ret
+/// ```
+pub struct Assembler<'a, P: TokenProvider> {
+    provider: &'a P,
+}
+
+impl<'a, P: TokenProvider> Assembler<'a, P> {
+    pub fn new(provider: &'a P) -> Self {
+        Self { provider }
+    }
+
+    /// Assembles the source text into a method. A tiny header is used when
+    /// the method has no locals, exception clauses or more than 8 stack slots,
+    /// and its code fits in a tiny header.
+    pub fn assemble(&self, source: &str) -> Result<Method, Error> {
+        let mut parser = Parser::new(source);
+        let mut max_stack = TinyMethodHeader::MAX_STACK as u16;
+        let mut local_var_sig_tok = mdTokenNil;
+        let mut init_locals = false;
+        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut labels: HashMap<String, usize> = HashMap::new();
+        let mut branches: Vec<(usize, Vec<String>, usize)> = Vec::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut clauses: Vec<Clause> = Vec::new();
+        let mut pending: Option<Pending> = None;
+        let mut pending_filter: Option<((usize, usize), usize)> = None;
+        let mut last_try: Option<(usize, usize)> = None;
+
+        while let Some(c) = parser.skip_whitespace() {
+            let line = parser.line_number();
+            if c == '{' {
+                parser.advance();
+                let start = instructions.len();
+                let block = match pending.take() {
+                    Some(Pending::Try) => Block::Try { start },
+                    Some(Pending::Handler(flag, class_token)) => Block::Handler {
+                        flag,
+                        class_token,
+                        try_range: last_try.ok_or_else(|| error(line, "handler without .try"))?,
+                        start,
+                    },
+                    Some(Pending::Filter) => Block::Filter {
+                        try_range: last_try.ok_or_else(|| error(line, "filter without .try"))?,
+                        start,
+                    },
+                    None => match pending_filter.take() {
+                        Some((try_range, filter_start)) => Block::FilterHandler {
+                            try_range,
+                            filter_start,
+                            start,
+                        },
+                        None => return Err(error(line, "unexpected '{'")),
+                    },
+                };
+                last_try = None;
+                blocks.push(block);
+                continue;
+            }
+
+            if pending.is_some() {
+                return Err(error(line, "expected '{'"));
+            }
+            if pending_filter.is_some() {
+                return Err(error(line, "expected handler block after filter"));
+            }
+
+            if c == '}' {
+                parser.advance();
+                let end = instructions.len();
+                match blocks.pop() {
+                    Some(Block::Try { start }) => last_try = Some((start, end)),
+                    Some(Block::Handler {
+                        flag,
+                        class_token,
+                        try_range,
+                        start,
+                    }) => {
+                        clauses.push(Clause {
+                            flag,
+                            try_range,
+                            handler_range: (start, end),
+                            class_token_or_filter_start: class_token,
+                        });
+                        last_try = Some(try_range);
+                    }
+                    Some(Block::Filter { try_range, start }) => {
+                        pending_filter = Some((try_range, start));
+                        last_try = None;
+                    }
+                    Some(Block::FilterHandler {
+                        try_range,
+                        filter_start,
+                        start,
+                    }) => {
+                        clauses.push(Clause {
+                            flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER,
+                            try_range,
+                            handler_range: (start, end),
+                            class_token_or_filter_start: filter_start as u32,
+                        });
+                        last_try = Some(try_range);
+                    }
+                    None => return Err(error(line, "unbalanced '}'")),
+                }
+                continue;
+            }
+
+            let word = parser.word();
+            match word.as_str() {
+                ".maxstack" => {
+                    let operand = parser.rest_of_line();
+                    max_stack = integer(&operand)
+                        .and_then(|v| u16::try_from(v).ok())
+                        .ok_or_else(|| error(line, format!("invalid .maxstack '{}'", operand)))?;
+                }
+                ".locals" => {
+                    let mut operand = parser.rest_of_line();
+                    if let Some(rest) = operand.strip_prefix("init") {
+                        init_locals = true;
+                        operand = rest.trim_start().to_string();
+                    }
+                    let locals = operand
+                        .strip_prefix('(')
+                        .and_then(|o| o.strip_suffix(')'))
+                        .map(str::trim)
+                        .ok_or_else(|| error(line, "expected '(' locals ')'"))?;
+                    local_var_sig_tok = token_literal(locals)
+                        .or_else(|| self.provider.locals_token(locals))
+                        .ok_or_else(|| error(line, format!("unresolved locals '{}'", locals)))?;
+                }
+                ".try" => {
+                    last_try = None;
+                    pending = Some(Pending::Try);
+                }
+                "catch" if last_try.is_some() => {
+                    let class = parser.until_brace();
+                    let class_token = self
+                        .token::<TypeToken>(&class, line, P::type_token)?
+                        .token();
+                    pending = Some(Pending::Handler(
+                        CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
+                        class_token,
+                    ));
+                }
+                "finally" if last_try.is_some() => {
+                    pending = Some(Pending::Handler(
+                        CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                        0,
+                    ));
+                }
+                "fault" if last_try.is_some() => {
+                    pending = Some(Pending::Handler(
+                        CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT,
+                        0,
+                    ));
+                }
+                "filter" if last_try.is_some() => pending = Some(Pending::Filter),
+                "catch" | "finally" | "fault" | "filter" => {
+                    return Err(error(line, format!("{} without .try", word)));
+                }
+                w if w.len() > 1 && w.ends_with(':') => {
+                    last_try = None;
+                    let name = &w[..w.len() - 1];
+                    if labels
+                        .insert(name.to_string(), instructions.len())
+                        .is_some()
+                    {
+                        return Err(error(line, format!("duplicate label '{}'", name)));
+                    }
+                }
+                w => {
+                    last_try = None;
+                    let opcode = Opcode::from_name(w)
+                        .ok_or_else(|| error(line, format!("unknown opcode '{}'", w)))?;
+                    let operand_text = parser.rest_of_line();
+                    let operand = match opcode.operand_params {
+                        OperandParams::ShortInlineBrTarget
+                        | OperandParams::InlineBrTarget
+                        | OperandParams::InlineSwitch => {
+                            let targets = branch_targets(&opcode, &operand_text)
+                                .ok_or_else(|| error(line, "expected branch target label"))?;
+                            let operand = match opcode.operand_params {
+                                OperandParams::ShortInlineBrTarget => {
+                                    Operand::ShortInlineBrTarget(0)
+                                }
+                                OperandParams::InlineBrTarget => Operand::InlineBrTarget(0),
+                                _ => Operand::InlineSwitch(
+                                    targets.len() as u32,
+                                    vec![0; targets.len()],
+                                ),
+                            };
+                            branches.push((instructions.len(), targets, line));
+                            operand
+                        }
+                        _ => self.operand(&opcode, &operand_text, line)?,
+                    };
+                    instructions.push(Instruction { opcode, operand });
+                }
+            }
+        }
+
+        let line = parser.line_number();
+        if pending.is_some() || !blocks.is_empty() {
+            return Err(error(line, "unclosed block"));
+        }
+        if pending_filter.is_some() {
+            return Err(error(line, "expected handler block after filter"));
+        }
+
+        let mut offsets = Vec::with_capacity(instructions.len() + 1);
+        let mut offset = 0;
+        offsets.push(offset);
+        for instruction in &instructions {
+            offset += instruction.len();
+            offsets.push(offset);
+        }
+
+        for (index, targets, line) in branches {
+            let next = offsets[index + 1] as i64;
+            let mut deltas = Vec::with_capacity(targets.len());
+            for target in &targets {
+                let target_index = labels
+                    .get(target)
+                    .ok_or_else(|| error(line, format!("undefined label '{}'", target)))?;
+                deltas.push(offsets[*target_index] as i64 - next);
+            }
+
+            let instruction = &mut instructions[index];
+            instruction.operand = match &instruction.operand {
+                Operand::ShortInlineBrTarget(_) => {
+                    Operand::ShortInlineBrTarget(i8::try_from(deltas[0]).map_err(|_| {
+                        error(
+                            line,
+                            format!(
+                                "branch to '{}' out of range for {}",
+                                targets[0], instruction.opcode.name
+                            ),
+                        )
+                    })?)
+                }
+                Operand::InlineBrTarget(_) => Operand::InlineBrTarget(deltas[0] as i32),
+                _ => Operand::InlineSwitch(
+                    deltas.len() as u32,
+                    deltas.into_iter().map(|d| d as i32).collect(),
+                ),
+            };
+        }
+
+        let code_size = offsets[instructions.len()];
+        let sections = if clauses.is_empty() {
+            vec![]
+        } else {
+            let clauses: Vec<FatSectionClause> = clauses
+                .into_iter()
+                .map(|c| FatSectionClause {
+                    class_token_or_filter_offset: if c.flag
+                        == CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER
+                    {
+                        offsets[c.class_token_or_filter_start as usize] as u32
+                    } else {
+                        c.class_token_or_filter_start
+                    },
+                    flag: c.flag,
+                    try_offset: offsets[c.try_range.0] as u32,
+                    try_length: (offsets[c.try_range.1] - offsets[c.try_range.0]) as u32,
+                    handler_offset: offsets[c.handler_range.0] as u32,
+                    handler_length: (offsets[c.handler_range.1] - offsets[c.handler_range.0])
+                        as u32,
+                })
+                .collect();
+            vec![Section::FatSection(
+                FatSectionHeader {
+                    is_eh_table: true,
+                    more_sects: false,
+                    data_size: (FatSectionHeader::LENGTH
+                        + (FatSectionClause::LENGTH * clauses.len()))
+                        as u32,
+                },
+                clauses,
+            )]
+        };
+
+        let header = if sections.is_empty()
+            && local_var_sig_tok == mdTokenNil
+            && max_stack <= TinyMethodHeader::MAX_STACK as u16
+            && code_size <= TinyMethodHeader::MAX_CODE_SIZE as usize
+        {
+            MethodHeader::tiny(code_size as u8)
+        } else {
+            MethodHeader::fat(
+                !sections.is_empty(),
+                init_locals,
+                max_stack,
+                code_size as u32,
+                local_var_sig_tok,
+            )
+        };
+
+        Ok(Method {
+            address: 0,
+            header,
+            instructions,
+            sections,
+            original_offsets: vec![],
+        })
+    }
+
+    fn operand(&self, opcode: &Opcode, text: &str, line: usize) -> Result<Operand, Error> {
+        let invalid = || {
+            error(
+                line,
+                format!("invalid operand '{}' for {}", text, opcode.name),
+            )
+        };
+        let operand = match opcode.operand_params {
+            OperandParams::InlineNone => {
+                if !text.is_empty() {
+                    return Err(invalid());
+                }
+                Operand::InlineNone
+            }
+            OperandParams::ShortInlineVar => Operand::ShortInlineVar(
+                integer(text)
+                    .and_then(|v| u8::try_from(v).ok())
+                    .ok_or_else(invalid)?,
+            ),
+            OperandParams::InlineVar => Operand::InlineVar(
+                integer(text)
+                    .and_then(|v| u16::try_from(v).ok())
+                    .ok_or_else(invalid)?,
+            ),
+            OperandParams::ShortInlineI => {
+                let value = integer(text).ok_or_else(invalid)?;
+                if *opcode == UNALIGNED {
+                    Operand::ShortInlineI(SingleByte::Unsigned(
+                        u8::try_from(value).map_err(|_| invalid())?,
+                    ))
+                } else {
+                    Operand::ShortInlineI(SingleByte::Signed(
+                        i8::try_from(value).map_err(|_| invalid())?,
+                    ))
+                }
+            }
+            OperandParams::InlineI => {
+                let value = integer(text).ok_or_else(invalid)?;
+                Operand::InlineI(
+                    i32::try_from(value)
+                        .or_else(|_| u32::try_from(value).map(|v| v as i32))
+                        .map_err(|_| invalid())?,
+                )
+            }
+            OperandParams::InlineI8 => {
+                let value = integer(text).ok_or_else(invalid)?;
+                Operand::InlineI8(
+                    i64::try_from(value)
+                        .or_else(|_| u64::try_from(value).map(|v| v as i64))
+                        .map_err(|_| invalid())?,
+                )
+            }
+            OperandParams::ShortInlineR => {
+                Operand::ShortInlineR(text.parse::<f32>().map_err(|_| invalid())?)
+            }
+            OperandParams::InlineR => Operand::InlineR(text.parse::<f64>().map_err(|_| invalid())?),
+            OperandParams::InlineMethod => {
+                Operand::InlineMethod(self.token(text, line, P::method_token)?)
+            }
+            OperandParams::InlineField => {
+                Operand::InlineField(self.token(text, line, P::field_token)?)
+            }
+            OperandParams::InlineType => {
+                Operand::InlineType(self.token(text, line, P::type_token)?)
+            }
+            OperandParams::InlineSig => {
+                Operand::InlineSig(self.token(text, line, P::signature_token)?)
+            }
+            OperandParams::InlineString => {
+                let token = match unquote(text) {
+                    Some(value) => self
+                        .provider
+                        .string_token(&value)
+                        .ok_or_else(|| error(line, format!("unresolved string {}", text)))?,
+                    None => token_literal(text).ok_or_else(invalid)?,
+                };
+                Operand::InlineString(typed_token(token, line)?)
+            }
+            OperandParams::InlineTok => {
+                let token: HandleToken = if let Some(method) = text.strip_prefix("method ") {
+                    self.token::<MethodToken>(method.trim_start(), line, P::method_token)?
+                        .into()
+                } else if let Some(field) = text.strip_prefix("field ") {
+                    self.token::<FieldToken>(field.trim_start(), line, P::field_token)?
+                        .into()
+                } else {
+                    self.token::<TypeToken>(text, line, P::type_token)?.into()
+                };
+                Operand::InlineTok(token)
+            }
+            OperandParams::ShortInlineBrTarget
+            | OperandParams::InlineBrTarget
+            | OperandParams::InlineSwitch => return Err(invalid()),
+        };
+        Ok(operand)
+    }
+
+    /// Gets a token from a raw token literal, or from the provider, checked
+    /// to be of a token type expected for the operand
+    fn token<T: TryFrom<mdToken, Error = Error>>(
+        &self,
+        text: &str,
+        line: usize,
+        f: impl Fn(&P, &str) -> Option<mdToken>,
+    ) -> Result<T, Error> {
+        if text.is_empty() {
+            return Err(error(line, "expected token"));
+        }
+        let token = token_literal(text)
+            .or_else(|| f(self.provider, text))
+            .ok_or_else(|| error(line, format!("unresolved token '{}'", text)))?;
+        typed_token(token, line)
+    }
+}
+
+/// Checks that a token is of a token type expected for the operand
+fn typed_token<T: TryFrom<mdToken, Error = Error>>(
+    token: mdToken,
+    line: usize,
+) -> Result<T, Error> {
+    T::try_from(token).map_err(|_| error(line, format!("unexpected token 0x{:08x}", token)))
+}
+
+/// Reads IL source text, ignoring comments
+struct Parser {
+    lines: Vec<String>,
+    line: usize,
+    column: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Self {
+        Self {
+            lines: source.lines().map(strip_comment).collect(),
+            line: 0,
+            column: 0,
+        }
+    }
+
+    /// The 1-based number of the current line
+    fn line_number(&self) -> usize {
+        self.line.min(self.lines.len().saturating_sub(1)) + 1
+    }
+
+    fn rest(&self) -> &str {
+        &self.lines[self.line][self.column..]
+    }
+
+    /// Skips whitespace across lines, returning the next character, if any
+    fn skip_whitespace(&mut self) -> Option<char> {
+        while self.line < self.lines.len() {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            let (skipped, next) = (rest.len() - trimmed.len(), trimmed.chars().next());
+            self.column += skipped;
+            match next {
+                Some(c) => return Some(c),
+                None => {
+                    self.line += 1;
+                    self.column = 0;
+                }
+            }
+        }
+        None
+    }
+
+    fn advance(&mut self) {
+        self.column += 1;
+    }
+
+    /// Reads a word up to whitespace or a brace
+    fn word(&mut self) -> String {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || c == '{' || c == '}')
+            .unwrap_or(rest.len());
+        let word = rest[..len].to_string();
+        self.column += len;
+        word
+    }
+
+    /// Reads the remainder of the current line
+    fn rest_of_line(&mut self) -> String {
+        let rest = self.rest().trim().to_string();
+        self.column = self.lines[self.line].len();
+        rest
+    }
+
+    /// Reads the remainder of the current line up to an opening brace
+    fn until_brace(&mut self) -> String {
+        let rest = self.rest();
+        let len = rest.find('{').unwrap_or(rest.len());
+        let text = rest[..len].trim().to_string();
+        self.column += len;
+        text
+    }
+}
+
+fn error<S: Into<String>>(line: usize, message: S) -> Error {
+    Error::InvalidIlSource {
+        line,
+        message: message.into(),
+    }
+}
+
+/// Removes a `//` comment from a line, ignoring `//` inside string literals
+fn strip_comment(line: &str) -> String {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut previous = None;
+    for (i, c) in line.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '/' && previous == Some('/') {
+            return line[..i - 1].to_string();
+        }
+        previous = Some(c);
+    }
+    line.to_string()
+}
+
+/// Parses a decimal or hexadecimal integer, with an optional sign
+fn integer(text: &str) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Parses a raw token written as a hexadecimal literal e.g. `0x0a000001`
+fn token_literal(text: &str) -> Option<mdToken> {
+    let hex = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))?;
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Parses the target labels of a branch or switch instruction
+fn branch_targets(opcode: &Opcode, text: &str) -> Option<Vec<String>> {
+    let is_label = |t: &str| !t.is_empty() && !t.contains(char::is_whitespace);
+    if opcode.operand_params == OperandParams::InlineSwitch {
+        let inner = text.strip_prefix('(')?.strip_suffix(')')?.trim();
+        if inner.is_empty() {
+            return Some(vec![]);
+        }
+        inner
+            .split(',')
+            .map(|t| Some(t.trim()).filter(|t| is_label(t)).map(str::to_string))
+            .collect()
+    } else if is_label(text) {
+        Some(vec![text.to_string()])
+    } else {
+        None
+    }
+}
+
+/// Parses a quoted string literal, processing escape sequences
+fn unquote(text: &str) -> Option<String> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '"' => value.push('"'),
+            '\\' => value.push('\\'),
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            'u' => {
+                let hex: String = chars.by_ref().take(4).collect();
+                value.push(std::char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{
+            Assembler, CorExceptionFlag, Disassembler, Instruction, Method, MethodToken, Operand,
+            Section, TokenProvider, TokenResolver,
+        },
+        error::Error,
+        ffi::mdToken,
+    };
+    use std::convert::TryFrom;
+
+    struct TestProvider;
+
+    impl TokenProvider for TestProvider {
+        fn type_token(&self, name: &str) -> Option<mdToken> {
+            match name {
+                "[System.Runtime]System.Exception" => Some(0x01000001),
+                _ => None,
+            }
+        }
+        fn method_token(&self, name: &str) -> Option<mdToken> {
+            match name {
+                "void Foo::Bar()" => Some(0x0a000001),
+                _ => None,
+            }
+        }
+        fn string_token(&self, value: &str) -> Option<mdToken> {
+            match value {
+                "a \"quoted\" // string" => Some(0x70000001),
+                _ => None,
+            }
+        }
+    }
+
+    impl TokenResolver for TestProvider {}
+
+    #[test]
+    fn assemble_labels_and_clauses() {
+        let source = r#"
+            .maxstack 2
+            .locals init (0x11000001)
+            .try
+            {
+              .try
+              {
+                ldarg.0
+                brfalse.s  EXIT // skip the call
+                call       void Foo::Bar()
+                ldstr      "a \"quoted\" // string"
+                pop
+              EXIT:
+                leave.s    END
+              }
+              catch [System.Runtime]System.Exception
+              {
+                pop
+                leave      END
+              }
+            }
+            finally
+            {
+              endfinally
+            }
+            END: ret
+        "#;
+
+        let method = Assembler::new(&TestProvider).assemble(source).unwrap();
+        assert_eq!(method.header.max_stack(), 2);
+        assert_eq!(method.header.local_var_sig_tok(), 0x11000001);
+        assert!(method.header.init_locals());
+        assert_eq!(method.header.code_size(), 24);
+
+        match &method.instructions[1].operand {
+            Operand::ShortInlineBrTarget(t) => assert_eq!(*t, 11),
+            o => panic!("unexpected operand {:?}", o),
+        }
+        match &method.instructions[3].operand {
+            Operand::InlineString(t) => assert_eq!(t.token(), 0x70000001),
+            o => panic!("unexpected operand {:?}", o),
+        }
+        match &method.instructions[7].operand {
+            Operand::InlineBrTarget(t) => assert_eq!(*t, 1),
+            o => panic!("unexpected operand {:?}", o),
+        }
+
+        match &method.sections[0] {
+            Section::FatSection(_, clauses) => {
+                assert_eq!(clauses.len(), 2);
+                assert_eq!(
+                    clauses[0].flag,
+                    CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE
+                );
+                assert_eq!(clauses[0].try_offset, 0);
+                assert_eq!(clauses[0].try_length, 16);
+                assert_eq!(clauses[0].handler_offset, 16);
+                assert_eq!(clauses[0].handler_length, 6);
+                assert_eq!(clauses[0].class_token_or_filter_offset, 0x01000001);
+                assert_eq!(
+                    clauses[1].flag,
+                    CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY
+                );
+                assert_eq!(clauses[1].try_offset, 0);
+                assert_eq!(clauses[1].try_length, 22);
+                assert_eq!(clauses[1].handler_offset, 22);
+                assert_eq!(clauses[1].handler_length, 1);
+            }
+            s => panic!("unexpected section {:?}", s),
+        }
+
+        let tiny = Assembler::new(&TestProvider)
+            .assemble("ldc.i4.s -1\nswitch (A, B)\nA: B: ret")
+            .unwrap();
+        assert_eq!(tiny.header.code_size(), 16);
+        assert_eq!(tiny.into_bytes()[0], 16 << 2 | 0x2);
+    }
+
+    #[test]
+    fn assemble_disassembled_method() {
+        let mut method = Method::tiny(vec![
+            Instruction::ldarg_0(),
+            Instruction::brfalse_s(6),
+            Instruction::ldarg_0(),
+            Instruction::call(MethodToken::try_from(0x0a000001).unwrap()),
+            Instruction::leave_s(7),
+            Instruction::pop(),
+            Instruction::ldc_i4_1(),
+            Instruction::endfilter(),
+            Instruction::pop(),
+            Instruction::leave_s(0),
+            Instruction::ret(),
+        ])
+        .unwrap();
+        method.expand_tiny_to_fat();
+        method
+            .push_clause(crate::cil::FatSectionClause {
+                flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER,
+                try_offset: 0,
+                try_length: 11,
+                handler_offset: 15,
+                handler_length: 3,
+                class_token_or_filter_offset: 11,
+            })
+            .unwrap();
+
+        let source = Disassembler::new(&TestProvider).disassemble(&method);
+        let assembled = Assembler::new(&TestProvider).assemble(&source).unwrap();
+        assert_eq!(assembled.into_bytes(), method.into_bytes());
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let assemble = |source: &str| Assembler::new(&TestProvider).assemble(source);
+        let line_of = |result: Result<Method, Error>| match result {
+            Err(Error::InvalidIlSource { line, .. }) => line,
+            r => panic!("unexpected result {:?}", r),
+        };
+
+        assert_eq!(line_of(assemble("nop\nbr.s MISSING")), 2);
+        assert_eq!(line_of(assemble("nop\nfoo.bar")), 2);
+        assert_eq!(line_of(assemble("call void Baz::Qux()")), 1);
+        assert_eq!(line_of(assemble(".try\n{\nnop\n")), 3);
+        assert_eq!(line_of(assemble("nop\n}")), 2);
+        assert_eq!(
+            line_of(assemble("catch [System.Runtime]System.Exception")),
+            1
+        );
+        assert_eq!(line_of(assemble("ldc.i4.s 128")), 1);
+        assert_eq!(line_of(assemble("A: nop\nA: ret")), 2);
+
+        let mut long = String::from("br.s END\n");
+        long.push_str(&"nop\n".repeat(128));
+        long.push_str("END: ret");
+        assert_eq!(line_of(assemble(&long)), 1);
+    }
+}
@@ -0,0 +1,581 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{
+        CorExceptionFlag, FatSectionClause, Instruction, Method, MethodHeader, Opcode, Operand,
+        OperandParams, Section, TinyMethodHeader, SWITCH,
+    },
+    error::Error,
+    ffi::{mdToken, mdTokenNil},
+};
+use std::{collections::HashMap, convert::TryFrom};
+
+/// A position in the instruction stream of a [MethodBuilder], resolved
+/// to an offset when the method is built
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Label(usize);
+
+/// The kind of handler of an [ExceptionClause]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ClauseKind {
+    /// A typed handler for the exception class token
+    Catch(mdToken),
+    /// A filtered handler, with the label of the start of the filter block
+    Filter(Label),
+    Finally,
+    Fault,
+}
+
+/// An exception handling clause whose boundaries are labels.
+/// The end labels are exclusive.
+#[derive(Debug, Copy, Clone)]
+pub struct ExceptionClause {
+    pub kind: ClauseKind,
+    pub try_start: Label,
+    pub try_end: Label,
+    pub handler_start: Label,
+    pub handler_end: Label,
+}
+
+#[derive(Debug)]
+enum Item {
+    Instruction(Instruction),
+    Branch(Opcode, Label),
+    Switch(Vec<Label>),
+    Mark(Label),
+    /// The offset in the original IL of the next instruction, branch or switch
+    Origin(u32),
+}
+
+/// Builds a method body where branches, switch targets and exception clause
+/// boundaries reference labels rather than offsets. Labels are resolved when
+/// the method is built, and each branch uses its short form when the target is
+/// within range, otherwise its long form.
+///
+/// ```ignore
+/// let mut builder = MethodBuilder::new();
+/// let end = builder.define_label();
+/// builder.emit(Instruction::ldarg_0());
+/// builder.emit_branch(BRFALSE, end);
+/// builder.emit(Instruction::call(token));
+/// builder.mark_label(end);
+/// builder.emit(Instruction::ret());
+/// let bytes = builder.into_bytes()?;
+/// ```
+#[derive(Debug)]
+pub struct MethodBuilder {
+    items: Vec<Item>,
+    label_count: usize,
+    clauses: Vec<ExceptionClause>,
+    max_stack: u16,
+    local_var_sig_tok: mdToken,
+    init_locals: bool,
+}
+
+impl Default for MethodBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MethodBuilder {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            label_count: 0,
+            clauses: Vec::new(),
+            max_stack: 0,
+            local_var_sig_tok: mdTokenNil,
+            init_locals: false,
+        }
+    }
+
+    /// Creates a builder from an existing method, with labels for all of its
+    /// branch targets and exception clause boundaries
+    pub fn from_method(method: &Method) -> Result<Self, Error> {
+        let mut builder = Self::new();
+        builder.max_stack = method.header.max_stack();
+        builder.local_var_sig_tok = method.header.local_var_sig_tok();
+        builder.init_locals = method.header.init_locals();
+        builder.append_method(method)?;
+        Ok(builder)
+    }
+
+    pub fn max_stack(&self) -> u16 {
+        self.max_stack
+    }
+
+    pub fn set_max_stack(&mut self, max_stack: u16) {
+        self.max_stack = max_stack;
+    }
+
+    pub fn set_local_var_sig_tok(&mut self, token: mdToken) {
+        self.local_var_sig_tok = token;
+    }
+
+    pub fn set_init_locals(&mut self, init_locals: bool) {
+        self.init_locals = init_locals;
+    }
+
+    /// Defines a new label, to be marked later with [MethodBuilder::mark_label]
+    pub fn define_label(&mut self) -> Label {
+        let label = Label(self.label_count);
+        self.label_count += 1;
+        label
+    }
+
+    /// Marks the label at the position of the next emitted instruction
+    pub fn mark_label(&mut self, label: Label) {
+        self.items.push(Item::Mark(label));
+    }
+
+    /// Defines a new label at the position of the next emitted instruction
+    pub fn mark_new_label(&mut self) -> Label {
+        let label = self.define_label();
+        self.mark_label(label);
+        label
+    }
+
+    /// Emits an instruction, growing max stack by its stack size.
+    /// Branches should be emitted with [MethodBuilder::emit_branch]
+    /// and [MethodBuilder::emit_switch].
+    pub fn emit(&mut self, instruction: Instruction) {
+        self.max_stack = self
+            .max_stack
+            .saturating_add(instruction.stack_size() as u16);
+        self.items.push(Item::Instruction(instruction));
+    }
+
+    pub fn emit_all<I: IntoIterator<Item = Instruction>>(&mut self, instructions: I) {
+        for instruction in instructions {
+            self.emit(instruction);
+        }
+    }
+
+    /// Emits a branch to the label. Either the short or long form of the
+    /// opcode can be passed; the form is chosen when the method is built.
+    pub fn emit_branch(&mut self, opcode: Opcode, target: Label) {
+        self.items
+            .push(Item::Branch(Opcode::short_to_long_form(opcode), target));
+    }
+
+    pub fn emit_switch(&mut self, targets: Vec<Label>) {
+        self.items.push(Item::Switch(targets));
+    }
+
+    /// Adds an exception clause. Clauses must be added innermost first.
+    pub fn add_clause(&mut self, clause: ExceptionClause) {
+        self.clauses.push(clause);
+    }
+
+    /// Appends the instructions and exception clauses of a method
+    pub fn append_method(&mut self, method: &Method) -> Result<(), Error> {
+        self.append_method_with(method, |_, _, _| false)
+    }
+
+    /// Appends the instructions and exception clauses of a method. Each
+    /// instruction is first passed to `rewrite` with its index in the method,
+    /// which may emit replacement instructions and return `true` to skip the
+    /// original instruction. Branch instructions are not passed to `rewrite`.
+    /// The original offsets of the instructions are kept in the built method,
+    /// with the first replacement instruction taking the original offset of the
+    /// instruction it replaces.
+    pub fn append_method_with<F>(&mut self, method: &Method, mut rewrite: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut Self, usize, &Instruction) -> bool,
+    {
+        let mut offsets = method.get_instruction_offsets();
+        offsets.push(
+            method
+                .instructions
+                .iter()
+                .map(|i| i.len() as u32)
+                .sum::<u32>(),
+        );
+
+        let mut labels: HashMap<u32, Label> = HashMap::new();
+        let mut label_at = |builder: &mut Self, offset: u32| -> Result<Label, Error> {
+            if offsets.binary_search(&offset).is_err() {
+                return Err(Error::InvalidCil);
+            }
+            Ok(*labels
+                .entry(offset)
+                .or_insert_with(|| builder.define_label()))
+        };
+
+        let mut branches = Vec::with_capacity(method.instructions.len());
+        for (i, instruction) in method.instructions.iter().enumerate() {
+            let next = offsets[i + 1] as i64;
+            let branch = match &instruction.operand {
+                Operand::ShortInlineBrTarget(delta) => Some(Item::Branch(
+                    Opcode::short_to_long_form(instruction.opcode),
+                    label_at(self, (next + *delta as i64) as u32)?,
+                )),
+                Operand::InlineBrTarget(delta) => Some(Item::Branch(
+                    instruction.opcode,
+                    label_at(self, (next + *delta as i64) as u32)?,
+                )),
+                Operand::InlineSwitch(_, deltas) => {
+                    let mut targets = Vec::with_capacity(deltas.len());
+                    for delta in deltas {
+                        targets.push(label_at(self, (next + *delta as i64) as u32)?);
+                    }
+                    Some(Item::Switch(targets))
+                }
+                _ => None,
+            };
+            branches.push(branch);
+        }
+
+        let mut clauses = Vec::new();
+        for clause in method.sections.iter().flat_map(|s| s.fat_clauses()) {
+            let flag = clause.flag;
+            let token = clause.class_token_or_filter_offset;
+            let kind = if flag.contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER) {
+                ClauseKind::Filter(label_at(self, token)?)
+            } else if flag.contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY) {
+                ClauseKind::Finally
+            } else if flag.contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT) {
+                ClauseKind::Fault
+            } else {
+                ClauseKind::Catch(token)
+            };
+            clauses.push(ExceptionClause {
+                kind,
+                try_start: label_at(self, clause.try_offset)?,
+                try_end: label_at(self, clause.try_offset + clause.try_length)?,
+                handler_start: label_at(self, clause.handler_offset)?,
+                handler_end: label_at(self, clause.handler_offset + clause.handler_length)?,
+            });
+        }
+
+        for (i, (instruction, branch)) in method.instructions.iter().zip(branches).enumerate() {
+            if let Some(label) = labels.get(&offsets[i]) {
+                self.mark_label(*label);
+            }
+            if let Some(offset) = method.original_offset(i) {
+                self.items.push(Item::Origin(offset));
+            }
+            match branch {
+                Some(branch) => self.items.push(branch),
+                None => {
+                    if !rewrite(self, i, instruction) {
+                        self.items.push(Item::Instruction(instruction.clone()));
+                    }
+                }
+            }
+        }
+        if let Some(label) = labels.get(&offsets[method.instructions.len()]) {
+            self.mark_label(*label);
+        }
+
+        self.clauses.append(&mut clauses);
+        Ok(())
+    }
+
+    /// Builds the method, resolving labels to offsets. A tiny header is used
+    /// when the method has no locals, exception clauses or more than 8 stack slots,
+    /// and its code fits in a tiny header.
+    pub fn into_method(self) -> Result<Method, Error> {
+        let mut positions: Vec<Option<usize>> = vec![None; self.label_count];
+        for (i, item) in self.items.iter().enumerate() {
+            if let Item::Mark(Label(label)) = item {
+                if positions[*label].replace(i).is_some() {
+                    return Err(Error::InvalidCil);
+                }
+            }
+        }
+        let position = |label: &Label| positions[label.0].ok_or(Error::InvalidCil);
+
+        // start with the short form of each branch where there is one, and promote
+        // to the long form until all targets are in range. Promoting a branch only
+        // ever increases offsets, so this terminates.
+        let mut forms: Vec<Option<bool>> = self
+            .items
+            .iter()
+            .map(|item| match item {
+                Item::Branch(opcode, _) => Some(Opcode::long_to_short_form(*opcode).is_some()),
+                _ => None,
+            })
+            .collect();
+
+        let mut offsets;
+        loop {
+            offsets = Vec::with_capacity(self.items.len() + 1);
+            let mut offset = 0usize;
+            for (item, form) in self.items.iter().zip(&forms) {
+                offsets.push(offset);
+                offset += match (item, form) {
+                    (Item::Instruction(instruction), _) => instruction.len(),
+                    (Item::Branch(opcode, _), Some(true)) => {
+                        Opcode::long_to_short_form(*opcode).unwrap().len as usize + 1
+                    }
+                    (Item::Branch(opcode, _), _) => opcode.len as usize + 4,
+                    (Item::Switch(targets), _) => SWITCH.len as usize + 4 + 4 * targets.len(),
+                    (Item::Mark(_), _) | (Item::Origin(_), _) => 0,
+                };
+            }
+            offsets.push(offset);
+
+            let mut promoted = false;
+            for (i, item) in self.items.iter().enumerate() {
+                if let (Item::Branch(_, target), Some(true)) = (item, forms[i]) {
+                    let delta = offsets[position(target)?] as i64 - offsets[i + 1] as i64;
+                    if i8::try_from(delta).is_err() {
+                        forms[i] = Some(false);
+                        promoted = true;
+                    }
+                }
+            }
+            if !promoted {
+                break;
+            }
+        }
+
+        let delta = |i: usize, target: &Label| -> Result<i64, Error> {
+            Ok(offsets[position(target)?] as i64 - offsets[i + 1] as i64)
+        };
+
+        let mut instructions = Vec::with_capacity(self.items.len());
+        let mut original_offsets = Vec::with_capacity(self.items.len());
+        let mut origin = None;
+        for (i, item) in self.items.iter().enumerate() {
+            match item {
+                Item::Mark(_) => continue,
+                Item::Origin(offset) => {
+                    origin = Some(*offset);
+                    continue;
+                }
+                _ => original_offsets.push(origin.take()),
+            }
+            match item {
+                Item::Instruction(instruction) => instructions.push(instruction.clone()),
+                Item::Branch(opcode, target) => {
+                    if opcode.operand_params != OperandParams::InlineBrTarget {
+                        return Err(Error::InvalidCil);
+                    }
+                    let delta = delta(i, target)?;
+                    instructions.push(if forms[i] == Some(true) {
+                        Instruction {
+                            opcode: Opcode::long_to_short_form(*opcode).unwrap(),
+                            operand: Operand::ShortInlineBrTarget(delta as i8),
+                        }
+                    } else {
+                        Instruction {
+                            opcode: *opcode,
+                            operand: Operand::InlineBrTarget(
+                                i32::try_from(delta).map_err(|_| Error::CodeSize)?,
+                            ),
+                        }
+                    });
+                }
+                Item::Switch(targets) => {
+                    let mut deltas = Vec::with_capacity(targets.len());
+                    for target in targets {
+                        deltas.push(i32::try_from(delta(i, target)?).map_err(|_| Error::CodeSize)?);
+                    }
+                    instructions.push(Instruction {
+                        opcode: SWITCH,
+                        operand: Operand::InlineSwitch(deltas.len() as u32, deltas),
+                    });
+                }
+                Item::Mark(_) | Item::Origin(_) => (),
+            }
+        }
+
+        let offset = |label: &Label| -> Result<u32, Error> {
+            u32::try_from(offsets[position(label)?]).map_err(|_| Error::CodeSize)
+        };
+        let length = |start: &Label, end: &Label| -> Result<u32, Error> {
+            offset(end)?
+                .checked_sub(offset(start)?)
+                .ok_or(Error::InvalidSectionHeader)
+        };
+
+        let mut clauses = Vec::with_capacity(self.clauses.len());
+        for clause in &self.clauses {
+            let (flag, class_token_or_filter_offset) = match &clause.kind {
+                ClauseKind::Catch(token) => (CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE, *token),
+                ClauseKind::Filter(label) => (
+                    CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER,
+                    offset(label)?,
+                ),
+                ClauseKind::Finally => (CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY, 0),
+                ClauseKind::Fault => (CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT, 0),
+            };
+            clauses.push(FatSectionClause {
+                flag,
+                try_offset: offset(&clause.try_start)?,
+                try_length: length(&clause.try_start, &clause.try_end)?,
+                handler_offset: offset(&clause.handler_start)?,
+                handler_length: length(&clause.handler_start, &clause.handler_end)?,
+                class_token_or_filter_offset,
+            });
+        }
+
+        let code_size = u32::try_from(offsets[self.items.len()]).map_err(|_| Error::CodeSize)?;
+        let sections = if clauses.is_empty() {
+            vec![]
+        } else {
+            vec![Section::from_clauses(clauses)?]
+        };
+
+        let header = if sections.is_empty()
+            && self.local_var_sig_tok == mdTokenNil
+            && self.max_stack <= TinyMethodHeader::MAX_STACK as u16
+            && code_size <= TinyMethodHeader::MAX_CODE_SIZE as u32
+        {
+            MethodHeader::tiny(code_size as u8)
+        } else {
+            MethodHeader::fat(
+                !sections.is_empty(),
+                self.init_locals,
+                self.max_stack,
+                code_size,
+                self.local_var_sig_tok,
+            )
+        };
+
+        Ok(Method {
+            address: 0,
+            header,
+            instructions,
+            sections,
+            original_offsets,
+        })
+    }
+
+    /// Builds the method and returns its bytes
+    pub fn into_bytes(self) -> Result<Vec<u8>, Error> {
+        Ok(self.into_method()?.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cil::{
+        ClauseKind, ExceptionClause, Instruction, Method, MethodBuilder, Operand, Section, BR,
+        BRFALSE_S, LEAVE, RET,
+    };
+
+    #[test]
+    fn branches_are_sized_by_distance() {
+        let mut builder = MethodBuilder::new();
+        let near = builder.define_label();
+        let far = builder.define_label();
+        let back = builder.mark_new_label();
+        builder.emit(Instruction::ldarg_0());
+        builder.emit_branch(BRFALSE_S, far);
+        builder.emit_branch(BR, near);
+        builder.mark_label(near);
+        builder.emit_switch(vec![near, far]);
+        // brfalse is in range of the short form until the branch back to the
+        // start is promoted to the long form
+        builder.emit_all((0..110).map(|_| Instruction::nop()));
+        builder.emit_branch(BR, back);
+        builder.mark_label(far);
+        builder.emit(Instruction::ret());
+
+        let method = builder.into_method().unwrap();
+        let operands: Vec<&Operand> = method
+            .instructions
+            .iter()
+            .filter(|i| i.opcode.control_flow != crate::cil::ControlFlow::Next)
+            .map(|i| &i.operand)
+            .collect();
+
+        // brfalse far, br near, switch, br back, ret
+        match operands[..] {
+            [Operand::InlineBrTarget(far), Operand::ShortInlineBrTarget(0), Operand::InlineSwitch(2, ref targets), Operand::InlineBrTarget(back), Operand::InlineNone] =>
+            {
+                assert_eq!(*far, 2 + 13 + 110 + 5);
+                assert_eq!(targets, &vec![-13, 110 + 5]);
+                assert_eq!(*back, -(1 + 5 + 2 + 13 + 110 + 5));
+            }
+            _ => panic!("unexpected operands {:?}", operands),
+        }
+    }
+
+    #[test]
+    fn append_method_relocates_branches_and_clauses() {
+        let mut original = Method::tiny(vec![
+            Instruction::ldarg_0(),
+            Instruction::brtrue_s(1),
+            Instruction::ret(),
+            Instruction::leave_s(1),
+            Instruction::endfinally(),
+            Instruction::ret(),
+        ])
+        .unwrap();
+        original.expand_tiny_to_fat();
+        original
+            .push_clause(crate::cil::FatSectionClause {
+                flag: crate::cil::CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                try_offset: 0,
+                try_length: 6,
+                handler_offset: 6,
+                handler_length: 1,
+                class_token_or_filter_offset: 0,
+            })
+            .unwrap();
+
+        let mut builder = MethodBuilder::new();
+        let start = builder.mark_new_label();
+        builder.emit_all((0..130).map(|_| Instruction::nop()));
+        let end = builder.define_label();
+        builder
+            .append_method_with(&original, |b, _, instruction| {
+                if instruction.opcode == RET {
+                    b.emit_branch(LEAVE, end);
+                    true
+                } else {
+                    false
+                }
+            })
+            .unwrap();
+        let handler_start = builder.mark_new_label();
+        builder.emit(Instruction::endfinally());
+        builder.mark_label(end);
+        builder.emit(Instruction::ret());
+        builder.add_clause(ExceptionClause {
+            kind: ClauseKind::Finally,
+            try_start: start,
+            try_end: handler_start,
+            handler_start,
+            handler_end: end,
+        });
+
+        let method = builder.into_method().unwrap();
+        let instructions = &method.instructions[130..];
+        assert_eq!(instructions[1].opcode.name, "brtrue.s");
+        assert_eq!(instructions[2].opcode.name, "leave.s");
+        assert_eq!(instructions[3].opcode.name, "leave.s");
+        let deltas: Vec<i8> = instructions
+            .iter()
+            .filter_map(|i| match i.operand {
+                Operand::ShortInlineBrTarget(t) => Some(t),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, vec![2, 6, 1, 1]);
+
+        match &method.sections[0] {
+            Section::SmallSection(_, clauses) => {
+                assert_eq!(clauses.len(), 2);
+                assert_eq!(clauses[0].try_offset, 130);
+                assert_eq!(clauses[0].try_length, 7);
+                assert_eq!(clauses[0].handler_offset, 137);
+                assert_eq!(clauses[0].handler_length, 1);
+                assert_eq!(clauses[1].try_offset, 0);
+                assert_eq!(clauses[1].try_length, 140);
+                assert_eq!(clauses[1].handler_length, 1);
+            }
+            s => panic!("unexpected section {:?}", s),
+        }
+
+        assert!(MethodBuilder::from_method(&method).is_ok());
+    }
+}
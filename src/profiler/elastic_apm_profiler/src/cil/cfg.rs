@@ -0,0 +1,501 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{
+        ControlFlow, CorExceptionFlag, Instruction, Method, Operand, ENDFILTER, ENDFINALLY, JMP,
+        LEAVE, LEAVE_S, RET, RETHROW, SWITCH,
+    },
+    error::Error,
+};
+use std::{collections::BTreeSet, convert::TryFrom};
+
+/// How control leaves a method or handler at the end of a basic block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockExit {
+    Return,
+    Throw,
+    Rethrow,
+    EndFinally,
+    EndFilter,
+    Jmp,
+}
+
+/// The kind of transfer of control along an edge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    FallThrough,
+    Branch,
+    Switch,
+    Leave,
+}
+
+/// An edge to a successor basic block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    /// The index of the target block
+    pub target: usize,
+}
+
+/// The part of an exception clause that a region covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Try,
+    Filter,
+    Handler,
+}
+
+/// A protected block, filter or handler of an exception clause
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    /// The index of the clause, in the order clauses are declared
+    pub clause: usize,
+    pub flag: CorExceptionFlag,
+    pub kind: RegionKind,
+    /// The offset of the first instruction in the region
+    pub start: u32,
+    /// The offset after the last instruction in the region
+    pub end: u32,
+}
+
+impl Region {
+    pub fn contains(&self, offset: u32) -> bool {
+        self.start <= offset && offset < self.end
+    }
+}
+
+/// A sequence of instructions entered only at the first instruction and
+/// exited only at the last instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// The index of the first instruction
+    pub start: usize,
+    /// The index after the last instruction
+    pub end: usize,
+    /// The offset of the first instruction
+    pub offset: u32,
+    pub successors: Vec<Edge>,
+    /// The indexes of the blocks with an edge to this block
+    pub predecessors: Vec<usize>,
+    /// How control leaves the method or handler, when the block ends with
+    /// ret, throw, rethrow, endfinally, endfilter or jmp
+    pub exit: Option<BlockExit>,
+    /// The indexes of the regions containing the block, innermost first
+    pub regions: Vec<usize>,
+}
+
+impl BasicBlock {
+    /// The index of the last instruction in the block
+    pub fn last(&self) -> usize {
+        self.end - 1
+    }
+}
+
+/// The basic blocks of a method, and the edges between them
+#[derive(Debug)]
+pub struct ControlFlowGraph {
+    /// Blocks in the order of their instructions
+    pub blocks: Vec<BasicBlock>,
+    /// Regions of the exception clauses, in the order clauses are declared
+    pub regions: Vec<Region>,
+}
+
+impl ControlFlowGraph {
+    /// Builds the control flow graph of the method. Returns [Error::InvalidCil]
+    /// when a branch target or clause boundary is not the start of an instruction.
+    pub fn new(method: &Method) -> Result<Self, Error> {
+        let mut offsets = method.get_instruction_offsets();
+        let code_size = offsets.last().copied().unwrap_or_default()
+            + method.instructions.last().map_or(0, |i| i.len() as u32);
+        offsets.push(code_size);
+
+        let index_of = |offset: i64| -> Result<usize, Error> {
+            u32::try_from(offset)
+                .ok()
+                .and_then(|o| offsets.binary_search(&o).ok())
+                .ok_or(Error::InvalidCil)
+        };
+        let target_of = |offset: i64| -> Result<usize, Error> {
+            index_of(offset).and_then(|i| {
+                if i < method.instructions.len() {
+                    Ok(i)
+                } else {
+                    Err(Error::InvalidCil)
+                }
+            })
+        };
+
+        let mut regions = Vec::new();
+        for (clause, c) in method
+            .sections
+            .iter()
+            .flat_map(|s| s.fat_clauses())
+            .enumerate()
+        {
+            let try_end = c
+                .try_offset
+                .checked_add(c.try_length)
+                .ok_or(Error::InvalidSectionHeader)?;
+            let handler_end = c
+                .handler_offset
+                .checked_add(c.handler_length)
+                .ok_or(Error::InvalidSectionHeader)?;
+            let mut add = |kind, start: u32, end: u32| {
+                regions.push(Region {
+                    clause,
+                    flag: c.flag,
+                    kind,
+                    start,
+                    end,
+                })
+            };
+            add(RegionKind::Try, c.try_offset, try_end);
+            if c.flag
+                .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER)
+            {
+                add(
+                    RegionKind::Filter,
+                    c.class_token_or_filter_offset,
+                    c.handler_offset,
+                );
+            }
+            add(RegionKind::Handler, c.handler_offset, handler_end);
+        }
+
+        // a block starts at the first instruction, at each branch target and
+        // region boundary, and after each instruction that transfers control
+        let mut leaders = BTreeSet::new();
+        if !method.instructions.is_empty() {
+            leaders.insert(0);
+        }
+        for region in &regions {
+            leaders.insert(index_of(region.start as i64)?);
+            leaders.insert(index_of(region.end as i64)?);
+        }
+        for (index, instruction) in method.instructions.iter().enumerate() {
+            for target in Self::targets(instruction, offsets[index + 1]) {
+                leaders.insert(target_of(target)?);
+            }
+            if Self::ends_block(instruction) {
+                leaders.insert(index + 1);
+            }
+        }
+        leaders.remove(&method.instructions.len());
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let block_of = |index: usize| -> usize { starts.binary_search(&index).unwrap() };
+        let mut blocks: Vec<BasicBlock> = Vec::with_capacity(starts.len());
+        for (i, start) in starts.iter().enumerate() {
+            let start = *start;
+            let end = starts
+                .get(i + 1)
+                .copied()
+                .unwrap_or(method.instructions.len());
+            let offset = offsets[start];
+            let instruction = &method.instructions[end - 1];
+
+            let mut successors = Vec::new();
+            let kind = if instruction.opcode == LEAVE || instruction.opcode == LEAVE_S {
+                EdgeKind::Leave
+            } else if instruction.opcode == SWITCH {
+                EdgeKind::Switch
+            } else {
+                EdgeKind::Branch
+            };
+            for target in Self::targets(instruction, offsets[end]) {
+                successors.push(Edge {
+                    kind,
+                    target: block_of(target_of(target)?),
+                });
+            }
+            if Self::falls_through(instruction) && end < method.instructions.len() {
+                successors.push(Edge {
+                    kind: EdgeKind::FallThrough,
+                    target: i + 1,
+                });
+            }
+
+            let exit = if instruction.opcode == RET {
+                Some(BlockExit::Return)
+            } else if instruction.opcode == RETHROW {
+                Some(BlockExit::Rethrow)
+            } else if instruction.opcode == ENDFINALLY {
+                Some(BlockExit::EndFinally)
+            } else if instruction.opcode == ENDFILTER {
+                Some(BlockExit::EndFilter)
+            } else if instruction.opcode == JMP {
+                Some(BlockExit::Jmp)
+            } else if instruction.opcode.control_flow == ControlFlow::Throw {
+                Some(BlockExit::Throw)
+            } else {
+                None
+            };
+
+            let mut containing: Vec<usize> = regions
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| r.contains(offset))
+                .map(|(r, _)| r)
+                .collect();
+            containing.sort_by_key(|r| regions[*r].end - regions[*r].start);
+
+            blocks.push(BasicBlock {
+                start,
+                end,
+                offset,
+                successors,
+                predecessors: Vec::new(),
+                exit,
+                regions: containing,
+            });
+        }
+
+        for i in 0..blocks.len() {
+            for edge in blocks[i].successors.clone() {
+                if !blocks[edge.target].predecessors.contains(&i) {
+                    blocks[edge.target].predecessors.push(i);
+                }
+            }
+        }
+
+        Ok(ControlFlowGraph { blocks, regions })
+    }
+
+    /// Gets the index of the block containing the instruction at the index
+    pub fn block_of(&self, instruction: usize) -> Option<usize> {
+        match self.blocks.binary_search_by_key(&instruction, |b| b.start) {
+            Ok(block) => Some(block),
+            Err(0) => None,
+            Err(block) if instruction < self.blocks[block - 1].end => Some(block - 1),
+            Err(_) => None,
+        }
+    }
+
+    /// Gets the blocks within the region
+    pub fn region_blocks(&self, region: usize) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks
+            .iter()
+            .filter(move |b| b.regions.contains(&region))
+    }
+
+    /// Gets the indexes of the blocks that end with the exit
+    pub fn exits(&self, exit: BlockExit) -> impl Iterator<Item = usize> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(move |(_, b)| b.exit == Some(exit))
+            .map(|(i, _)| i)
+    }
+
+    /// Gets the indexes of the ret instructions in the method
+    pub fn return_points(&self) -> Vec<usize> {
+        self.exits(BlockExit::Return)
+            .map(|b| self.blocks[b].last())
+            .collect()
+    }
+
+    /// Gets whether control only passes from the instructions before the index to the
+    /// instructions from the index by falling through to the instruction at the index,
+    /// and never passes back, and no region spans the index. Instructions inserted at
+    /// the index then run exactly once on every path through the method that reaches it
+    pub fn falls_through_to(&self, instruction: usize) -> bool {
+        let (before, after) = match (
+            instruction.checked_sub(1).and_then(|i| self.block_of(i)),
+            self.block_of(instruction),
+        ) {
+            (Some(before), Some(after)) => (&self.blocks[before], &self.blocks[after]),
+            _ => return false,
+        };
+        if before.regions.iter().any(|r| after.regions.contains(r)) {
+            return false;
+        }
+
+        self.blocks.iter().all(|block| {
+            block.successors.iter().all(|edge| {
+                let target = self.blocks[edge.target].start;
+                if block.last() >= instruction {
+                    target >= instruction
+                } else {
+                    target < instruction
+                        || (target == instruction && edge.kind == EdgeKind::FallThrough)
+                }
+            })
+        })
+    }
+
+    fn targets(instruction: &Instruction, next: u32) -> Vec<i64> {
+        let next = next as i64;
+        match &instruction.operand {
+            Operand::ShortInlineBrTarget(delta) => vec![next + *delta as i64],
+            Operand::InlineBrTarget(delta) => vec![next + *delta as i64],
+            Operand::InlineSwitch(_, deltas) => deltas.iter().map(|d| next + *d as i64).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn falls_through(instruction: &Instruction) -> bool {
+        match instruction.opcode.control_flow {
+            ControlFlow::Branch | ControlFlow::Return | ControlFlow::Throw => false,
+            _ => instruction.opcode != JMP,
+        }
+    }
+
+    fn ends_block(instruction: &Instruction) -> bool {
+        !Self::falls_through(instruction)
+            || instruction.opcode.control_flow == ControlFlow::CondBranch
+    }
+}
+
+impl Method {
+    /// Builds the control flow graph of the method
+    pub fn control_flow_graph(&self) -> Result<ControlFlowGraph, Error> {
+        ControlFlowGraph::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cil::{
+        BlockExit, CorExceptionFlag, Edge, EdgeKind, FatSectionClause, Instruction, Method,
+        MethodHeader, MethodToken, RegionKind,
+    };
+    use crate::error::Error;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn control_flow_graph_blocks_edges_and_regions() {
+        let instructions = vec![
+            // IL_0000: try
+            Instruction::ldarg_0(),
+            Instruction::switch(2, vec![0, 2]),
+            Instruction::leave_s(5),
+            // IL_0010
+            Instruction::ldarg_0(),
+            Instruction::throw(),
+            // IL_0012: catch
+            Instruction::pop(),
+            Instruction::leave_s(0),
+            // IL_0015
+            Instruction::ldarg_0(),
+            Instruction::brtrue_s(1),
+            Instruction::ret(),
+            // IL_0019
+            Instruction::ldc_i4_0(),
+            Instruction::ret(),
+        ];
+        let code_size: usize = instructions.iter().map(|i| i.len()).sum();
+        let mut method = Method {
+            address: 0,
+            header: MethodHeader::fat(false, false, 8, code_size as u32, 0),
+            instructions,
+            sections: vec![],
+            original_offsets: vec![],
+        };
+        method
+            .push_clauses(vec![FatSectionClause {
+                flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
+                try_offset: 0,
+                try_length: 0x12,
+                handler_offset: 0x12,
+                handler_length: 3,
+                class_token_or_filter_offset: 0x01000001,
+            }])
+            .unwrap();
+
+        let cfg = method.control_flow_graph().unwrap();
+        let starts: Vec<usize> = cfg.blocks.iter().map(|b| b.start).collect();
+        assert_eq!(starts, vec![0, 2, 3, 5, 7, 9, 10]);
+
+        assert_eq!(
+            cfg.blocks[0].successors,
+            vec![
+                Edge {
+                    kind: EdgeKind::Switch,
+                    target: 1
+                },
+                Edge {
+                    kind: EdgeKind::Switch,
+                    target: 2
+                },
+                Edge {
+                    kind: EdgeKind::FallThrough,
+                    target: 1
+                },
+            ]
+        );
+        assert_eq!(
+            cfg.blocks[1].successors,
+            vec![Edge {
+                kind: EdgeKind::Leave,
+                target: 4
+            }]
+        );
+        assert_eq!(cfg.blocks[2].exit, Some(BlockExit::Throw));
+        assert_eq!(cfg.blocks[4].predecessors, vec![1, 3]);
+        assert_eq!(cfg.blocks[6].predecessors, vec![4]);
+
+        assert_eq!(cfg.regions[0].kind, RegionKind::Try);
+        let try_blocks: Vec<usize> = cfg.region_blocks(0).map(|b| b.start).collect();
+        assert_eq!(try_blocks, vec![0, 2, 3]);
+        assert_eq!(cfg.blocks[3].regions, vec![1]);
+        assert_eq!(cfg.block_of(8), Some(4));
+        assert_eq!(cfg.return_points(), vec![9, 11]);
+    }
+
+    #[test]
+    fn control_flow_graph_rejects_overflowing_clause() {
+        let mut method = Method::tiny(vec![Instruction::ret()]).unwrap();
+        method.expand_tiny_to_fat();
+        method
+            .push_clause(FatSectionClause {
+                flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                try_offset: 0,
+                try_length: 1,
+                handler_offset: 1,
+                handler_length: u32::MAX,
+                class_token_or_filter_offset: 0,
+            })
+            .unwrap();
+        assert!(matches!(
+            method.control_flow_graph(),
+            Err(Error::InvalidSectionHeader)
+        ));
+    }
+
+    #[test]
+    fn falls_through_to() {
+        let instructions = vec![
+            Instruction::ldarg_0(),
+            Instruction::brtrue_s(1),
+            Instruction::nop(),
+            // IL_0004
+            Instruction::ldarg_0(),
+            Instruction::call(MethodToken::try_from(0x0A000001).unwrap()),
+            // IL_000A
+            Instruction::ldarg_0(),
+            Instruction::brfalse_s(-3),
+            Instruction::ret(),
+        ];
+        let code_size: usize = instructions.iter().map(|i| i.len()).sum();
+        let method = Method {
+            address: 0,
+            header: MethodHeader::fat(false, false, 8, code_size as u32, 0),
+            instructions,
+            sections: vec![],
+            original_offsets: vec![],
+        };
+
+        let cfg = method.control_flow_graph().unwrap();
+        // the instructions before the call only fall through to the instruction after it,
+        // and the branch back to it does not pass the instructions before it
+        assert!(cfg.falls_through_to(5));
+        // branched to from before
+        assert!(!cfg.falls_through_to(3));
+        // branches back to before
+        assert!(!cfg.falls_through_to(6));
+        assert!(!cfg.falls_through_to(0));
+    }
+}
@@ -105,6 +105,54 @@ pub fn uncompress_data(data: &[u8]) -> Option<(ULONG, usize)> {
     }
 }
 
+/// Given a signed integer, store it in a compressed format, with the sign in the least significant bit.
+/// Based on CorSigCompressSignedInt: https://github.com/dotnet/runtime/blob/01b7e73cd378145264a7cb7a09365b41ed42b240/src/coreclr/inc/cor.h#L2154
+pub fn compress_signed_data(int: i32) -> Option<Vec<u8>> {
+    const SIGN_MASK_ONEBYTE: u32 = 0xffffffc0;
+    const SIGN_MASK_TWOBYTE: u32 = 0xffffe000;
+    const SIGN_MASK_FOURBYTE: u32 = 0xf0000000;
+
+    let sign = (int < 0) as u32;
+    let int = int as u32;
+    for (mask, len) in [
+        (SIGN_MASK_ONEBYTE, 1),
+        (SIGN_MASK_TWOBYTE, 2),
+        (SIGN_MASK_FOURBYTE, 4),
+    ] {
+        if int & mask == 0 || int & mask == mask {
+            let rotated = ((int & !mask) << 1) | sign;
+            let buffer = match len {
+                1 => vec![rotated as BYTE],
+                2 => vec![((rotated >> 8) | 0x80) as BYTE, (rotated & 0xff) as BYTE],
+                _ => vec![
+                    ((rotated >> 24) | 0xC0) as BYTE,
+                    ((rotated >> 16) & 0xff) as BYTE,
+                    ((rotated >> 8) & 0xff) as BYTE,
+                    (rotated & 0xff) as BYTE,
+                ],
+            };
+            return Some(buffer);
+        }
+    }
+
+    None
+}
+
+/// Reads a signed integer stored in a compressed format.
+/// Based on CorSigUncompressSignedInt: https://github.com/dotnet/runtime/blob/01b7e73cd378145264a7cb7a09365b41ed42b240/src/coreclr/inc/cor.h#L1888
+pub fn uncompress_signed_data(data: &[u8]) -> Option<(i32, usize)> {
+    let (rotated, len) = uncompress_data(data)?;
+    let mut int = rotated >> 1;
+    if rotated & 1 == 1 {
+        int |= match len {
+            1 => 0xffffffc0,
+            2 => 0xffffe000,
+            _ => 0xf0000000,
+        };
+    }
+    Some((int as i32, len))
+}
+
 pub fn uncompress_token(data: &[u8]) -> (mdToken, usize) {
     if let Some((uncompressed_data, len)) = uncompress_data(data) {
         let token_type = ENCODE_TOKEN[(uncompressed_data & 0x3) as usize];
@@ -0,0 +1,844 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{
+        uncompress_data, uncompress_token, CorExceptionFlag, Instruction, Method, Operand, Section,
+    },
+    ffi::{
+        mdToken, mdTokenNil, type_from_token, CorCallingConvention, CorElementType, CorTokenType,
+    },
+};
+use num_traits::FromPrimitive;
+
+/// The parent, name and signature of a MethodDef, FieldDef or MemberRef token
+#[derive(Debug, Clone)]
+pub struct MemberName {
+    pub parent: mdToken,
+    pub name: String,
+    pub signature: Vec<u8>,
+}
+
+/// Resolves metadata tokens found in IL operands and signatures.
+///
+/// All methods default to `None`, in which case the raw token is rendered.
+pub trait TokenResolver {
+    /// Gets the name of a TypeDef or TypeRef, qualified with its resolution scope
+    /// when defined outside of the current module e.g. `[System.Runtime]System.Object`.
+    /// Nested types are separated from their enclosing type with `/`.
+    fn type_name(&self, token: mdToken) -> Option<String> {
+        None
+    }
+
+    /// Gets the signature blob of a TypeSpec
+    fn type_spec(&self, token: mdToken) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Gets the parent, name and signature of a MethodDef, FieldDef or MemberRef
+    fn member(&self, token: mdToken) -> Option<MemberName> {
+        None
+    }
+
+    /// Gets the generic method and the instantiation signature of a MethodSpec
+    fn method_spec(&self, token: mdToken) -> Option<(mdToken, Vec<u8>)> {
+        None
+    }
+
+    /// Gets the literal value of a user string
+    fn user_string(&self, token: mdToken) -> Option<String> {
+        None
+    }
+
+    /// Gets the signature blob of a StandAloneSig
+    fn signature(&self, token: mdToken) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Reads compressed values from a signature blob
+struct SigReader<'a> {
+    sig: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> SigReader<'a> {
+    fn new(sig: &'a [u8]) -> Self {
+        Self { sig, idx: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.sig.get(self.idx).copied()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.idx += 1;
+        Some(b)
+    }
+
+    fn data(&mut self) -> Option<u32> {
+        let (data, len) = uncompress_data(self.sig.get(self.idx..)?)?;
+        self.idx += len;
+        Some(data)
+    }
+
+    fn signed_data(&mut self) -> Option<i32> {
+        let start = self.idx;
+        let data = self.data()?;
+        // compressed signed integers are rotated so that the sign bit is the least significant bit
+        let sign_extension: u32 = match self.idx - start {
+            1 => 0xFFFF_FFC0,
+            2 => 0xFFFF_E000,
+            _ => 0xF000_0000,
+        };
+        let value = data >> 1;
+        if data & 1 == 1 {
+            Some((value | sign_extension) as i32)
+        } else {
+            Some(value as i32)
+        }
+    }
+
+    fn token(&mut self) -> Option<mdToken> {
+        let (token, len) = uncompress_token(self.sig.get(self.idx..)?);
+        if len == 0 {
+            return None;
+        }
+        self.idx += len;
+        Some(token)
+    }
+}
+
+/// The textual parts of a method signature
+struct MethodSigText {
+    calling_convention: CorCallingConvention,
+    generic_param_count: u32,
+    return_type: String,
+    params: Vec<String>,
+}
+
+impl MethodSigText {
+    fn prefix(&self) -> &'static str {
+        if self
+            .calling_convention
+            .contains(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_HASTHIS)
+        {
+            if self
+                .calling_convention
+                .contains(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_EXPLICITTHIS)
+            {
+                "instance explicit "
+            } else {
+                "instance "
+            }
+        } else {
+            ""
+        }
+    }
+
+    fn vararg(&self) -> &'static str {
+        if self.calling_convention.bits() & CorCallingConvention::IMAGE_CEE_CS_CALLCONV_MASK.bits()
+            == CorCallingConvention::IMAGE_CEE_CS_CALLCONV_VARARG.bits()
+        {
+            "vararg "
+        } else {
+            ""
+        }
+    }
+}
+
+/// A lexical block of an exception handling clause
+struct Block {
+    start: u32,
+    end: u32,
+    header: Option<String>,
+    footer: &'static str,
+}
+
+/// Disassembles a [Method] into ILDasm-like text, resolving metadata tokens to names
+/// with a [TokenResolver].
+///
+/// The output contains only offsets, opcodes and resolved operands, and does not
+/// depend on the address at which the method body was read, so that it is stable
+/// for comparison.
+pub struct Disassembler<'a, R: TokenResolver> {
+    resolver: &'a R,
+}
+
+impl<'a, R: TokenResolver> Disassembler<'a, R> {
+    pub fn new(resolver: &'a R) -> Self {
+        Self { resolver }
+    }
+
+    /// Disassembles the method header, locals, instructions and exception handling clauses
+    pub fn disassemble(&self, method: &Method) -> String {
+        let mut buf = String::new();
+        buf.push_str(&format!(".maxstack {}\n", method.header.max_stack()));
+
+        let local_var_sig_tok = method.header.local_var_sig_tok();
+        if local_var_sig_tok != mdTokenNil {
+            let init = if method.header.init_locals() {
+                "init "
+            } else {
+                ""
+            };
+            let locals = self
+                .resolver
+                .signature(local_var_sig_tok)
+                .and_then(|sig| self.locals(&sig))
+                .unwrap_or_else(|| self.token(local_var_sig_tok));
+            buf.push_str(&format!(".locals {}({})\n", init, locals));
+        }
+
+        let mut blocks = self.blocks(&method.sections);
+        blocks.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+        let mut blocks = blocks.into_iter().peekable();
+        let mut open: Vec<Block> = Vec::new();
+        let mut offset = 0;
+
+        for instruction in &method.instructions {
+            while open.last().is_some_and(|b| b.end <= offset) {
+                let block = open.pop().unwrap();
+                Self::close(&mut buf, &block, open.len());
+            }
+            while blocks.peek().is_some_and(|b| b.start <= offset) {
+                let block = blocks.next().unwrap();
+                Self::open(&mut buf, &block, open.len());
+                open.push(block);
+            }
+
+            buf.push_str(&"  ".repeat(open.len()));
+            buf.push_str(&self.instruction(offset, instruction));
+            buf.push('\n');
+            offset += instruction.len() as u32;
+        }
+
+        while let Some(block) = open.pop() {
+            Self::close(&mut buf, &block, open.len());
+        }
+
+        buf
+    }
+
+    /// Disassembles a single instruction at the given offset
+    pub fn instruction(&self, offset: u32, instruction: &Instruction) -> String {
+        let next = offset as i64 + instruction.len() as i64;
+        let operand = match &instruction.operand {
+            Operand::InlineNone => String::new(),
+            Operand::ShortInlineVar(v) => v.to_string(),
+            Operand::InlineVar(v) => v.to_string(),
+            Operand::ShortInlineI(v) => v.to_string(),
+            Operand::InlineI(v) => v.to_string(),
+            Operand::InlineI8(v) => v.to_string(),
+            Operand::ShortInlineR(v) => format!("{:?}", v),
+            Operand::InlineR(v) => format!("{:?}", v),
+            Operand::ShortInlineBrTarget(t) => label(next + *t as i64),
+            Operand::InlineBrTarget(t) => label(next + *t as i64),
+            Operand::InlineSwitch(_, targets) => format!(
+                "({})",
+                targets
+                    .iter()
+                    .map(|t| label(next + *t as i64))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Operand::InlineMethod(t) => self.method(t.token()),
+            Operand::InlineField(t) => self.field(t.token()),
+            Operand::InlineType(t) => self.type_def_or_ref(t.token()),
+            Operand::InlineString(t) => self
+                .resolver
+                .user_string(t.token())
+                .map(|s| quote(&s))
+                .unwrap_or_else(|| self.token(t.token())),
+            Operand::InlineSig(t) => self
+                .resolver
+                .signature(t.token())
+                .and_then(|sig| {
+                    let m = self.method_sig(&mut SigReader::new(&sig))?;
+                    Some(format!(
+                        "{}{}{}({})",
+                        m.prefix(),
+                        m.vararg(),
+                        m.return_type,
+                        m.params.join(", ")
+                    ))
+                })
+                .unwrap_or_else(|| self.token(t.token())),
+            Operand::InlineTok(t) => self.inline_tok(t.token()),
+        };
+
+        if operand.is_empty() {
+            format!("{}:  {}", label(offset as i64), instruction.opcode.name)
+        } else {
+            format!(
+                "{}:  {:<10} {}",
+                label(offset as i64),
+                instruction.opcode.name,
+                operand
+            )
+        }
+    }
+
+    /// Renders a type signature blob
+    pub fn type_signature(&self, sig: &[u8]) -> Option<String> {
+        self.sig_type(&mut SigReader::new(sig))
+    }
+
+    /// Renders a method reference token
+    pub fn method(&self, token: mdToken) -> String {
+        let token_type = type_from_token(token);
+        if token_type == CorTokenType::mdtMethodSpec.bits() {
+            return self
+                .resolver
+                .method_spec(token)
+                .and_then(|(parent, instantiation)| {
+                    let member = self.resolver.member(parent)?;
+                    let mut r = SigReader::new(&instantiation);
+                    r.byte()?;
+                    let count = r.data()?;
+                    let mut args = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        args.push(self.sig_type(&mut r)?);
+                    }
+                    self.member_method(&member, Some(&args))
+                })
+                .unwrap_or_else(|| self.token(token));
+        }
+
+        self.resolver
+            .member(token)
+            .and_then(|member| self.member_method(&member, None))
+            .unwrap_or_else(|| self.token(token))
+    }
+
+    /// Renders a field reference token
+    pub fn field(&self, token: mdToken) -> String {
+        self.resolver
+            .member(token)
+            .and_then(|member| self.member_field(&member))
+            .unwrap_or_else(|| self.token(token))
+    }
+
+    /// Renders a TypeDef, TypeRef or TypeSpec token
+    pub fn type_def_or_ref(&self, token: mdToken) -> String {
+        if type_from_token(token) == CorTokenType::mdtTypeSpec.bits() {
+            self.resolver
+                .type_spec(token)
+                .and_then(|sig| self.type_signature(&sig))
+                .unwrap_or_else(|| self.token(token))
+        } else {
+            self.resolver
+                .type_name(token)
+                .unwrap_or_else(|| self.token(token))
+        }
+    }
+
+    fn inline_tok(&self, token: mdToken) -> String {
+        let token_type = type_from_token(token);
+        if token_type == CorTokenType::mdtMethodDef.bits()
+            || token_type == CorTokenType::mdtMethodSpec.bits()
+        {
+            format!("method {}", self.method(token))
+        } else if token_type == CorTokenType::mdtFieldDef.bits() {
+            format!("field {}", self.field(token))
+        } else if token_type == CorTokenType::mdtMemberRef.bits() {
+            match self.resolver.member(token) {
+                Some(member)
+                    if member.signature.first().copied()
+                        == Some(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_FIELD.bits()) =>
+                {
+                    format!("field {}", self.field(token))
+                }
+                _ => format!("method {}", self.method(token)),
+            }
+        } else {
+            self.type_def_or_ref(token)
+        }
+    }
+
+    fn member_method(
+        &self,
+        member: &MemberName,
+        generic_args: Option<&[String]>,
+    ) -> Option<String> {
+        let sig = self.method_sig(&mut SigReader::new(&member.signature))?;
+        let generic_args = match generic_args {
+            Some(args) => format!("<{}>", args.join(", ")),
+            None => String::new(),
+        };
+        Some(format!(
+            "{}{}{} {}::{}{}({})",
+            sig.prefix(),
+            sig.vararg(),
+            sig.return_type,
+            self.parent(member.parent),
+            member.name,
+            generic_args,
+            sig.params.join(", ")
+        ))
+    }
+
+    fn member_field(&self, member: &MemberName) -> Option<String> {
+        let mut r = SigReader::new(&member.signature);
+        if r.byte()? != CorCallingConvention::IMAGE_CEE_CS_CALLCONV_FIELD.bits() {
+            return None;
+        }
+        let field_type = self.sig_type(&mut r)?;
+        Some(format!(
+            "{} {}::{}",
+            field_type,
+            self.parent(member.parent),
+            member.name
+        ))
+    }
+
+    fn parent(&self, token: mdToken) -> String {
+        let token_type = type_from_token(token);
+        if token_type == CorTokenType::mdtTypeDef.bits()
+            || token_type == CorTokenType::mdtTypeRef.bits()
+            || token_type == CorTokenType::mdtTypeSpec.bits()
+        {
+            self.type_def_or_ref(token)
+        } else {
+            self.token(token)
+        }
+    }
+
+    fn locals(&self, sig: &[u8]) -> Option<String> {
+        let mut r = SigReader::new(sig);
+        if r.byte()? != CorCallingConvention::IMAGE_CEE_CS_CALLCONV_LOCAL_SIG.bits() {
+            return None;
+        }
+        let count = r.data()?;
+        let mut locals = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            locals.push(format!("[{}] {}", i, self.sig_type(&mut r)?));
+        }
+        Some(locals.join(", "))
+    }
+
+    fn method_sig(&self, r: &mut SigReader) -> Option<MethodSigText> {
+        let calling_convention = CorCallingConvention::from_bits_truncate(r.byte()?);
+        let generic_param_count = if calling_convention.is_generic() {
+            r.data()?
+        } else {
+            0
+        };
+        let param_count = r.data()?;
+        let return_type = self.sig_type(r)?;
+        let mut params = Vec::with_capacity(param_count as usize);
+        let mut i = 0;
+        while i < param_count {
+            if r.peek()? == CorElementType::ELEMENT_TYPE_SENTINEL as u8 {
+                r.byte()?;
+                params.push("...".to_string());
+                continue;
+            }
+            params.push(self.sig_type(r)?);
+            i += 1;
+        }
+        Some(MethodSigText {
+            calling_convention,
+            generic_param_count,
+            return_type,
+            params,
+        })
+    }
+
+    fn sig_type(&self, r: &mut SigReader) -> Option<String> {
+        let element_type = CorElementType::from_u8(r.byte()?)?;
+        let name = match element_type {
+            CorElementType::ELEMENT_TYPE_VOID => "void".to_string(),
+            CorElementType::ELEMENT_TYPE_BOOLEAN => "bool".to_string(),
+            CorElementType::ELEMENT_TYPE_CHAR => "char".to_string(),
+            CorElementType::ELEMENT_TYPE_I1 => "int8".to_string(),
+            CorElementType::ELEMENT_TYPE_U1 => "uint8".to_string(),
+            CorElementType::ELEMENT_TYPE_I2 => "int16".to_string(),
+            CorElementType::ELEMENT_TYPE_U2 => "uint16".to_string(),
+            CorElementType::ELEMENT_TYPE_I4 => "int32".to_string(),
+            CorElementType::ELEMENT_TYPE_U4 => "uint32".to_string(),
+            CorElementType::ELEMENT_TYPE_I8 => "int64".to_string(),
+            CorElementType::ELEMENT_TYPE_U8 => "uint64".to_string(),
+            CorElementType::ELEMENT_TYPE_R4 => "float32".to_string(),
+            CorElementType::ELEMENT_TYPE_R8 => "float64".to_string(),
+            CorElementType::ELEMENT_TYPE_STRING => "string".to_string(),
+            CorElementType::ELEMENT_TYPE_OBJECT => "object".to_string(),
+            CorElementType::ELEMENT_TYPE_I => "native int".to_string(),
+            CorElementType::ELEMENT_TYPE_U => "native uint".to_string(),
+            CorElementType::ELEMENT_TYPE_TYPEDBYREF => "typedref".to_string(),
+            CorElementType::ELEMENT_TYPE_PTR => format!("{}*", self.sig_type(r)?),
+            CorElementType::ELEMENT_TYPE_BYREF => format!("{}&", self.sig_type(r)?),
+            CorElementType::ELEMENT_TYPE_PINNED => format!("{} pinned", self.sig_type(r)?),
+            CorElementType::ELEMENT_TYPE_SZARRAY => format!("{}[]", self.sig_type(r)?),
+            CorElementType::ELEMENT_TYPE_VALUETYPE => {
+                format!("valuetype {}", self.type_def_or_ref(r.token()?))
+            }
+            CorElementType::ELEMENT_TYPE_CLASS => {
+                format!("class {}", self.type_def_or_ref(r.token()?))
+            }
+            CorElementType::ELEMENT_TYPE_VAR => format!("!{}", r.data()?),
+            CorElementType::ELEMENT_TYPE_MVAR => format!("!!{}", r.data()?),
+            CorElementType::ELEMENT_TYPE_CMOD_REQD | CorElementType::ELEMENT_TYPE_CMOD_OPT => {
+                let modifier = self.type_def_or_ref(r.token()?);
+                let modified = self.sig_type(r)?;
+                let kind = if element_type == CorElementType::ELEMENT_TYPE_CMOD_REQD {
+                    "modreq"
+                } else {
+                    "modopt"
+                };
+                format!("{} {}({})", modified, kind, modifier)
+            }
+            CorElementType::ELEMENT_TYPE_GENERICINST => {
+                let generic_type = self.sig_type(r)?;
+                let count = r.data()?;
+                let mut args = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    args.push(self.sig_type(r)?);
+                }
+                format!("{}<{}>", generic_type, args.join(", "))
+            }
+            CorElementType::ELEMENT_TYPE_ARRAY => {
+                let element = self.sig_type(r)?;
+                let rank = r.data()?;
+                let size_count = r.data()?;
+                let mut sizes = Vec::with_capacity(size_count as usize);
+                for _ in 0..size_count {
+                    sizes.push(r.data()?);
+                }
+                let lower_bound_count = r.data()?;
+                let mut lower_bounds = Vec::with_capacity(lower_bound_count as usize);
+                for _ in 0..lower_bound_count {
+                    lower_bounds.push(r.signed_data()?);
+                }
+                let dimensions: Vec<String> = (0..rank as usize)
+                    .map(|i| match (lower_bounds.get(i), sizes.get(i)) {
+                        (None, None) => String::new(),
+                        (Some(lower), None) => format!("{}...", lower),
+                        (None, Some(size)) | (Some(0), Some(size)) => size.to_string(),
+                        (Some(lower), Some(size)) => {
+                            format!("{}...{}", lower, *lower as i64 + *size as i64 - 1)
+                        }
+                    })
+                    .collect();
+                format!("{}[{}]", element, dimensions.join(","))
+            }
+            CorElementType::ELEMENT_TYPE_FNPTR => {
+                let m = self.method_sig(r)?;
+                format!(
+                    "method {}{}{} *({})",
+                    m.prefix(),
+                    m.vararg(),
+                    m.return_type,
+                    m.params.join(", ")
+                )
+            }
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    fn token(&self, token: mdToken) -> String {
+        format!("{:#010x}", token)
+    }
+
+    fn blocks(&self, sections: &[Section]) -> Vec<Block> {
+        let mut clauses = Vec::new();
+        for section in sections {
+            match section {
+                Section::FatSection(_, s) => {
+                    for c in s {
+                        clauses.push((
+                            c.flag,
+                            c.try_offset,
+                            c.try_offset + c.try_length,
+                            c.handler_offset,
+                            c.handler_offset + c.handler_length,
+                            c.class_token_or_filter_offset,
+                        ));
+                    }
+                }
+                Section::SmallSection(_, s) => {
+                    for c in s {
+                        clauses.push((
+                            c.flag,
+                            c.try_offset as u32,
+                            c.try_offset as u32 + c.try_length as u32,
+                            c.handler_offset as u32,
+                            c.handler_offset as u32 + c.handler_length as u32,
+                            c.class_token_or_filter_offset,
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut blocks: Vec<Block> = Vec::new();
+        for (flag, try_start, try_end, handler_start, handler_end, class_token_or_filter) in clauses
+        {
+            // clauses that share a protected region render as a single .try with many handlers
+            if !blocks
+                .iter()
+                .any(|b| b.start == try_start && b.end == try_end && b.footer == "// end .try")
+            {
+                blocks.push(Block {
+                    start: try_start,
+                    end: try_end,
+                    header: Some(".try".into()),
+                    footer: "// end .try",
+                });
+            }
+
+            if flag.contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER) {
+                blocks.push(Block {
+                    start: class_token_or_filter,
+                    end: handler_start,
+                    header: Some("filter".into()),
+                    footer: "// end filter",
+                });
+                blocks.push(Block {
+                    start: handler_start,
+                    end: handler_end,
+                    header: None,
+                    footer: "// end handler",
+                });
+            } else {
+                let header = if flag.contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY) {
+                    "finally".to_string()
+                } else if flag.contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT) {
+                    "fault".to_string()
+                } else {
+                    format!("catch {}", self.type_def_or_ref(class_token_or_filter))
+                };
+                blocks.push(Block {
+                    start: handler_start,
+                    end: handler_end,
+                    header: Some(header),
+                    footer: "// end handler",
+                });
+            }
+        }
+
+        blocks
+    }
+
+    fn open(buf: &mut String, block: &Block, depth: usize) {
+        let indent = "  ".repeat(depth);
+        match &block.header {
+            Some(header) => {
+                buf.push_str(&format!("{}{}\n{}{{\n", indent, header, indent));
+            }
+            None => buf.push_str(&format!("{}{{  // handler\n", indent)),
+        }
+    }
+
+    fn close(buf: &mut String, block: &Block, depth: usize) {
+        buf.push_str(&format!("{}}}  {}\n", "  ".repeat(depth), block.footer));
+    }
+}
+
+fn label(offset: i64) -> String {
+    format!("IL_{:04x}", offset)
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{
+            CorExceptionFlag, Disassembler, FatSectionClause, FatSectionHeader, Instruction,
+            MemberName, Method, MethodHeader, MethodToken, Section, StringToken, TokenResolver,
+        },
+        ffi::mdToken,
+    };
+    use std::{collections::HashMap, convert::TryFrom};
+
+    #[derive(Default)]
+    struct TestResolver {
+        types: HashMap<mdToken, String>,
+        members: HashMap<mdToken, MemberName>,
+        strings: HashMap<mdToken, String>,
+        signatures: HashMap<mdToken, Vec<u8>>,
+    }
+
+    impl TokenResolver for TestResolver {
+        fn type_name(&self, token: mdToken) -> Option<String> {
+            self.types.get(&token).cloned()
+        }
+        fn member(&self, token: mdToken) -> Option<MemberName> {
+            self.members.get(&token).cloned()
+        }
+        fn user_string(&self, token: mdToken) -> Option<String> {
+            self.strings.get(&token).cloned()
+        }
+        fn signature(&self, token: mdToken) -> Option<Vec<u8>> {
+            self.signatures.get(&token).cloned()
+        }
+    }
+
+    fn resolver() -> TestResolver {
+        let mut resolver = TestResolver::default();
+        resolver
+            .types
+            .insert(0x01000001, "[System.Runtime]System.Exception".into());
+        resolver
+            .types
+            .insert(0x01000002, "[System.Console]System.Console".into());
+        resolver.members.insert(
+            0x0A000001,
+            MemberName {
+                parent: 0x01000002,
+                name: "WriteLine".into(),
+                // default, 1 param, void, string
+                signature: vec![0x00, 0x01, 0x01, 0x0E],
+            },
+        );
+        resolver.strings.insert(0x70000001, "say \"hi\"".into());
+        // locals: int32, class System.Exception
+        resolver
+            .signatures
+            .insert(0x11000001, vec![0x07, 0x02, 0x08, 0x12, 0x05]);
+        resolver
+    }
+
+    #[test]
+    fn disassemble_nested_clauses() {
+        // try { try { ldstr; call; leave } catch Exception { stloc.1; leave } } finally { endfinally } ret
+        let instructions = vec![
+            Instruction::ldstr(StringToken::try_from(0x70000001).unwrap()), // 0..5
+            Instruction::call(MethodToken::try_from(0x0A000001).unwrap()),  // 5..10
+            Instruction::leave_s(5),                                        // 10..12
+            Instruction::stloc_1(),                                         // 12..13
+            Instruction::leave_s(2),                                        // 13..15
+            Instruction::leave_s(1),                                        // 15..17
+            Instruction::endfinally(),                                      // 17..18
+            Instruction::ret(),                                             // 18..19
+        ];
+        let method = Method {
+            address: 0,
+            header: MethodHeader::fat(true, true, 1, 19, 0x11000001),
+            instructions,
+            sections: vec![Section::FatSection(
+                FatSectionHeader {
+                    is_eh_table: true,
+                    more_sects: false,
+                    data_size: 52,
+                },
+                vec![
+                    FatSectionClause {
+                        flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
+                        try_offset: 0,
+                        try_length: 12,
+                        handler_offset: 12,
+                        handler_length: 3,
+                        class_token_or_filter_offset: 0x01000001,
+                    },
+                    FatSectionClause {
+                        flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                        try_offset: 0,
+                        try_length: 17,
+                        handler_offset: 17,
+                        handler_length: 1,
+                        class_token_or_filter_offset: 0,
+                    },
+                ],
+            )],
+            original_offsets: vec![],
+        };
+
+        let resolver = resolver();
+        let text = Disassembler::new(&resolver).disassemble(&method);
+        let expected = r#".maxstack 1
+.locals init ([0] int32, [1] class [System.Runtime]System.Exception)
+.try
+{
+  .try
+  {
+    IL_0000:  ldstr      "say \"hi\""
+    IL_0005:  call       void [System.Console]System.Console::WriteLine(string)
+    IL_000a:  leave.s    IL_0011
+  }  // end .try
+  catch [System.Runtime]System.Exception
+  {
+    IL_000c:  stloc.1
+    IL_000d:  leave.s    IL_0011
+  }  // end handler
+  IL_000f:  leave.s    IL_0012
+}  // end .try
+finally
+{
+  IL_0011:  endfinally
+}  // end handler
+IL_0012:  ret
+"#;
+        assert_eq!(expected, text);
+    }
+
+    #[test]
+    fn disassemble_unresolved_tokens_and_switch() {
+        let method = Method::tiny(vec![
+            Instruction::ldarg_0(),
+            Instruction::switch(2, vec![0, 1]),
+            Instruction::newobj(MethodToken::try_from(0x0A000009).unwrap()),
+            Instruction::ret(),
+        ])
+        .unwrap();
+
+        let text = Disassembler::new(&TestResolver::default()).disassemble(&method);
+        let expected = ".maxstack 8
+IL_0000:  ldarg.0
+IL_0001:  switch     (IL_000e, IL_000f)
+IL_000e:  newobj     0x0a000009
+IL_0013:  ret
+";
+        assert_eq!(expected, text);
+    }
+
+    #[test]
+    fn render_type_signatures() {
+        let resolver = resolver();
+        let disassembler = Disassembler::new(&resolver);
+        // GENERICINST CLASS Exception 1 I4
+        assert_eq!(
+            Some("class [System.Runtime]System.Exception<int32>".to_string()),
+            disassembler.type_signature(&[0x15, 0x12, 0x05, 0x01, 0x08])
+        );
+        // ARRAY I4 rank 2, 1 size 3, 1 lower bound 1
+        assert_eq!(
+            Some("int32[1...3,]".to_string()),
+            disassembler.type_signature(&[0x14, 0x08, 0x02, 0x01, 0x03, 0x01, 0x02])
+        );
+        // PTR VOID, BYREF MVAR 0, CMOD_OPT Exception I4
+        assert_eq!(
+            Some("void*".to_string()),
+            disassembler.type_signature(&[0x0F, 0x01])
+        );
+        assert_eq!(
+            Some("!!0&".to_string()),
+            disassembler.type_signature(&[0x10, 0x1E, 0x00])
+        );
+        assert_eq!(
+            Some("int32 modopt([System.Runtime]System.Exception)".to_string()),
+            disassembler.type_signature(&[0x20, 0x05, 0x08])
+        );
+    }
+}
@@ -0,0 +1,378 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{CorExceptionFlag, Instruction, Method, Opcode, Operand},
+    error::Error,
+};
+use std::{collections::BTreeMap, convert::TryFrom};
+
+/// A batch of instruction insertions and replacements, applied to a method
+/// in a single pass with [Method::apply_edits].
+///
+/// Indexes refer to the instructions of the method before any edits are applied.
+/// Branch targets and exception clause boundaries stay with the original
+/// instruction they refer to: instructions inserted before an instruction are
+/// placed ahead of branches and clauses that start at it, whereas prepended and
+/// replacement instructions take the place of the original instruction. When
+/// both are used at the same index, inserted instructions come first.
+///
+/// Branch and switch operands of inserted and replacement instructions are
+/// relative to the sequence of instructions they are part of, and must target
+/// an instruction in the same sequence, or the end of the sequence.
+#[derive(Debug, Default)]
+pub struct MethodEdits {
+    inserts: BTreeMap<usize, Vec<Instruction>>,
+    prepends: BTreeMap<usize, Vec<Instruction>>,
+    replacements: BTreeMap<usize, Vec<Instruction>>,
+}
+
+impl MethodEdits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts instructions before the instruction at the index. An index equal
+    /// to the number of instructions appends instructions to the end of the method.
+    /// Instructions inserted at the same index are kept in the order inserted.
+    pub fn insert(&mut self, index: usize, instructions: Vec<Instruction>) {
+        self.inserts.entry(index).or_default().extend(instructions);
+    }
+
+    /// Inserts instructions before the instruction at the index, which take its
+    /// place as the target of branches and the boundary of exception clauses, so
+    /// that they run on every path that reaches the instruction.
+    pub fn prepend(&mut self, index: usize, instructions: Vec<Instruction>) {
+        self.prepends.entry(index).or_default().extend(instructions);
+    }
+
+    /// Replaces the instruction at the index with instructions
+    pub fn replace(&mut self, index: usize, instructions: Vec<Instruction>) {
+        self.replacements.insert(index, instructions);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inserts.is_empty() && self.prepends.is_empty() && self.replacements.is_empty()
+    }
+}
+
+/// An instruction in the new layout, with the indexes of its branch targets in the new layout
+/// and its offset in the original IL
+pub(crate) struct Item {
+    pub instruction: Instruction,
+    pub targets: Vec<usize>,
+    pub origin: Option<u32>,
+}
+
+impl Method {
+    /// Applies a batch of edits, laying out the method once to update branch
+    /// offsets, switch tables, exception clauses, code size and max stack.
+    /// Short branches whose targets move out of range are promoted to long
+    /// branches.
+    pub fn apply_edits(&mut self, edits: MethodEdits) -> Result<(), Error> {
+        if edits.is_empty() {
+            return Ok(());
+        }
+
+        let len = self.instructions.len();
+        if edits
+            .inserts
+            .keys()
+            .chain(edits.prepends.keys())
+            .any(|i| *i > len)
+            || edits.replacements.keys().any(|i| *i >= len)
+        {
+            return Err(Error::InvalidCil);
+        }
+
+        let mut offsets = self.get_instruction_offsets();
+        let code_size = offsets.last().copied().unwrap_or_default()
+            + self.instructions.last().map_or(0, |i| i.len() as u32);
+        offsets.push(code_size);
+
+        // the index in the new layout of each original instruction, and of the end of the method
+        let mut anchors = Vec::with_capacity(len + 1);
+        let mut count = 0;
+        for index in 0..=len {
+            count += edits.inserts.get(&index).map_or(0, |i| i.len());
+            anchors.push(count);
+            count += edits.prepends.get(&index).map_or(0, |p| p.len());
+            if index < len {
+                count += edits.replacements.get(&index).map_or(1, |r| r.len());
+            }
+        }
+
+        let anchor_of = |offset: i64| -> Result<usize, Error> {
+            u32::try_from(offset)
+                .ok()
+                .and_then(|o| offsets.binary_search(&o).ok())
+                .map(|i| anchors[i])
+                .ok_or(Error::InvalidCil)
+        };
+
+        let mut stack_size: i64 = 0;
+        let mut items: Vec<Item> = Vec::with_capacity(count);
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if let Some(inserts) = edits.inserts.get(&index) {
+                stack_size += Self::push_sequence(&mut items, inserts, None)?;
+            }
+            if let Some(prepends) = edits.prepends.get(&index) {
+                stack_size += Self::push_sequence(&mut items, prepends, None)?;
+            }
+            match edits.replacements.get(&index) {
+                Some(replacement) => {
+                    stack_size +=
+                        Self::push_sequence(&mut items, replacement, self.original_offset(index))?
+                            - instruction.stack_size() as i64;
+                }
+                None => {
+                    let next = offsets[index + 1] as i64;
+                    let targets = branch_targets(instruction)
+                        .iter()
+                        .map(|delta| anchor_of(next + *delta))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    items.push(Item {
+                        instruction: instruction.clone(),
+                        targets,
+                        origin: self.original_offset(index),
+                    });
+                }
+            }
+        }
+        for sequence in edits
+            .inserts
+            .get(&len)
+            .iter()
+            .chain(&edits.prepends.get(&len))
+        {
+            stack_size += Self::push_sequence(&mut items, sequence, None)?;
+        }
+
+        let new_offsets = Self::layout(&mut items)?;
+        let new_code_size = new_offsets[items.len()];
+
+        let mut updated_sections = Vec::with_capacity(self.sections.len());
+        for section in &self.sections {
+            let mut clauses = section.fat_clauses();
+            for clause in &mut clauses {
+                let relocate = |offset: u32| -> Result<u32, Error> {
+                    anchor_of(offset as i64)
+                        .map(|i| new_offsets[i])
+                        .or(Err(Error::InvalidSectionHeader))
+                };
+                let try_end = relocate(clause.try_offset + clause.try_length)?;
+                let handler_end = relocate(clause.handler_offset + clause.handler_length)?;
+                clause.try_offset = relocate(clause.try_offset)?;
+                clause.try_length = try_end - clause.try_offset;
+                clause.handler_offset = relocate(clause.handler_offset)?;
+                clause.handler_length = handler_end - clause.handler_offset;
+                if clause
+                    .flag
+                    .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER)
+                {
+                    clause.class_token_or_filter_offset =
+                        relocate(clause.class_token_or_filter_offset)?;
+                }
+            }
+            updated_sections.push(clauses);
+        }
+
+        self.update_header(
+            new_code_size as i64 - code_size as i64,
+            Some(stack_size.max(0)),
+        )?;
+        self.set_clauses(updated_sections)?;
+        self.original_offsets = items.iter().map(|i| i.origin).collect();
+        self.instructions = items.into_iter().map(|i| i.instruction).collect();
+        Ok(())
+    }
+
+    /// Pushes a sequence of inserted or replacement instructions, resolving
+    /// branch targets within the sequence. The first instruction is given the
+    /// original offset, if any. Returns the stack size of the sequence.
+    fn push_sequence(
+        items: &mut Vec<Item>,
+        instructions: &[Instruction],
+        origin: Option<u32>,
+    ) -> Result<i64, Error> {
+        let base = items.len();
+        let mut offsets = Vec::with_capacity(instructions.len() + 1);
+        let mut offset = 0;
+        for instruction in instructions {
+            offsets.push(offset);
+            offset += instruction.len() as i64;
+        }
+        offsets.push(offset);
+
+        let mut stack_size = 0;
+        for (index, instruction) in instructions.iter().enumerate() {
+            let next = offsets[index + 1];
+            let targets = branch_targets(instruction)
+                .iter()
+                .map(|delta| {
+                    offsets
+                        .binary_search(&(next + *delta))
+                        .map(|i| base + i)
+                        .or(Err(Error::InvalidCil))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            stack_size += instruction.stack_size() as i64;
+            items.push(Item {
+                instruction: instruction.clone(),
+                targets,
+                origin: if index == 0 { origin } else { None },
+            });
+        }
+        Ok(stack_size)
+    }
+
+    /// Lays out the items, promoting short branches that no longer reach their
+    /// targets until the layout is stable, then writes the branch operands.
+    /// Returns the offset of each item, followed by the code size.
+    pub(crate) fn layout(items: &mut [Item]) -> Result<Vec<u32>, Error> {
+        let mut offsets = vec![0u32; items.len() + 1];
+        loop {
+            let mut offset: u32 = 0;
+            for (index, item) in items.iter().enumerate() {
+                offsets[index] = offset;
+                offset = offset
+                    .checked_add(item.instruction.len() as u32)
+                    .ok_or(Error::CodeSize)?;
+            }
+            offsets[items.len()] = offset;
+
+            let mut promoted = false;
+            for (index, item) in items.iter_mut().enumerate() {
+                if let Operand::ShortInlineBrTarget(_) = item.instruction.operand {
+                    let delta = offsets[item.targets[0]] as i64 - offsets[index + 1] as i64;
+                    if i8::try_from(delta).is_err() {
+                        item.instruction.opcode =
+                            Opcode::short_to_long_form(item.instruction.opcode);
+                        item.instruction.operand = Operand::InlineBrTarget(0);
+                        promoted = true;
+                    }
+                }
+            }
+
+            if !promoted {
+                break;
+            }
+        }
+
+        for (index, item) in items.iter_mut().enumerate() {
+            let next = offsets[index + 1] as i64;
+            let delta = |target: usize| offsets[target] as i64 - next;
+            match &mut item.instruction.operand {
+                Operand::ShortInlineBrTarget(target) => {
+                    *target = delta(item.targets[0]) as i8;
+                }
+                Operand::InlineBrTarget(target) => {
+                    *target = i32::try_from(delta(item.targets[0])).or(Err(Error::CodeSize))?;
+                }
+                Operand::InlineSwitch(_, targets) => {
+                    for (target, index) in targets.iter_mut().zip(&item.targets) {
+                        *target = i32::try_from(delta(*index)).or(Err(Error::CodeSize))?;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(offsets)
+    }
+}
+
+/// Gets the branch or switch deltas of an instruction
+pub(crate) fn branch_targets(instruction: &Instruction) -> Vec<i64> {
+    match &instruction.operand {
+        Operand::ShortInlineBrTarget(delta) => vec![*delta as i64],
+        Operand::InlineBrTarget(delta) => vec![*delta as i64],
+        Operand::InlineSwitch(_, deltas) => deltas.iter().map(|d| *d as i64).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cil::{
+        CorExceptionFlag, FatSectionClause, Instruction, Method, MethodEdits, MethodHeader,
+        Operand, Section, BR,
+    };
+
+    #[test]
+    fn apply_edits_relocates_branches_switches_and_clauses() {
+        let instructions = vec![
+            // IL_0000
+            Instruction::ldarg_0(),
+            Instruction::switch(2, vec![0, 2]),
+            // IL_000e
+            Instruction::br_s(1),
+            // IL_0010
+            Instruction::nop(),
+            // IL_0011: try
+            Instruction::nop(),
+            Instruction::leave_s(1),
+            // IL_0014: finally
+            Instruction::endfinally(),
+            // IL_0015
+            Instruction::br_s(-23),
+        ];
+        let code_size: usize = instructions.iter().map(|i| i.len()).sum();
+        let mut method = Method {
+            address: 0,
+            header: MethodHeader::fat(false, false, 2, code_size as u32, 0),
+            instructions,
+            sections: vec![],
+            original_offsets: vec![],
+        };
+        method
+            .push_clauses(vec![FatSectionClause {
+                flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                try_offset: 0x11,
+                try_length: 3,
+                handler_offset: 0x14,
+                handler_length: 1,
+                class_token_or_filter_offset: 0,
+            }])
+            .unwrap();
+
+        let mut edits = MethodEdits::new();
+        // inserted ahead of the branch targets and try block starting at the instruction
+        edits.insert(3, vec![Instruction::nop(); 200]);
+        edits.insert(4, vec![Instruction::ldc_i4_1(), Instruction::pop()]);
+        edits.replace(6, vec![Instruction::nop(), Instruction::endfinally()]);
+        method.apply_edits(edits).unwrap();
+
+        let offsets = method.get_instruction_offsets();
+        assert_eq!(method.instructions.len(), 211);
+        assert_eq!(method.header.code_size(), 232);
+
+        // switch targets the br.s, now promoted to br, and the original nop after the inserted nops
+        match &method.instructions[1].operand {
+            Operand::InlineSwitch(2, deltas) => assert_eq!(deltas, &vec![0, 5 + 200]),
+            operand => panic!("unexpected operand {:?}", operand),
+        }
+        assert_eq!(method.instructions[2].opcode, BR);
+        assert!(matches!(
+            method.instructions[2].operand,
+            Operand::InlineBrTarget(203)
+        ));
+        assert_eq!(method.instructions[210].opcode, BR);
+        match method.instructions[210].operand {
+            Operand::InlineBrTarget(delta) => assert_eq!(delta, -(offsets[210] as i32 + 5)),
+            ref operand => panic!("unexpected operand {:?}", operand),
+        }
+
+        match &method.sections[0] {
+            Section::FatSection(_, clauses) => {
+                assert_eq!(clauses[0].try_offset, offsets[206]);
+                assert_eq!(clauses[0].try_length, 3);
+                assert_eq!(clauses[0].handler_offset, offsets[208]);
+                assert_eq!(clauses[0].handler_length, 2);
+            }
+            section => panic!("unexpected section {:?}", section),
+        }
+    }
+}
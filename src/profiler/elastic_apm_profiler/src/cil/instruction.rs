@@ -23,10 +23,16 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use crate::{
-    cil::{il_f32, il_f64, il_i32, il_i64, il_i8, il_u16, il_u32, il_u8, opcode::*, OperandParams},
+    cil::{
+        il_f32, il_f64, il_i32, il_i64, il_i8, il_u16, il_u32, il_u8, opcode::*, FieldToken,
+        HandleToken, MethodToken, OperandParams, SignatureToken, StringToken, TypeToken,
+    },
     error::{Error, Error::InvalidCil},
 };
-use std::fmt::{Display, Formatter};
+use std::{
+    convert::TryFrom,
+    fmt::{Display, Formatter},
+};
 
 /// A signed or unsigned 8-bit integer type
 #[derive(Debug, Copy, Clone)]
@@ -64,15 +70,15 @@ pub enum Operand {
     InlineI8(i64),
     ShortInlineR(f32),
     InlineR(f64),
-    InlineMethod(u32),
-    InlineSig(u32),
+    InlineMethod(MethodToken),
+    InlineSig(SignatureToken),
     ShortInlineBrTarget(i8),
     InlineBrTarget(i32),
     InlineSwitch(u32, Vec<i32>),
-    InlineType(u32),
-    InlineString(u32),
-    InlineField(u32),
-    InlineTok(u32),
+    InlineType(TypeToken),
+    InlineString(StringToken),
+    InlineField(FieldToken),
+    InlineTok(HandleToken),
 }
 
 #[allow(clippy::len_without_is_empty)]
@@ -109,7 +115,8 @@ pub struct Instruction {
 impl Instruction {
     /// Attempts to parse the first instruction at the beginning
     /// of the given byte array. Array must be at a valid instruction
-    /// boundary.
+    /// boundary. Returns [Error::InvalidToken] when a token operand is
+    /// not of a token type expected by the opcode.
     pub fn from_bytes(il: &[u8]) -> Result<Self, Error> {
         let byte_1 = il_u8(il, 0)?;
         let opcode = if byte_1 == 0xFE {
@@ -164,11 +171,11 @@ impl Instruction {
             }
             OperandParams::InlineMethod => {
                 let val = il_u32(il, operand_index)?;
-                Operand::InlineMethod(val)
+                Operand::InlineMethod(MethodToken::try_from(val)?)
             }
             OperandParams::InlineSig => {
                 let val = il_u32(il, operand_index)?;
-                Operand::InlineSig(val)
+                Operand::InlineSig(SignatureToken::try_from(val)?)
             }
             OperandParams::ShortInlineBrTarget => {
                 let val = il_i8(il, operand_index)?;
@@ -190,19 +197,19 @@ impl Instruction {
             }
             OperandParams::InlineType => {
                 let val = il_u32(il, operand_index)?;
-                Operand::InlineType(val)
+                Operand::InlineType(TypeToken::try_from(val)?)
             }
             OperandParams::InlineString => {
                 let val = il_u32(il, operand_index)?;
-                Operand::InlineString(val)
+                Operand::InlineString(StringToken::try_from(val)?)
             }
             OperandParams::InlineField => {
                 let val = il_u32(il, operand_index)?;
-                Operand::InlineField(val)
+                Operand::InlineField(FieldToken::try_from(val)?)
             }
             OperandParams::InlineTok => {
                 let val = il_u32(il, operand_index)?;
-                Operand::InlineTok(val)
+                Operand::InlineTok(HandleToken::try_from(val)?)
             }
         };
         Ok(Instruction { opcode, operand })
@@ -224,8 +231,8 @@ impl Instruction {
             Operand::InlineI8(val) => bytes.extend_from_slice(&val.to_le_bytes()),
             Operand::ShortInlineR(val) => bytes.extend_from_slice(&val.to_le_bytes()),
             Operand::InlineR(val) => bytes.extend_from_slice(&val.to_le_bytes()),
-            Operand::InlineMethod(val) => bytes.extend_from_slice(&val.to_le_bytes()),
-            Operand::InlineSig(val) => bytes.extend_from_slice(&val.to_le_bytes()),
+            Operand::InlineMethod(val) => bytes.extend_from_slice(&val.token().to_le_bytes()),
+            Operand::InlineSig(val) => bytes.extend_from_slice(&val.token().to_le_bytes()),
             Operand::ShortInlineBrTarget(val) => bytes.extend_from_slice(&val.to_le_bytes()),
             Operand::InlineBrTarget(val) => bytes.extend_from_slice(&val.to_le_bytes()),
             Operand::InlineSwitch(length, val) => {
@@ -234,10 +241,10 @@ impl Instruction {
                     val.iter().flat_map(|s| s.to_le_bytes().to_vec()).collect();
                 bytes.append(&mut target_bytes);
             }
-            Operand::InlineType(val) => bytes.extend_from_slice(&val.to_le_bytes()),
-            Operand::InlineString(val) => bytes.extend_from_slice(&val.to_le_bytes()),
-            Operand::InlineField(val) => bytes.extend_from_slice(&val.to_le_bytes()),
-            Operand::InlineTok(val) => bytes.extend_from_slice(&val.to_le_bytes()),
+            Operand::InlineType(val) => bytes.extend_from_slice(&val.token().to_le_bytes()),
+            Operand::InlineString(val) => bytes.extend_from_slice(&val.token().to_le_bytes()),
+            Operand::InlineField(val) => bytes.extend_from_slice(&val.token().to_le_bytes()),
+            Operand::InlineTok(val) => bytes.extend_from_slice(&val.token().to_le_bytes()),
         }
 
         bytes
@@ -484,19 +491,19 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn jmp(val: u32) -> Self {
+    pub fn jmp(val: MethodToken) -> Self {
         Self {
             opcode: JMP,
             operand: Operand::InlineMethod(val),
         }
     }
-    pub fn call(val: u32) -> Self {
+    pub fn call(val: MethodToken) -> Self {
         Self {
             opcode: CALL,
             operand: Operand::InlineMethod(val),
         }
     }
-    pub fn calli(val: u32) -> Self {
+    pub fn calli(val: SignatureToken) -> Self {
         Self {
             opcode: CALLI,
             operand: Operand::InlineSig(val),
@@ -916,43 +923,43 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn callvirt(val: u32) -> Self {
+    pub fn callvirt(val: MethodToken) -> Self {
         Self {
             opcode: CALLVIRT,
             operand: Operand::InlineMethod(val),
         }
     }
-    pub fn cpobj(val: u32) -> Self {
+    pub fn cpobj(val: TypeToken) -> Self {
         Self {
             opcode: CPOBJ,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn ldobj(val: u32) -> Self {
+    pub fn ldobj(val: TypeToken) -> Self {
         Self {
             opcode: LDOBJ,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn ldstr(val: u32) -> Self {
+    pub fn ldstr(val: StringToken) -> Self {
         Self {
             opcode: LDSTR,
             operand: Operand::InlineString(val),
         }
     }
-    pub fn newobj(val: u32) -> Self {
+    pub fn newobj(val: MethodToken) -> Self {
         Self {
             opcode: NEWOBJ,
             operand: Operand::InlineMethod(val),
         }
     }
-    pub fn castclass(val: u32) -> Self {
+    pub fn castclass(val: TypeToken) -> Self {
         Self {
             opcode: CASTCLASS,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn isinst(val: u32) -> Self {
+    pub fn isinst(val: TypeToken) -> Self {
         Self {
             opcode: ISINST,
             operand: Operand::InlineType(val),
@@ -964,7 +971,7 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn unbox(val: u32) -> Self {
+    pub fn unbox(val: TypeToken) -> Self {
         Self {
             opcode: UNBOX,
             operand: Operand::InlineType(val),
@@ -976,43 +983,43 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn ldfld(val: u32) -> Self {
+    pub fn ldfld(val: FieldToken) -> Self {
         Self {
             opcode: LDFLD,
             operand: Operand::InlineField(val),
         }
     }
-    pub fn ldflda(val: u32) -> Self {
+    pub fn ldflda(val: FieldToken) -> Self {
         Self {
             opcode: LDFLDA,
             operand: Operand::InlineField(val),
         }
     }
-    pub fn stfld(val: u32) -> Self {
+    pub fn stfld(val: FieldToken) -> Self {
         Self {
             opcode: STFLD,
             operand: Operand::InlineField(val),
         }
     }
-    pub fn ldsfld(val: u32) -> Self {
+    pub fn ldsfld(val: FieldToken) -> Self {
         Self {
             opcode: LDSFLD,
             operand: Operand::InlineField(val),
         }
     }
-    pub fn ldsflda(val: u32) -> Self {
+    pub fn ldsflda(val: FieldToken) -> Self {
         Self {
             opcode: LDSFLDA,
             operand: Operand::InlineField(val),
         }
     }
-    pub fn stsfld(val: u32) -> Self {
+    pub fn stsfld(val: FieldToken) -> Self {
         Self {
             opcode: STSFLD,
             operand: Operand::InlineField(val),
         }
     }
-    pub fn stobj(val: u32) -> Self {
+    pub fn stobj(val: TypeToken) -> Self {
         Self {
             opcode: STOBJ,
             operand: Operand::InlineType(val),
@@ -1078,13 +1085,13 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn box_(val: u32) -> Self {
+    pub fn box_(val: TypeToken) -> Self {
         Self {
             opcode: BOX,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn newarr(val: u32) -> Self {
+    pub fn newarr(val: TypeToken) -> Self {
         Self {
             opcode: NEWARR,
             operand: Operand::InlineType(val),
@@ -1096,7 +1103,7 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn ldelema(val: u32) -> Self {
+    pub fn ldelema(val: TypeToken) -> Self {
         Self {
             opcode: LDELEMA,
             operand: Operand::InlineType(val),
@@ -1216,19 +1223,19 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn ldelem(val: u32) -> Self {
+    pub fn ldelem(val: TypeToken) -> Self {
         Self {
             opcode: LDELEM,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn stelem(val: u32) -> Self {
+    pub fn stelem(val: TypeToken) -> Self {
         Self {
             opcode: STELEM,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn unbox_any(val: u32) -> Self {
+    pub fn unbox_any(val: TypeToken) -> Self {
         Self {
             opcode: UNBOX_ANY,
             operand: Operand::InlineType(val),
@@ -1282,7 +1289,7 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn refanyval(val: u32) -> Self {
+    pub fn refanyval(val: TypeToken) -> Self {
         Self {
             opcode: REFANYVAL,
             operand: Operand::InlineType(val),
@@ -1294,13 +1301,13 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn mkrefany(val: u32) -> Self {
+    pub fn mkrefany(val: TypeToken) -> Self {
         Self {
             opcode: MKREFANY,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn ldtoken(val: u32) -> Self {
+    pub fn ldtoken(val: HandleToken) -> Self {
         Self {
             opcode: LDTOKEN,
             operand: Operand::InlineTok(val),
@@ -1438,13 +1445,13 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn ldftn(val: u32) -> Self {
+    pub fn ldftn(val: MethodToken) -> Self {
         Self {
             opcode: LDFTN,
             operand: Operand::InlineMethod(val),
         }
     }
-    pub fn ldvirtftn(val: u32) -> Self {
+    pub fn ldvirtftn(val: MethodToken) -> Self {
         Self {
             opcode: LDVIRTFTN,
             operand: Operand::InlineMethod(val),
@@ -1516,13 +1523,13 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn initobj(val: u32) -> Self {
+    pub fn initobj(val: TypeToken) -> Self {
         Self {
             opcode: INITOBJ,
             operand: Operand::InlineType(val),
         }
     }
-    pub fn constrained(val: u32) -> Self {
+    pub fn constrained(val: TypeToken) -> Self {
         Self {
             opcode: CONSTRAINED,
             operand: Operand::InlineType(val),
@@ -1546,7 +1553,7 @@ impl Instruction {
             operand: Operand::InlineNone,
         }
     }
-    pub fn sizeof(val: u32) -> Self {
+    pub fn sizeof(val: TypeToken) -> Self {
         Self {
             opcode: SIZEOF,
             operand: Operand::InlineType(val),
@@ -1616,6 +1623,22 @@ impl Instruction {
         }
     }
 
+    pub fn load_argument_address(val: u16) -> Self {
+        if val <= u8::MAX as u16 {
+            Self::ldarga_s(val as u8)
+        } else {
+            Self::ldarga(val)
+        }
+    }
+
+    pub fn store_argument(val: u16) -> Self {
+        if val <= u8::MAX as u16 {
+            Self::starg_s(val as u8)
+        } else {
+            Self::starg(val)
+        }
+    }
+
     pub fn load_local_address(val: u16) -> Self {
         if val <= u8::MAX as u16 {
             Self::ldloca_s(val as u8)
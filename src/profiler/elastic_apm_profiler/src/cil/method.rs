@@ -26,13 +26,11 @@
 
 use crate::{
     cil::{
-        check_flag, il_u32, nearest_multiple, CorExceptionFlag, FatSectionClause, FatSectionHeader,
-        Instruction, Opcode,
-        Operand::{InlineBrTarget, InlineSwitch, ShortInlineBrTarget},
-        Section,
+        check_flag, il_u32, nearest_multiple, FatSectionClause, FatSectionHeader, Instruction,
+        MethodEdits, Section, SmallSectionClause,
     },
     error::Error,
-    ffi::{mdToken, mdTokenNil},
+    ffi::{mdToken, mdTokenNil, COR_IL_MAP},
 };
 use std::{
     convert::TryFrom,
@@ -53,7 +51,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FatMethodHeader {
     more_sects: bool,
     init_locals: bool,
@@ -66,16 +64,18 @@ impl FatMethodHeader {
     pub const SIZE: u8 = 12;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TinyMethodHeader {
     code_size: u8,
 }
 
 impl TinyMethodHeader {
     pub const MAX_STACK: u8 = 8;
+    /// The code size is stored in the upper 6 bits of the header byte
+    pub const MAX_CODE_SIZE: u8 = 63;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MethodHeader {
     Fat(FatMethodHeader),
     Tiny(TinyMethodHeader),
@@ -111,7 +111,7 @@ impl MethodHeader {
             Ok(MethodHeader::Tiny(TinyMethodHeader { code_size }))
         } else if Self::is_fat(header_flags) {
             let more_sects = Self::more_sects(header_flags);
-            let init_locals = Self::init_locals(header_flags);
+            let init_locals = Self::has_init_locals(header_flags);
             let max_stack = u16::from_le_bytes([method_il[2], method_il[3]]);
             let code_size = il_u32(method_il, 4)?;
             let local_var_sig_tok = il_u32(method_il, 8)?;
@@ -177,6 +177,21 @@ impl MethodHeader {
         }
     }
 
+    pub fn set_max_stack(&mut self, max_stack: u16) {
+        match self {
+            MethodHeader::Fat(header) => header.max_stack = max_stack,
+            MethodHeader::Tiny(_) => (),
+        }
+    }
+
+    /// Sets whether more sections follow the code. Has no effect on a tiny header
+    pub fn set_more_sects(&mut self, more_sects: bool) {
+        match self {
+            MethodHeader::Fat(header) => header.more_sects = more_sects,
+            MethodHeader::Tiny(_) => (),
+        }
+    }
+
     pub fn max_stack(&self) -> u16 {
         match self {
             MethodHeader::Fat(header) => header.max_stack,
@@ -184,6 +199,14 @@ impl MethodHeader {
         }
     }
 
+    /// Whether local variables are initialized to their default value
+    pub fn init_locals(&self) -> bool {
+        match self {
+            MethodHeader::Fat(header) => header.init_locals,
+            MethodHeader::Tiny(_) => false,
+        }
+    }
+
     pub fn code_size(&self) -> u32 {
         match self {
             MethodHeader::Fat(header) => header.code_size,
@@ -197,7 +220,7 @@ impl MethodHeader {
             CorILMethodFlags::CorILMethod_MoreSects.bits(),
         )
     }
-    fn init_locals(method_header_flags: u8) -> bool {
+    fn has_init_locals(method_header_flags: u8) -> bool {
         check_flag(
             method_header_flags,
             CorILMethodFlags::CorILMethod_InitLocals.bits(),
@@ -215,21 +238,24 @@ impl MethodHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Method {
     /// The starting memory address of the method, if read from IL
     pub address: usize,
     pub header: MethodHeader,
     pub instructions: Vec<Instruction>,
     pub sections: Vec<Section>,
+    /// The offset of each instruction in the IL the method was read from, or
+    /// `None` for an added instruction. Instructions beyond the end have no offset.
+    pub(crate) original_offsets: Vec<Option<u32>>,
 }
 
 impl Method {
     /// Creates a tiny method with the given instructions. If the code size
-    /// of the instructions is greater than [u8::MAX], an error result is returned.
+    /// of the instructions is greater than [TinyMethodHeader::MAX_CODE_SIZE], an error result is returned.
     pub fn tiny(instructions: Vec<Instruction>) -> Result<Self, Error> {
         let code_size: usize = instructions.iter().map(|i| i.len()).sum();
-        if code_size > u8::MAX as usize {
+        if code_size > TinyMethodHeader::MAX_CODE_SIZE as usize {
             Err(Error::InvalidCil)
         } else {
             Ok(Method {
@@ -237,6 +263,7 @@ impl Method {
                 header: MethodHeader::tiny(code_size as u8),
                 instructions,
                 sections: vec![],
+                original_offsets: vec![],
             })
         }
     }
@@ -256,12 +283,19 @@ impl Method {
             }
             _ => vec![], // only fat headers with the more sections flag set have additional sections
         };
-        Ok(Method {
+        let mut method = Method {
             address,
             header,
             instructions,
             sections,
-        })
+            original_offsets: vec![],
+        };
+        method.original_offsets = method
+            .get_instruction_offsets()
+            .into_iter()
+            .map(Some)
+            .collect();
+        Ok(method)
     }
 
     /// Expands a tiny method into a fat method
@@ -370,51 +404,23 @@ impl Method {
         bytes
     }
 
-    /// replaces an instruction at the specified index
+    /// Replaces the instruction at the specified index. Branches to the
+    /// instruction and exception clauses starting or ending at it are
+    /// relocated to the replacement.
     pub fn replace(&mut self, index: usize, instruction: Instruction) -> Result<(), Error> {
-        let offset = self.get_offset(index);
-
-        let (existing_len, existing_stack_size) = {
-            let existing_instruction = &self.instructions[index];
-            (
-                existing_instruction.len() as i64,
-                existing_instruction.stack_size() as i64,
-            )
-        };
-
-        let len = instruction.len() as i64;
-        let stack_size = instruction.stack_size() as i64;
-        let len_diff = len - existing_len;
-        let stack_size_diff = stack_size - existing_stack_size;
-
-        self.update_header(len_diff, Some(stack_size_diff))?;
-        self.update_sections(offset, len_diff)?;
-        self.update_instructions(index, offset, len_diff);
-        let _ = self.instructions.remove(index);
-        self.instructions.insert(index, instruction);
-        Ok(())
+        let mut edits = MethodEdits::new();
+        edits.replace(index, vec![instruction]);
+        self.apply_edits(edits)
     }
 
-    /// inserts an instruction at the specified index
+    /// Inserts an instruction at the specified index. The inserted instruction
+    /// takes the place of the instruction at the index as the target of branches
+    /// and the boundary of exception clauses, so that it runs on every path
+    /// that reaches the instruction.
     pub fn insert(&mut self, index: usize, instruction: Instruction) -> Result<(), Error> {
-        let len = instruction.len() as i64;
-        let stack_size = instruction.stack_size() as i64;
-        self.update_header(len, Some(stack_size))?;
-
-        let offset = self.get_offset(index);
-        self.update_sections(offset, len)?;
-        self.update_instructions(index, offset, len);
-        self.instructions.insert(index, instruction);
-        Ok(())
-    }
-
-    #[inline]
-    fn get_offset(&self, index: usize) -> usize {
-        self.instructions
-            .iter()
-            .take(index + 1)
-            .map(|i| i.len())
-            .sum()
+        let mut edits = MethodEdits::new();
+        edits.prepend(index, vec![instruction]);
+        self.apply_edits(edits)
     }
 
     pub fn get_instruction_offsets(&self) -> Vec<u32> {
@@ -428,142 +434,44 @@ impl Method {
         offsets
     }
 
-    fn update_instructions(&mut self, index: usize, offset: usize, len: i64) {
-        // update the offsets of control flow instructions and expand any short instructions:
-        //
-        // 1. for control flow instructions before the target index,
-        //    if the offset is positive and results in an index after the target index,
-        //    add len to the offset
-        // 2. for control flow instructions after the target index,
-        //    if the offset is negative and results in an index before the target index,
-        //    subtract len from the offset i.e. offset is further away
-        let mut map: Vec<usize> = self.instructions.iter().map(|i| i.len()).collect();
-        let mut updated_instructions = vec![];
-        for (i, instruction) in self.instructions.iter_mut().enumerate() {
-            if i < index {
-                if let ShortInlineBrTarget(target_offset) = &mut instruction.operand {
-                    if *target_offset >= 0 {
-                        let mut sum_len = 0;
-                        let mut j = 1;
-                        while sum_len < *target_offset as usize {
-                            sum_len += map[i + j];
-                            j += 1;
-                        }
-                        if i + j > index {
-                            let n = *target_offset as i32 + len as i32;
-                            if n > i8::MAX as i32 {
-                                let current_len = instruction.len();
-
-                                // update the instruction
-                                instruction.operand = InlineBrTarget(n);
-                                instruction.opcode = Opcode::short_to_long_form(instruction.opcode);
-
-                                // update the map with the new instruction len and record
-                                // the original offset and len diff.
-                                let new_len = instruction.len();
-                                map[i] = new_len;
-                                updated_instructions.push((offset, (new_len - current_len) as i64));
-                            } else {
-                                *target_offset = n as i8;
-                            }
-                        }
-                    }
-                } else if let InlineBrTarget(target_offset) = &mut instruction.operand {
-                    if *target_offset >= 0 {
-                        let mut sum_len = 0;
-                        let mut j = 1;
-                        while sum_len < *target_offset as usize {
-                            sum_len += map[i + j];
-                            j += 1;
-                        }
-                        if i + j > index {
-                            let n = *target_offset + len as i32;
-                            *target_offset = n;
-                        }
-                    }
-                } else if let InlineSwitch(count, target_offsets) = &mut instruction.operand {
-                    for target_offset in target_offsets {
-                        if *target_offset >= 0 {
-                            let mut sum_len = 0;
-                            let mut j = 1;
-                            while sum_len < *target_offset as usize {
-                                sum_len += map[i + j];
-                                j += 1;
-                            }
-                            if i + j > index {
-                                *target_offset += len as i32;
-                            }
-                        }
-                    }
-                }
-            } else {
-                if let ShortInlineBrTarget(target_offset) = &mut instruction.operand {
-                    if *target_offset < 0 {
-                        let mut sum_len = 0;
-                        let mut j = 0;
-                        while *target_offset < sum_len {
-                            sum_len -= map[i - j] as i8;
-                            j += 1;
-                        }
-                        if i - j < index {
-                            let n = *target_offset as i32 - len as i32;
-                            if n < i8::MIN as i32 {
-                                let current_len = instruction.len();
-
-                                // update the instruction
-                                instruction.operand = InlineBrTarget(n);
-                                instruction.opcode = Opcode::short_to_long_form(instruction.opcode);
-
-                                // update the map with the new instruction len and record
-                                // the original offset and len diff.
-                                let new_len = instruction.len();
-                                map[i] = new_len;
-                                updated_instructions.push((offset, (new_len - current_len) as i64));
-                            } else {
-                                *target_offset = n as i8;
-                            }
-                        }
-                    }
-                } else if let InlineBrTarget(target_offset) = &mut instruction.operand {
-                    if *target_offset < 0 {
-                        let mut sum_len = 0;
-                        let mut j = 0;
-                        while *target_offset < sum_len {
-                            sum_len -= map[i - j] as i32;
-                            j += 1;
-                        }
-                        if i - j < index {
-                            let n = *target_offset - len as i32;
-                            *target_offset = n;
-                        }
-                    }
-                } else if let InlineSwitch(count, target_offsets) = &mut instruction.operand {
-                    for target_offset in target_offsets {
-                        if *target_offset < 0 {
-                            let mut sum_len = 0;
-                            let mut j = 0;
-                            while *target_offset < sum_len {
-                                sum_len -= map[i - j] as i32;
-                                j += 1;
-                            }
-                            if i - j < index {
-                                *target_offset -= len as i32;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        if !updated_instructions.is_empty() {
-            for (offset, len) in updated_instructions {
-                self.update_header(len, None).unwrap();
-                self.update_sections(offset, len).unwrap();
+    /// Gets the offset of the instruction at the index in the IL the method was
+    /// read from, or `None` if the instruction was added by an edit
+    pub fn original_offset(&self, index: usize) -> Option<u32> {
+        self.original_offsets.get(index).copied().flatten()
+    }
+
+    /// Gets the map of the offsets of instructions in the IL the method was read
+    /// from to their offsets in the current IL, ordered by original offset. The
+    /// map is passed to SetILInstrumentedCodeMap so that debuggers and stack traces
+    /// resolve offsets in the rewritten IL to the original IL, and is empty when
+    /// none of the original instructions remain.
+    pub fn il_map(&self) -> Vec<COR_IL_MAP> {
+        let mut map: Vec<COR_IL_MAP> = self
+            .get_instruction_offsets()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, offset)| {
+                self.original_offset(index).map(|original| COR_IL_MAP {
+                    oldOffset: original,
+                    newOffset: offset,
+                    fAccurate: 1,
+                })
+            })
+            .collect();
+        map.sort_by_key(|entry| (entry.oldOffset, entry.newOffset));
+        map
+    }
+
+    pub(crate) fn update_header(&mut self, len: i64, stack_size: Option<i64>) -> Result<(), Error> {
+        // a tiny header cannot represent more than 63 bytes of code, or a max stack above 8,
+        // so expand to a fat header when the change no longer fits
+        if let MethodHeader::Tiny(header) = &self.header {
+            let size = header.code_size as i64 + len;
+            if size > TinyMethodHeader::MAX_CODE_SIZE as i64 || stack_size.unwrap_or_default() > 0 {
+                self.expand_tiny_to_fat();
             }
         }
-    }
 
-    fn update_header(&mut self, len: i64, stack_size: Option<i64>) -> Result<(), Error> {
         // update code_size and max_stack in method_header
         match &mut self.header {
             MethodHeader::Fat(header) => {
@@ -576,86 +484,39 @@ impl Method {
             }
             MethodHeader::Tiny(header) => {
                 let size = header.code_size as i64 + len as i64;
-                header.code_size = u8::try_from(size)
-                    .or_else(|err| todo!("Expand tiny into fat header!, {:?}", err))?;
+                header.code_size = u8::try_from(size).or(Err(Error::CodeSize))?;
             }
         }
 
         Ok(())
     }
 
-    fn update_sections(&mut self, offset: usize, len: i64) -> Result<(), Error> {
-        // update try offset, try length, handler offset, handler length, and filter offset
-        for section in &mut self.sections {
-            match section {
-                Section::FatSection(_, clauses) => {
-                    for clause in clauses {
-                        if (offset as u32) <= clause.try_offset {
-                            let try_offset = clause.try_offset as i64 + len as i64;
-                            clause.try_offset =
-                                u32::try_from(try_offset).or(Err(Error::CodeSize))?;
-                        } else if (offset as u32) <= clause.try_offset + clause.try_length {
-                            let try_length = clause.try_length as i64 + len as i64;
-                            clause.try_length =
-                                u32::try_from(try_length).or(Err(Error::CodeSize))?;
-                        }
+    /// Sets the clauses of each section from updated fat clauses
+    pub(crate) fn set_clauses(
+        &mut self,
+        updated_sections: Vec<Vec<FatSectionClause>>,
+    ) -> Result<(), Error> {
+        let expand = self
+            .sections
+            .iter()
+            .zip(&updated_sections)
+            .any(|(section, clauses)| {
+                matches!(section, Section::SmallSection(_, _))
+                    && !clauses.iter().all(SmallSectionClause::fits)
+            });
 
-                        if (offset as u32) <= clause.handler_offset {
-                            let handler_offset = clause.handler_offset as i64 + len as i64;
-                            clause.handler_offset =
-                                u32::try_from(handler_offset).or(Err(Error::CodeSize))?;
-                        } else if (offset as u32) <= clause.handler_offset + clause.handler_length {
-                            let handler_length = clause.handler_length as i64 + len as i64;
-                            clause.handler_length =
-                                u32::try_from(handler_length).or(Err(Error::CodeSize))?;
-                        }
+        // small clauses hold 16-bit offsets and 8-bit lengths, so expand
+        // to fat sections when an updated clause no longer fits
+        if expand {
+            self.expand_small_sections_to_fat();
+        }
 
-                        if clause
-                            .flag
-                            .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER)
-                            && (offset as u32) <= clause.class_token_or_filter_offset
-                        {
-                            let filter_offset =
-                                clause.class_token_or_filter_offset as i64 + len as i64;
-                            clause.class_token_or_filter_offset =
-                                u32::try_from(filter_offset).or(Err(Error::CodeSize))?;
-                        }
-                    }
-                }
+        for (section, updated_clauses) in self.sections.iter_mut().zip(updated_sections) {
+            match section {
+                Section::FatSection(_, clauses) => *clauses = updated_clauses,
                 Section::SmallSection(_, clauses) => {
-                    for clause in clauses {
-                        if (offset as u16) <= clause.try_offset {
-                            let try_offset = clause.try_offset as i64 + len as i64;
-                            clause.try_offset = u16::try_from(try_offset)
-                                .or_else(|err| todo!("Expand into fat section!, {:?}", err))?;
-                        } else if (offset as u16) <= clause.try_offset + clause.try_length as u16 {
-                            let try_length = clause.try_length as i64 + len as i64;
-                            clause.try_length =
-                                u8::try_from(try_length).or(Err(Error::CodeSize))?;
-                        }
-
-                        if (offset as u16) <= clause.handler_offset {
-                            let handler_offset = clause.handler_offset as i64 + len as i64;
-                            clause.handler_offset = u16::try_from(handler_offset)
-                                .or_else(|err| todo!("Expand into fat section!, {:?}", err))?;
-                        } else if (offset as u16)
-                            <= clause.handler_offset + clause.handler_length as u16
-                        {
-                            let handler_length = clause.handler_length as i64 + len as i64;
-                            clause.handler_length =
-                                u8::try_from(handler_length).or(Err(Error::CodeSize))?;
-                        }
-
-                        if clause
-                            .flag
-                            .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER)
-                            && (offset as u16) <= clause.class_token_or_filter_offset as u16
-                        {
-                            let filter_offset =
-                                clause.class_token_or_filter_offset as i64 + len as i64;
-                            clause.class_token_or_filter_offset =
-                                u32::try_from(filter_offset).or(Err(Error::CodeSize))?;
-                        }
+                    for (clause, updated) in clauses.iter_mut().zip(updated_clauses) {
+                        *clause = SmallSectionClause::try_from(updated)?;
                     }
                 }
             }
@@ -666,12 +527,9 @@ impl Method {
 
     /// Inserts instructions at the start
     pub fn insert_prelude(&mut self, instructions: Vec<Instruction>) -> Result<(), Error> {
-        let len: usize = instructions.iter().map(|i| i.len()).sum();
-        let stack_size: usize = instructions.iter().map(|i| i.stack_size()).sum();
-        self.update_header(len as i64, Some(stack_size as i64))?;
-        self.update_sections(0, len as i64)?;
-        self.instructions.splice(0..0, instructions);
-        Ok(())
+        let mut edits = MethodEdits::new();
+        edits.insert(0, instructions);
+        self.apply_edits(edits)
     }
 
     fn instructions_from_bytes(il: &[u8]) -> Result<Vec<Instruction>, Error> {
@@ -753,3 +611,146 @@ impl Display for Method {
         d.finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{
+            CorExceptionFlag, Instruction, Method, MethodBuilder, MethodHeader, MethodToken,
+            Operand, Section, SmallSectionClause, SmallSectionHeader, BR,
+        },
+        ffi::COR_IL_MAP,
+    };
+    use std::convert::TryFrom;
+
+    fn fat(instructions: Vec<Instruction>) -> Method {
+        let code_size: usize = instructions.iter().map(|i| i.len()).sum();
+        Method {
+            address: 0,
+            header: MethodHeader::fat(false, false, 8, code_size as u32, 0),
+            instructions,
+            sections: vec![],
+            original_offsets: vec![],
+        }
+    }
+
+    fn long_branch_delta(instruction: &Instruction) -> i32 {
+        assert_eq!(instruction.opcode, BR);
+        match instruction.operand {
+            Operand::InlineBrTarget(delta) => delta,
+            ref operand => panic!("unexpected operand {:?}", operand),
+        }
+    }
+
+    #[test]
+    fn insert_relocates_switch_targets() {
+        // IL_0000: switch (IL_0012, IL_0011, IL_0000); IL_0011: nop; IL_0012: ret
+        let mut method = fat(vec![
+            Instruction::switch(3, vec![1, 0, -17]),
+            Instruction::nop(),
+            Instruction::ret(),
+        ]);
+        method.insert(1, Instruction::ldc_i4(0)).unwrap();
+        method.insert(3, Instruction::pop()).unwrap();
+
+        match &method.instructions[0].operand {
+            Operand::InlineSwitch(3, deltas) => assert_eq!(deltas, &vec![6, 0, -17]),
+            operand => panic!("unexpected operand {:?}", operand),
+        }
+        assert_eq!(method.header.code_size(), 25);
+    }
+
+    #[test]
+    fn insert_cascades_short_to_long_promotions() {
+        // the inserted instruction pushes the backward branch out of short range,
+        // and its promotion pushes the forward branch out of short range
+        let mut instructions = vec![Instruction::nop(); 121];
+        instructions.push(Instruction::br_s(126));
+        instructions.push(Instruction::br_s(-125));
+        instructions.extend(vec![Instruction::nop(); 124]);
+        instructions.push(Instruction::ret());
+        let mut method = fat(instructions);
+
+        method.insert(1, Instruction::ldc_i4(0)).unwrap();
+
+        assert_eq!(long_branch_delta(&method.instructions[122]), 129);
+        assert_eq!(long_branch_delta(&method.instructions[123]), -136);
+        assert_eq!(method.header.code_size(), 261);
+    }
+
+    #[test]
+    fn insert_expands_tiny_header_and_small_sections() {
+        let mut instructions = vec![Instruction::nop(); 62];
+        instructions.push(Instruction::ret());
+        let mut method = Method::tiny(instructions).unwrap();
+        method
+            .insert_prelude(vec![Instruction::call(
+                MethodToken::try_from(0x06000001).unwrap(),
+            )])
+            .unwrap();
+        assert!(matches!(method.header, MethodHeader::Fat(_)));
+        assert_eq!(method.header.code_size(), 68);
+
+        let mut instructions = vec![Instruction::nop(); 255];
+        instructions.push(Instruction::ret());
+        let mut method = Method {
+            address: 0,
+            header: MethodHeader::fat(true, false, 8, 256, 0),
+            instructions,
+            sections: vec![Section::SmallSection(
+                SmallSectionHeader {
+                    is_eh_table: true,
+                    more_sects: false,
+                    data_size: 16,
+                },
+                vec![SmallSectionClause {
+                    flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                    try_offset: 0,
+                    try_length: 254,
+                    handler_offset: 254,
+                    handler_length: 1,
+                    class_token_or_filter_offset: 0,
+                }],
+            )],
+            original_offsets: vec![],
+        };
+        method.insert(10, Instruction::ldc_i4(0)).unwrap();
+        match &method.sections[0] {
+            Section::FatSection(_, clauses) => {
+                assert_eq!(clauses[0].try_length, 259);
+                assert_eq!(clauses[0].handler_offset, 259);
+            }
+            Section::SmallSection(_, _) => panic!("expected fat section"),
+        }
+    }
+
+    #[test]
+    fn il_map_follows_edits_and_rebuilds() {
+        // ldarg.0; pop; ret
+        let mut method = Method::new(&[0x0e, 0x02, 0x26, 0x2a]).unwrap();
+        method
+            .insert_prelude(vec![Instruction::call(
+                MethodToken::try_from(0x0a000001).unwrap(),
+            )])
+            .unwrap();
+        method
+            .replace(3, Instruction::nop())
+            .and_then(|_| method.insert(4, Instruction::ret()))
+            .unwrap();
+
+        let entry = |old, new| COR_IL_MAP {
+            oldOffset: old,
+            newOffset: new,
+            fAccurate: 1,
+        };
+        assert_eq!(method.original_offset(0), None);
+        assert_eq!(method.original_offset(4), None);
+        assert_eq!(method.il_map(), vec![entry(0, 5), entry(1, 6), entry(2, 7)]);
+
+        let rebuilt = MethodBuilder::from_method(&method)
+            .unwrap()
+            .into_method()
+            .unwrap();
+        assert_eq!(rebuilt.il_map(), method.il_map());
+    }
+}
@@ -3,13 +3,27 @@
 // Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
 // See the LICENSE file in the project root for more information
 
+mod assembler;
+mod builder;
+mod cfg;
 mod cor;
+mod disassembler;
+mod edit;
 mod helpers;
 mod instruction;
 mod method;
 mod opcode;
+mod optimizer;
+mod region;
 mod section;
+mod signature;
+mod stack;
+mod token;
+mod verifier;
 
-pub use self::{cor::*, helpers::*, instruction::*, method::*, opcode::*, section::*};
+pub use self::{
+    assembler::*, builder::*, cfg::*, cor::*, disassembler::*, edit::*, helpers::*, instruction::*,
+    method::*, opcode::*, region::*, section::*, signature::*, stack::*, token::*, verifier::*,
+};
 
 pub const MAX_LENGTH: u32 = 1024;
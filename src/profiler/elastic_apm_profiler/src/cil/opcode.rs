@@ -46,6 +46,20 @@ pub enum StackBehaviorPop {
     PopRefPopIPop1,
     PopIPopIPopI,
 }
+impl StackBehaviorPop {
+    /// the number of values popped from the stack. [StackBehaviorPop::VarPop]
+    /// depends on the instruction operand, and returns 0
+    pub fn size(&self) -> usize {
+        match self {
+            Pop0 | VarPop => 0,
+            Pop1 | PopI | PopRef => 1,
+            Pop1Pop1 | PopIPopI | PopIPopI8 | PopIPopR4 | PopIPopR8 | PopRefPop1 | PopIPop1
+            | PopRefPopI => 2,
+            PopRefPopIPopI | PopRefPopIPopI8 | PopRefPopIPopR4 | PopRefPopIPopR8
+            | PopRefPopIPopRef | PopRefPopIPop1 | PopIPopIPopI => 3,
+        }
+    }
+}
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum StackBehaviorPush {
     Push0,
@@ -470,9 +484,41 @@ impl Opcode {
             BLE_UN_S => BLE_UN,
             BLT_UN_S => BLT_UN,
             BNE_UN_S => BNE_UN,
+            LEAVE_S => LEAVE,
             _ => opcode,
         }
     }
+
+    /// Gets the short form of a long branch opcode, if there is one
+    pub fn long_to_short_form(opcode: Opcode) -> Option<Opcode> {
+        match opcode {
+            BRFALSE => Some(BRFALSE_S),
+            BRTRUE => Some(BRTRUE_S),
+            BEQ => Some(BEQ_S),
+            BGE => Some(BGE_S),
+            BGT => Some(BGT_S),
+            BLE => Some(BLE_S),
+            BLT => Some(BLT_S),
+            BR => Some(BR_S),
+            BGE_UN => Some(BGE_UN_S),
+            BGT_UN => Some(BGT_UN_S),
+            BLE_UN => Some(BLE_UN_S),
+            BLT_UN => Some(BLT_UN_S),
+            BNE_UN => Some(BNE_UN_S),
+            LEAVE => Some(LEAVE_S),
+            _ => None,
+        }
+    }
+
+    /// Looks up an opcode by its mnemonic e.g. `ldarg.0`. Unused and internal
+    /// opcodes cannot be looked up.
+    pub fn from_name(name: &str) -> Option<Opcode> {
+        (0..=u8::MAX)
+            .filter(|b| *b != 0xFE)
+            .map(Opcode::from_byte)
+            .chain((0..=0x22).filter_map(|b| Opcode::from_byte_pair((0xFE, b)).ok()))
+            .find(|o| o.name == name && o.name != "unused" && o.opcode_kind != Internal)
+    }
 }
 
 use self::{
@@ -0,0 +1,281 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{
+        edit::{branch_targets, Item},
+        HandlerKind, Instruction, Method, Opcode, Operand, Section, SingleByte, LDARG, LDARGA,
+        LDARGA_S, LDARG_S, LDC_I4, LDC_I4_S, LDLOC, LDLOCA, LDLOCA_S, LDLOC_S, NOP, STARG, STARG_S,
+        STLOC, STLOC_S,
+    },
+    error::Error,
+};
+use std::convert::TryFrom;
+
+impl Method {
+    /// Rewrites the method to use the shortest encoding of branches, argument
+    /// and local variable instructions and 32-bit integer constants, and removes
+    /// nops. Branch targets and exception handling clauses are kept on the same
+    /// instructions, and the code size is updated. The method is left unchanged
+    /// when an error is returned.
+    ///
+    /// A nop is kept when it is the last instruction, the only instruction of a
+    /// try, filter or handler block, or a branch target immediately before the
+    /// boundary of a block, where moving the target would move it across the boundary.
+    pub fn optimize(&mut self) -> Result<(), Error> {
+        let len = self.instructions.len();
+        let mut offsets = self.get_instruction_offsets();
+        let code_size = offsets.last().copied().unwrap_or_default()
+            + self.instructions.last().map_or(0, |i| i.len() as u32);
+        offsets.push(code_size);
+
+        let mut targets = Vec::with_capacity(len);
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let next = offsets[index + 1] as i64;
+            targets.push(
+                branch_targets(instruction)
+                    .iter()
+                    .map(|delta| {
+                        u32::try_from(next + *delta)
+                            .ok()
+                            .and_then(|o| offsets[..len].binary_search(&o).ok())
+                            .ok_or(Error::InvalidCil)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+
+        let mut regions = self.exception_regions()?;
+        let mut blocks = Vec::with_capacity(regions.len() * 3);
+        for region in &regions {
+            blocks.push((region.try_start, region.try_end));
+            blocks.push((region.handler_start, region.handler_end));
+            if let HandlerKind::Filter(filter_start) = region.handler {
+                blocks.push((filter_start, region.handler_start));
+            }
+        }
+
+        let is_target = |index: usize| targets.iter().flatten().any(|t| *t == index);
+        let is_boundary = |index: usize| {
+            blocks
+                .iter()
+                .any(|(start, end)| *start == index || *end == index)
+        };
+
+        let mut keep: Vec<bool> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| {
+                instruction.opcode != NOP
+                    || index + 1 == len
+                    || (is_target(index) && is_boundary(index + 1))
+            })
+            .collect();
+        for (start, end) in &blocks {
+            if *start < *end && !keep[*start..*end].iter().any(|k| *k) {
+                keep[*start] = true;
+            }
+        }
+
+        // the index of each instruction after nops are removed. A removed nop
+        // takes the index of the next instruction that is kept
+        let mut new_index = Vec::with_capacity(len + 1);
+        let mut count = 0;
+        for k in &keep {
+            new_index.push(count);
+            if *k {
+                count += 1;
+            }
+        }
+        new_index.push(count);
+
+        let mut items: Vec<Item> = self
+            .instructions
+            .iter()
+            .zip(targets)
+            .enumerate()
+            .filter(|(index, _)| keep[*index])
+            .map(|(index, (instruction, targets))| Item {
+                instruction: shortest_form(instruction),
+                targets: targets.iter().map(|t| new_index[*t]).collect(),
+                origin: self.original_offset(index),
+            })
+            .collect();
+
+        let new_offsets = Self::layout(&mut items)?;
+        let new_code_size = new_offsets[items.len()];
+
+        // build the clauses before changing the method, so that it is left
+        // unchanged if they cannot be represented
+        let mut clauses = Vec::with_capacity(regions.len());
+        for region in &mut regions {
+            if let HandlerKind::Filter(filter_start) = &mut region.handler {
+                *filter_start = new_index[*filter_start];
+            }
+            region.try_start = new_index[region.try_start];
+            region.try_end = new_index[region.try_end];
+            region.handler_start = new_index[region.handler_start];
+            region.handler_end = new_index[region.handler_end];
+            clauses.push(region.to_clause(&new_offsets)?);
+        }
+        let sections = if clauses.is_empty() {
+            vec![]
+        } else {
+            vec![Section::from_clauses(clauses)?]
+        };
+
+        self.update_header(new_code_size as i64 - code_size as i64, None)?;
+        self.original_offsets = items.iter().map(|i| i.origin).collect();
+        self.instructions = items.into_iter().map(|i| i.instruction).collect();
+        self.sections = sections;
+        self.header.set_more_sects(!self.sections.is_empty());
+
+        Ok(())
+    }
+}
+
+/// Gets the shortest encoding of an instruction. Branches are given their short
+/// form, to be promoted back to the long form by layout when the target is out of range.
+fn shortest_form(instruction: &Instruction) -> Instruction {
+    let opcode = instruction.opcode;
+    let index = match instruction.operand {
+        Operand::InlineVar(index) => Some(index),
+        Operand::ShortInlineVar(index) => Some(index as u16),
+        _ => None,
+    };
+
+    match (opcode, index) {
+        (LDARG, Some(i)) | (LDARG_S, Some(i)) => Instruction::load_argument(i),
+        (LDLOC, Some(i)) | (LDLOC_S, Some(i)) => Instruction::load_local(i),
+        (STLOC, Some(i)) | (STLOC_S, Some(i)) => Instruction::store_local(i),
+        (LDLOCA, Some(i)) | (LDLOCA_S, Some(i)) => Instruction::load_local_address(i),
+        (LDARGA, Some(i)) | (LDARGA_S, Some(i)) if i <= u8::MAX as u16 => {
+            Instruction::ldarga_s(i as u8)
+        }
+        (STARG, Some(i)) | (STARG_S, Some(i)) if i <= u8::MAX as u16 => {
+            Instruction::starg_s(i as u8)
+        }
+        _ => match instruction.operand {
+            Operand::InlineI(val) if opcode == LDC_I4 => Instruction::load_int32(val),
+            // ldc.i4.s sign extends its operand
+            Operand::ShortInlineI(SingleByte::Signed(val)) if opcode == LDC_I4_S => {
+                Instruction::load_int32(val as i32)
+            }
+            Operand::ShortInlineI(SingleByte::Unsigned(val)) if opcode == LDC_I4_S => {
+                Instruction::load_int32(val as i8 as i32)
+            }
+            Operand::InlineBrTarget(_) => match Opcode::long_to_short_form(opcode) {
+                Some(short) => Instruction {
+                    opcode: short,
+                    operand: Operand::ShortInlineBrTarget(0),
+                },
+                None => instruction.clone(),
+            },
+            _ => instruction.clone(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cil::{
+        CorExceptionFlag, FatSectionClause, Instruction, Method, MethodHeader, Operand, Section,
+        BR_S, LDARG_1, LDC_I4_S, LDLOC_S, LEAVE_S, STLOC_0,
+    };
+
+    #[test]
+    fn optimize_shrinks_encodings_and_keeps_targets() {
+        let instructions = vec![
+            // IL_0000
+            Instruction::ldarg(1),
+            Instruction::nop(),
+            Instruction::brtrue(0),
+            // IL_000a: try
+            Instruction::nop(),
+            Instruction::ldc_i4(100),
+            Instruction::stloc(0),
+            Instruction::leave(13),
+            // IL_0019: finally
+            Instruction::nop(),
+            Instruction::endfinally(),
+            // IL_001b
+            Instruction::ldloc(200),
+            Instruction::pop(),
+            Instruction::nop(),
+            Instruction::br(-38),
+            // IL_0026
+            Instruction::nop(),
+            Instruction::ret(),
+        ];
+        let code_size: usize = instructions.iter().map(|i| i.len()).sum();
+        let mut method = Method {
+            address: 0,
+            header: MethodHeader::fat(false, false, 1, code_size as u32, 0),
+            instructions,
+            sections: vec![],
+            original_offsets: vec![],
+        };
+        method
+            .push_clauses(vec![FatSectionClause {
+                flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                try_offset: 0x0a,
+                try_length: 0x0f,
+                handler_offset: 0x19,
+                handler_length: 2,
+                class_token_or_filter_offset: 0,
+            }])
+            .unwrap();
+
+        method.optimize().unwrap();
+
+        let names: Vec<&str> = method.instructions.iter().map(|i| i.opcode.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "ldarg.1",
+                "brtrue.s",
+                "ldc.i4.s",
+                "stloc.0",
+                "leave.s",
+                "endfinally",
+                "ldloc.s",
+                "pop",
+                "br.s",
+                "ret"
+            ]
+        );
+        assert_eq!(method.instructions[0].opcode, LDARG_1);
+        assert_eq!(method.instructions[2].opcode, LDC_I4_S);
+        assert_eq!(method.instructions[3].opcode, STLOC_0);
+        assert_eq!(method.instructions[6].opcode, LDLOC_S);
+
+        let deltas: Vec<i8> = method
+            .instructions
+            .iter()
+            .filter_map(|i| match i.operand {
+                Operand::ShortInlineBrTarget(delta) => Some(delta),
+                _ => None,
+            })
+            .collect();
+        // brtrue.s to the try block, leave.s to the ret, br.s to the start
+        assert_eq!(method.instructions[1].opcode.name, "brtrue.s");
+        assert_eq!(method.instructions[4].opcode, LEAVE_S);
+        assert_eq!(method.instructions[8].opcode, BR_S);
+        assert_eq!(deltas, vec![0, 6, -14]);
+        assert_eq!(method.header.code_size(), 15);
+
+        // the try block loses its leading nop, and the finally block its leading nop
+        match &method.sections[0] {
+            Section::SmallSection(_, clauses) => {
+                assert_eq!(clauses[0].try_offset, 3);
+                assert_eq!(clauses[0].try_length, 5);
+                assert_eq!(clauses[0].handler_offset, 8);
+                assert_eq!(clauses[0].handler_length, 1);
+            }
+            section => panic!("unexpected section {:?}", section),
+        }
+    }
+}
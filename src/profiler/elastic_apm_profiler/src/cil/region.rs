@@ -0,0 +1,223 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{CorExceptionFlag, FatSectionClause, Method, Section},
+    error::Error,
+    ffi::mdToken,
+};
+
+/// The handler of an exception region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerKind {
+    /// Handles exceptions of the type token
+    Catch(mdToken),
+    /// Handles exceptions for which the filter starting at the instruction index returns true
+    Filter(usize),
+    Finally,
+    Fault,
+}
+
+/// An exception handling clause with the try and handler blocks expressed as
+/// instruction indexes, rather than byte offsets. End indexes are exclusive, and
+/// may be equal to the number of instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionRegion {
+    pub handler: HandlerKind,
+    pub try_start: usize,
+    pub try_end: usize,
+    pub handler_start: usize,
+    pub handler_end: usize,
+}
+
+impl ExceptionRegion {
+    /// Creates a clause from the region, with indexes translated to offsets
+    pub(crate) fn to_clause(self, offsets: &[u32]) -> Result<FatSectionClause, Error> {
+        let offset = |index: usize| -> Result<u32, Error> {
+            offsets
+                .get(index)
+                .copied()
+                .ok_or(Error::InvalidSectionHeader)
+        };
+        let length = |start: usize, end: usize| -> Result<u32, Error> {
+            offset(end)?
+                .checked_sub(offset(start)?)
+                .ok_or(Error::InvalidSectionHeader)
+        };
+
+        let (flag, class_token_or_filter_offset) = match self.handler {
+            HandlerKind::Catch(token) => (CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE, token),
+            HandlerKind::Filter(index) => (
+                CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER,
+                offset(index)?,
+            ),
+            HandlerKind::Finally => (CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY, 0),
+            HandlerKind::Fault => (CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT, 0),
+        };
+
+        Ok(FatSectionClause {
+            flag,
+            try_offset: offset(self.try_start)?,
+            try_length: length(self.try_start, self.try_end)?,
+            handler_offset: offset(self.handler_start)?,
+            handler_length: length(self.handler_start, self.handler_end)?,
+            class_token_or_filter_offset,
+        })
+    }
+}
+
+impl Method {
+    /// Gets the exception handling clauses of the method as regions. Returns
+    /// [Error::InvalidSectionHeader] when a clause boundary is not the start
+    /// of an instruction or the end of the method.
+    pub fn exception_regions(&self) -> Result<Vec<ExceptionRegion>, Error> {
+        let mut offsets = self.get_instruction_offsets();
+        offsets.push(
+            offsets.last().copied().unwrap_or_default()
+                + self.instructions.last().map_or(0, |i| i.len() as u32),
+        );
+        let index = |offset: u32| -> Result<usize, Error> {
+            offsets
+                .binary_search(&offset)
+                .or(Err(Error::InvalidSectionHeader))
+        };
+        let end = |offset: u32, length: u32| -> Result<usize, Error> {
+            index(
+                offset
+                    .checked_add(length)
+                    .ok_or(Error::InvalidSectionHeader)?,
+            )
+        };
+
+        let mut regions = Vec::new();
+        for clause in self.sections.iter().flat_map(|s| s.fat_clauses()) {
+            let handler = if clause
+                .flag
+                .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER)
+            {
+                HandlerKind::Filter(index(clause.class_token_or_filter_offset)?)
+            } else if clause
+                .flag
+                .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY)
+            {
+                HandlerKind::Finally
+            } else if clause
+                .flag
+                .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT)
+            {
+                HandlerKind::Fault
+            } else {
+                HandlerKind::Catch(clause.class_token_or_filter_offset)
+            };
+            regions.push(ExceptionRegion {
+                handler,
+                try_start: index(clause.try_offset)?,
+                try_end: end(clause.try_offset, clause.try_length)?,
+                handler_start: index(clause.handler_offset)?,
+                handler_end: end(clause.handler_offset, clause.handler_length)?,
+            });
+        }
+
+        Ok(regions)
+    }
+
+    /// Sets the exception handling clauses of the method from regions, which
+    /// should be ordered with nested regions before the regions enclosing them.
+    /// The clauses are written to a small section when they fit, otherwise to a
+    /// fat section, and a tiny method is expanded to a fat method to hold them.
+    pub fn set_exception_regions(&mut self, regions: &[ExceptionRegion]) -> Result<(), Error> {
+        let mut offsets = self.get_instruction_offsets();
+        offsets.push(
+            offsets.last().copied().unwrap_or_default()
+                + self.instructions.last().map_or(0, |i| i.len() as u32),
+        );
+        let clauses = regions
+            .iter()
+            .map(|r| r.to_clause(&offsets))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if clauses.is_empty() {
+            self.sections.clear();
+        } else {
+            self.expand_tiny_to_fat();
+            self.sections = vec![Section::from_clauses(clauses)?];
+        }
+        self.header.set_more_sects(!self.sections.is_empty());
+        Ok(())
+    }
+
+    /// Adds an exception handling clause after the existing clauses. A region
+    /// nested within an existing region must be added with
+    /// [Method::set_exception_regions] instead, so that it precedes it.
+    pub fn push_exception_region(&mut self, region: ExceptionRegion) -> Result<(), Error> {
+        let mut regions = self.exception_regions()?;
+        regions.push(region);
+        self.set_exception_regions(&regions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cil::{
+        ExceptionRegion, HandlerKind, Instruction, Method, MethodEdits, MethodHeader, Section,
+    };
+
+    #[test]
+    fn exception_regions_follow_edits_and_use_small_sections() {
+        // try { nop; leave.s end } filter { pop; ldc.i4.1; endfilter } { pop; leave.s end } end: ret
+        let mut method = Method::tiny(vec![
+            Instruction::nop(),
+            Instruction::leave_s(7),
+            Instruction::pop(),
+            Instruction::ldc_i4_1(),
+            Instruction::endfilter(),
+            Instruction::pop(),
+            Instruction::leave_s(0),
+            Instruction::ret(),
+        ])
+        .unwrap();
+        let filter = ExceptionRegion {
+            handler: HandlerKind::Filter(2),
+            try_start: 0,
+            try_end: 2,
+            handler_start: 5,
+            handler_end: 7,
+        };
+        method.push_exception_region(filter).unwrap();
+        assert!(matches!(method.header, MethodHeader::Fat(_)));
+        assert!(matches!(method.sections[0], Section::SmallSection(_, _)));
+
+        let mut edits = MethodEdits::new();
+        edits.insert(0, vec![Instruction::nop(); 300]);
+        edits.prepend(3, vec![Instruction::nop()]);
+        edits.insert(8, vec![Instruction::endfinally()]);
+        method.apply_edits(edits).unwrap();
+
+        // a fault region enclosing the filter region, too long for a small clause
+        let fault = ExceptionRegion {
+            handler: HandlerKind::Fault,
+            try_start: 0,
+            try_end: 308,
+            handler_start: 309,
+            handler_end: 310,
+        };
+        method.push_exception_region(fault).unwrap();
+
+        assert_eq!(
+            method.exception_regions().unwrap(),
+            vec![
+                ExceptionRegion {
+                    handler: HandlerKind::Filter(302),
+                    try_start: 300,
+                    try_end: 302,
+                    handler_start: 306,
+                    handler_end: 308,
+                },
+                fault,
+            ]
+        );
+        assert!(matches!(method.sections[0], Section::FatSection(_, _)));
+    }
+}
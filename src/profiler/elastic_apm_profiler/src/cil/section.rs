@@ -28,6 +28,7 @@ use crate::{
     cil::{check_flag, il_u16, il_u32, il_u8},
     error::Error,
 };
+use std::convert::TryFrom;
 
 bitflags! {
     pub struct SectionHeaderFlags: u8 {
@@ -46,7 +47,7 @@ bitflags! {
         const COR_ILEXCEPTION_CLAUSE_DUPLICATED = 0x8;
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FatSectionHeader {
     pub is_eh_table: bool,
     pub more_sects: bool,
@@ -72,7 +73,7 @@ impl FatSectionHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FatSectionClause {
     pub flag: CorExceptionFlag,
     pub try_offset: u32,
@@ -112,7 +113,7 @@ impl FatSectionClause {
         bytes
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SmallSectionHeader {
     pub is_eh_table: bool,
     pub more_sects: bool,
@@ -120,6 +121,7 @@ pub struct SmallSectionHeader {
 }
 
 impl SmallSectionHeader {
+    pub const LENGTH: usize = 4;
     pub fn into_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(4);
         let mut flags = 0u8;
@@ -137,7 +139,7 @@ impl SmallSectionHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SmallSectionClause {
     pub flag: CorExceptionFlag,
     pub try_offset: u16,
@@ -147,7 +149,16 @@ pub struct SmallSectionClause {
     pub class_token_or_filter_offset: u32,
 }
 impl SmallSectionClause {
-    const LENGTH: usize = 12;
+    pub(crate) const LENGTH: usize = 12;
+
+    /// Whether the offsets and lengths of a fat clause can be represented by a small clause
+    pub fn fits(clause: &FatSectionClause) -> bool {
+        clause.try_offset <= u16::MAX as u32
+            && clause.try_length <= u8::MAX as u32
+            && clause.handler_offset <= u16::MAX as u32
+            && clause.handler_length <= u8::MAX as u32
+    }
+
     pub fn from_bytes(il: &[u8]) -> Result<Self, Error> {
         let flag = CorExceptionFlag::from_bits(il[0]).unwrap();
         let try_offset = il_u16(il, 2)?;
@@ -177,7 +188,22 @@ impl SmallSectionClause {
         bytes
     }
 }
-#[derive(Debug)]
+impl TryFrom<FatSectionClause> for SmallSectionClause {
+    type Error = Error;
+
+    fn try_from(clause: FatSectionClause) -> Result<Self, Self::Error> {
+        Ok(SmallSectionClause {
+            flag: clause.flag,
+            try_offset: u16::try_from(clause.try_offset).or(Err(Error::CodeSize))?,
+            try_length: u8::try_from(clause.try_length).or(Err(Error::CodeSize))?,
+            handler_offset: u16::try_from(clause.handler_offset).or(Err(Error::CodeSize))?,
+            handler_length: u8::try_from(clause.handler_length).or(Err(Error::CodeSize))?,
+            class_token_or_filter_offset: clause.class_token_or_filter_offset,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Section {
     FatSection(FatSectionHeader, Vec<FatSectionClause>),
     SmallSection(SmallSectionHeader, Vec<SmallSectionClause>),
@@ -233,6 +259,69 @@ impl Section {
         }
         bytes
     }
+
+    /// Creates an exception handling section from clauses, in the small format
+    /// when all clauses can be represented by small clauses
+    pub fn from_clauses(clauses: Vec<FatSectionClause>) -> Result<Self, Error> {
+        let small_size = SmallSectionHeader::LENGTH + SmallSectionClause::LENGTH * clauses.len();
+        if small_size <= u8::MAX as usize && clauses.iter().all(SmallSectionClause::fits) {
+            let clauses = clauses
+                .into_iter()
+                .map(SmallSectionClause::try_from)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Section::SmallSection(
+                SmallSectionHeader {
+                    is_eh_table: true,
+                    more_sects: false,
+                    data_size: small_size as u8,
+                },
+                clauses,
+            ))
+        } else {
+            let data_size = FatSectionHeader::LENGTH + FatSectionClause::LENGTH * clauses.len();
+            // data size is stored in 3 bytes
+            if data_size > 0xFFFFFF {
+                return Err(Error::InvalidSectionHeader);
+            }
+            Ok(Section::FatSection(
+                FatSectionHeader {
+                    is_eh_table: true,
+                    more_sects: false,
+                    data_size: data_size as u32,
+                },
+                clauses,
+            ))
+        }
+    }
+
+    /// Gets the clauses of the section, with small clauses widened to fat clauses
+    pub fn fat_clauses(&self) -> Vec<FatSectionClause> {
+        match self {
+            Section::FatSection(_, clauses) => clauses
+                .iter()
+                .map(|c| FatSectionClause {
+                    flag: c.flag,
+                    try_offset: c.try_offset,
+                    try_length: c.try_length,
+                    handler_offset: c.handler_offset,
+                    handler_length: c.handler_length,
+                    class_token_or_filter_offset: c.class_token_or_filter_offset,
+                })
+                .collect(),
+            Section::SmallSection(_, clauses) => clauses
+                .iter()
+                .map(|c| FatSectionClause {
+                    flag: c.flag,
+                    try_offset: c.try_offset as u32,
+                    try_length: c.try_length as u32,
+                    handler_offset: c.handler_offset as u32,
+                    handler_length: c.handler_length as u32,
+                    class_token_or_filter_offset: c.class_token_or_filter_offset,
+                })
+                .collect(),
+        }
+    }
+
     pub fn data_size(&self) -> usize {
         match self {
             Self::FatSection(header, _) => header.data_size as usize,
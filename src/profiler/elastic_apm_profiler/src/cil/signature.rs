@@ -0,0 +1,743 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+//
+// Typed representation of the signature blobs described in ECMA-335 II.23.2
+
+use crate::{
+    cil::{
+        compress_data, compress_signed_data, compress_token, uncompress_data,
+        uncompress_signed_data, uncompress_token,
+    },
+    error::Error,
+    ffi::{mdToken, CorCallingConvention, CorElementType},
+};
+use num_traits::FromPrimitive;
+
+/// A custom modifier applied to a type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomMod {
+    /// Whether the modifier is required (modreq), or optional (modopt)
+    pub required: bool,
+    /// The TypeDef, TypeRef or TypeSpec of the modifier
+    pub token: mdToken,
+}
+
+/// The shape of a multi-dimensional array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArrayShape {
+    pub rank: u32,
+    /// The sizes of the leading dimensions that have a size
+    pub sizes: Vec<u32>,
+    /// The lower bounds of the leading dimensions that have a lower bound
+    pub lower_bounds: Vec<i32>,
+}
+
+/// A type in a signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeSig {
+    Void,
+    Boolean,
+    Char,
+    I1,
+    U1,
+    I2,
+    U2,
+    I4,
+    U4,
+    I8,
+    U8,
+    R4,
+    R8,
+    String,
+    Object,
+    I,
+    U,
+    TypedByRef,
+    /// A value type, by TypeDef, TypeRef or TypeSpec
+    ValueType(mdToken),
+    /// A reference type, by TypeDef, TypeRef or TypeSpec
+    Class(mdToken),
+    /// An instantiation of a generic type
+    GenericInst {
+        value_type: bool,
+        token: mdToken,
+        args: Vec<TypeSig>,
+    },
+    /// A generic parameter of the type, by index
+    Var(u32),
+    /// A generic parameter of the method, by index
+    MVar(u32),
+    /// An unmanaged pointer. A pointer to void has an element type of [TypeSig::Void]
+    Ptr(Box<TypeSig>),
+    /// A managed pointer
+    ByRef(Box<TypeSig>),
+    /// A single dimension array with a lower bound of zero
+    SzArray(Box<TypeSig>),
+    /// A multi-dimensional array
+    Array(Box<TypeSig>, ArrayShape),
+    /// A pointer to a function
+    FnPtr(Box<MethodSig>),
+    /// A type with a custom modifier. Multiple modifiers are nested in the order they appear
+    Modified(CustomMod, Box<TypeSig>),
+    /// A local variable that pins the object it refers to
+    Pinned(Box<TypeSig>),
+}
+
+/// The signature of a method definition, method reference, call site or function pointer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodSig {
+    /// The calling convention, including the HASTHIS, EXPLICITTHIS and GENERIC flags
+    pub calling_convention: CorCallingConvention,
+    pub generic_param_count: u32,
+    pub return_type: TypeSig,
+    pub params: Vec<TypeSig>,
+    /// The index of the first parameter following the sentinel of a vararg call site
+    pub sentinel: Option<usize>,
+}
+
+/// The signature of a field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSig {
+    pub field_type: TypeSig,
+}
+
+/// The signature of the local variables of a method
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LocalVarSig {
+    pub locals: Vec<TypeSig>,
+}
+
+/// The signature of a generic method instantiation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodSpecSig {
+    pub args: Vec<TypeSig>,
+}
+
+/// Reads a signature blob
+struct SigReader<'a> {
+    data: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> SigReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, idx: 0 }
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.data
+            .get(self.idx)
+            .copied()
+            .ok_or(Error::InvalidSignature)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = self.peek()?;
+        self.idx += 1;
+        Ok(byte)
+    }
+
+    fn number(&mut self) -> Result<u32, Error> {
+        let (number, len) =
+            uncompress_data(&self.data[self.idx..]).ok_or(Error::InvalidSignature)?;
+        self.idx += len;
+        Ok(number)
+    }
+
+    fn signed_number(&mut self) -> Result<i32, Error> {
+        let (number, len) =
+            uncompress_signed_data(&self.data[self.idx..]).ok_or(Error::InvalidSignature)?;
+        self.idx += len;
+        Ok(number)
+    }
+
+    fn token(&mut self) -> Result<mdToken, Error> {
+        let (token, len) = uncompress_token(&self.data[self.idx..]);
+        if len == 0 {
+            return Err(Error::InvalidSignature);
+        }
+        self.idx += len;
+        Ok(token)
+    }
+
+    fn count(&mut self) -> Result<usize, Error> {
+        let count = self.number()? as usize;
+        // each item takes at least one byte, so a larger count is malformed
+        if count > self.data.len() - self.idx {
+            return Err(Error::InvalidSignature);
+        }
+        Ok(count)
+    }
+
+    /// Checks that the whole blob has been read
+    fn end(&self) -> Result<(), Error> {
+        if self.idx == self.data.len() {
+            Ok(())
+        } else {
+            Err(Error::InvalidSignature)
+        }
+    }
+
+    fn type_sig(&mut self) -> Result<TypeSig, Error> {
+        let element_type = self.byte()?;
+        let sig = match CorElementType::from_u8(element_type).ok_or(Error::InvalidSignature)? {
+            CorElementType::ELEMENT_TYPE_VOID => TypeSig::Void,
+            CorElementType::ELEMENT_TYPE_BOOLEAN => TypeSig::Boolean,
+            CorElementType::ELEMENT_TYPE_CHAR => TypeSig::Char,
+            CorElementType::ELEMENT_TYPE_I1 => TypeSig::I1,
+            CorElementType::ELEMENT_TYPE_U1 => TypeSig::U1,
+            CorElementType::ELEMENT_TYPE_I2 => TypeSig::I2,
+            CorElementType::ELEMENT_TYPE_U2 => TypeSig::U2,
+            CorElementType::ELEMENT_TYPE_I4 => TypeSig::I4,
+            CorElementType::ELEMENT_TYPE_U4 => TypeSig::U4,
+            CorElementType::ELEMENT_TYPE_I8 => TypeSig::I8,
+            CorElementType::ELEMENT_TYPE_U8 => TypeSig::U8,
+            CorElementType::ELEMENT_TYPE_R4 => TypeSig::R4,
+            CorElementType::ELEMENT_TYPE_R8 => TypeSig::R8,
+            CorElementType::ELEMENT_TYPE_STRING => TypeSig::String,
+            CorElementType::ELEMENT_TYPE_OBJECT => TypeSig::Object,
+            CorElementType::ELEMENT_TYPE_I => TypeSig::I,
+            CorElementType::ELEMENT_TYPE_U => TypeSig::U,
+            CorElementType::ELEMENT_TYPE_TYPEDBYREF => TypeSig::TypedByRef,
+            CorElementType::ELEMENT_TYPE_VALUETYPE => TypeSig::ValueType(self.token()?),
+            CorElementType::ELEMENT_TYPE_CLASS => TypeSig::Class(self.token()?),
+            CorElementType::ELEMENT_TYPE_GENERICINST => {
+                let value_type = match CorElementType::from_u8(self.byte()?) {
+                    Some(CorElementType::ELEMENT_TYPE_VALUETYPE) => true,
+                    Some(CorElementType::ELEMENT_TYPE_CLASS) => false,
+                    _ => return Err(Error::InvalidSignature),
+                };
+                let token = self.token()?;
+                let count = self.count()?;
+                let args = (0..count)
+                    .map(|_| self.type_sig())
+                    .collect::<Result<Vec<_>, _>>()?;
+                TypeSig::GenericInst {
+                    value_type,
+                    token,
+                    args,
+                }
+            }
+            CorElementType::ELEMENT_TYPE_VAR => TypeSig::Var(self.number()?),
+            CorElementType::ELEMENT_TYPE_MVAR => TypeSig::MVar(self.number()?),
+            CorElementType::ELEMENT_TYPE_PTR => TypeSig::Ptr(Box::new(self.type_sig()?)),
+            CorElementType::ELEMENT_TYPE_BYREF => TypeSig::ByRef(Box::new(self.type_sig()?)),
+            CorElementType::ELEMENT_TYPE_SZARRAY => TypeSig::SzArray(Box::new(self.type_sig()?)),
+            CorElementType::ELEMENT_TYPE_ARRAY => {
+                let element = self.type_sig()?;
+                let rank = self.number()?;
+                let count = self.count()?;
+                let sizes = (0..count)
+                    .map(|_| self.number())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let count = self.count()?;
+                let lower_bounds = (0..count)
+                    .map(|_| self.signed_number())
+                    .collect::<Result<Vec<_>, _>>()?;
+                TypeSig::Array(
+                    Box::new(element),
+                    ArrayShape {
+                        rank,
+                        sizes,
+                        lower_bounds,
+                    },
+                )
+            }
+            CorElementType::ELEMENT_TYPE_FNPTR => TypeSig::FnPtr(Box::new(self.method_sig()?)),
+            CorElementType::ELEMENT_TYPE_CMOD_REQD | CorElementType::ELEMENT_TYPE_CMOD_OPT => {
+                let modifier = CustomMod {
+                    required: element_type == CorElementType::ELEMENT_TYPE_CMOD_REQD as u8,
+                    token: self.token()?,
+                };
+                TypeSig::Modified(modifier, Box::new(self.type_sig()?))
+            }
+            CorElementType::ELEMENT_TYPE_PINNED => TypeSig::Pinned(Box::new(self.type_sig()?)),
+            _ => return Err(Error::InvalidSignature),
+        };
+        Ok(sig)
+    }
+
+    fn method_sig(&mut self) -> Result<MethodSig, Error> {
+        let calling_convention = CorCallingConvention::from_bits_truncate(self.byte()?);
+        let generic_param_count = if calling_convention.is_generic() {
+            self.number()?
+        } else {
+            0
+        };
+        let count = self.count()?;
+        let return_type = self.type_sig()?;
+        let mut params = Vec::with_capacity(count);
+        let mut sentinel = None;
+        while params.len() < count {
+            if self.peek()? == CorElementType::ELEMENT_TYPE_SENTINEL as u8 {
+                if sentinel.is_some() {
+                    return Err(Error::InvalidSignature);
+                }
+                self.idx += 1;
+                sentinel = Some(params.len());
+            }
+            params.push(self.type_sig()?);
+        }
+
+        Ok(MethodSig {
+            calling_convention,
+            generic_param_count,
+            return_type,
+            params,
+            sentinel,
+        })
+    }
+
+    fn expect_calling_convention(&mut self, expected: CorCallingConvention) -> Result<(), Error> {
+        let calling_convention = CorCallingConvention::from_bits_truncate(self.byte()?);
+        if calling_convention & CorCallingConvention::IMAGE_CEE_CS_CALLCONV_MASK == expected {
+            Ok(())
+        } else {
+            Err(Error::InvalidSignature)
+        }
+    }
+}
+
+fn write_number(bytes: &mut Vec<u8>, number: u32) -> Result<(), Error> {
+    bytes.extend(compress_data(number).ok_or(Error::InvalidSignature)?);
+    Ok(())
+}
+
+fn write_count(bytes: &mut Vec<u8>, count: usize) -> Result<(), Error> {
+    write_number(bytes, count as u32)
+}
+
+fn write_token(bytes: &mut Vec<u8>, token: mdToken) -> Result<(), Error> {
+    bytes.extend(compress_token(token).ok_or(Error::InvalidSignature)?);
+    Ok(())
+}
+
+impl TypeSig {
+    /// Parses a type from the start of a signature blob, returning the type
+    /// and the number of bytes read
+    pub fn parse(signature: &[u8]) -> Result<(Self, usize), Error> {
+        let mut reader = SigReader::new(signature);
+        let sig = reader.type_sig()?;
+        Ok((sig, reader.idx))
+    }
+
+    /// Parses a type from a signature blob holding only the type, such as a TypeSpec
+    pub fn from_bytes(signature: &[u8]) -> Result<Self, Error> {
+        let mut reader = SigReader::new(signature);
+        let sig = reader.type_sig()?;
+        reader.end()?;
+        Ok(sig)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.encode(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Appends the encoded type to a signature blob
+    pub fn encode(&self, bytes: &mut Vec<u8>) -> Result<(), Error> {
+        let element_type = |e: CorElementType| e as u8;
+        match self {
+            TypeSig::Void => bytes.push(element_type(CorElementType::ELEMENT_TYPE_VOID)),
+            TypeSig::Boolean => bytes.push(element_type(CorElementType::ELEMENT_TYPE_BOOLEAN)),
+            TypeSig::Char => bytes.push(element_type(CorElementType::ELEMENT_TYPE_CHAR)),
+            TypeSig::I1 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_I1)),
+            TypeSig::U1 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_U1)),
+            TypeSig::I2 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_I2)),
+            TypeSig::U2 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_U2)),
+            TypeSig::I4 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_I4)),
+            TypeSig::U4 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_U4)),
+            TypeSig::I8 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_I8)),
+            TypeSig::U8 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_U8)),
+            TypeSig::R4 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_R4)),
+            TypeSig::R8 => bytes.push(element_type(CorElementType::ELEMENT_TYPE_R8)),
+            TypeSig::String => bytes.push(element_type(CorElementType::ELEMENT_TYPE_STRING)),
+            TypeSig::Object => bytes.push(element_type(CorElementType::ELEMENT_TYPE_OBJECT)),
+            TypeSig::I => bytes.push(element_type(CorElementType::ELEMENT_TYPE_I)),
+            TypeSig::U => bytes.push(element_type(CorElementType::ELEMENT_TYPE_U)),
+            TypeSig::TypedByRef => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_TYPEDBYREF))
+            }
+            TypeSig::ValueType(token) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_VALUETYPE));
+                write_token(bytes, *token)?;
+            }
+            TypeSig::Class(token) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_CLASS));
+                write_token(bytes, *token)?;
+            }
+            TypeSig::GenericInst {
+                value_type,
+                token,
+                args,
+            } => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_GENERICINST));
+                bytes.push(if *value_type {
+                    element_type(CorElementType::ELEMENT_TYPE_VALUETYPE)
+                } else {
+                    element_type(CorElementType::ELEMENT_TYPE_CLASS)
+                });
+                write_token(bytes, *token)?;
+                write_count(bytes, args.len())?;
+                for arg in args {
+                    arg.encode(bytes)?;
+                }
+            }
+            TypeSig::Var(index) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_VAR));
+                write_number(bytes, *index)?;
+            }
+            TypeSig::MVar(index) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_MVAR));
+                write_number(bytes, *index)?;
+            }
+            TypeSig::Ptr(element) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_PTR));
+                element.encode(bytes)?;
+            }
+            TypeSig::ByRef(element) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_BYREF));
+                element.encode(bytes)?;
+            }
+            TypeSig::SzArray(element) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_SZARRAY));
+                element.encode(bytes)?;
+            }
+            TypeSig::Array(element, shape) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_ARRAY));
+                element.encode(bytes)?;
+                write_number(bytes, shape.rank)?;
+                write_count(bytes, shape.sizes.len())?;
+                for size in &shape.sizes {
+                    write_number(bytes, *size)?;
+                }
+                write_count(bytes, shape.lower_bounds.len())?;
+                for lower_bound in &shape.lower_bounds {
+                    bytes
+                        .extend(compress_signed_data(*lower_bound).ok_or(Error::InvalidSignature)?);
+                }
+            }
+            TypeSig::FnPtr(method) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_FNPTR));
+                method.encode(bytes)?;
+            }
+            TypeSig::Modified(modifier, element) => {
+                bytes.push(if modifier.required {
+                    element_type(CorElementType::ELEMENT_TYPE_CMOD_REQD)
+                } else {
+                    element_type(CorElementType::ELEMENT_TYPE_CMOD_OPT)
+                });
+                write_token(bytes, modifier.token)?;
+                element.encode(bytes)?;
+            }
+            TypeSig::Pinned(element) => {
+                bytes.push(element_type(CorElementType::ELEMENT_TYPE_PINNED));
+                element.encode(bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an instantiation of a generic type
+    pub fn generic_inst(value_type: bool, token: mdToken, args: Vec<TypeSig>) -> Self {
+        TypeSig::GenericInst {
+            value_type,
+            token,
+            args,
+        }
+    }
+
+    /// Creates a value type or reference type from a TypeDef, TypeRef or TypeSpec
+    pub fn type_token(value_type: bool, token: mdToken) -> Self {
+        if value_type {
+            TypeSig::ValueType(token)
+        } else {
+            TypeSig::Class(token)
+        }
+    }
+
+    /// Gets the type without custom modifiers
+    pub fn unmodified(&self) -> &TypeSig {
+        match self {
+            TypeSig::Modified(_, element) => element.unmodified(),
+            sig => sig,
+        }
+    }
+}
+
+impl MethodSig {
+    /// Creates a signature for a non-generic method
+    pub fn new(
+        calling_convention: CorCallingConvention,
+        return_type: TypeSig,
+        params: Vec<TypeSig>,
+    ) -> Self {
+        Self {
+            calling_convention,
+            generic_param_count: 0,
+            return_type,
+            params,
+            sentinel: None,
+        }
+    }
+
+    /// Creates a signature for a generic method
+    pub fn generic(
+        calling_convention: CorCallingConvention,
+        generic_param_count: u32,
+        return_type: TypeSig,
+        params: Vec<TypeSig>,
+    ) -> Self {
+        Self {
+            calling_convention: calling_convention
+                | CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC,
+            generic_param_count,
+            return_type,
+            params,
+            sentinel: None,
+        }
+    }
+
+    pub fn from_bytes(signature: &[u8]) -> Result<Self, Error> {
+        let mut reader = SigReader::new(signature);
+        let sig = reader.method_sig()?;
+        reader.end()?;
+        Ok(sig)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.encode(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Appends the encoded method signature to a signature blob
+    pub fn encode(&self, bytes: &mut Vec<u8>) -> Result<(), Error> {
+        bytes.push(self.calling_convention.bits());
+        if self.calling_convention.is_generic() {
+            write_number(bytes, self.generic_param_count)?;
+        }
+        write_count(bytes, self.params.len())?;
+        self.return_type.encode(bytes)?;
+        for (index, param) in self.params.iter().enumerate() {
+            if self.sentinel == Some(index) {
+                bytes.push(CorElementType::ELEMENT_TYPE_SENTINEL as u8);
+            }
+            param.encode(bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the method has an implicit this parameter, that is not
+    /// included in the parameters of the signature
+    pub fn has_this(&self) -> bool {
+        self.calling_convention
+            .contains(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_HASTHIS)
+            && !self
+                .calling_convention
+                .contains(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_EXPLICITTHIS)
+    }
+
+    pub fn returns_value(&self) -> bool {
+        *self.return_type.unmodified() != TypeSig::Void
+    }
+}
+
+impl FieldSig {
+    pub fn from_bytes(signature: &[u8]) -> Result<Self, Error> {
+        let mut reader = SigReader::new(signature);
+        reader.expect_calling_convention(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_FIELD)?;
+        let field_type = reader.type_sig()?;
+        reader.end()?;
+        Ok(Self { field_type })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![CorCallingConvention::IMAGE_CEE_CS_CALLCONV_FIELD.bits()];
+        self.field_type.encode(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl LocalVarSig {
+    /// The largest index of a local variable that can be addressed by ldloc,
+    /// stloc and ldloca, as described in ECMA-335 III.3.43
+    pub const MAX_INDEX: u16 = 0xFFFE;
+
+    pub fn from_bytes(signature: &[u8]) -> Result<Self, Error> {
+        let mut reader = SigReader::new(signature);
+        reader.expect_calling_convention(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_LOCAL_SIG)?;
+        let count = reader.count()?;
+        let locals = (0..count)
+            .map(|_| reader.type_sig())
+            .collect::<Result<Vec<_>, _>>()?;
+        reader.end()?;
+        Ok(Self { locals })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![CorCallingConvention::IMAGE_CEE_CS_CALLCONV_LOCAL_SIG.bits()];
+        write_count(&mut bytes, self.locals.len())?;
+        for local in &self.locals {
+            local.encode(&mut bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Appends a local variable, returning its index. Returns an error when the
+    /// index would be greater than [LocalVarSig::MAX_INDEX]
+    pub fn push(&mut self, local: TypeSig) -> Result<u16, Error> {
+        let index = self.locals.len();
+        if index > Self::MAX_INDEX as usize {
+            return Err(Error::InvalidSignature);
+        }
+        self.locals.push(local);
+        Ok(index as u16)
+    }
+}
+
+impl MethodSpecSig {
+    pub fn new(args: Vec<TypeSig>) -> Self {
+        Self { args }
+    }
+
+    pub fn from_bytes(signature: &[u8]) -> Result<Self, Error> {
+        let mut reader = SigReader::new(signature);
+        reader
+            .expect_calling_convention(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST)?;
+        let count = reader.count()?;
+        let args = (0..count)
+            .map(|_| reader.type_sig())
+            .collect::<Result<Vec<_>, _>>()?;
+        reader.end()?;
+        Ok(Self { args })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST.bits()];
+        write_count(&mut bytes, self.args.len())?;
+        for arg in &self.args {
+            arg.encode(&mut bytes)?;
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{ArrayShape, CustomMod, LocalVarSig, MethodSig, MethodSpecSig, TypeSig},
+        ffi::CorCallingConvention,
+    };
+
+    #[test]
+    fn method_sig_round_trips() {
+        // instance !!0 M<T>(int32&, valuetype List`1<!!0>, int32 modopt(IsConst)*, string[0...,-2...], method void *(), ..., object)
+        let sig = MethodSig {
+            calling_convention: CorCallingConvention::IMAGE_CEE_CS_CALLCONV_HASTHIS
+                | CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC
+                | CorCallingConvention::IMAGE_CEE_CS_CALLCONV_VARARG,
+            generic_param_count: 1,
+            return_type: TypeSig::MVar(0),
+            params: vec![
+                TypeSig::ByRef(Box::new(TypeSig::I4)),
+                TypeSig::generic_inst(true, 0x01000012, vec![TypeSig::MVar(0)]),
+                TypeSig::Ptr(Box::new(TypeSig::Modified(
+                    CustomMod {
+                        required: false,
+                        token: 0x01000100,
+                    },
+                    Box::new(TypeSig::I4),
+                ))),
+                TypeSig::Array(
+                    Box::new(TypeSig::String),
+                    ArrayShape {
+                        rank: 2,
+                        sizes: vec![],
+                        lower_bounds: vec![0, -2],
+                    },
+                ),
+                TypeSig::FnPtr(Box::new(MethodSig::new(
+                    CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                    TypeSig::Void,
+                    vec![],
+                ))),
+                TypeSig::Object,
+            ],
+            sentinel: Some(5),
+        };
+
+        let bytes = sig.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x35, 1, 6, 0x1e, 0, // header, MVar(0) return type
+                0x10, 0x08, // int32&
+                0x15, 0x11, 0x49, 1, 0x1e, 0, // valuetype List`1<!!0>
+                0x0f, 0x20, 0x84, 0x01, 0x08, // int32 modopt(..)*
+                0x14, 0x0e, 2, 0, 2, 0, 0x7d, // string[0..., -2...]
+                0x1b, 0, 0, 0x01, // method void *()
+                0x41, 0x1c, // sentinel, object
+            ]
+        );
+        assert_eq!(MethodSig::from_bytes(&bytes).unwrap(), sig);
+        assert!(sig.has_this());
+        assert!(sig.returns_value());
+
+        // trailing bytes and truncated blobs are rejected
+        assert!(MethodSig::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(MethodSig::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn local_var_and_method_spec_sigs_round_trip() {
+        let locals = LocalVarSig {
+            locals: vec![
+                TypeSig::Pinned(Box::new(TypeSig::ByRef(Box::new(TypeSig::U1)))),
+                TypeSig::SzArray(Box::new(TypeSig::Class(0x02000003))),
+                TypeSig::Var(1),
+            ],
+        };
+        let bytes = locals.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            vec![0x07, 3, 0x45, 0x10, 0x05, 0x1d, 0x12, 0x0c, 0x13, 1]
+        );
+        assert_eq!(LocalVarSig::from_bytes(&bytes).unwrap(), locals);
+
+        let spec = MethodSpecSig::new(vec![TypeSig::I8, TypeSig::ValueType(0x1b000001)]);
+        let bytes = spec.to_bytes().unwrap();
+        assert_eq!(bytes, vec![0x0a, 2, 0x0a, 0x11, 0x06]);
+        assert_eq!(MethodSpecSig::from_bytes(&bytes).unwrap(), spec);
+        assert!(LocalVarSig::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn local_var_sig_push_returns_indexes() {
+        let mut locals = LocalVarSig::default();
+        assert_eq!(locals.push(TypeSig::I4).unwrap(), 0);
+        for _ in 1..300 {
+            locals.push(TypeSig::Object).unwrap();
+        }
+        assert_eq!(
+            locals
+                .push(TypeSig::generic_inst(true, 0x01000002, vec![TypeSig::I4]))
+                .unwrap(),
+            300
+        );
+
+        // more than 127 locals needs a two byte count
+        let bytes = locals.to_bytes().unwrap();
+        assert_eq!(&bytes[..4], &[0x07, 0x81, 0x2d, 0x08]);
+        assert_eq!(LocalVarSig::from_bytes(&bytes).unwrap(), locals);
+
+        locals
+            .locals
+            .resize(LocalVarSig::MAX_INDEX as usize, TypeSig::Object);
+        assert_eq!(locals.push(TypeSig::I4).unwrap(), LocalVarSig::MAX_INDEX);
+        assert!(locals.push(TypeSig::I4).is_err());
+    }
+}
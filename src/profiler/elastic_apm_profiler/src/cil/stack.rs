@@ -0,0 +1,425 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{
+        ControlFlow, CorExceptionFlag, Instruction, Method, MethodHeader, MethodSig, Operand,
+        StackBehaviorPop, StackBehaviorPush, TinyMethodHeader, TokenResolver, CALLI, JMP, LEAVE,
+        LEAVE_S, NEWOBJ, RET,
+    },
+    error::Error,
+    ffi::{mdToken, type_from_token, CorTokenType},
+};
+use std::convert::TryFrom;
+
+impl Instruction {
+    /// The number of values that the instruction pops from and pushes onto the
+    /// evaluation stack. Signatures of called methods are resolved with the resolver
+    /// to determine variable stack behavior, and `returns_value` indicates whether
+    /// the method containing the instruction returns a value, for `ret`.
+    pub fn stack_effect<R: TokenResolver>(
+        &self,
+        resolver: &R,
+        returns_value: bool,
+    ) -> Result<(usize, usize), Error> {
+        let call_site = || -> Result<MethodSig, Error> {
+            let sig = match &self.operand {
+                Operand::InlineSig(token) => resolver.signature(token.token()),
+                Operand::InlineMethod(token) => method_signature(resolver, token.token()),
+                _ => None,
+            };
+            sig.and_then(|sig| MethodSig::from_bytes(&sig).ok())
+                .ok_or(Error::InvalidCil)
+        };
+
+        let pops = match self.opcode.stack_behavior_pop {
+            StackBehaviorPop::VarPop if self.opcode == RET => returns_value as usize,
+            StackBehaviorPop::VarPop => {
+                let call_site = call_site()?;
+                let mut pops = call_site.params.len();
+                if call_site.has_this() && self.opcode != NEWOBJ {
+                    pops += 1;
+                }
+                if self.opcode == CALLI {
+                    // the function pointer
+                    pops += 1;
+                }
+                pops
+            }
+            pop => pop.size(),
+        };
+
+        let pushes = match self.opcode.stack_behavior_push {
+            StackBehaviorPush::VarPush => call_site()?.returns_value() as usize,
+            push => push.size(),
+        };
+
+        Ok((pops, pushes))
+    }
+}
+
+/// Gets the signature of a MethodDef, MemberRef or MethodSpec
+fn method_signature<R: TokenResolver>(resolver: &R, token: mdToken) -> Option<Vec<u8>> {
+    if type_from_token(token) == CorTokenType::mdtMethodSpec.bits() {
+        let (parent, _) = resolver.method_spec(token)?;
+        resolver.member(parent).map(|m| m.signature)
+    } else {
+        resolver.member(token).map(|m| m.signature)
+    }
+}
+
+/// The stack depth before each instruction of a method, and the maximum
+/// stack depth, determined by following all paths through the method
+pub struct StackDepths {
+    /// The stack depth before each instruction, or `None` when the
+    /// instruction is unreachable
+    pub depths: Vec<Option<usize>>,
+    pub max_stack: usize,
+}
+
+/// A problem found while following the stack depth through a method
+pub(crate) enum StackIssue {
+    Underflow,
+    Mismatch { expected: usize, actual: usize },
+    UnresolvedSignature,
+    InvalidTarget,
+}
+
+impl Method {
+    /// Computes the stack depth before each instruction by following fall
+    /// through, branches, switch targets and exception handler entries.
+    /// Returns [Error::StackSize] when the stack underflows or the depths
+    /// at a merge point differ.
+    pub fn stack_depths<R: TokenResolver>(
+        &self,
+        resolver: &R,
+        returns_value: bool,
+    ) -> Result<StackDepths, Error> {
+        let mut error = None;
+        let depths = self.walk_stack(resolver, returns_value, |_, issue| {
+            if error.is_none() {
+                error = Some(match issue {
+                    StackIssue::Underflow | StackIssue::Mismatch { .. } => Error::StackSize,
+                    StackIssue::UnresolvedSignature | StackIssue::InvalidTarget => {
+                        Error::InvalidCil
+                    }
+                });
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(depths),
+        }
+    }
+
+    /// Follows the stack depth through the method, reporting each issue found
+    /// with the index of the instruction. Paths are not followed past an issue.
+    pub(crate) fn walk_stack<R, F>(
+        &self,
+        resolver: &R,
+        returns_value: bool,
+        mut report: F,
+    ) -> StackDepths
+    where
+        R: TokenResolver,
+        F: FnMut(usize, StackIssue),
+    {
+        let mut offsets: Vec<u32> = self.get_instruction_offsets();
+        let code_size = offsets.last().copied().unwrap_or_default()
+            + self.instructions.last().map_or(0, |i| i.len() as u32);
+        offsets.push(code_size);
+
+        let index_of = |offset: i64| -> Option<usize> {
+            u32::try_from(offset)
+                .ok()
+                .and_then(|o| offsets.binary_search(&o).ok())
+                .filter(|i| *i < self.instructions.len())
+        };
+
+        let mut depths: Vec<Option<usize>> = vec![None; self.instructions.len()];
+        let mut work: Vec<(usize, usize)> = Vec::new();
+        if !self.instructions.is_empty() {
+            work.push((0, 0));
+        }
+
+        // an exception object is on the stack on entry to catch handlers and filters
+        for clause in self.sections.iter().flat_map(|s| s.fat_clauses()) {
+            let mut entries = vec![];
+            if clause.flag.intersects(
+                CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY
+                    | CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT,
+            ) {
+                entries.push((clause.handler_offset, 0));
+            } else {
+                entries.push((clause.handler_offset, 1));
+                if clause
+                    .flag
+                    .contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER)
+                {
+                    entries.push((clause.class_token_or_filter_offset, 1));
+                }
+            }
+            for (offset, depth) in entries {
+                match index_of(offset as i64) {
+                    Some(index) => work.push((index, depth)),
+                    None => report(0, StackIssue::InvalidTarget),
+                }
+            }
+        }
+
+        let mut max_stack = 0;
+        while let Some((index, depth)) = work.pop() {
+            match depths[index] {
+                Some(d) if d == depth => continue,
+                Some(d) => {
+                    report(
+                        index,
+                        StackIssue::Mismatch {
+                            expected: d,
+                            actual: depth,
+                        },
+                    );
+                    continue;
+                }
+                None => depths[index] = Some(depth),
+            }
+            // the depth on entry counts, such as the exception object on entry to a handler
+            max_stack = max_stack.max(depth);
+
+            let instruction = &self.instructions[index];
+            let (pops, pushes) = match instruction.stack_effect(resolver, returns_value) {
+                Ok(effect) => effect,
+                Err(_) => {
+                    report(index, StackIssue::UnresolvedSignature);
+                    continue;
+                }
+            };
+            let depth = match depth.checked_sub(pops) {
+                Some(depth) => depth + pushes,
+                None => {
+                    report(index, StackIssue::Underflow);
+                    continue;
+                }
+            };
+            max_stack = max_stack.max(depth);
+
+            let next = offsets[index + 1] as i64;
+            let mut targets = vec![];
+            match &instruction.operand {
+                Operand::ShortInlineBrTarget(delta) => {
+                    // leave empties the evaluation stack
+                    let target_depth = if instruction.opcode == LEAVE_S {
+                        0
+                    } else {
+                        depth
+                    };
+                    targets.push((next + *delta as i64, target_depth));
+                }
+                Operand::InlineBrTarget(delta) => {
+                    let target_depth = if instruction.opcode == LEAVE {
+                        0
+                    } else {
+                        depth
+                    };
+                    targets.push((next + *delta as i64, target_depth));
+                }
+                Operand::InlineSwitch(_, deltas) => {
+                    for delta in deltas {
+                        targets.push((next + *delta as i64, depth));
+                    }
+                }
+                _ => (),
+            }
+            for (target, target_depth) in targets {
+                match index_of(target) {
+                    Some(target) => work.push((target, target_depth)),
+                    None => report(index, StackIssue::InvalidTarget),
+                }
+            }
+
+            let falls_through = match instruction.opcode.control_flow {
+                ControlFlow::Branch | ControlFlow::Return | ControlFlow::Throw => false,
+                _ => instruction.opcode != JMP,
+            };
+            if falls_through && index + 1 < self.instructions.len() {
+                work.push((index + 1, depth));
+            }
+        }
+
+        StackDepths { depths, max_stack }
+    }
+
+    /// Computes the exact maximum stack depth of the method
+    pub fn compute_max_stack<R: TokenResolver>(
+        &self,
+        resolver: &R,
+        returns_value: bool,
+    ) -> Result<u16, Error> {
+        let max_stack = self.stack_depths(resolver, returns_value)?.max_stack;
+        u16::try_from(max_stack).map_err(|_| Error::StackSize)
+    }
+
+    /// Recomputes the maximum stack depth of the method and updates the header,
+    /// expanding a tiny header to a fat header when more than 8 stack slots are needed,
+    /// then returns the bytes of the method
+    pub fn into_bytes_with_max_stack<R: TokenResolver>(
+        &mut self,
+        resolver: &R,
+        returns_value: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let max_stack = self.compute_max_stack(resolver, returns_value)?;
+        if let MethodHeader::Tiny(_) = self.header {
+            if max_stack > TinyMethodHeader::MAX_STACK as u16 {
+                self.expand_tiny_to_fat();
+            }
+        }
+        self.header.set_max_stack(max_stack);
+        Ok(self.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{
+            CorExceptionFlag, FatSectionClause, Instruction, MemberName, Method, MethodToken,
+            TokenResolver,
+        },
+        error::Error,
+        ffi::mdToken,
+    };
+    use std::convert::TryFrom;
+
+    struct TestResolver;
+
+    impl TokenResolver for TestResolver {
+        fn member(&self, token: mdToken) -> Option<MemberName> {
+            let signature = match token {
+                // instance void (int32, int32)
+                0x0a000001 => vec![0x20, 0x02, 0x01, 0x08, 0x08],
+                // int32 (int32)
+                0x0a000002 => vec![0x00, 0x01, 0x08, 0x08],
+                _ => return None,
+            };
+            Some(MemberName {
+                parent: 0x01000001,
+                name: "M".into(),
+                signature,
+            })
+        }
+    }
+
+    #[test]
+    fn compute_max_stack_follows_calls_branches_and_handlers() {
+        let mut method = Method::tiny(vec![
+            Instruction::ldarg_0(),
+            Instruction::ldc_i4_1(),
+            Instruction::ldc_i4_2(),
+            Instruction::call(MethodToken::try_from(0x0a000001).unwrap()),
+            Instruction::ldc_i4_1(),
+            Instruction::call(MethodToken::try_from(0x0a000002).unwrap()),
+            Instruction::pop(),
+            Instruction::leave_s(3),
+            Instruction::pop(),
+            Instruction::leave_s(0),
+            Instruction::ldc_i4_0(),
+            Instruction::ret(),
+        ])
+        .unwrap();
+        assert_eq!(method.compute_max_stack(&TestResolver, true).unwrap(), 3);
+
+        method.expand_tiny_to_fat();
+        method
+            .push_clause(FatSectionClause {
+                flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
+                try_offset: 0,
+                try_length: 17,
+                handler_offset: 17,
+                handler_length: 3,
+                class_token_or_filter_offset: 0x01000002,
+            })
+            .unwrap();
+        let depths = method.stack_depths(&TestResolver, true).unwrap();
+        assert_eq!(
+            depths.depths,
+            vec![
+                Some(0),
+                Some(1),
+                Some(2),
+                Some(3),
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(0),
+                Some(1),
+                Some(0),
+                Some(0),
+                Some(1)
+            ]
+        );
+
+        method.header.set_max_stack(8);
+        let bytes = method
+            .into_bytes_with_max_stack(&TestResolver, true)
+            .unwrap();
+        assert_eq!(u16::from_le_bytes([bytes[2], bytes[3]]), 3);
+    }
+
+    #[test]
+    fn compute_max_stack_counts_exception_on_handler_entry() {
+        let mut method = Method::tiny(vec![
+            // try
+            Instruction::leave_s(3),
+            // catch
+            Instruction::pop(),
+            Instruction::leave_s(0),
+            Instruction::ret(),
+        ])
+        .unwrap();
+        method.expand_tiny_to_fat();
+        method
+            .push_clause(FatSectionClause {
+                flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
+                try_offset: 0,
+                try_length: 2,
+                handler_offset: 2,
+                handler_length: 3,
+                class_token_or_filter_offset: 0x01000002,
+            })
+            .unwrap();
+        assert_eq!(method.compute_max_stack(&TestResolver, false).unwrap(), 1);
+    }
+
+    #[test]
+    fn compute_max_stack_errors() {
+        let underflow = Method::tiny(vec![Instruction::pop(), Instruction::ret()]).unwrap();
+        assert!(matches!(
+            underflow.compute_max_stack(&TestResolver, false),
+            Err(Error::StackSize)
+        ));
+
+        // one path reaches the ret with a value on the stack, the other without
+        let mismatch = Method::tiny(vec![
+            Instruction::ldarg_0(),
+            Instruction::brtrue_s(1),
+            Instruction::ldarg_0(),
+            Instruction::ret(),
+        ])
+        .unwrap();
+        assert!(matches!(
+            mismatch.compute_max_stack(&TestResolver, true),
+            Err(Error::StackSize)
+        ));
+
+        let unresolved = Method::tiny(vec![Instruction::call(
+            MethodToken::try_from(0x0a000003).unwrap(),
+        )])
+        .unwrap();
+        assert!(matches!(
+            unresolved.compute_max_stack(&TestResolver, false),
+            Err(Error::InvalidCil)
+        ));
+    }
+}
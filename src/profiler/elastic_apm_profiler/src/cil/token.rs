@@ -0,0 +1,129 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    error::Error,
+    ffi::{is_nil_token, mdToken, type_from_token, CorTokenType},
+};
+use std::convert::TryFrom;
+
+/// Defines a typed wrapper of a metadata token that can only hold
+/// non-nil tokens of the given token types
+macro_rules! typed_token {
+    ($(#[$meta:meta])* $name:ident, $($token_type:ident)|+) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct $name(mdToken);
+
+        impl $name {
+            /// Gets the metadata token
+            pub fn token(self) -> mdToken {
+                self.0
+            }
+        }
+
+        impl TryFrom<mdToken> for $name {
+            type Error = Error;
+
+            /// Checks that the token is a non-nil token of an expected token type.
+            /// Returns [Error::InvalidToken] otherwise.
+            fn try_from(token: mdToken) -> Result<Self, Self::Error> {
+                let token_type = type_from_token(token);
+                if !is_nil_token(token) && ($(token_type == CorTokenType::$token_type.bits())||+) {
+                    Ok(Self(token))
+                } else {
+                    Err(Error::InvalidToken(token))
+                }
+            }
+        }
+
+        impl From<$name> for mdToken {
+            fn from(token: $name) -> Self {
+                token.0
+            }
+        }
+    };
+}
+
+typed_token!(
+    /// A MethodDef, MemberRef or MethodSpec token, the operand of call, callvirt,
+    /// jmp, newobj, ldftn and ldvirtftn
+    MethodToken,
+    mdtMethodDef | mdtMemberRef | mdtMethodSpec
+);
+
+typed_token!(
+    /// A TypeDef, TypeRef or TypeSpec token, the operand of box, newarr, ldobj,
+    /// castclass, initobj and other object model instructions
+    TypeToken,
+    mdtTypeDef | mdtTypeRef | mdtTypeSpec
+);
+
+typed_token!(
+    /// A FieldDef or MemberRef token, the operand of ldfld, stfld and their
+    /// static and address variants
+    FieldToken,
+    mdtFieldDef | mdtMemberRef
+);
+
+typed_token!(
+    /// A String token of a user string, the operand of ldstr
+    StringToken,
+    mdtString
+);
+
+typed_token!(
+    /// A StandAloneSig token of a call site signature, the operand of calli
+    SignatureToken,
+    mdtSignature
+);
+
+typed_token!(
+    /// A type, method or field token, the operand of ldtoken
+    HandleToken,
+    mdtTypeDef | mdtTypeRef | mdtTypeSpec | mdtMethodDef | mdtMemberRef | mdtMethodSpec | mdtFieldDef
+);
+
+impl From<TypeToken> for HandleToken {
+    fn from(token: TypeToken) -> Self {
+        Self(token.0)
+    }
+}
+
+impl From<MethodToken> for HandleToken {
+    fn from(token: MethodToken) -> Self {
+        Self(token.0)
+    }
+}
+
+impl From<FieldToken> for HandleToken {
+    fn from(token: FieldToken) -> Self {
+        Self(token.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cil::{FieldToken, HandleToken, MethodToken, StringToken, TypeToken};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn tokens_are_checked_by_token_type() {
+        let member_ref = 0x0a000001;
+        assert_eq!(
+            MethodToken::try_from(member_ref).unwrap().token(),
+            member_ref
+        );
+        assert!(FieldToken::try_from(member_ref).is_ok());
+        assert!(TypeToken::try_from(member_ref).is_err());
+        assert!(MethodToken::try_from(0x01000001).is_err());
+        assert!(MethodToken::try_from(0x0a000000).is_err());
+        assert!(StringToken::try_from(0x70000001).is_ok());
+
+        let type_spec = TypeToken::try_from(0x1b000002).unwrap();
+        assert_eq!(HandleToken::from(type_spec).token(), 0x1b000002);
+        assert!(HandleToken::try_from(0x70000001).is_err());
+    }
+}
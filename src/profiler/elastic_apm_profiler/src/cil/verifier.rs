@@ -0,0 +1,539 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{
+        uncompress_data, ControlFlow, CorExceptionFlag, Instruction, Method, Operand, StackIssue,
+        TokenResolver, JMP, LDLOC, LDLOCA, LDLOCA_S, LDLOC_0, LDLOC_1, LDLOC_2, LDLOC_3, LDLOC_S,
+        LEAVE, LEAVE_S, RET, STLOC, STLOC_0, STLOC_1, STLOC_2, STLOC_3, STLOC_S,
+    },
+    ffi::{mdTokenNil, CorCallingConvention},
+};
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    fmt::{Display, Formatter},
+};
+
+/// A problem found when verifying a method body. Offsets are IL offsets
+/// of the instruction at fault, and clauses are indexes into the
+/// exception clauses of the method, in the order they are declared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// A branch or switch target is not the start of an instruction
+    InvalidBranchTarget { offset: u32, target: i64 },
+    /// A clause boundary is not the start of an instruction or the end of the method
+    InvalidClauseBoundary { clause: usize, offset: u32 },
+    /// A clause has an empty block, or its blocks overlap each other
+    InvalidClause { clause: usize },
+    /// The blocks of two clauses overlap without one being nested in the other
+    OverlappingClauses { first: usize, second: usize },
+    /// A clause is declared after a clause that encloses it
+    ClauseOrder { inner: usize, outer: usize },
+    /// Control is transferred into or out of a protected block, handler or
+    /// filter other than by leave, or leave exits a block it cannot exit
+    InvalidBlockTransfer { offset: u32, target: u32 },
+    /// ret is used within a protected block, handler or filter
+    ReturnFromBlock { offset: u32 },
+    /// Control falls through past the end of a block or the method
+    FallThrough { offset: u32 },
+    /// An instruction pops more values than are on the stack
+    StackUnderflow { offset: u32 },
+    /// The stack depth differs between paths reaching an instruction
+    InconsistentStackDepth {
+        offset: u32,
+        expected: usize,
+        actual: usize,
+    },
+    /// The signature of a called method could not be resolved
+    UnresolvedSignature { offset: u32 },
+    /// A local variable index is not within the local variable signature
+    InvalidLocalIndex {
+        offset: u32,
+        index: u16,
+        locals: u32,
+    },
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostic::InvalidBranchTarget { offset, target } => write!(
+                f,
+                "IL_{:04x}: branch target {:#x} is not the start of an instruction",
+                offset, target
+            ),
+            Diagnostic::InvalidClauseBoundary { clause, offset } => write!(
+                f,
+                "clause {}: boundary {:#x} is not the start of an instruction",
+                clause, offset
+            ),
+            Diagnostic::InvalidClause { clause } => write!(
+                f,
+                "clause {}: blocks are empty or overlap each other",
+                clause
+            ),
+            Diagnostic::OverlappingClauses { first, second } => write!(
+                f,
+                "clauses {} and {} overlap without being nested",
+                first, second
+            ),
+            Diagnostic::ClauseOrder { inner, outer } => write!(
+                f,
+                "clause {} is nested in clause {} but declared after it",
+                inner, outer
+            ),
+            Diagnostic::InvalidBlockTransfer { offset, target } => write!(
+                f,
+                "IL_{:04x}: invalid transfer of control to IL_{:04x} across a block boundary",
+                offset, target
+            ),
+            Diagnostic::ReturnFromBlock { offset } => {
+                write!(
+                    f,
+                    "IL_{:04x}: ret within a protected block or handler",
+                    offset
+                )
+            }
+            Diagnostic::FallThrough { offset } => write!(
+                f,
+                "IL_{:04x}: control falls through the end of a block or the method",
+                offset
+            ),
+            Diagnostic::StackUnderflow { offset } => {
+                write!(f, "IL_{:04x}: stack underflow", offset)
+            }
+            Diagnostic::InconsistentStackDepth {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "IL_{:04x}: stack depth {} differs from depth {} on another path",
+                offset, actual, expected
+            ),
+            Diagnostic::UnresolvedSignature { offset } => {
+                write!(f, "IL_{:04x}: unable to resolve signature", offset)
+            }
+            Diagnostic::InvalidLocalIndex {
+                offset,
+                index,
+                locals,
+            } => write!(
+                f,
+                "IL_{:04x}: local {} is out of range of {} locals",
+                offset, index, locals
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockKind {
+    Try,
+    Filter,
+    Handler,
+    /// A finally or fault handler, which is exited with endfinally
+    FinallyHandler,
+}
+
+/// A protected block, filter or handler of an exception clause
+struct Block {
+    clause: usize,
+    kind: BlockKind,
+    start: u32,
+    end: u32,
+}
+
+impl Block {
+    fn contains(&self, offset: u32) -> bool {
+        self.start <= offset && offset < self.end
+    }
+
+    fn encloses(&self, other: &Block) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+
+    fn is_disjoint(&self, other: &Block) -> bool {
+        self.end <= other.start || other.end <= self.start
+    }
+}
+
+/// Verifies the structure of a method body before it is handed to the runtime
+pub struct Verifier<'a, R: TokenResolver> {
+    resolver: &'a R,
+    returns_value: bool,
+}
+
+impl<'a, R: TokenResolver> Verifier<'a, R> {
+    /// Creates a new verifier. The resolver is used to resolve the signatures of
+    /// called methods and of the local variables.
+    pub fn new(resolver: &'a R, returns_value: bool) -> Self {
+        Self {
+            resolver,
+            returns_value,
+        }
+    }
+
+    /// Verifies the method, returning all the problems found. An empty
+    /// collection indicates the method is valid.
+    pub fn verify(&self, method: &Method) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut offsets = method.get_instruction_offsets();
+        let code_size = offsets.last().copied().unwrap_or_default()
+            + method.instructions.last().map_or(0, |i| i.len() as u32);
+        let instruction_offsets = offsets.clone();
+        offsets.push(code_size);
+
+        let blocks = self.verify_clauses(method, &offsets, &mut diagnostics);
+        let regions = |offset: u32| -> BTreeSet<usize> {
+            blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| b.contains(offset))
+                .map(|(i, _)| i)
+                .collect()
+        };
+
+        let locals = self.local_count(method);
+        for (index, instruction) in method.instructions.iter().enumerate() {
+            let offset = offsets[index];
+            let next = offsets[index + 1];
+
+            for target in Self::branch_targets(instruction, next) {
+                let target = match u32::try_from(target)
+                    .ok()
+                    .filter(|t| instruction_offsets.binary_search(t).is_ok())
+                {
+                    Some(target) => target,
+                    None => {
+                        diagnostics.push(Diagnostic::InvalidBranchTarget { offset, target });
+                        continue;
+                    }
+                };
+
+                let source_regions = regions(offset);
+                let target_regions = regions(target);
+                // control may only enter a protected block at its first instruction
+                let valid_entry = target_regions
+                    .difference(&source_regions)
+                    .all(|b| blocks[*b].kind == BlockKind::Try && blocks[*b].start == target);
+                let mut exited = source_regions.difference(&target_regions);
+                let valid_exit = if instruction.opcode == LEAVE || instruction.opcode == LEAVE_S {
+                    exited.all(|b| matches!(blocks[*b].kind, BlockKind::Try | BlockKind::Handler))
+                } else {
+                    exited.next().is_none()
+                };
+                if !valid_entry || !valid_exit {
+                    diagnostics.push(Diagnostic::InvalidBlockTransfer { offset, target });
+                }
+            }
+
+            if instruction.opcode == RET && blocks.iter().any(|b| b.contains(offset)) {
+                diagnostics.push(Diagnostic::ReturnFromBlock { offset });
+            }
+
+            let falls_through = match instruction.opcode.control_flow {
+                ControlFlow::Branch | ControlFlow::Return | ControlFlow::Throw => false,
+                _ => instruction.opcode != JMP,
+            };
+            if falls_through
+                && (next == code_size || blocks.iter().any(|b| b.contains(offset) && b.end == next))
+            {
+                diagnostics.push(Diagnostic::FallThrough { offset });
+            }
+
+            if let (Some(local), Some(locals)) = (Self::local_index(instruction), locals) {
+                if local as u32 >= locals {
+                    diagnostics.push(Diagnostic::InvalidLocalIndex {
+                        offset,
+                        index: local,
+                        locals,
+                    });
+                }
+            }
+        }
+
+        method.walk_stack(self.resolver, self.returns_value, |index, issue| {
+            let offset = offsets[index];
+            match issue {
+                StackIssue::Underflow => diagnostics.push(Diagnostic::StackUnderflow { offset }),
+                StackIssue::Mismatch { expected, actual } => {
+                    diagnostics.push(Diagnostic::InconsistentStackDepth {
+                        offset,
+                        expected,
+                        actual,
+                    })
+                }
+                StackIssue::UnresolvedSignature => {
+                    diagnostics.push(Diagnostic::UnresolvedSignature { offset })
+                }
+                // already reported as an invalid branch target or clause boundary
+                StackIssue::InvalidTarget => (),
+            }
+        });
+
+        diagnostics
+    }
+
+    /// Checks the boundaries, nesting and order of the exception clauses,
+    /// returning the blocks of the clauses
+    fn verify_clauses(
+        &self,
+        method: &Method,
+        offsets: &[u32],
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        let clauses = method.sections.iter().flat_map(|s| s.fat_clauses());
+        for (clause, c) in clauses.enumerate() {
+            let flag = c.flag;
+            let handler_kind = if flag.intersects(
+                CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY
+                    | CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FAULT,
+            ) {
+                BlockKind::FinallyHandler
+            } else {
+                BlockKind::Handler
+            };
+            let mut clause_blocks = vec![
+                (
+                    BlockKind::Try,
+                    c.try_offset,
+                    c.try_offset.saturating_add(c.try_length),
+                ),
+                (
+                    handler_kind,
+                    c.handler_offset,
+                    c.handler_offset.saturating_add(c.handler_length),
+                ),
+            ];
+            if flag.contains(CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FILTER) {
+                // a filter runs from its first instruction up to the handler
+                clause_blocks.push((
+                    BlockKind::Filter,
+                    c.class_token_or_filter_offset,
+                    c.handler_offset,
+                ));
+            }
+
+            let first = blocks.len();
+            for (kind, start, end) in clause_blocks {
+                for boundary in [start, end] {
+                    if offsets.binary_search(&boundary).is_err() {
+                        diagnostics.push(Diagnostic::InvalidClauseBoundary {
+                            clause,
+                            offset: boundary,
+                        });
+                    }
+                }
+                blocks.push(Block {
+                    clause,
+                    kind,
+                    start,
+                    end,
+                });
+            }
+
+            let own = &blocks[first..];
+            let empty = own.iter().any(|b| b.start >= b.end);
+            let overlapping = own
+                .iter()
+                .enumerate()
+                .any(|(i, a)| own[i + 1..].iter().any(|b| !a.is_disjoint(b)));
+            if empty || overlapping {
+                diagnostics.push(Diagnostic::InvalidClause { clause });
+            }
+        }
+
+        // blocks of different clauses must be disjoint or nested, with the inner
+        // clause declared first. Clauses may share an identical protected block.
+        let mut reported = Vec::new();
+        for (i, a) in blocks.iter().enumerate() {
+            for b in blocks[i + 1..].iter().filter(|b| b.clause != a.clause) {
+                let diagnostic = if a.is_disjoint(b) {
+                    continue;
+                } else if a.start == b.start && a.end == b.end {
+                    if a.kind == BlockKind::Try && b.kind == BlockKind::Try {
+                        continue;
+                    }
+                    Diagnostic::OverlappingClauses {
+                        first: a.clause,
+                        second: b.clause,
+                    }
+                } else if b.encloses(a) {
+                    continue;
+                } else if a.encloses(b) {
+                    Diagnostic::ClauseOrder {
+                        inner: b.clause,
+                        outer: a.clause,
+                    }
+                } else {
+                    Diagnostic::OverlappingClauses {
+                        first: a.clause,
+                        second: b.clause,
+                    }
+                };
+                if !reported.contains(&diagnostic) {
+                    reported.push(diagnostic.clone());
+                    diagnostics.push(diagnostic);
+                }
+            }
+        }
+
+        blocks
+    }
+
+    /// Gets the targets of a branch or switch instruction, relative to the start of the method
+    fn branch_targets(instruction: &Instruction, next: u32) -> Vec<i64> {
+        let next = next as i64;
+        match &instruction.operand {
+            Operand::ShortInlineBrTarget(delta) => vec![next + *delta as i64],
+            Operand::InlineBrTarget(delta) => vec![next + *delta as i64],
+            Operand::InlineSwitch(_, deltas) => deltas.iter().map(|d| next + *d as i64).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Gets the index of the local variable loaded, stored or addressed by the instruction
+    fn local_index(instruction: &Instruction) -> Option<u16> {
+        let opcode = instruction.opcode;
+        if opcode == LDLOC_0 || opcode == STLOC_0 {
+            Some(0)
+        } else if opcode == LDLOC_1 || opcode == STLOC_1 {
+            Some(1)
+        } else if opcode == LDLOC_2 || opcode == STLOC_2 {
+            Some(2)
+        } else if opcode == LDLOC_3 || opcode == STLOC_3 {
+            Some(3)
+        } else if opcode == LDLOC_S || opcode == LDLOCA_S || opcode == STLOC_S {
+            match instruction.operand {
+                Operand::ShortInlineVar(index) => Some(index as u16),
+                _ => None,
+            }
+        } else if opcode == LDLOC || opcode == LDLOCA || opcode == STLOC {
+            match instruction.operand {
+                Operand::InlineVar(index) => Some(index),
+                _ => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Gets the number of local variables declared by the method, or `None`
+    /// when the local variable signature cannot be resolved
+    fn local_count(&self, method: &Method) -> Option<u32> {
+        let token = method.header.local_var_sig_tok();
+        if token == mdTokenNil {
+            return Some(0);
+        }
+        let signature = self.resolver.signature(token)?;
+        if *signature.first()? != CorCallingConvention::IMAGE_CEE_CS_CALLCONV_LOCAL_SIG.bits() {
+            return None;
+        }
+        uncompress_data(&signature[1..]).map(|(count, _)| count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{
+            Diagnostic, FatSectionClause, Instruction, Method, MethodHeader, TokenResolver,
+            Verifier,
+        },
+        ffi::mdToken,
+    };
+
+    struct Resolver;
+
+    impl TokenResolver for Resolver {
+        fn signature(&self, token: mdToken) -> Option<Vec<u8>> {
+            // two int32 locals
+            (token == 0x11000001).then(|| vec![0x07, 0x02, 0x08, 0x08])
+        }
+    }
+
+    fn method(instructions: Vec<Instruction>, clauses: Vec<FatSectionClause>) -> Method {
+        let code_size: usize = instructions.iter().map(|i| i.len()).sum();
+        let mut method = Method {
+            address: 0,
+            header: MethodHeader::fat(false, false, 8, code_size as u32, 0x11000001),
+            instructions,
+            sections: vec![],
+            original_offsets: vec![],
+        };
+        method.push_clauses(clauses).unwrap();
+        method
+    }
+
+    #[test]
+    fn verify_valid_method() {
+        // try { ldc.i4.1; stloc.0; leave.s end } finally { endfinally } end: ldloc.0; ret
+        let method = method(
+            vec![
+                Instruction::ldc_i4_1(),
+                Instruction::stloc_0(),
+                Instruction::leave_s(1),
+                Instruction::endfinally(),
+                Instruction::ldloc_0(),
+                Instruction::ret(),
+            ],
+            vec![FatSectionClause {
+                flag: crate::cil::CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                try_offset: 0,
+                try_length: 4,
+                handler_offset: 4,
+                handler_length: 1,
+                class_token_or_filter_offset: 0,
+            }],
+        );
+
+        assert_eq!(Verifier::new(&Resolver, true).verify(&method), vec![]);
+    }
+
+    #[test]
+    fn verify_reports_diagnostics() {
+        // try { ldc.i4.1; br.s into ldc.i4; stloc.2; ret } finally { endfinally } pop
+        let method = method(
+            vec![
+                Instruction::ldc_i4_1(),
+                Instruction::br_s(1),
+                Instruction::ldc_i4(1),
+                Instruction::stloc_2(),
+                Instruction::ret(),
+                Instruction::endfinally(),
+                Instruction::pop(),
+            ],
+            vec![FatSectionClause {
+                flag: crate::cil::CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
+                try_offset: 0,
+                try_length: 10,
+                handler_offset: 10,
+                handler_length: 1,
+                class_token_or_filter_offset: 0,
+            }],
+        );
+
+        let diagnostics = Verifier::new(&Resolver, false).verify(&method);
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic::InvalidBranchTarget {
+                    offset: 1,
+                    target: 4
+                },
+                Diagnostic::InvalidLocalIndex {
+                    offset: 8,
+                    index: 2,
+                    locals: 2
+                },
+                Diagnostic::ReturnFromBlock { offset: 9 },
+                Diagnostic::FallThrough { offset: 11 },
+            ]
+        );
+    }
+}
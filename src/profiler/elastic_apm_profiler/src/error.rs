@@ -14,4 +14,9 @@ pub enum Error {
     StackSize,
     InvalidVersion,
     InvalidAssemblyReference,
+    InvalidSignature,
+    /// A metadata token is nil, or not of a token type expected for its use
+    InvalidToken(u32),
+    /// IL source text could not be assembled
+    InvalidIlSource { line: usize, message: String },
 }
@@ -37,4 +37,18 @@ impl ICorProfilerFunctionControl {
             Ok(())
         }
     }
+
+    pub fn set_il_instrumented_code_map(
+        &self,
+        il_map_entries: &[COR_IL_MAP],
+    ) -> Result<(), HRESULT> {
+        let len = il_map_entries.len() as ULONG;
+        let ptr = il_map_entries.as_ptr();
+        let hr = unsafe { self.SetILInstrumentedCodeMap(len, ptr) };
+        if FAILED(hr) {
+            Err(hr)
+        } else {
+            Ok(())
+        }
+    }
 }
@@ -591,6 +591,136 @@ impl IMetaDataImport {
         }
     }
 
+    /// Enumerates the TypeDef tokens of the types defined in the current scope
+    pub fn enum_type_defs(&self) -> Result<Vec<mdTypeDef>, HRESULT> {
+        let mut en = ptr::null_mut() as HCORENUM;
+        let max = 256;
+        let mut all_type_defs = Vec::new();
+        loop {
+            let mut type_defs: Vec<mdTypeDef> = Vec::with_capacity(max as usize);
+            let mut type_defs_len = 0;
+            let hr = unsafe {
+                self.EnumTypeDefs(&mut en, type_defs.as_mut_ptr(), max, &mut type_defs_len)
+            };
+
+            if FAILED(hr) {
+                unsafe { self.CloseEnum(en) };
+                return Err(hr);
+            }
+
+            unsafe {
+                type_defs.set_len(type_defs_len as usize);
+            }
+            all_type_defs.append(&mut type_defs);
+
+            if hr == S_FALSE || type_defs_len < max {
+                unsafe { self.CloseEnum(en) };
+                return Ok(all_type_defs);
+            }
+        }
+    }
+
+    /// Enumerates the TypeRef tokens of the types referenced in the current scope
+    pub fn enum_type_refs(&self) -> Result<Vec<mdTypeRef>, HRESULT> {
+        let mut en = ptr::null_mut() as HCORENUM;
+        let max = 256;
+        let mut all_type_refs = Vec::new();
+        loop {
+            let mut type_refs: Vec<mdTypeRef> = Vec::with_capacity(max as usize);
+            let mut type_refs_len = 0;
+            let hr = unsafe {
+                self.EnumTypeRefs(&mut en, type_refs.as_mut_ptr(), max, &mut type_refs_len)
+            };
+
+            if FAILED(hr) {
+                unsafe { self.CloseEnum(en) };
+                return Err(hr);
+            }
+
+            unsafe {
+                type_refs.set_len(type_refs_len as usize);
+            }
+            all_type_refs.append(&mut type_refs);
+
+            if hr == S_FALSE || type_refs_len < max {
+                unsafe { self.CloseEnum(en) };
+                return Ok(all_type_refs);
+            }
+        }
+    }
+
+    /// Enumerates the MemberRef tokens of the members of the type referenced by
+    /// the specified TypeDef, TypeRef, TypeSpec, MethodDef or ModuleRef token
+    pub fn enum_member_refs(&self, parent: mdToken) -> Result<Vec<mdMemberRef>, HRESULT> {
+        let mut en = ptr::null_mut() as HCORENUM;
+        let max = 256;
+        let mut all_member_refs = Vec::new();
+        loop {
+            let mut member_refs: Vec<mdMemberRef> = Vec::with_capacity(max as usize);
+            let mut member_refs_len = 0;
+            let hr = unsafe {
+                self.EnumMemberRefs(
+                    &mut en,
+                    parent,
+                    member_refs.as_mut_ptr(),
+                    max,
+                    &mut member_refs_len,
+                )
+            };
+
+            if FAILED(hr) {
+                unsafe { self.CloseEnum(en) };
+                return Err(hr);
+            }
+
+            unsafe {
+                member_refs.set_len(member_refs_len as usize);
+            }
+            all_member_refs.append(&mut member_refs);
+
+            if hr == S_FALSE || member_refs_len < max {
+                unsafe { self.CloseEnum(en) };
+                return Ok(all_member_refs);
+            }
+        }
+    }
+
+    /// Enumerates the MethodDef tokens of the methods defined by the type referenced
+    /// by the specified TypeDef token
+    pub fn enum_methods(&self, type_def: mdTypeDef) -> Result<Vec<mdMethodDef>, HRESULT> {
+        let mut en = ptr::null_mut() as HCORENUM;
+        let max = 256;
+        let mut all_method_defs = Vec::new();
+        loop {
+            let mut method_defs: Vec<mdMethodDef> = Vec::with_capacity(max as usize);
+            let mut method_defs_len = 0;
+            let hr = unsafe {
+                self.EnumMethods(
+                    &mut en,
+                    type_def,
+                    method_defs.as_mut_ptr(),
+                    max,
+                    &mut method_defs_len,
+                )
+            };
+
+            if FAILED(hr) {
+                unsafe { self.CloseEnum(en) };
+                return Err(hr);
+            }
+
+            unsafe {
+                method_defs.set_len(method_defs_len as usize);
+            }
+            all_method_defs.append(&mut method_defs);
+
+            if hr == S_FALSE || method_defs_len < max {
+                unsafe { self.CloseEnum(en) };
+                return Ok(all_method_defs);
+            }
+        }
+    }
+
     /// Gets metadata associated with the member referenced by the specified token.
     pub fn get_member_ref_props(&self, mr: mdMemberRef) -> Result<MemberRefProps, HRESULT> {
         let mut name_buffer_length = MaybeUninit::uninit();
@@ -1014,6 +1144,40 @@ impl IMetaDataImport {
 }
 
 impl IMetaDataImport2 {
+    /// Enumerates the generic parameters of the type or method referenced by the
+    /// specified TypeDef or MethodDef token, in the order of their index.
+    pub fn enum_generic_params(&self, tk: mdToken) -> Result<Vec<mdGenericParam>, HRESULT> {
+        let mut en = ptr::null_mut() as HCORENUM;
+        let max = 256;
+        let mut generic_params = Vec::with_capacity(max as usize);
+        let mut generic_params_len = MaybeUninit::uninit();
+        let hr = unsafe {
+            self.EnumGenericParams(
+                &mut en,
+                tk,
+                generic_params.as_mut_ptr(),
+                max,
+                generic_params_len.as_mut_ptr(),
+            )
+        };
+
+        match hr {
+            S_OK => {
+                unsafe {
+                    let len = generic_params_len.assume_init();
+                    generic_params.set_len(len as usize);
+                    self.CloseEnum(en);
+                }
+                Ok(generic_params)
+            }
+            S_FALSE => {
+                unsafe { self.CloseEnum(en) };
+                Ok(Vec::new())
+            }
+            _ => Err(hr),
+        }
+    }
+
     /// Gets the metadata signature of the method referenced by the specified MethodSpec token.
     pub fn get_method_spec_props(&self, token: mdMethodSpec) -> Result<MethodSpecProps, HRESULT> {
         let mut parent = 0;
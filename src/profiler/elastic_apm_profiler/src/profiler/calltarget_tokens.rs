@@ -4,14 +4,15 @@
 // See the LICENSE file in the project root for more information
 
 use crate::{
-    cil::{compress_data, compress_token, uncompress_data, Instruction, Method},
+    cil::{Instruction, Method, MethodSig, MethodSpecSig, MethodToken, TypeSig, TypeToken},
+    error::Error,
     ffi::{
-        mdAssemblyRef, mdAssemblyRefNil, mdMemberRef, mdMemberRefNil, mdMethodSpec, mdToken,
-        mdTokenNil, mdTypeRef, mdTypeRefNil, mdTypeSpec, mdTypeSpecNil, CorAssemblyFlags,
-        CorCallingConvention, CorElementType, ASSEMBLYMETADATA, COR_SIGNATURE, E_FAIL, ULONG,
-        WCHAR,
+        mdAssemblyRef, mdAssemblyRefNil, mdToken, mdTokenNil, mdTypeSpecNil, CorAssemblyFlags,
+        CorCallingConvention, ASSEMBLYMETADATA, E_FAIL, ULONG, WCHAR,
     },
     profiler::{
+        helpers::operand_token,
+        local_sig::LocalSigEditor,
         managed,
         types::{
             FunctionInfo, FunctionMethodArgument, MethodArgumentTypeFlag, ModuleMetadata, TypeInfo,
@@ -25,56 +26,82 @@ use widestring::U16CString;
 /// Metadata tokens to modify call targets
 pub struct CallTargetTokens {
     cor_lib_assembly_ref: mdAssemblyRef,
-    object_type_ref: mdTypeRef,
-    ex_type_ref: mdTypeRef,
-    type_ref: mdTypeRef,
-    runtime_type_handle_ref: mdTypeRef,
-    get_type_from_handle_token: mdToken,
-    runtime_method_handle_ref: mdTypeRef,
+    cor_lib_tokens: Option<CorLibTokens>,
     profiler_assembly_ref: mdAssemblyRef,
-    call_target_type_ref: mdTypeRef,
-    call_target_state_type_ref: mdTypeRef,
-    call_target_return_void_type_ref: mdTypeRef,
-    call_target_return_type_ref: mdTypeRef,
-    begin_array_member_ref: mdMemberRef,
-    begin_method_fast_path_refs: Vec<mdMemberRef>,
-    end_void_member_ref: mdMemberRef,
-    log_exception_ref: mdMemberRef,
-    call_target_state_type_get_default: mdMemberRef,
-    call_target_return_void_type_get_default: mdMemberRef,
-    get_default_member_ref: mdMemberRef,
+    base_tokens: Option<BaseTokens>,
+    call_target_return_void_type_ref: Option<TypeToken>,
+    call_target_return_type_ref: Option<TypeToken>,
+    begin_array_member_ref: Option<MethodToken>,
+    begin_method_fast_path_refs: Vec<Option<MethodToken>>,
+    end_void_member_ref: Option<MethodToken>,
+    log_exception_ref: Option<MethodToken>,
+    call_target_state_type_get_skip_method_body: Option<MethodToken>,
+    call_target_return_void_type_get_default: Option<MethodToken>,
+    get_default_member_ref: Option<MethodToken>,
+}
+
+/// Tokens of the core library types, defined by [CallTargetTokens::ensure_cor_lib_tokens]
+#[derive(Clone, Copy)]
+struct CorLibTokens {
+    object_type_ref: TypeToken,
+    ex_type_ref: TypeToken,
+    type_ref: TypeToken,
+    runtime_type_handle_ref: TypeToken,
+    get_type_from_handle_token: MethodToken,
+    runtime_method_handle_ref: TypeToken,
+}
+
+/// Tokens of the CallTarget types, defined by [CallTargetTokens::ensure_base_calltarget_tokens]
+#[derive(Clone, Copy)]
+struct BaseTokens {
+    cor_lib: CorLibTokens,
+    call_target_type_ref: TypeToken,
+    call_target_state_type_ref: TypeToken,
+    call_target_state_type_get_default: MethodToken,
 }
 
 impl CallTargetTokens {
     pub const FAST_PATH_COUNT: usize = 9;
+
+    /// Whether arguments are passed to BeginMethod in an object array. This is the case for
+    /// methods with more arguments than the fast path supports, and for methods with by-ref
+    /// arguments and wrappers that modify arguments, since only the values in the array are
+    /// copied back after BeginMethod
+    pub fn uses_arguments_array(
+        method_arguments: &[FunctionMethodArgument],
+        modify_arguments: bool,
+    ) -> bool {
+        modify_arguments
+            || method_arguments.len() >= Self::FAST_PATH_COUNT
+            || method_arguments.iter().any(is_by_ref)
+    }
+
     pub fn new() -> Self {
         Self {
             cor_lib_assembly_ref: mdAssemblyRefNil,
-            object_type_ref: mdTypeRefNil,
-            ex_type_ref: mdTypeRefNil,
-            type_ref: mdTypeRefNil,
-            runtime_type_handle_ref: mdTypeRefNil,
-            get_type_from_handle_token: mdTokenNil,
-            runtime_method_handle_ref: mdTypeRefNil,
+            cor_lib_tokens: None,
             profiler_assembly_ref: mdAssemblyRefNil,
-            call_target_type_ref: mdTypeRefNil,
-            call_target_state_type_ref: mdTypeRefNil,
-            call_target_return_void_type_ref: mdTypeRefNil,
-            call_target_return_type_ref: mdTypeRefNil,
-            begin_array_member_ref: mdMemberRefNil,
-            begin_method_fast_path_refs: vec![mdMemberRefNil; Self::FAST_PATH_COUNT],
-            end_void_member_ref: mdMemberRefNil,
-            log_exception_ref: mdMemberRefNil,
-            call_target_state_type_get_default: mdMemberRefNil,
-            call_target_return_void_type_get_default: mdMemberRefNil,
-            get_default_member_ref: mdMemberRefNil,
+            base_tokens: None,
+            call_target_return_void_type_ref: None,
+            call_target_return_type_ref: None,
+            begin_array_member_ref: None,
+            begin_method_fast_path_refs: vec![None; Self::FAST_PATH_COUNT],
+            end_void_member_ref: None,
+            log_exception_ref: None,
+            call_target_state_type_get_skip_method_body: None,
+            call_target_return_void_type_get_default: None,
+            get_default_member_ref: None,
         }
     }
 
-    pub fn ensure_cor_lib_tokens(
+    fn ensure_cor_lib_tokens(
         &mut self,
         module_metadata: &ModuleMetadata,
-    ) -> Result<(), HRESULT> {
+    ) -> Result<CorLibTokens, HRESULT> {
+        if let Some(cor_lib_tokens) = self.cor_lib_tokens {
+            return Ok(cor_lib_tokens);
+        }
+
         if self.cor_lib_assembly_ref == mdAssemblyRefNil {
             let cor_assembly_property = &module_metadata.cor_assembly_property;
             let assembly_metadata = ASSEMBLYMETADATA {
@@ -99,88 +126,62 @@ impl CallTargetTokens {
             )?;
         }
 
-        if self.object_type_ref == mdTypeRefNil {
-            self.object_type_ref = module_metadata
+        let define_type_ref = |name: &str| -> Result<TypeToken, HRESULT> {
+            let type_ref = module_metadata
                 .emit
-                .define_type_ref_by_name(self.cor_lib_assembly_ref, "System.Object")
+                .define_type_ref_by_name(self.cor_lib_assembly_ref, name)
                 .map_err(|e| {
-                    log::warn!("Could not define type_ref for System.Object");
+                    log::warn!("Could not define type_ref for {}", name);
                     e
                 })?;
-        }
-
-        if self.ex_type_ref == mdTypeRefNil {
-            self.ex_type_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(self.cor_lib_assembly_ref, "System.Exception")
-                .map_err(|e| {
-                    log::warn!("Could not define type_ref for System.Exception");
-                    e
-                })?;
-        }
-
-        if self.type_ref == mdTypeRefNil {
-            self.type_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(self.cor_lib_assembly_ref, "System.Type")
-                .map_err(|e| {
-                    log::warn!("Could not define type_ref for System.Type");
-                    e
-                })?;
-        }
-
-        if self.runtime_type_handle_ref == mdTypeRefNil {
-            self.runtime_type_handle_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(self.cor_lib_assembly_ref, "System.RuntimeTypeHandle")
-                .map_err(|e| {
-                    log::warn!("Could not define type_ref for System.RuntimeTypeHandle");
-                    e
-                })?;
-        }
-
-        if self.get_type_from_handle_token == mdTokenNil {
-            let mut runtime_type_handle_compressed =
-                compress_token(self.runtime_type_handle_ref).unwrap();
-            let mut type_ref_compressed = compress_token(self.type_ref).unwrap();
-
-            let mut signature = Vec::with_capacity(
-                4 + runtime_type_handle_compressed.len() + type_ref_compressed.len(),
-            );
-            signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT.bits());
-            signature.push(1);
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-            signature.append(&mut type_ref_compressed);
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-            signature.append(&mut runtime_type_handle_compressed);
-
-            self.get_type_from_handle_token = module_metadata
-                .emit
-                .define_member_ref(self.type_ref, "GetTypeFromHandle", &signature)
-                .map_err(|e| {
-                    log::warn!("Could not define get_type_from_handle_token");
-                    e
-                })?;
-        }
+            operand_token(type_ref)
+        };
+
+        let object_type_ref = define_type_ref("System.Object")?;
+        let ex_type_ref = define_type_ref("System.Exception")?;
+        let type_ref = define_type_ref("System.Type")?;
+        let runtime_type_handle_ref = define_type_ref("System.RuntimeTypeHandle")?;
+
+        let signature = signature_bytes(
+            MethodSig::new(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                TypeSig::Class(type_ref.token()),
+                vec![TypeSig::ValueType(runtime_type_handle_ref.token())],
+            )
+            .to_bytes(),
+        )?;
 
-        if self.runtime_method_handle_ref == mdTypeRefNil {
-            self.runtime_method_handle_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(self.cor_lib_assembly_ref, "System.RuntimeMethodHandle")
-                .map_err(|e| {
-                    log::warn!("Could not define type_ref for System.RuntimeMethodHandle");
-                    e
-                })?;
-        }
+        let get_type_from_handle_token = module_metadata
+            .emit
+            .define_member_ref(type_ref.token(), "GetTypeFromHandle", &signature)
+            .map_err(|e| {
+                log::warn!("Could not define get_type_from_handle_token");
+                e
+            })?;
 
-        Ok(())
+        let runtime_method_handle_ref = define_type_ref("System.RuntimeMethodHandle")?;
+
+        let cor_lib_tokens = CorLibTokens {
+            object_type_ref,
+            ex_type_ref,
+            type_ref,
+            runtime_type_handle_ref,
+            get_type_from_handle_token: operand_token(get_type_from_handle_token)?,
+            runtime_method_handle_ref,
+        };
+        self.cor_lib_tokens = Some(cor_lib_tokens);
+        Ok(cor_lib_tokens)
     }
 
-    pub fn ensure_base_calltarget_tokens(
+    fn ensure_base_calltarget_tokens(
         &mut self,
         module_metadata: &ModuleMetadata,
-    ) -> Result<(), HRESULT> {
-        self.ensure_cor_lib_tokens(module_metadata)?;
+    ) -> Result<BaseTokens, HRESULT> {
+        if let Some(base_tokens) = self.base_tokens {
+            return Ok(base_tokens);
+        }
+
+        let cor_lib = self.ensure_cor_lib_tokens(module_metadata)?;
 
         if self.profiler_assembly_ref == mdAssemblyRefNil {
             let assembly_reference =
@@ -226,225 +227,261 @@ impl CallTargetTokens {
                 })?;
         }
 
-        if self.call_target_type_ref == mdTypeRefNil {
-            self.call_target_type_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(
-                    self.profiler_assembly_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_TYPE,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define type_ref for {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_TYPE
-                    );
-                    e
-                })?;
-        }
-
-        if self.call_target_state_type_ref == mdTypeRefNil {
-            self.call_target_state_type_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(
-                    self.profiler_assembly_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_STATETYPE,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define type_ref for {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_STATETYPE
-                    );
-                    e
-                })?;
-        }
-
-        if self.call_target_state_type_get_default == mdMemberRefNil {
-            let mut call_target_state_type_compressed =
-                compress_token(self.call_target_state_type_ref).unwrap();
+        let call_target_type_ref = self
+            .define_profiler_type_ref(managed::MANAGED_PROFILER_CALLTARGET_TYPE, module_metadata)?;
+        let call_target_state_type_ref = self.define_profiler_type_ref(
+            managed::MANAGED_PROFILER_CALLTARGET_STATETYPE,
+            module_metadata,
+        )?;
+
+        let signature = signature_bytes(
+            MethodSig::new(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                TypeSig::ValueType(call_target_state_type_ref.token()),
+                vec![],
+            )
+            .to_bytes(),
+        )?;
 
-            let mut signature = Vec::with_capacity(3 + call_target_state_type_compressed.len());
-            signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT.bits());
-            signature.push(0);
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-            signature.append(&mut call_target_state_type_compressed);
+        let call_target_state_type_get_default = module_metadata
+            .emit
+            .define_member_ref(
+                call_target_state_type_ref.token(),
+                managed::MANAGED_PROFILER_CALLTARGET_STATETYPE_GETDEFAULT_NAME,
+                &signature,
+            )
+            .map_err(|e| {
+                log::warn!(
+                    "Could not define member ref {}",
+                    managed::MANAGED_PROFILER_CALLTARGET_STATETYPE_GETDEFAULT_NAME
+                );
+                e
+            })?;
 
-            self.call_target_state_type_get_default = module_metadata
-                .emit
-                .define_member_ref(
-                    self.call_target_state_type_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_STATETYPE_GETDEFAULT_NAME,
-                    &signature,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define member ref {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_STATETYPE_GETDEFAULT_NAME
-                    );
-                    e
-                })?;
-        }
+        let base_tokens = BaseTokens {
+            cor_lib,
+            call_target_type_ref,
+            call_target_state_type_ref,
+            call_target_state_type_get_default: operand_token(call_target_state_type_get_default)?,
+        };
+        self.base_tokens = Some(base_tokens);
+        Ok(base_tokens)
+    }
 
-        Ok(())
+    /// Defines a type ref to a type in the profiler assembly
+    fn define_profiler_type_ref(
+        &self,
+        name: &str,
+        module_metadata: &ModuleMetadata,
+    ) -> Result<TypeToken, HRESULT> {
+        let type_ref = module_metadata
+            .emit
+            .define_type_ref_by_name(self.profiler_assembly_ref, name)
+            .map_err(|e| {
+                log::warn!("Could not define type_ref for {}", name);
+                e
+            })?;
+        operand_token(type_ref)
     }
 
     pub fn get_cor_lib_assembly_ref(&self) -> mdAssemblyRef {
         self.cor_lib_assembly_ref
     }
 
-    pub fn get_object_type_ref(&self) -> mdTypeRef {
-        self.object_type_ref
+    /// Gets the core library tokens, which must already have been defined
+    fn cor_lib_tokens(&self) -> Result<CorLibTokens, HRESULT> {
+        self.cor_lib_tokens.ok_or_else(|| {
+            log::warn!("core library tokens have not been defined");
+            E_FAIL
+        })
+    }
+
+    pub fn get_object_type_ref(&self) -> Result<TypeToken, HRESULT> {
+        Ok(self.cor_lib_tokens()?.object_type_ref)
     }
 
-    pub fn get_ex_type_ref(&self) -> mdTypeRef {
-        self.ex_type_ref
+    pub fn get_ex_type_ref(&self) -> Result<TypeToken, HRESULT> {
+        Ok(self.cor_lib_tokens()?.ex_type_ref)
     }
 
     pub fn get_target_state_type_ref(
         &mut self,
         module_metadata: &ModuleMetadata,
-    ) -> Result<mdTypeRef, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
-        Ok(self.call_target_state_type_ref)
+    ) -> Result<TypeToken, HRESULT> {
+        Ok(self
+            .ensure_base_calltarget_tokens(module_metadata)?
+            .call_target_state_type_ref)
     }
 
     pub fn get_target_void_return_type_ref(
         &mut self,
         module_metadata: &ModuleMetadata,
-    ) -> Result<mdTypeRef, HRESULT> {
+    ) -> Result<TypeToken, HRESULT> {
         self.ensure_base_calltarget_tokens(module_metadata)?;
-        if self.call_target_return_void_type_ref == mdTypeRefNil {
-            self.call_target_return_void_type_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(
-                    self.profiler_assembly_ref,
+        match self.call_target_return_void_type_ref {
+            Some(type_ref) => Ok(type_ref),
+            None => {
+                let type_ref = self.define_profiler_type_ref(
                     managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define type_ref for {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE
-                    );
-                    e
-                })?;
+                    module_metadata,
+                )?;
+                self.call_target_return_void_type_ref = Some(type_ref);
+                Ok(type_ref)
+            }
         }
-
-        Ok(self.call_target_return_void_type_ref)
     }
 
-    pub fn get_target_return_value_type_ref(
+    /// Gets the generic CallTargetReturn type ref, instantiated by
+    /// [CallTargetTokens::get_target_return_value_type_ref]
+    fn get_target_return_type_ref(
         &mut self,
-        return_argument: &FunctionMethodArgument,
         module_metadata: &ModuleMetadata,
-    ) -> Result<mdTypeSpec, HRESULT> {
+    ) -> Result<TypeToken, HRESULT> {
         self.ensure_base_calltarget_tokens(module_metadata)?;
-
-        if self.call_target_return_type_ref == mdTypeRefNil {
-            self.call_target_return_type_ref = module_metadata
-                .emit
-                .define_type_ref_by_name(
-                    self.profiler_assembly_ref,
+        match self.call_target_return_type_ref {
+            Some(type_ref) => Ok(type_ref),
+            None => {
+                let type_ref = self.define_profiler_type_ref(
                     managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GENERICS,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define type_ref for {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GENERICS
-                    );
-                    e
-                })?;
+                    module_metadata,
+                )?;
+                self.call_target_return_type_ref = Some(type_ref);
+                Ok(type_ref)
+            }
         }
+    }
 
-        let return_signature = return_argument.signature();
-        let mut call_target_return_type_ref_compressed =
-            compress_token(self.call_target_return_type_ref).unwrap();
-
-        let mut signature = Vec::with_capacity(
-            3 + return_signature.len() + call_target_return_type_ref_compressed.len(),
-        );
-        signature.push(CorElementType::ELEMENT_TYPE_GENERICINST as COR_SIGNATURE);
-        signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        signature.append(&mut call_target_return_type_ref_compressed);
-        signature.push(1);
-        signature.extend_from_slice(return_signature);
+    pub fn get_target_return_value_type_ref(
+        &mut self,
+        return_argument: &FunctionMethodArgument,
+        module_metadata: &ModuleMetadata,
+    ) -> Result<TypeToken, HRESULT> {
+        let call_target_return_type_ref = self.get_target_return_type_ref(module_metadata)?;
+
+        let signature = signature_bytes(
+            TypeSig::generic_inst(
+                true,
+                call_target_return_type_ref.token(),
+                vec![argument_type(return_argument)?],
+            )
+            .to_bytes(),
+        )?;
 
         let return_value_type_spec = module_metadata.emit.get_token_from_type_spec(&signature)?;
 
-        Ok(return_value_type_spec)
+        operand_token(return_value_type_spec)
     }
 
     pub fn get_call_target_state_default_member_ref(
         &mut self,
         module_metadata: &ModuleMetadata,
-    ) -> Result<mdMemberRef, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
-        Ok(self.call_target_state_type_get_default)
+    ) -> Result<MethodToken, HRESULT> {
+        Ok(self
+            .ensure_base_calltarget_tokens(module_metadata)?
+            .call_target_state_type_get_default)
     }
 
-    pub fn get_call_target_return_void_default_member_ref(
+    pub fn get_call_target_state_skip_method_body_member_ref(
         &mut self,
         module_metadata: &ModuleMetadata,
-    ) -> Result<mdMemberRef, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
-
-        if self.call_target_return_void_type_get_default == mdMemberRefNil {
-            let call_target_return_void_type_compressed =
-                compress_token(self.call_target_return_void_type_ref).unwrap();
-
-            let mut signature =
-                Vec::with_capacity(3 + call_target_return_void_type_compressed.len());
-            signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT.bits());
-            signature.push(0);
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-            signature.extend_from_slice(&call_target_return_void_type_compressed);
+    ) -> Result<MethodToken, HRESULT> {
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
 
-            self.call_target_return_void_type_get_default = module_metadata
-                .emit
-                .define_member_ref(
-                    self.call_target_return_void_type_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GETDEFAULT_NAME,
-                    &signature,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define member ref {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GETDEFAULT_NAME
-                    );
-                    e
-                })?;
+        if let Some(member_ref) = self.call_target_state_type_get_skip_method_body {
+            return Ok(member_ref);
         }
 
-        Ok(self.call_target_return_void_type_get_default)
+        let signature = signature_bytes(
+            MethodSig::new(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_HASTHIS,
+                TypeSig::Boolean,
+                vec![],
+            )
+            .to_bytes(),
+        )?;
+
+        let member_ref = match module_metadata.emit.define_member_ref(
+            base_tokens.call_target_state_type_ref.token(),
+            managed::MANAGED_PROFILER_CALLTARGET_STATETYPE_GETSKIPMETHODBODY_NAME,
+            &signature,
+        ) {
+            Ok(member_ref) => operand_token(member_ref)?,
+            Err(e) => {
+                log::warn!(
+                    "Could not define member ref {}",
+                    managed::MANAGED_PROFILER_CALLTARGET_STATETYPE_GETSKIPMETHODBODY_NAME
+                );
+                return Err(e);
+            }
+        };
+        self.call_target_state_type_get_skip_method_body = Some(member_ref);
+        Ok(member_ref)
     }
 
-    pub fn get_call_target_return_value_default_member_ref(
+    pub fn get_call_target_return_void_default_member_ref(
         &mut self,
-        call_target_return_type_spec: mdTypeSpec,
         module_metadata: &ModuleMetadata,
-    ) -> Result<mdMemberRef, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
+    ) -> Result<MethodToken, HRESULT> {
+        let call_target_return_void_type_ref =
+            self.get_target_void_return_type_ref(module_metadata)?;
 
-        if self.call_target_return_type_ref == mdTypeRefNil {
-            log::warn!("Could not define call_target_return_type_get_default because call_target_return_type_ref is null");
-            return Err(E_FAIL);
+        if let Some(member_ref) = self.call_target_return_void_type_get_default {
+            return Ok(member_ref);
         }
 
-        let mut call_target_return_type_compressed =
-            compress_token(self.call_target_return_type_ref).unwrap();
+        let signature = signature_bytes(
+            MethodSig::new(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                TypeSig::ValueType(call_target_return_void_type_ref.token()),
+                vec![],
+            )
+            .to_bytes(),
+        )?;
+
+        let member_ref = module_metadata
+            .emit
+            .define_member_ref(
+                call_target_return_void_type_ref.token(),
+                managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GETDEFAULT_NAME,
+                &signature,
+            )
+            .map_err(|e| {
+                log::warn!(
+                    "Could not define member ref {}",
+                    managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GETDEFAULT_NAME
+                );
+                e
+            })?;
+
+        let member_ref = operand_token(member_ref)?;
+        self.call_target_return_void_type_get_default = Some(member_ref);
+        Ok(member_ref)
+    }
 
-        let mut signature = Vec::with_capacity(7 + call_target_return_type_compressed.len());
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT.bits());
-        signature.push(0);
-        signature.push(CorElementType::ELEMENT_TYPE_GENERICINST as COR_SIGNATURE);
-        signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        signature.append(&mut call_target_return_type_compressed);
-        signature.push(1);
-        signature.push(CorElementType::ELEMENT_TYPE_VAR as COR_SIGNATURE);
-        signature.push(0);
+    pub fn get_call_target_return_value_default_member_ref(
+        &mut self,
+        call_target_return_type_spec: TypeToken,
+        module_metadata: &ModuleMetadata,
+    ) -> Result<MethodToken, HRESULT> {
+        let call_target_return_type_ref = self.get_target_return_type_ref(module_metadata)?;
+
+        let signature = signature_bytes(
+            MethodSig::new(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                TypeSig::generic_inst(
+                    true,
+                    call_target_return_type_ref.token(),
+                    vec![TypeSig::Var(0)],
+                ),
+                vec![],
+            )
+            .to_bytes(),
+        )?;
 
-        module_metadata
+        let call_target_return_type_get_default = module_metadata
             .emit
             .define_member_ref(
-                call_target_return_type_spec,
+                call_target_return_type_spec.token(),
                 managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GETDEFAULT_NAME,
                 &signature,
             )
@@ -455,59 +492,67 @@ impl CallTargetTokens {
                     e
                 );
                 e
-            })
+            })?;
+
+        operand_token(call_target_return_type_get_default)
     }
 
     pub fn get_call_target_default_value_method_spec(
         &mut self,
         method_argument: &FunctionMethodArgument,
         module_metadata: &ModuleMetadata,
-    ) -> Result<mdMethodSpec, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
-
-        if self.get_default_member_ref == mdMemberRefNil {
-            let signature = vec![
-                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC.bits(),
-                1,
-                0,
-                CorElementType::ELEMENT_TYPE_MVAR as COR_SIGNATURE,
-                0,
-            ];
-
-            self.get_default_member_ref = module_metadata
-                .emit
-                .define_member_ref(
-                    self.call_target_type_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_GETDEFAULTVALUE_NAME,
-                    &signature,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define member ref {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_GETDEFAULTVALUE_NAME
-                    );
-                    e
-                })?;
-        }
+    ) -> Result<MethodToken, HRESULT> {
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
+
+        let get_default_member_ref = match self.get_default_member_ref {
+            Some(member_ref) => member_ref,
+            None => {
+                let signature = signature_bytes(
+                    MethodSig::generic(
+                        CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                        1,
+                        TypeSig::MVar(0),
+                        vec![],
+                    )
+                    .to_bytes(),
+                )?;
+
+                let member_ref = module_metadata
+                    .emit
+                    .define_member_ref(
+                        base_tokens.call_target_type_ref.token(),
+                        managed::MANAGED_PROFILER_CALLTARGET_GETDEFAULTVALUE_NAME,
+                        &signature,
+                    )
+                    .map_err(|e| {
+                        log::warn!(
+                            "Could not define member ref {}",
+                            managed::MANAGED_PROFILER_CALLTARGET_GETDEFAULTVALUE_NAME
+                        );
+                        e
+                    })?;
+
+                let member_ref = operand_token(member_ref)?;
+                self.get_default_member_ref = Some(member_ref);
+                member_ref
+            }
+        };
 
-        let method_argument_signature = method_argument.signature();
-        let mut signature = Vec::with_capacity(2 + method_argument_signature.len());
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST.bits());
-        signature.push(1);
-        signature.extend_from_slice(method_argument_signature);
+        let signature =
+            signature_bytes(MethodSpecSig::new(vec![argument_type(method_argument)?]).to_bytes())?;
 
         let default_method_spec = module_metadata
             .emit
-            .define_method_spec(self.get_default_member_ref, &signature)
+            .define_method_spec(get_default_member_ref.token(), &signature)
             .map_err(|e| {
                 log::warn!("Could not define default method spec");
                 e
             })?;
 
-        Ok(default_method_spec)
+        operand_token(default_method_spec)
     }
 
-    fn get_current_type_ref(&self, current_type: &TypeInfo) -> (mdToken, bool) {
+    fn get_current_type_ref(base_tokens: &BaseTokens, current_type: &TypeInfo) -> (mdToken, bool) {
         let mut is_value_type = current_type.is_value_type;
         if current_type.type_spec != mdTypeSpecNil {
             (current_type.type_spec, is_value_type)
@@ -522,191 +567,242 @@ impl CallTargetTokens {
             }
 
             is_value_type = false;
-            (self.object_type_ref, is_value_type)
+            (base_tokens.cor_lib.object_type_ref.token(), is_value_type)
         }
     }
 
+    /// Creates the instantiation of a CallTarget method for the integration type,
+    /// the current type and additional type arguments
+    fn integration_method_spec(
+        base_tokens: &BaseTokens,
+        integration_type_ref: TypeToken,
+        current_type: &TypeInfo,
+        mut args: Vec<TypeSig>,
+    ) -> MethodSpecSig {
+        let (current_type_ref, is_value_type) =
+            Self::get_current_type_ref(base_tokens, current_type);
+        args.insert(0, TypeSig::type_token(is_value_type, current_type_ref));
+        args.insert(0, TypeSig::Class(integration_type_ref.token()));
+        MethodSpecSig::new(args)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn create_local_sig(
         &mut self,
         method: &Method,
         method_return_value: &FunctionMethodArgument,
+        method_arguments: &[FunctionMethodArgument],
+        instance_type: Option<&TypeSig>,
+        wrapper_count: usize,
+        modify_arguments: bool,
         module_metadata: &ModuleMetadata,
     ) -> Result<LocalSig, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
+
+        let mut editor = LocalSigEditor::load(module_metadata, method.header.local_var_sig_tok())?;
+        if editor.locals().last()
+            == Some(&TypeSig::ValueType(
+                base_tokens.call_target_state_type_ref.token(),
+            ))
+        {
+            log::warn!("method signature has already been modified");
+            return Err(E_FAIL);
+        }
 
-        let local_var_sig = method.header.local_var_sig_tok();
-        let mut call_target_state_type_ref_compressed =
-            compress_token(self.call_target_state_type_ref).unwrap();
-        let mut original_sig = vec![];
-        let mut original_signature_size = 0;
-
-        if local_var_sig != mdTokenNil {
-            original_sig = module_metadata.import.get_sig_from_token(local_var_sig)?;
-            original_signature_size += original_sig.len();
-            let offset = original_sig.len() - call_target_state_type_ref_compressed.len();
-            if offset > 0
-                && original_sig[offset - 1] == CorElementType::ELEMENT_TYPE_VALUETYPE as u8
-                && original_sig[offset..] == call_target_state_type_ref_compressed
-            {
-                log::warn!("method signature has already been modified");
-                return Err(E_FAIL);
-            }
+        let mut local_sig = self.push_locals(
+            &mut editor,
+            method_return_value,
+            method_arguments,
+            instance_type,
+            wrapper_count,
+            modify_arguments,
+            module_metadata,
+        )?;
+        local_sig.new_local_var_sig = editor.emit(module_metadata)?;
+        Ok(local_sig)
+    }
+
+    /// Appends the locals of a call target to the local variables of a call site
+    /// in a caller, which are the instance of the call, if the target is an instance
+    /// method, and each argument of the call, followed by the locals of
+    /// [CallTargetTokens::create_local_sig]. The new signature is emitted by the
+    /// caller once the locals of all call sites have been appended
+    pub fn create_call_site_locals(
+        &mut self,
+        editor: &mut LocalSigEditor,
+        target: &FunctionInfo,
+        wrapper_count: usize,
+        modify_arguments: bool,
+        module_metadata: &ModuleMetadata,
+    ) -> Result<CallSiteLocals, HRESULT> {
+        let parsed_method = target.method_signature.try_parse().ok_or(E_FAIL)?;
+        let method_arguments = parsed_method.arguments();
+
+        let instance_index = if target.signature.is_instance_method() {
+            let type_info = target.type_info.as_ref().ok_or(E_FAIL)?;
+            Some(editor.push(TypeSig::Class(type_info.id))?)
+        } else {
+            None
+        };
+        let mut argument_indexes = Vec::with_capacity(method_arguments.len());
+        for method_argument in &method_arguments {
+            argument_indexes.push(editor.push(argument_type(method_argument)?)?);
         }
 
-        let mut new_locals_count: usize = 3;
-        let mut ex_type_ref_compressed = compress_token(self.ex_type_ref).unwrap();
+        let local_sig = self.push_locals(
+            editor,
+            &parsed_method.return_type(),
+            &method_arguments,
+            None,
+            wrapper_count,
+            modify_arguments,
+            module_metadata,
+        )?;
+
+        Ok(CallSiteLocals {
+            instance_index,
+            argument_indexes,
+            local_sig,
+        })
+    }
+
+    /// Appends the locals of a call target, leaving the new signature nil
+    #[allow(clippy::too_many_arguments)]
+    fn push_locals(
+        &mut self,
+        editor: &mut LocalSigEditor,
+        method_return_value: &FunctionMethodArgument,
+        method_arguments: &[FunctionMethodArgument],
+        instance_type: Option<&TypeSig>,
+        wrapper_count: usize,
+        modify_arguments: bool,
+        module_metadata: &ModuleMetadata,
+    ) -> Result<LocalSig, HRESULT> {
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
+        let call_target_state = TypeSig::ValueType(base_tokens.call_target_state_type_ref.token());
+        let ex_type_ref = base_tokens.cor_lib.ex_type_ref;
         let (_, ret_type_flags) = method_return_value.get_type_flags();
+        let returns_value = ret_type_flags != MethodArgumentTypeFlag::VOID;
 
         let call_target_return;
-        let mut call_target_return_signature = vec![];
-        let mut call_target_return_compressed = vec![];
-        let mut ret_sig = vec![];
-
-        if ret_type_flags != MethodArgumentTypeFlag::VOID {
-            ret_sig = method_return_value.signature().to_vec();
+        let return_value_index;
+        let exception_index;
+        let call_target_return_index;
+        if returns_value {
+            return_value_index = Some(editor.push(argument_type(method_return_value)?)?);
             call_target_return =
                 self.get_target_return_value_type_ref(method_return_value, module_metadata)?;
-            call_target_return_signature = {
-                let type_spec = module_metadata.import.get_type_spec_from_token(
-                    call_target_return
-                ).map_err(|e| {
-                    log::warn!("Could not get type spec from token, call_target_return={}, signature={:?}", call_target_return, &ret_sig);
+            exception_index = editor.push(TypeSig::Class(ex_type_ref.token()))?;
+            let type_spec = module_metadata
+                .import
+                .get_type_spec_from_token(call_target_return.token())
+                .map_err(|e| {
+                    log::warn!(
+                        "Could not get type spec from token, call_target_return={}, signature={:?}",
+                        call_target_return.token(),
+                        method_return_value.signature()
+                    );
                     e
                 })?;
-                type_spec.signature
-            };
-
-            new_locals_count += 1;
+            call_target_return_index =
+                editor.push(TypeSig::from_bytes(&type_spec.signature).map_err(|e| {
+                    log::warn!(
+                        "Could not parse type spec signature {:?}, {:?}",
+                        &type_spec.signature,
+                        e
+                    );
+                    E_FAIL
+                })?)?;
         } else {
+            return_value_index = None;
             call_target_return = self.get_target_void_return_type_ref(module_metadata)?;
-            call_target_return_compressed = compress_token(call_target_return).unwrap();
-        }
-
-        let mut new_locals_buffer;
-        let mut old_locals_len = 0;
-
-        if original_signature_size == 0 {
-            new_locals_buffer = compress_data(new_locals_count as ULONG).unwrap();
-        } else {
-            let (data, len) = uncompress_data(&original_sig[1..]).unwrap();
-            old_locals_len += len;
-            new_locals_count += data as usize;
-            new_locals_buffer = compress_data(new_locals_count as ULONG).unwrap();
-        }
-
-        let mut new_signature = vec![CorCallingConvention::IMAGE_CEE_CS_CALLCONV_LOCAL_SIG.bits()];
-        new_signature.append(&mut new_locals_buffer);
-
-        if original_signature_size > 0 {
-            new_signature.extend_from_slice(&original_sig[(1 + old_locals_len)..]);
-        }
-
-        if !ret_sig.is_empty() {
-            new_signature.extend_from_slice(&ret_sig);
+            exception_index = editor.push(TypeSig::Class(ex_type_ref.token()))?;
+            call_target_return_index =
+                editor.push(TypeSig::ValueType(call_target_return.token()))?;
         }
-
-        new_signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        new_signature.append(&mut ex_type_ref_compressed);
-
-        if !call_target_return_signature.is_empty() {
-            new_signature.append(&mut call_target_return_signature);
+        // by-ref arguments passed in an object array are copied back from the array,
+        // as are all arguments when a wrapper modifies arguments
+        let arguments_index = if modify_arguments || method_arguments.iter().any(is_by_ref) {
+            Some(editor.push(TypeSig::SzArray(Box::new(TypeSig::Object)))?)
         } else {
-            new_signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-            new_signature.append(&mut call_target_return_compressed);
-        }
-
-        new_signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        new_signature.append(&mut call_target_state_type_ref_compressed);
-
-        let new_local_var_sig = module_metadata
-            .emit
-            .get_token_from_sig(&new_signature)
-            .map_err(|e| {
-                log::warn!(
-                    "Error creating new local vars signature {:?}",
-                    &new_signature
-                );
-                e
-            })?;
+            None
+        };
+        let instance_index = match instance_type {
+            Some(instance_type) => Some(editor.push(instance_type.clone())?),
+            None => None,
+        };
+        // a state for each wrapper, the last of which marks the signature as modified
+        let call_target_state_indexes = (0..wrapper_count)
+            .map(|_| editor.push(call_target_state.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok(LocalSig {
-            new_local_var_sig,
-            call_target_state_token: self.call_target_state_type_ref,
-            exception_token: self.ex_type_ref,
+            new_local_var_sig: mdTokenNil,
+            call_target_state_token: base_tokens.call_target_state_type_ref,
+            exception_token: ex_type_ref,
             call_target_return_token: call_target_return,
-            return_value_index: if !ret_sig.is_empty() {
-                new_locals_count - 4
-            } else {
-                usize::MAX
-            },
-            exception_index: new_locals_count - 3,
-            call_target_return_index: new_locals_count - 2,
-            call_target_state_index: new_locals_count - 1,
+            return_value_index,
+            exception_index,
+            call_target_return_index,
+            arguments_index,
+            instance_index,
+            call_target_state_indexes,
         })
     }
 
     pub fn write_begin_method_with_arguments_array(
         &mut self,
-        integration_type_ref: mdTypeRef,
+        integration_type_ref: TypeToken,
         current_type: &TypeInfo,
         module_metadata: &ModuleMetadata,
     ) -> Result<Instruction, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
-
-        if self.begin_array_member_ref == mdMemberRefNil {
-            let call_target_state_compressed =
-                compress_token(self.call_target_state_type_ref).unwrap();
-
-            let mut signature = Vec::with_capacity(8 + call_target_state_compressed.len());
-            signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC.bits());
-            signature.push(2);
-            signature.push(2);
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-            signature.extend_from_slice(&call_target_state_compressed);
-            signature.push(CorElementType::ELEMENT_TYPE_MVAR as COR_SIGNATURE);
-            signature.push(1);
-            signature.push(CorElementType::ELEMENT_TYPE_SZARRAY as COR_SIGNATURE);
-            signature.push(CorElementType::ELEMENT_TYPE_OBJECT as COR_SIGNATURE);
-
-            self.begin_array_member_ref = module_metadata
-                .emit
-                .define_member_ref(
-                    self.call_target_type_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME,
-                    &signature,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define member ref {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME
-                    );
-                    e
-                })?;
-        }
-
-        let integration_type_compressed = compress_token(integration_type_ref).unwrap();
-        let (current_type_ref, is_value_type) = self.get_current_type_ref(current_type);
-        let current_type_compressed = compress_token(current_type_ref).unwrap();
-
-        let mut signature = Vec::with_capacity(
-            4 + integration_type_compressed.len() + current_type_compressed.len(),
-        );
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST.bits());
-        signature.push(2);
-        signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        signature.extend_from_slice(&integration_type_compressed);
-
-        if is_value_type {
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        } else {
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        }
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
+
+        let begin_array_member_ref = match self.begin_array_member_ref {
+            Some(member_ref) => member_ref,
+            None => {
+                let signature = signature_bytes(
+                    MethodSig::generic(
+                        CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                        2,
+                        TypeSig::ValueType(base_tokens.call_target_state_type_ref.token()),
+                        vec![
+                            TypeSig::MVar(1),
+                            TypeSig::SzArray(Box::new(TypeSig::Object)),
+                        ],
+                    )
+                    .to_bytes(),
+                )?;
+
+                let member_ref = module_metadata
+                    .emit
+                    .define_member_ref(
+                        base_tokens.call_target_type_ref.token(),
+                        managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME,
+                        &signature,
+                    )
+                    .map_err(|e| {
+                        log::warn!(
+                            "Could not define member ref {}",
+                            managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME
+                        );
+                        e
+                    })?;
+
+                let member_ref = operand_token(member_ref)?;
+                self.begin_array_member_ref = Some(member_ref);
+                member_ref
+            }
+        };
 
-        signature.extend_from_slice(&current_type_compressed);
+        let signature = signature_bytes(
+            Self::integration_method_spec(&base_tokens, integration_type_ref, current_type, vec![])
+                .to_bytes(),
+        )?;
 
         let begin_array_method_spec = module_metadata
             .emit
-            .define_method_spec(self.begin_array_member_ref, &signature)
+            .define_method_spec(begin_array_member_ref.token(), &signature)
             .map_err(|e| {
                 log::warn!(
                     "Could not define method spec for {}",
@@ -715,32 +811,55 @@ impl CallTargetTokens {
                 e
             })?;
 
-        Ok(Instruction::call(begin_array_method_spec))
+        Ok(Instruction::call(operand_token(begin_array_method_spec)?))
     }
 
     pub fn modify_local_sig_and_initialize(
         &mut self,
         method: &Method,
         function_info: &FunctionInfo,
+        instance_type: Option<&TypeSig>,
+        wrapper_count: usize,
+        modify_arguments: bool,
         module_metadata: &ModuleMetadata,
     ) -> Result<(LocalSig, Vec<Instruction>), HRESULT> {
         // TODO: cache the parsed method in method_signature...
         let parsed_method = function_info.method_signature.try_parse().unwrap();
         let return_function_method = parsed_method.return_type();
+        let method_arguments = parsed_method.arguments();
+
+        let local_sig = self.create_local_sig(
+            method,
+            &return_function_method,
+            &method_arguments,
+            instance_type,
+            wrapper_count,
+            modify_arguments,
+            module_metadata,
+        )?;
+        let instructions =
+            self.initialize_locals(&local_sig, &return_function_method, module_metadata)?;
+        Ok((local_sig, instructions))
+    }
 
-        let local_sig = self.create_local_sig(method, &return_function_method, module_metadata)?;
+    /// Writes the instructions that initialize the return value, call target return
+    /// and exception locals
+    pub fn initialize_locals(
+        &mut self,
+        local_sig: &LocalSig,
+        return_function_method: &FunctionMethodArgument,
+        module_metadata: &ModuleMetadata,
+    ) -> Result<Vec<Instruction>, HRESULT> {
         let mut instructions = Vec::with_capacity(6);
 
-        if local_sig.return_value_index != usize::MAX {
+        if let Some(return_value_index) = local_sig.return_value_index {
             let call_target_default_value = self.get_call_target_default_value_method_spec(
-                &return_function_method,
+                return_function_method,
                 module_metadata,
             )?;
 
             instructions.push(Instruction::call(call_target_default_value));
-            instructions.push(Instruction::store_local(
-                local_sig.return_value_index as u16,
-            ));
+            instructions.push(Instruction::store_local(return_value_index));
 
             let call_target_return_value = self.get_call_target_return_value_default_member_ref(
                 local_sig.call_target_return_token,
@@ -748,34 +867,31 @@ impl CallTargetTokens {
             )?;
 
             instructions.push(Instruction::call(call_target_return_value));
-            instructions.push(Instruction::store_local(
-                local_sig.call_target_return_index as u16,
-            ));
+            instructions.push(Instruction::store_local(local_sig.call_target_return_index));
         } else {
             let call_target_void =
                 self.get_call_target_return_void_default_member_ref(module_metadata)?;
             instructions.push(Instruction::call(call_target_void));
-            instructions.push(Instruction::store_local(
-                local_sig.call_target_return_index as u16,
-            ));
+            instructions.push(Instruction::store_local(local_sig.call_target_return_index));
         }
 
         instructions.push(Instruction::ldnull());
-        instructions.push(Instruction::store_local(local_sig.exception_index as u16));
-        Ok((local_sig, instructions))
+        instructions.push(Instruction::store_local(local_sig.exception_index));
+        Ok(instructions)
     }
 
     pub fn write_begin_method(
         &mut self,
-        integration_type_ref: mdTypeRef,
+        integration_type_ref: TypeToken,
         current_type: &TypeInfo,
         method_arguments: &[FunctionMethodArgument],
+        modify_arguments: bool,
         module_metadata: &ModuleMetadata,
     ) -> Result<Instruction, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
 
         let len = method_arguments.len();
-        if len >= Self::FAST_PATH_COUNT {
+        if Self::uses_arguments_array(method_arguments, modify_arguments) {
             // slow path
             return self.write_begin_method_with_arguments_array(
                 integration_type_ref,
@@ -785,214 +901,164 @@ impl CallTargetTokens {
         }
 
         // fast path
-        if self.begin_method_fast_path_refs[len] == mdMemberRefNil {
-            let call_target_state = compress_token(self.call_target_state_type_ref).unwrap();
-
-            let mut signature = Vec::with_capacity(6 + (len * 2) + call_target_state.len());
-            signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC.bits());
-            signature.push(2 + len as u8);
-            signature.push(1 + len as u8);
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-            signature.extend_from_slice(&call_target_state);
-            signature.push(CorElementType::ELEMENT_TYPE_MVAR as COR_SIGNATURE);
-            signature.push(1);
-
-            for i in 0..len {
-                signature.push(CorElementType::ELEMENT_TYPE_MVAR as COR_SIGNATURE);
-                signature.push(1 + (i as u8 + 1));
+        let begin_method_ref = match self.begin_method_fast_path_refs[len] {
+            Some(member_ref) => member_ref,
+            None => {
+                // the instance, followed by each argument, as generic method parameters
+                let params = (0..=len).map(|i| TypeSig::MVar(1 + i as u32)).collect();
+                let signature = signature_bytes(
+                    MethodSig::generic(
+                        CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                        2 + len as u32,
+                        TypeSig::ValueType(base_tokens.call_target_state_type_ref.token()),
+                        params,
+                    )
+                    .to_bytes(),
+                )?;
+
+                let member_ref = module_metadata
+                    .emit
+                    .define_member_ref(
+                        base_tokens.call_target_type_ref.token(),
+                        managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME,
+                        &signature,
+                    )
+                    .map_err(|e| {
+                        log::warn!(
+                            "Could not define member ref {}",
+                            managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME
+                        );
+                        e
+                    })?;
+
+                let member_ref = operand_token(member_ref)?;
+                self.begin_method_fast_path_refs[len] = Some(member_ref);
+                member_ref
             }
-
-            self.begin_method_fast_path_refs[len] = module_metadata
-                .emit
-                .define_member_ref(
-                    self.call_target_type_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME,
-                    &signature,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define member ref {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_BEGINMETHOD_NAME
-                    );
-                    e
-                })?
-        }
-
-        let integration_type_ref_compressed = compress_token(integration_type_ref).unwrap();
-
-        let (current_type_ref, is_value_type) = self.get_current_type_ref(current_type);
-
-        let current_type_ref_compressed = compress_token(current_type_ref).unwrap();
-
-        let mut arguments_signature = Vec::with_capacity(Self::FAST_PATH_COUNT);
-        for method_argument in method_arguments {
-            let sig = method_argument.signature();
-            arguments_signature.extend_from_slice(sig);
-        }
-
-        let mut signature = Vec::with_capacity(
-            4 + integration_type_ref_compressed.len()
-                + current_type_ref_compressed.len()
-                + arguments_signature.len(),
-        );
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST.bits());
-        signature.push(2 + len as u8);
-        signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        signature.extend_from_slice(&integration_type_ref_compressed);
-
-        if is_value_type {
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        } else {
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        }
-
-        signature.extend_from_slice(&current_type_ref_compressed);
-        signature.extend_from_slice(&arguments_signature);
+        };
+
+        let arguments = method_arguments
+            .iter()
+            .map(argument_type)
+            .collect::<Result<Vec<_>, _>>()?;
+        let signature = signature_bytes(
+            Self::integration_method_spec(
+                &base_tokens,
+                integration_type_ref,
+                current_type,
+                arguments,
+            )
+            .to_bytes(),
+        )?;
 
         let begin_method_spec = module_metadata
             .emit
-            .define_method_spec(self.begin_method_fast_path_refs[len], &signature)
+            .define_method_spec(begin_method_ref.token(), &signature)
             .map_err(|e| {
                 log::warn!("Could not define member spec for fast path args {}", len);
                 e
             })?;
 
-        Ok(Instruction::call(begin_method_spec))
+        Ok(Instruction::call(operand_token(begin_method_spec)?))
     }
 
     pub fn write_end_void_return_member_ref(
         &mut self,
-        integration_type_ref: mdTypeRef,
+        integration_type_ref: TypeToken,
         current_type: &TypeInfo,
         module_metadata: &ModuleMetadata,
     ) -> Result<Instruction, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
-
-        if self.end_void_member_ref == mdMemberRefNil {
-            let mut call_target_return_void_compressed =
-                compress_token(self.call_target_return_void_type_ref).unwrap();
-
-            let mut ex_type_ref_compressed = compress_token(self.ex_type_ref).unwrap();
-
-            let mut call_target_state = compress_token(self.call_target_state_type_ref).unwrap();
-
-            let mut signature = Vec::with_capacity(
-                8 + call_target_return_void_compressed.len()
-                    + ex_type_ref_compressed.len()
-                    + call_target_state.len(),
-            );
-
-            signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC.bits());
-            signature.push(2);
-            signature.push(3);
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as u8);
-            signature.append(&mut call_target_return_void_compressed);
-
-            signature.push(CorElementType::ELEMENT_TYPE_MVAR as u8);
-            signature.push(1);
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as u8);
-            signature.append(&mut ex_type_ref_compressed);
-
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as u8);
-            signature.append(&mut call_target_state);
-
-            self.end_void_member_ref = module_metadata
-                .emit
-                .define_member_ref(
-                    self.call_target_type_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_ENDMETHOD_NAME,
-                    &signature,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define member ref {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_ENDMETHOD_NAME
-                    );
-                    e
-                })?;
-        }
-
-        let mut integration_type_ref_compressed = compress_token(integration_type_ref).unwrap();
-
-        let (current_type_ref, is_value_type) = self.get_current_type_ref(current_type);
-
-        let mut current_type_ref_compressed = compress_token(current_type_ref).unwrap();
-
-        let mut signature = Vec::with_capacity(
-            4 + integration_type_ref_compressed.len() + current_type_ref_compressed.len(),
-        );
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST.bits());
-        signature.push(2);
-        signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        signature.append(&mut integration_type_ref_compressed);
-
-        if is_value_type {
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        } else {
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        }
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
+        let call_target_return_void_type_ref =
+            self.get_target_void_return_type_ref(module_metadata)?;
+
+        let end_void_member_ref = match self.end_void_member_ref {
+            Some(member_ref) => member_ref,
+            None => {
+                let signature = signature_bytes(
+                    MethodSig::generic(
+                        CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                        2,
+                        TypeSig::ValueType(call_target_return_void_type_ref.token()),
+                        vec![
+                            TypeSig::MVar(1),
+                            TypeSig::Class(base_tokens.cor_lib.ex_type_ref.token()),
+                            TypeSig::ValueType(base_tokens.call_target_state_type_ref.token()),
+                        ],
+                    )
+                    .to_bytes(),
+                )?;
+
+                let member_ref = module_metadata
+                    .emit
+                    .define_member_ref(
+                        base_tokens.call_target_type_ref.token(),
+                        managed::MANAGED_PROFILER_CALLTARGET_ENDMETHOD_NAME,
+                        &signature,
+                    )
+                    .map_err(|e| {
+                        log::warn!(
+                            "Could not define member ref {}",
+                            managed::MANAGED_PROFILER_CALLTARGET_ENDMETHOD_NAME
+                        );
+                        e
+                    })?;
+
+                let member_ref = operand_token(member_ref)?;
+                self.end_void_member_ref = Some(member_ref);
+                member_ref
+            }
+        };
 
-        signature.append(&mut current_type_ref_compressed);
+        let signature = signature_bytes(
+            Self::integration_method_spec(&base_tokens, integration_type_ref, current_type, vec![])
+                .to_bytes(),
+        )?;
 
         let end_void_method_spec = module_metadata
             .emit
-            .define_method_spec(self.end_void_member_ref, &signature)
+            .define_method_spec(end_void_member_ref.token(), &signature)
             .map_err(|e| {
                 log::warn!("Could not define member spec for end void method");
                 e
             })?;
 
-        Ok(Instruction::call(end_void_method_spec))
+        Ok(Instruction::call(operand_token(end_void_method_spec)?))
     }
 
     pub fn write_end_return_member_ref(
         &mut self,
-        integration_type_ref: mdTypeRef,
+        integration_type_ref: TypeToken,
         current_type: &TypeInfo,
         return_argument: &FunctionMethodArgument,
         module_metadata: &ModuleMetadata,
     ) -> Result<Instruction, HRESULT> {
-        let return_type_spec =
-            self.get_target_return_value_type_ref(return_argument, module_metadata)?;
-
-        let mut call_target_return_type_compressed =
-            compress_token(self.call_target_return_type_ref).unwrap();
-
-        let mut ex_type_ref_compressed = compress_token(self.ex_type_ref).unwrap();
-
-        let mut call_target_state = compress_token(self.call_target_state_type_ref).unwrap();
-
-        let mut signature = Vec::with_capacity(
-            14 + call_target_return_type_compressed.len()
-                + ex_type_ref_compressed.len()
-                + call_target_state.len(),
-        );
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC.bits());
-        signature.push(3);
-        signature.push(4);
-        signature.push(CorElementType::ELEMENT_TYPE_GENERICINST as COR_SIGNATURE);
-        signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        signature.append(&mut call_target_return_type_compressed);
-        signature.push(1);
-        signature.push(CorElementType::ELEMENT_TYPE_MVAR as COR_SIGNATURE);
-        signature.push(2);
-
-        signature.push(CorElementType::ELEMENT_TYPE_MVAR as COR_SIGNATURE);
-        signature.push(1);
-
-        signature.push(CorElementType::ELEMENT_TYPE_MVAR as COR_SIGNATURE);
-        signature.push(2);
-
-        signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        signature.append(&mut ex_type_ref_compressed);
-
-        signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        signature.append(&mut call_target_state);
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
+        let call_target_return_type_ref = self.get_target_return_type_ref(module_metadata)?;
+
+        let signature = signature_bytes(
+            MethodSig::generic(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                3,
+                TypeSig::generic_inst(
+                    true,
+                    call_target_return_type_ref.token(),
+                    vec![TypeSig::MVar(2)],
+                ),
+                vec![
+                    TypeSig::MVar(1),
+                    TypeSig::MVar(2),
+                    TypeSig::Class(base_tokens.cor_lib.ex_type_ref.token()),
+                    TypeSig::ValueType(base_tokens.call_target_state_type_ref.token()),
+                ],
+            )
+            .to_bytes(),
+        )?;
 
         let end_method_member_ref = module_metadata
             .emit
             .define_member_ref(
-                self.call_target_type_ref,
+                base_tokens.call_target_type_ref.token(),
                 managed::MANAGED_PROFILER_CALLTARGET_ENDMETHOD_NAME,
                 &signature,
             )
@@ -1004,33 +1070,15 @@ impl CallTargetTokens {
                 e
             })?;
 
-        let mut integration_type_ref_compressed = compress_token(integration_type_ref).unwrap();
-
-        let (current_type_ref, is_value_type) = self.get_current_type_ref(current_type);
-
-        let mut current_type_ref_compressed = compress_token(current_type_ref).unwrap();
-
-        let ret_type_sig = return_argument.signature();
-
-        let mut signature = Vec::with_capacity(
-            4 + integration_type_ref_compressed.len()
-                + current_type_ref_compressed.len()
-                + ret_type_sig.len(),
-        );
-
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST.bits());
-        signature.push(3);
-        signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        signature.append(&mut integration_type_ref_compressed);
-
-        if is_value_type {
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        } else {
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        }
-
-        signature.append(&mut current_type_ref_compressed);
-        signature.extend_from_slice(ret_type_sig);
+        let signature = signature_bytes(
+            Self::integration_method_spec(
+                &base_tokens,
+                integration_type_ref,
+                current_type,
+                vec![argument_type(return_argument)?],
+            )
+            .to_bytes(),
+        )?;
 
         let end_method_spec = module_metadata
             .emit
@@ -1040,96 +1088,88 @@ impl CallTargetTokens {
                 e
             })?;
 
-        Ok(Instruction::call(end_method_spec))
+        Ok(Instruction::call(operand_token(end_method_spec)?))
     }
 
     pub fn write_log_exception(
         &mut self,
-        integration_type_ref: mdTypeRef,
+        integration_type_ref: TypeToken,
         current_type: &TypeInfo,
         module_metadata: &ModuleMetadata,
     ) -> Result<Instruction, HRESULT> {
-        self.ensure_base_calltarget_tokens(module_metadata)?;
-
-        if self.log_exception_ref == mdMemberRefNil {
-            let mut ex_type_ref = compress_token(self.ex_type_ref).unwrap();
-            let mut signature = Vec::with_capacity(5 + ex_type_ref.len());
-            signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC.bits());
-            signature.push(2);
-            signature.push(1);
-            signature.push(CorElementType::ELEMENT_TYPE_VOID as COR_SIGNATURE);
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-            signature.append(&mut ex_type_ref);
-
-            self.log_exception_ref = module_metadata
-                .emit
-                .define_member_ref(
-                    self.call_target_type_ref,
-                    managed::MANAGED_PROFILER_CALLTARGET_LOGEXCEPTION_NAME,
-                    &signature,
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "Could not define member ref {}",
-                        managed::MANAGED_PROFILER_CALLTARGET_LOGEXCEPTION_NAME
-                    );
-                    e
-                })?;
-        }
-
-        let mut integration_type_ref_compressed = compress_token(integration_type_ref).unwrap();
-
-        let (current_type_ref, is_value_type) = self.get_current_type_ref(current_type);
-
-        let mut current_type_ref_compressed = compress_token(current_type_ref).unwrap();
-        let mut signature = Vec::with_capacity(
-            4 + integration_type_ref_compressed.len() + current_type_ref_compressed.len(),
-        );
-
-        signature.push(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERICINST.bits());
-        signature.push(2);
-        signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        signature.append(&mut integration_type_ref_compressed);
-
-        if is_value_type {
-            signature.push(CorElementType::ELEMENT_TYPE_VALUETYPE as COR_SIGNATURE);
-        } else {
-            signature.push(CorElementType::ELEMENT_TYPE_CLASS as COR_SIGNATURE);
-        }
+        let base_tokens = self.ensure_base_calltarget_tokens(module_metadata)?;
+
+        let log_exception_ref = match self.log_exception_ref {
+            Some(member_ref) => member_ref,
+            None => {
+                let signature = signature_bytes(
+                    MethodSig::generic(
+                        CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                        2,
+                        TypeSig::Void,
+                        vec![TypeSig::Class(base_tokens.cor_lib.ex_type_ref.token())],
+                    )
+                    .to_bytes(),
+                )?;
+
+                let member_ref = module_metadata
+                    .emit
+                    .define_member_ref(
+                        base_tokens.call_target_type_ref.token(),
+                        managed::MANAGED_PROFILER_CALLTARGET_LOGEXCEPTION_NAME,
+                        &signature,
+                    )
+                    .map_err(|e| {
+                        log::warn!(
+                            "Could not define member ref {}",
+                            managed::MANAGED_PROFILER_CALLTARGET_LOGEXCEPTION_NAME
+                        );
+                        e
+                    })?;
+
+                let member_ref = operand_token(member_ref)?;
+                self.log_exception_ref = Some(member_ref);
+                member_ref
+            }
+        };
 
-        signature.append(&mut current_type_ref_compressed);
+        let signature = signature_bytes(
+            Self::integration_method_spec(&base_tokens, integration_type_ref, current_type, vec![])
+                .to_bytes(),
+        )?;
 
         let log_exception_method_spec = module_metadata
             .emit
-            .define_method_spec(self.log_exception_ref, &signature)
+            .define_method_spec(log_exception_ref.token(), &signature)
             .map_err(|e| {
                 log::warn!("Could not define member spec for log exception method");
                 e
             })?;
 
-        Ok(Instruction::call(log_exception_method_spec))
+        Ok(Instruction::call(operand_token(log_exception_method_spec)?))
     }
 
     pub fn write_call_target_return_get_return_value(
         &mut self,
-        call_target_return_type_spec: mdTypeSpec,
+        call_target_return_type_spec: TypeToken,
         module_metadata: &ModuleMetadata,
     ) -> Result<Instruction, HRESULT> {
         self.ensure_base_calltarget_tokens(module_metadata)?;
 
-        let signature = vec![
-            (CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT
-                | CorCallingConvention::IMAGE_CEE_CS_CALLCONV_HASTHIS)
-                .bits(),
-            0,
-            CorElementType::ELEMENT_TYPE_VAR as COR_SIGNATURE,
-            0,
-        ];
+        let signature = signature_bytes(
+            MethodSig::new(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT
+                    | CorCallingConvention::IMAGE_CEE_CS_CALLCONV_HASTHIS,
+                TypeSig::Var(0),
+                vec![],
+            )
+            .to_bytes(),
+        )?;
 
         let call_target_return_get_value_member_ref = module_metadata
             .emit
             .define_member_ref(
-                call_target_return_type_spec,
+                call_target_return_type_spec.token(),
                 managed::MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GETRETURNVALUE_NAME,
                 &signature,
             )
@@ -1141,7 +1181,9 @@ impl CallTargetTokens {
                 e
             })?;
 
-        Ok(Instruction::call(call_target_return_get_value_member_ref))
+        Ok(Instruction::call(operand_token(
+            call_target_return_get_value_member_ref,
+        )?))
     }
 }
 
@@ -1149,11 +1191,56 @@ impl CallTargetTokens {
 #[derive(Debug)]
 pub struct LocalSig {
     pub new_local_var_sig: mdToken,
-    pub call_target_state_token: mdTypeRef,
-    pub exception_token: mdTypeRef,
-    pub call_target_return_token: mdToken,
-    pub return_value_index: usize,
-    pub exception_index: usize,
-    pub call_target_return_index: usize,
-    pub call_target_state_index: usize,
+    pub call_target_state_token: TypeToken,
+    pub exception_token: TypeToken,
+    pub call_target_return_token: TypeToken,
+    /// The index of the local holding the return value, when the method returns a value
+    pub return_value_index: Option<u16>,
+    pub exception_index: u16,
+    pub call_target_return_index: u16,
+    /// The index of the local holding the arguments array, when by-ref arguments
+    /// are passed to BeginMethod in an object array
+    pub arguments_index: Option<u16>,
+    /// The index of the local holding the default value passed as the instance
+    /// of a static method on a value type
+    pub instance_index: Option<u16>,
+    /// The index of the local holding the state of each wrapper, in the order of the wrappers
+    pub call_target_state_indexes: Vec<u16>,
+}
+
+/// The local variables of a call site instrumented in a caller
+#[derive(Debug)]
+pub struct CallSiteLocals {
+    /// The index of the local holding the instance of the call, when the target
+    /// is an instance method
+    pub instance_index: Option<u16>,
+    /// The index of the local holding each argument of the call
+    pub argument_indexes: Vec<u16>,
+    pub local_sig: LocalSig,
+}
+
+/// Gets whether a method argument is passed by reference, as a ref or out parameter
+pub(crate) fn is_by_ref(argument: &FunctionMethodArgument) -> bool {
+    let (_, flags) = argument.get_type_flags();
+    flags.contains(MethodArgumentTypeFlag::BY_REF)
+}
+
+/// Gets the type of a method argument or return type
+fn argument_type(argument: &FunctionMethodArgument) -> Result<TypeSig, HRESULT> {
+    TypeSig::from_bytes(argument.signature()).map_err(|e| {
+        log::warn!(
+            "Could not parse type signature {:?}, {:?}",
+            argument.signature(),
+            e
+        );
+        E_FAIL
+    })
+}
+
+/// Gets the bytes of an encoded signature
+fn signature_bytes(signature: Result<Vec<u8>, Error>) -> Result<Vec<u8>, HRESULT> {
+    signature.map_err(|e| {
+        log::warn!("Could not encode signature, {:?}", e);
+        E_FAIL
+    })
 }
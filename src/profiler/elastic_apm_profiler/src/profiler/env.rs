@@ -22,7 +22,11 @@ use log4rs::{
 };
 use once_cell::sync::Lazy;
 use std::time::SystemTime;
-use std::{collections::HashSet, fs::File, io::BufReader, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 const APP_POOL_ID_ENV_VAR: &str = "APP_POOL_ID";
 const DOTNET_CLI_TELEMETRY_PROFILE_ENV_VAR: &str = "DOTNET_CLI_TELEMETRY_PROFILE";
@@ -37,6 +41,7 @@ const ELASTIC_APM_PROFILER_EXCLUDE_SERVICE_NAMES_ENV_VAR: &str = "ELASTIC_APM_PR
 const ELASTIC_APM_PROFILER_HOME_ENV_VAR: &str = "ELASTIC_APM_PROFILER_HOME";
 const ELASTIC_APM_PROFILER_INTEGRATIONS_ENV_VAR: &str = "ELASTIC_APM_PROFILER_INTEGRATIONS";
 const ELASTIC_APM_PROFILER_LOG_IL_ENV_VAR: &str = "ELASTIC_APM_PROFILER_LOG_IL";
+const ELASTIC_APM_PROFILER_OPTIMIZE_IL_ENV_VAR: &str = "ELASTIC_APM_PROFILER_OPTIMIZE_IL";
 
 const ELASTIC_APM_PROFILER_LOG_TARGETS_ENV_VAR: &str = "ELASTIC_APM_PROFILER_LOG_TARGETS";
 const ELASTIC_OTEL_LOG_TARGETS_ENV_VAR: &str = "ELASTIC_OTEL_LOG_TARGETS";
@@ -71,6 +76,10 @@ const DEFAULT_EXCLUDED_SERVICE_NAMES: &[&str] = &[
 pub static ELASTIC_APM_PROFILER_LOG_IL: Lazy<bool> =
     Lazy::new(|| read_bool_env_var(ELASTIC_APM_PROFILER_LOG_IL_ENV_VAR, false));
 
+/// Whether to rewrite modified IL to the shortest encodings before it is handed to the runtime
+pub static ELASTIC_APM_PROFILER_OPTIMIZE_IL: Lazy<bool> =
+    Lazy::new(|| read_bool_env_var(ELASTIC_APM_PROFILER_OPTIMIZE_IL_ENV_VAR, false));
+
 pub static ELASTIC_APM_PROFILER_CALLTARGET_ENABLED: Lazy<bool> =
     Lazy::new(|| read_bool_env_var(ELASTIC_APM_PROFILER_CALLTARGET_ENABLED_ENV_VAR, true));
 
@@ -442,7 +451,7 @@ pub fn initialize_logging(process_name: &str) -> Option<Handle> {
     };
 }
 
-/// Loads the integrations by reading the yml file pointed to
+/// Loads the integrations by reading the yml or json file pointed to
 /// by [ELASTIC_APM_PROFILER_INTEGRATIONS] environment variable, filtering
 /// integrations by [ELASTIC_APM_PROFILER_EXCLUDE_INTEGRATIONS_ENV_VAR] environment variable,
 /// if present
@@ -475,7 +484,7 @@ pub fn load_integrations() -> Result<Vec<Integration>, HRESULT> {
         }
     };
 
-    let file = File::open(&path).map_err(|e| {
+    let content = std::fs::read_to_string(&path).map_err(|e| {
         log::warn!(
             "problem reading integrations file {}: {}. profiler is disabled.",
             &path,
@@ -484,12 +493,11 @@ pub fn load_integrations() -> Result<Vec<Integration>, HRESULT> {
         E_FAIL
     })?;
 
-    let reader = BufReader::new(file);
-    let mut integrations: Vec<Integration> = serde_yml::from_reader(reader).map_err(|e| {
+    let mut integrations = parse_integrations(&path, &content).map_err(|e| {
         log::warn!(
             "problem reading integrations file {}: {}. profiler is disabled.",
             &path,
-            e.to_string()
+            e
         );
         E_FAIL
     })?;
@@ -511,3 +519,76 @@ pub fn load_integrations() -> Result<Vec<Integration>, HRESULT> {
 
     Ok(integrations)
 }
+
+/// Parses the content of an integrations file as json or yml, ignoring a leading byte order mark
+fn parse_integrations(path: &str, content: &str) -> Result<Vec<Integration>, String> {
+    let content = content.trim_start_matches('\u{feff}');
+    if is_json(path, content) {
+        serde_json::from_str(content).map_err(|e| e.to_string())
+    } else {
+        serde_yml::from_str(content).map_err(|e| e.to_string())
+    }
+}
+
+/// Gets whether an integrations file is json, either by its extension or, when the
+/// extension is neither json nor yml, by its content starting with an array
+fn is_json(path: &str, content: &str) -> bool {
+    match Path::new(path).extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("json") => true,
+        Some(extension)
+            if extension.eq_ignore_ascii_case("yml") || extension.eq_ignore_ascii_case("yaml") =>
+        {
+            false
+        }
+        _ => content.trim_start().starts_with('['),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON_INTEGRATIONS: &str = r#"[{"name": "Test", "method_replacements": []}]"#;
+
+    #[test]
+    fn json_extension_is_json() {
+        assert!(is_json("integrations.json", JSON_INTEGRATIONS));
+        assert!(is_json("INTEGRATIONS.JSON", "name: Test"));
+        assert_eq!(
+            parse_integrations("integrations.json", JSON_INTEGRATIONS).unwrap()[0].name,
+            "Test"
+        );
+    }
+
+    #[test]
+    fn yml_extension_with_json_content_is_yml() {
+        assert!(!is_json("integrations.yml", JSON_INTEGRATIONS));
+        assert_eq!(
+            parse_integrations("integrations.yml", JSON_INTEGRATIONS).unwrap()[0].name,
+            "Test"
+        );
+    }
+
+    #[test]
+    fn byte_order_mark_is_ignored() {
+        let content = format!("\u{feff}{}", JSON_INTEGRATIONS);
+        assert_eq!(
+            parse_integrations("integrations", &content).unwrap()[0].name,
+            "Test"
+        );
+        assert_eq!(
+            parse_integrations("integrations.json", &content).unwrap()[0].name,
+            "Test"
+        );
+    }
+
+    #[test]
+    fn yml_document_start_is_yml() {
+        let content = "---\n- name: Test\n  method_replacements: []\n";
+        assert!(!is_json("integrations", content));
+        assert_eq!(
+            parse_integrations("integrations", content).unwrap()[0].name,
+            "Test"
+        );
+    }
+}
@@ -5,28 +5,26 @@
 
 use crate::{
     cil::{
-        uncompress_data, uncompress_token, CorExceptionFlag, Method,
-        Operand::{
-            InlineBrTarget, InlineField, InlineI, InlineI8, InlineMethod, InlineString, InlineType,
-            ShortInlineBrTarget, ShortInlineI, ShortInlineVar,
-        },
-        Section, BOX, CALL, CALLVIRT, CASTCLASS, INITOBJ, LDSTR, NEWARR, NEWOBJ, UNBOX_ANY,
+        uncompress_data, uncompress_token, Disassembler, MemberName, Method, TokenResolver, TypeSig,
     },
+    error::Error,
     ffi::{
-        mdAssemblyRef, mdToken, mdTokenNil, mdTypeDef, mdTypeDefNil, type_from_token,
-        CorElementType, CorTokenType, ASSEMBLYMETADATA,
+        is_nil_token, mdAssemblyRef, mdMethodDef, mdToken, mdTypeDef, mdTypeDefNil, mdTypeSpecNil,
+        type_from_token, CorElementType, CorTokenType, ASSEMBLYMETADATA, E_FAIL,
     },
     interfaces::{IMetaDataAssemblyEmit, IMetaDataEmit2, IMetaDataImport2},
     profiler::{
-        sig::parse_type,
+        managed::IGNORE,
+        sig::{parse_signature_types, parse_type, signature_type_matches},
         types::{
-            AssemblyMetaData, FunctionInfo, Integration, IntegrationMethod, MethodSignature,
-            ModuleMetadata, WrapperMethodAction,
+            AssemblyMetaData, CallerMethodReference, FunctionInfo, Integration, IntegrationMethod,
+            MethodSignature, ModuleMetadata, TargetMethodReference, TypeInfo, WrapperMethodAction,
         },
     },
 };
 use com::sys::HRESULT;
 use num_traits::FromPrimitive;
+use std::convert::TryFrom;
 
 pub(crate) fn return_type_is_value_type_or_generic(
     module_metadata: &ModuleMetadata,
@@ -348,6 +346,68 @@ pub fn create_assembly_ref_to_corlib(
     )
 }
 
+/// Converts a metadata token to the typed token of an instruction operand, such as
+/// a [crate::cil::MethodToken] for a call, logging when the token is of the wrong type
+pub fn operand_token<T>(token: mdToken) -> Result<T, HRESULT>
+where
+    T: TryFrom<mdToken, Error = Error>,
+{
+    T::try_from(token).map_err(|e| {
+        log::warn!("Invalid operand token {}. {:?}", token, e);
+        E_FAIL
+    })
+}
+
+/// Gets the exact type of the instance of a method declared on a value type, as a
+/// signature and as a token for ldobj and initobj. Within the method, the instance
+/// of a generic value type is the type instantiated over its own generic parameters,
+/// !0 to !n, for which a TypeSpec is emitted.
+pub fn value_type_instance(
+    module_metadata: &ModuleMetadata,
+    type_info: &TypeInfo,
+) -> Result<(TypeSig, mdToken), HRESULT> {
+    if !is_nil_token(type_info.type_spec) {
+        let type_spec = module_metadata
+            .import
+            .get_type_spec_from_token(type_info.type_spec)?;
+        let type_sig = TypeSig::from_bytes(&type_spec.signature).map_err(|e| {
+            log::warn!(
+                "Could not parse type spec signature {:?}, {:?}",
+                &type_spec.signature,
+                e
+            );
+            E_FAIL
+        })?;
+        return Ok((type_sig, type_info.type_spec));
+    }
+
+    let generic_param_count = if type_info.token_type == CorTokenType::mdtTypeDef {
+        module_metadata
+            .import
+            .enum_generic_params(type_info.id)?
+            .len()
+    } else {
+        0
+    };
+
+    if generic_param_count == 0 {
+        return Ok((TypeSig::ValueType(type_info.id), type_info.id));
+    }
+
+    let type_sig = TypeSig::generic_inst(
+        true,
+        type_info.id,
+        (0..generic_param_count as u32).map(TypeSig::Var).collect(),
+    );
+    let signature = type_sig.to_bytes().map_err(|e| {
+        log::warn!("Could not encode type spec signature, {:?}", e);
+        E_FAIL
+    })?;
+    let type_spec = module_metadata.emit.get_token_from_type_spec(&signature)?;
+    Ok((type_sig, type_spec))
+}
+
+/// Disassembles the method for logging, prefixed with the title and caller name
 pub fn get_il_codes(
     title: &str,
     method: &Method,
@@ -359,250 +419,94 @@ pub fn get_il_codes(
     buf.push_str(caller.type_info.as_ref().map_or("", |t| t.name.as_str()));
     buf.push('.');
     buf.push_str(&caller.name);
-    buf.push_str(&format!(" => (max_stack: {})", method.header.max_stack()));
-
-    let local_sig = method.header.local_var_sig_tok();
-    if local_sig != mdTokenNil {
-        if let Ok(signature) = module_metadata.import.get_sig_from_token(local_sig) {
-            buf.push('\n');
-            buf.push_str(". Local Var Signature ");
-            buf.push_str(hex::encode(signature).as_str());
-            buf.push('\n');
-        }
-    }
-
     buf.push('\n');
+    buf.push_str(&Disassembler::new(module_metadata).disassemble(method));
+    buf
+}
 
-    let mut address = method.address;
-    let mut sum_len = 0;
-    let mut indent = 1;
-
-    for (idx, instruction) in method.instructions.iter().enumerate() {
-        for section in &method.sections {
-            match section {
-                Section::FatSection(h, s) => {
-                    for ss in s {
-                        if ss.flag == CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY {
-                            if ss.try_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".try {\n");
-                                indent += 1;
-                            }
-                            if (ss.try_offset + ss.try_length) as usize == sum_len {
-                                indent -= 1;
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str("}\n");
-                            }
-                            if ss.handler_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".finally {\n");
-                                indent += 1;
-                            }
-                        }
-                    }
-                }
-                Section::SmallSection(h, s) => {
-                    for ss in s {
-                        if ss.flag == CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY {
-                            if ss.try_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".try {\n");
-                                indent += 1;
-                            }
-                            if (ss.try_offset + ss.try_length as u16) as usize == sum_len {
-                                indent -= 1;
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str("}\n");
-                            }
-                            if ss.handler_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".finally {\n");
-                                indent += 1;
-                            }
-                        }
+impl TokenResolver for ModuleMetadata {
+    fn type_name(&self, token: mdToken) -> Option<String> {
+        let token_type = CorTokenType::from_bits(type_from_token(token))?;
+        match token_type {
+            CorTokenType::mdtTypeDef => {
+                let name = self.import.get_type_def_props(token).ok()?.name;
+                match self.import.get_nested_class_props(token) {
+                    Ok(parent) if !is_nil_token(parent) => {
+                        Some(format!("{}/{}", self.type_name(parent)?, name))
                     }
+                    _ => Some(name),
                 }
             }
-        }
-
-        for section in &method.sections {
-            match section {
-                Section::FatSection(h, s) => {
-                    for ss in s {
-                        if ss.flag == CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE {
-                            if ss.try_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".try {\n");
-                                indent += 1;
-                            }
-                            if (ss.try_offset + ss.try_length) as usize == sum_len {
-                                indent -= 1;
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str("}\n");
-                            }
-                            if ss.handler_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".catch {\n");
-                                indent += 1;
-                            }
-                        }
+            CorTokenType::mdtTypeRef => {
+                let type_ref_props = self.import.get_type_ref_props(token).ok()?;
+                let scope = type_ref_props.parent_token;
+                let scope_type = CorTokenType::from_bits(type_from_token(scope));
+                match scope_type {
+                    Some(CorTokenType::mdtAssemblyRef) if !is_nil_token(scope) => {
+                        let assembly = self
+                            .assembly_import
+                            .get_referenced_assembly_metadata(scope)
+                            .ok()?;
+                        Some(format!("[{}]{}", assembly.name, type_ref_props.name))
                     }
-                }
-                Section::SmallSection(h, s) => {
-                    for ss in s {
-                        if ss.flag == CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE {
-                            if ss.try_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".try {\n");
-                                indent += 1;
-                            }
-                            if (ss.try_offset + ss.try_length as u16) as usize == sum_len {
-                                indent -= 1;
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str("}\n");
-                            }
-                            if ss.handler_offset as usize == sum_len {
-                                if indent > 0 {
-                                    buf.push_str(&"  ".repeat(indent));
-                                }
-                                buf.push_str(".catch {\n");
-                                indent += 1;
-                            }
-                        }
+                    Some(CorTokenType::mdtTypeRef) if !is_nil_token(scope) => {
+                        Some(format!("{}/{}", self.type_name(scope)?, type_ref_props.name))
                     }
+                    Some(CorTokenType::mdtModuleRef) if !is_nil_token(scope) => {
+                        let module = self.import.get_module_ref_props(scope).ok()?;
+                        Some(format!("[.module {}]{}", module.name, type_ref_props.name))
+                    }
+                    _ => Some(type_ref_props.name),
                 }
             }
+            _ => None,
         }
+    }
 
-        if indent > 0 {
-            buf.push_str(&"  ".repeat(indent));
-        }
+    fn type_spec(&self, token: mdToken) -> Option<Vec<u8>> {
+        self.import
+            .get_type_spec_from_token(token)
+            .ok()
+            .map(|t| t.signature)
+    }
 
-        buf.push_str(&format!("{} {:>10}", address, instruction.opcode.name));
-
-        if instruction.opcode == CALL
-            || instruction.opcode == CALLVIRT
-            || instruction.opcode == NEWOBJ
-        {
-            if let InlineMethod(token) = instruction.operand {
-                buf.push_str(&format!(" {}", token));
-                if let Ok(member_info) = module_metadata.import.get_function_info(token) {
-                    buf.push_str("  | ");
-                    buf.push_str(member_info.full_name().as_str());
-                    if member_info.signature.arguments_len() > 0 {
-                        buf.push_str(&format!(
-                            "({} argument{{s}})",
-                            member_info.signature.arguments_len()
-                        ));
-                    } else {
-                        buf.push_str("()");
-                    }
-                }
-            }
-        } else if instruction.opcode == CASTCLASS
-            || instruction.opcode == BOX
-            || instruction.opcode == UNBOX_ANY
-            || instruction.opcode == NEWARR
-            || instruction.opcode == INITOBJ
-        {
-            if let InlineType(token) = instruction.operand {
-                buf.push_str(&format!(" {}", token));
-                if let Ok(type_info) = module_metadata.import.get_type_info(token) {
-                    if let Some(t) = type_info {
-                        buf.push_str("  | ");
-                        buf.push_str(&t.name);
-                    } else {
-                        buf.push_str(&format!(" {}", token));
-                    }
-                }
+    fn member(&self, token: mdToken) -> Option<MemberName> {
+        let token_type = CorTokenType::from_bits(type_from_token(token))?;
+        match token_type {
+            CorTokenType::mdtMemberRef => {
+                let props = self.import.get_member_ref_props(token).ok()?;
+                Some(MemberName {
+                    parent: props.class_token,
+                    name: props.name,
+                    signature: props.signature,
+                })
             }
-        } else if instruction.opcode == LDSTR {
-            if let InlineString(token) = instruction.operand {
-                buf.push_str(&format!(" {}", token));
-                if let Ok(str) = module_metadata.import.get_user_string(token) {
-                    buf.push_str("  | \"");
-                    buf.push_str(&str);
-                    buf.push('"');
-                }
+            CorTokenType::mdtMethodDef | CorTokenType::mdtFieldDef => {
+                let props = self.import.get_member_props(token).ok()?;
+                Some(MemberName {
+                    parent: props.class_token,
+                    name: props.name,
+                    signature: props.signature,
+                })
             }
-        } else if let InlineI8(arg) = instruction.operand {
-            buf.push_str(&format!(" {}", arg));
-        } else if let InlineBrTarget(t) = instruction.operand {
-            buf.push_str(&format!(
-                " {}",
-                address as i64 + (t as i64) + instruction.len() as i64
-            ));
-        } else if let ShortInlineBrTarget(t) = instruction.operand {
-            buf.push_str(&format!(
-                " {}",
-                address as i64 + (t as i64) + instruction.len() as i64
-            ));
-        } else if let ShortInlineVar(arg) = instruction.operand {
-            buf.push_str(&format!(" {}", arg));
-        } else if let ShortInlineI(arg) = instruction.operand {
-            buf.push_str(&format!(" {}", arg));
-        } else if let InlineI(arg) = instruction.operand {
-            buf.push_str(&format!(" {}", arg));
-        } else if let InlineField(arg) = instruction.operand {
-            buf.push_str(&format!(" {}", arg));
+            _ => None,
         }
+    }
 
-        buf.push('\n');
-        sum_len += instruction.len();
-
-        for section in &method.sections {
-            match section {
-                Section::FatSection(h, s) => {
-                    for ss in s {
-                        if (ss.handler_offset + ss.handler_length) as usize == sum_len {
-                            indent -= 1;
-                            if indent > 0 {
-                                buf.push_str(&"  ".repeat(indent));
-                            }
-                            buf.push_str("}\n");
-                        }
-                    }
-                }
-                Section::SmallSection(h, s) => {
-                    for ss in s {
-                        if (ss.handler_offset + ss.handler_length as u16) as usize == sum_len {
-                            indent -= 1;
-                            if indent > 0 {
-                                buf.push_str(&"  ".repeat(indent));
-                            }
-                            buf.push_str("}\n");
-                        }
-                    }
-                }
-            }
-        }
+    fn method_spec(&self, token: mdToken) -> Option<(mdToken, Vec<u8>)> {
+        self.import
+            .get_method_spec_props(token)
+            .ok()
+            .map(|props| (props.parent, props.signature))
+    }
 
-        address += instruction.len();
+    fn user_string(&self, token: mdToken) -> Option<String> {
+        self.import.get_user_string(token).ok()
     }
 
-    buf
+    fn signature(&self, token: mdToken) -> Option<Vec<u8>> {
+        self.import.get_sig_from_token(token).ok()
+    }
 }
 
 pub fn find_type_def_by_name(
@@ -651,6 +555,138 @@ pub fn find_type_def_by_name(
     }
 }
 
+/// Gets the methods of a caller defined in the module, which are all methods of the
+/// caller type, or of all types, when the caller does not specify a method or type
+pub fn find_caller_method_defs(
+    caller: &CallerMethodReference,
+    assembly_name: &str,
+    metadata_import: &IMetaDataImport2,
+) -> Result<Vec<mdMethodDef>, HRESULT> {
+    let type_defs = if caller.type_name.is_empty() {
+        metadata_import.enum_type_defs()?
+    } else {
+        match find_type_def_by_name(&caller.type_name, assembly_name, metadata_import) {
+            Some(type_def) => vec![type_def],
+            None => return Ok(Vec::new()),
+        }
+    };
+
+    let mut method_defs = Vec::new();
+    for type_def in type_defs {
+        if caller.method_name.is_empty() {
+            method_defs.extend(metadata_import.enum_methods(type_def)?);
+        } else {
+            method_defs
+                .extend(metadata_import.enum_methods_with_name(type_def, &caller.method_name)?);
+        }
+    }
+    Ok(method_defs)
+}
+
+/// Gets the MethodDef and MemberRef tokens in the module of the target method, which
+/// are the tokens of the calls to the target. MethodDefs are only matched in the target
+/// assembly, and MemberRefs only when their type resolves to the target assembly. See
+/// [is_call_site_target] for how a method is matched with the target
+pub fn find_target_method_tokens(
+    module_metadata: &ModuleMetadata,
+    target: &TargetMethodReference,
+) -> Result<Vec<mdToken>, HRESULT> {
+    let metadata_import = &module_metadata.import;
+    let is_target = |token: mdToken| {
+        matches!(metadata_import.get_function_info(token),
+            Ok(f) if is_call_site_target(module_metadata, target, &f))
+    };
+
+    // methods defined in the module are only targets when the module is the target assembly
+    let assembly_import = &module_metadata.assembly_import;
+    let defines_target = target.is_valid_for_assembly(
+        &module_metadata.assembly_name,
+        &assembly_import.get_assembly_metadata()?.version,
+    );
+
+    let mut tokens = Vec::new();
+    if defines_target {
+        if let Some(type_def) = find_type_def_by_name(
+            target.type_name(),
+            &module_metadata.assembly_name,
+            metadata_import,
+        ) {
+            let method_defs =
+                metadata_import.enum_methods_with_name(type_def, target.method_name())?;
+            tokens.extend(method_defs.into_iter().filter(|t| is_target(*t)));
+        }
+    }
+
+    for type_ref in metadata_import.enum_type_refs()? {
+        let mut scope = match metadata_import.get_type_ref_props(type_ref) {
+            Ok(props) if props.name == target.type_name() => props.parent_token,
+            _ => continue,
+        };
+
+        // the resolution scope of a type ref, or of its outermost enclosing type ref,
+        // is the assembly that defines the type
+        while type_from_token(scope) == CorTokenType::mdtTypeRef.bits() {
+            scope = match metadata_import.get_type_ref_props(scope) {
+                Ok(props) => props.parent_token,
+                Err(_) => break,
+            };
+        }
+        let in_target_assembly = match CorTokenType::from_bits(type_from_token(scope)) {
+            Some(CorTokenType::mdtAssemblyRef) => matches!(
+                assembly_import.get_referenced_assembly_metadata(scope),
+                Ok(m) if target.is_valid_for_assembly(&m.name, &m.version)
+            ),
+            Some(CorTokenType::mdtModule) | Some(CorTokenType::mdtModuleRef) => defines_target,
+            _ => false,
+        };
+        if !in_target_assembly {
+            continue;
+        }
+
+        let member_refs = metadata_import.enum_member_refs(type_ref)?;
+        tokens.extend(member_refs.into_iter().filter(|t| is_target(*t)));
+    }
+
+    Ok(tokens)
+}
+
+/// Gets whether a method called by a caller is the target method, by type name, method
+/// name and signature types. Methods of generic types and generic methods are not
+/// matched, as their signatures refer to generic parameters outside of the caller
+pub fn is_call_site_target(
+    module_metadata: &ModuleMetadata,
+    target: &TargetMethodReference,
+    function_info: &FunctionInfo,
+) -> bool {
+    let type_info = match &function_info.type_info {
+        Some(t) => t,
+        None => return false,
+    };
+
+    if type_info.name != target.type_name()
+        || function_info.name != target.method_name()
+        || function_info.is_generic
+        || type_info.is_generic
+        || type_info.type_spec != mdTypeSpecNil
+        || function_info.signature.type_arguments_len() > 0
+    {
+        return false;
+    }
+
+    match (
+        target.signature_types(),
+        parse_signature_types(module_metadata, function_info),
+    ) {
+        (Some(expected), Some(actual)) => {
+            expected.len() == actual.len()
+                && expected.iter().zip(&actual).all(|(expected, actual)| {
+                    expected == IGNORE || signature_type_matches(expected, actual)
+                })
+        }
+        _ => false,
+    }
+}
+
 /// Flattens integrations into relevant integration methods
 pub fn flatten_integrations(
     integrations: Vec<Integration>,
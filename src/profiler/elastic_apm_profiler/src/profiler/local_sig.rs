@@ -0,0 +1,113 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+use crate::{
+    cil::{LocalVarSig, TypeSig},
+    ffi::{is_nil_token, mdSignature, mdToken, mdTokenNil, E_FAIL},
+    profiler::types::ModuleMetadata,
+};
+use com::sys::HRESULT;
+
+/// Appends local variables to the local variable signature of a method,
+/// emitting a new signature token for the method body that uses them
+pub struct LocalSigEditor {
+    sig: LocalVarSig,
+    original_count: usize,
+}
+
+impl LocalSigEditor {
+    /// Loads the local variables of a local variable signature token. A nil token,
+    /// for a method with no locals, loads an empty signature
+    pub fn load(
+        module_metadata: &ModuleMetadata,
+        local_var_sig_tok: mdToken,
+    ) -> Result<Self, HRESULT> {
+        let sig = if is_nil_token(local_var_sig_tok) {
+            LocalVarSig::default()
+        } else {
+            let signature = module_metadata
+                .import
+                .get_sig_from_token(local_var_sig_tok)?;
+            LocalVarSig::from_bytes(&signature).map_err(|e| {
+                log::warn!(
+                    "Could not parse local vars signature {:?}, {:?}",
+                    &signature,
+                    e
+                );
+                E_FAIL
+            })?
+        };
+
+        let original_count = sig.locals.len();
+        Ok(Self {
+            sig,
+            original_count,
+        })
+    }
+
+    /// All local variables, the original locals followed by the appended locals
+    pub fn locals(&self) -> &[TypeSig] {
+        &self.sig.locals
+    }
+
+    /// The number of local variables in the original signature
+    pub fn original_count(&self) -> usize {
+        self.original_count
+    }
+
+    /// Appends a local variable, returning its index
+    pub fn push(&mut self, local: TypeSig) -> Result<u16, HRESULT> {
+        self.sig.push(local).map_err(|e| {
+            log::warn!(
+                "Could not add local variable, method has {} locals. {:?}",
+                self.sig.locals.len(),
+                e
+            );
+            E_FAIL
+        })
+    }
+
+    /// Appends a local variable of a type referenced by name from a resolution scope,
+    /// such as an assembly reference for a type defined in another assembly. The local
+    /// is an instantiation of the type when type arguments are given. Returns the
+    /// index of the local
+    pub fn push_type_ref(
+        &mut self,
+        module_metadata: &ModuleMetadata,
+        scope: mdToken,
+        name: &str,
+        value_type: bool,
+        args: Vec<TypeSig>,
+    ) -> Result<u16, HRESULT> {
+        let type_ref = module_metadata.emit.define_type_ref_by_name(scope, name)?;
+
+        let local = if args.is_empty() {
+            TypeSig::type_token(value_type, type_ref)
+        } else {
+            TypeSig::generic_inst(value_type, type_ref, args)
+        };
+        self.push(local)
+    }
+
+    /// Emits the signature of the local variables, returning its token. Returns a
+    /// nil token when there are no local variables
+    pub fn emit(&self, module_metadata: &ModuleMetadata) -> Result<mdSignature, HRESULT> {
+        if self.sig.locals.is_empty() {
+            return Ok(mdTokenNil);
+        }
+
+        let signature = self.sig.to_bytes().map_err(|e| {
+            log::warn!("Could not encode local vars signature, {:?}", e);
+            E_FAIL
+        })?;
+        module_metadata
+            .emit
+            .get_token_from_sig(&signature)
+            .map_err(|e| {
+                log::warn!("Error creating new local vars signature {:?}", &signature);
+                e
+            })
+    }
+}
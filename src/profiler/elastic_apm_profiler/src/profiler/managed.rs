@@ -41,6 +41,7 @@ pub const MANAGED_PROFILER_CALLTARGET_GETDEFAULTVALUE_NAME: &str = "GetDefaultVa
 pub const MANAGED_PROFILER_CALLTARGET_STATETYPE: &str =
     "Elastic.Apm.Profiler.Managed.CallTarget.CallTargetState";
 pub const MANAGED_PROFILER_CALLTARGET_STATETYPE_GETDEFAULT_NAME: &str = "GetDefault";
+pub const MANAGED_PROFILER_CALLTARGET_STATETYPE_GETSKIPMETHODBODY_NAME: &str = "get_SkipMethodBody";
 pub const MANAGED_PROFILER_CALLTARGET_RETURNTYPE: &str =
     "Elastic.Apm.Profiler.Managed.CallTarget.CallTargetReturn";
 pub const MANAGED_PROFILER_CALLTARGET_RETURNTYPE_GETDEFAULT_NAME: &str = "GetDefault";
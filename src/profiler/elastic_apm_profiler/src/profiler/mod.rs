@@ -4,6 +4,7 @@
 // See the LICENSE file in the project root for more information
 
 use crate::{
+    cil::{Method, Operand::InlineMethod, CALL, CALLVIRT},
     ffi::{types::RuntimeInfo, *},
     interfaces::{
         ICorProfilerAssemblyReferenceProvider, ICorProfilerCallback, ICorProfilerCallback2,
@@ -20,10 +21,10 @@ use crate::{
             MANAGED_PROFILER_FULL_ASSEMBLY_VERSION,
         },
         rejit::RejitHandler,
-        sig::get_sig_type_token_name,
+        sig::{get_sig_type_token_name, signature_type_matches},
         types::{
-            IntegrationMethod, MethodReplacement, ModuleMetadata, ModuleWrapperTokens,
-            WrapperMethodAction,
+            CallerMethodReference, IntegrationMethod, MethodReplacement, ModuleMetadata,
+            ModuleWrapperTokens, WrapperMethodAction,
         },
     },
 };
@@ -51,6 +52,7 @@ use widestring::{U16CStr, U16CString};
 mod calltarget_tokens;
 pub mod env;
 mod helpers;
+mod local_sig;
 pub mod managed;
 mod process;
 mod rejit;
@@ -884,23 +886,19 @@ impl Profiler {
             let call_target_enabled = *env::ELASTIC_APM_PROFILER_CALLTARGET_ENABLED;
 
             // TODO: Avoid cloning integration methods. Should be possible to make all filtered_integrations a collection of references
-            let mut filtered_integrations = if call_target_enabled {
-                self.integration_methods.read().unwrap().to_vec()
-            } else {
-                self.integration_methods
-                    .read()
-                    .unwrap()
-                    .iter()
-                    .filter(|m| {
-                        if let Some(caller) = m.method_replacement.caller() {
-                            caller.assembly.is_empty() || &caller.assembly == assembly_name
-                        } else {
-                            true
-                        }
-                    })
-                    .cloned()
-                    .collect()
-            };
+            // an integration with a caller instruments calls to the target from the caller,
+            // so is only relevant to modules of the caller assembly
+            let mut filtered_integrations: Vec<IntegrationMethod> = self
+                .integration_methods
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|m| match m.method_replacement.caller() {
+                    Some(caller) => caller.matches_assembly(assembly_name),
+                    None => true,
+                })
+                .cloned()
+                .collect();
 
             if filtered_integrations.is_empty() {
                 log::debug!(
@@ -1206,6 +1204,7 @@ impl Profiler {
             startup_hook::run_il_startup_hook(
                 profiler_info,
                 &module_metadata,
+                function_id,
                 function_info.module_id,
                 function_info.token,
             )?;
@@ -1230,7 +1229,26 @@ impl Profiler {
                 .get_mut(&function_info.module_id)
                 .unwrap();
 
-            process::process_insertion_calls(
+            // the method is decoded once, for calls to be inserted and then replaced
+            let il_body =
+                profiler_info.get_il_function_body(function_info.module_id, function_info.token)?;
+            let mut method = Method::new(il_body.into()).map_err(|e| {
+                log::warn!("JITCompilationStarted: error decoding il. {:?}", e);
+                E_FAIL
+            })?;
+
+            let original_il = if *env::ELASTIC_APM_PROFILER_LOG_IL {
+                Some(helpers::get_il_codes(
+                    "IL original code for caller: ",
+                    &method,
+                    &caller,
+                    module_metadata,
+                ))
+            } else {
+                None
+            };
+
+            let inserted = match process::process_insertion_calls(
                 profiler_info,
                 &module_metadata,
                 &mut module_wrapper_token,
@@ -1239,9 +1257,20 @@ impl Profiler {
                 function_info.token,
                 &caller,
                 &method_replacements,
-            )?;
+                &mut method,
+            ) {
+                Ok(inserted) => inserted,
+                Err(e) => {
+                    log::warn!(
+                        "JITCompilationStarted: failed to insert calls into {}(). {:X}",
+                        caller.full_name(),
+                        e
+                    );
+                    false
+                }
+            };
 
-            process::process_replacement_calls(
+            let replaced = process::process_replacement_calls(
                 profiler_info,
                 &module_metadata,
                 &mut module_wrapper_token,
@@ -1250,7 +1279,21 @@ impl Profiler {
                 function_info.token,
                 &caller,
                 &method_replacements,
+                &mut method,
             )?;
+
+            if inserted || replaced {
+                process::set_method_body(
+                    profiler_info,
+                    module_metadata,
+                    function_id,
+                    function_info.module_id,
+                    function_info.token,
+                    &caller,
+                    method,
+                    original_il,
+                )?;
+            }
         }
 
         Ok(())
@@ -1521,6 +1564,26 @@ impl Profiler {
         let mut method_ids = vec![];
 
         for integration in &module_metadata.integrations {
+            // an integration with a caller instruments the calls from the caller
+            if let Some(caller) = integration.method_replacement.caller() {
+                if let Err(e) = self.calltarget_find_callers(
+                    module_id,
+                    module_metadata,
+                    &integration.method_replacement,
+                    caller,
+                    &mut method_ids,
+                ) {
+                    log::warn!(
+                        "Could not find callers for integration {} in module_id={}, assembly={}. {:X}",
+                        &integration.name,
+                        module_id,
+                        &module_metadata.assembly_name,
+                        e
+                    );
+                }
+                continue;
+            }
+
             let target = match integration.method_replacement.target() {
                 Some(t)
                     if t.is_valid_for_assembly(
@@ -1612,8 +1675,11 @@ impl Profiler {
                         &argument_type_name,
                         integration_argument_type_name
                     );
-                    if &argument_type_name != integration_argument_type_name
-                        && integration_argument_type_name != IGNORE
+                    if integration_argument_type_name != IGNORE
+                        && !signature_type_matches(
+                            integration_argument_type_name,
+                            &argument_type_name,
+                        )
                     {
                         mismatch = true;
                         break;
@@ -1635,9 +1701,12 @@ impl Profiler {
                 let rejit_module = rejit_handler.get_or_add_module(module_id);
                 let rejit_method = rejit_module.get_or_add_method(method_def);
                 rejit_method.set_function_info(caller);
-                rejit_method.set_method_replacement(integration.method_replacement.clone());
+                rejit_method.add_method_replacement(integration.method_replacement.clone());
 
-                method_ids.push(method_def);
+                // several integrations may instrument the same method
+                if !method_ids.contains(&method_def) {
+                    method_ids.push(method_def);
+                }
 
                 if log::log_enabled!(Level::Info) {
                     let caller_assembly_is_domain_neutral = IS_DESKTOP_CLR.load(Ordering::SeqCst)
@@ -1678,6 +1747,122 @@ impl Profiler {
 
         Ok(len)
     }
+
+    /// Finds the methods of a caller in the module that call the target of a method
+    /// replacement, adding them to the methods to rejit so that the calls are instrumented
+    fn calltarget_find_callers(
+        &self,
+        module_id: ModuleID,
+        module_metadata: &ModuleMetadata,
+        method_replacement: &MethodReplacement,
+        caller_ref: &CallerMethodReference,
+        method_ids: &mut Vec<mdMethodDef>,
+    ) -> Result<(), HRESULT> {
+        let target = match method_replacement.target() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        match method_replacement.wrapper() {
+            Some(w) if w.action == WrapperMethodAction::CallTargetModification => (),
+            _ => return Ok(()),
+        }
+
+        // the target is defined in the module, or in an assembly that it references
+        let assembly_import = &module_metadata.assembly_import;
+        let assembly_metadata = assembly_import.get_assembly_metadata()?;
+        let references_target = target
+            .is_valid_for_assembly(&module_metadata.assembly_name, &assembly_metadata.version)
+            || assembly_import.enum_assembly_refs()?.into_iter().any(|r| {
+                matches!(assembly_import.get_referenced_assembly_metadata(r),
+                        Ok(m) if target.is_valid_for_assembly(&m.name, &m.version))
+            });
+        if !references_target {
+            return Ok(());
+        }
+
+        // only the bodies of callers are scanned for calls to the target, by token. Callers
+        // are the methods of the type and with the name of the caller, when specified
+        let target_tokens = helpers::find_target_method_tokens(module_metadata, target)?;
+        if target_tokens.is_empty() {
+            return Ok(());
+        }
+
+        let profiler_borrow = self.profiler_info.borrow();
+        let profiler_info = profiler_borrow.as_ref().unwrap();
+        let metadata_import = &module_metadata.import;
+        let method_defs = helpers::find_caller_method_defs(
+            caller_ref,
+            &module_metadata.assembly_name,
+            metadata_import,
+        )?;
+
+        // a body is only decoded when it contains the bytes of a target token
+        let target_token_bytes = target_tokens
+            .iter()
+            .map(|token| token.to_le_bytes())
+            .collect::<Vec<_>>();
+        for method_def in method_defs {
+            // abstract and extern methods have no body
+            let il_body: &[u8] = match profiler_info.get_il_function_body(module_id, method_def) {
+                Ok(il_body) => il_body.into(),
+                Err(_) => continue,
+            };
+            if !il_body
+                .windows(4)
+                .any(|bytes| target_token_bytes.iter().any(|token| token == bytes))
+            {
+                continue;
+            }
+
+            let method = match Method::new(il_body) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+
+            let calls_target = method.instructions.iter().any(|instruction| {
+                (instruction.opcode == CALL || instruction.opcode == CALLVIRT)
+                    && matches!(&instruction.operand,
+                        InlineMethod(token) if target_tokens.contains(&token.token()))
+            });
+            if !calls_target {
+                continue;
+            }
+
+            let caller: FunctionInfo = match metadata_import.get_function_info(method_def) {
+                Ok(c) if c.method_signature.try_parse().is_some() => c,
+                _ => {
+                    log::warn!("Could not get function_info for method_def={}", method_def);
+                    continue;
+                }
+            };
+
+            log::info!(
+                "enqueue for ReJIT module_id={}, method_def={}, app_domain_id={}, assembly={}, \
+                caller={}(), calling target={}.{}()",
+                module_id,
+                method_def,
+                module_metadata.app_domain_id,
+                &module_metadata.assembly_name,
+                caller.full_name(),
+                target.type_name(),
+                target.method_name()
+            );
+
+            let mut borrow = self.rejit_handler.borrow_mut();
+            let rejit_handler: &mut RejitHandler = borrow.as_mut().unwrap();
+            let rejit_module = rejit_handler.get_or_add_module(module_id);
+            let rejit_method = rejit_module.get_or_add_method(method_def);
+            rejit_method.set_function_info(caller);
+            rejit_method.add_call_site_replacement(method_replacement.clone());
+
+            if !method_ids.contains(&method_def) {
+                method_ids.push(method_def);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 pub fn profiler_assembly_loaded_in_app_domain(app_domain_id: AppDomainID) -> bool {
@@ -5,7 +5,8 @@
 
 use crate::{
     cil::{
-        uncompress_token, Instruction, Method, Operand::InlineMethod, CALL, CALLVIRT, CONSTRAINED,
+        uncompress_token, Instruction, Method, MethodEdits, MethodSig, Operand::InlineMethod,
+        TypeSig, Verifier, CALL, CALLVIRT, CONSTRAINED,
     },
     ffi::{
         mdMemberRefNil, mdToken, mdTypeRefNil, CorElementType, FunctionID, ModuleID, E_FAIL, ULONG,
@@ -16,10 +17,14 @@ use crate::{
         env, helpers,
         helpers::return_type_is_value_type_or_generic,
         managed::IGNORE,
-        sig::{parse_signature_types, parse_type},
+        sig::{
+            get_type_name, parse_signature_types, parse_type, render_type_name,
+            signature_type_matches,
+        },
         types::{
-            FunctionInfo, MetadataBuilder, MethodReplacement, ModuleMetadata, ModuleWrapperTokens,
-            WrapperMethodAction, WrapperMethodRef, WrapperMethodReference,
+            FunctionInfo, MetadataBuilder, MethodArgumentTypeFlag, MethodReplacement,
+            ModuleMetadata, ModuleWrapperTokens, WrapperMethodAction, WrapperMethodRef,
+            WrapperMethodReference,
         },
     },
 };
@@ -28,6 +33,12 @@ use log::Level;
 use num_traits::FromPrimitive;
 use std::mem::transmute;
 
+/// Inserts calls to wrapper methods with the [WrapperMethodAction::InsertFirst] action at the
+/// start of the method body of the caller, when the caller is the target of the method replacement.
+/// The wrapper is a static method whose arguments are loaded from the caller's arguments, in order,
+/// starting with the this argument of an instance method. Returns whether calls were inserted.
+/// The method is left unmodified when an error is returned.
+#[allow(clippy::too_many_arguments)]
 pub fn process_insertion_calls(
     profiler_info: &ICorProfilerInfo4,
     module_metadata: &ModuleMetadata,
@@ -37,12 +48,333 @@ pub fn process_insertion_calls(
     function_token: mdToken,
     caller: &FunctionInfo,
     method_replacements: &[MethodReplacement],
-) -> Result<(), HRESULT> {
-    // TODO: implement
+    method: &mut Method,
+) -> Result<bool, HRESULT> {
+    let caller_type_name = match &caller.type_info {
+        Some(t) => &t.name,
+        None => return Ok(false),
+    };
+
+    let mut prelude = Vec::new();
+    for method_replacement in method_replacements {
+        let wrapper = match method_replacement.wrapper() {
+            Some(w) if w.action == WrapperMethodAction::InsertFirst => w,
+            _ => continue,
+        };
+
+        let target = match method_replacement.target() {
+            Some(t) if t.type_name() == caller_type_name && t.method_name() == caller.name => t,
+            _ => continue,
+        };
+
+        if !target.is_valid_for_assembly(
+            &module_metadata.assembly_name,
+            &module_metadata
+                .assembly_import
+                .get_assembly_metadata()?
+                .version,
+        ) {
+            continue;
+        }
 
-    Ok(())
+        if let Some(expected_sig) = target.signature_types() {
+            let actual_sig = match parse_signature_types(module_metadata, caller) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            if actual_sig.len() != expected_sig.len()
+                || expected_sig
+                    .iter()
+                    .zip(actual_sig.iter())
+                    .any(|(expected, actual)| {
+                        expected != IGNORE && !signature_type_matches(expected, actual)
+                    })
+            {
+                if log::log_enabled!(log::Level::Debug) {
+                    log::debug!(
+                        "process_insertion_calls: skipping function, types don't match. function_id={}, function_token={}, name={}(), expected_sig={:?}, actual_sig={:?}",
+                        function_id,
+                        function_token,
+                        caller.full_name(),
+                        expected_sig,
+                        &actual_sig
+                    );
+                }
+                continue;
+            }
+        }
+
+        let wrapper_method_signature = match &wrapper.method_signature {
+            Some(s) => s,
+            None => continue,
+        };
+
+        if wrapper_method_signature.is_instance_method() {
+            log::warn!(
+                "process_insertion_calls: skipping {}() because wrapper {}() is not a static method",
+                caller.full_name(),
+                wrapper.full_name()
+            );
+            continue;
+        }
+
+        // the wrapper can take up to as many arguments as the caller, including this
+        let caller_arg_count =
+            caller.signature.arguments_len() as u16 + caller.signature.is_instance_method() as u16;
+        let wrapper_arg_count = wrapper_method_signature.arguments_len() as u16;
+        if wrapper_arg_count > caller_arg_count {
+            log::warn!(
+                "process_insertion_calls: skipping {}() because wrapper {}() expects {} arguments but caller has {}",
+                caller.full_name(),
+                wrapper.full_name(),
+                wrapper_arg_count,
+                caller_arg_count
+            );
+            continue;
+        }
+
+        let wrapper_params = match MethodSig::from_bytes(wrapper_method_signature.bytes()) {
+            Ok(wrapper_sig) => wrapper_sig.params,
+            Err(e) => {
+                log::warn!(
+                    "process_insertion_calls: skipping {}() because the signature of wrapper {}() could not be parsed. {:?}",
+                    caller.full_name(),
+                    wrapper.full_name(),
+                    e
+                );
+                continue;
+            }
+        };
+
+        let arguments = match load_insertion_arguments(module_metadata, caller, &wrapper_params)? {
+            Some(arguments) => arguments,
+            None => {
+                log::warn!(
+                    "process_insertion_calls: skipping {}() because the parameter types of wrapper {}() do not match its argument types",
+                    caller.full_name(),
+                    wrapper.full_name()
+                );
+                continue;
+            }
+        };
+
+        let wrapper_method_key = wrapper.get_method_cache_key();
+        if module_wrapper_tokens.is_failed_wrapper_member_key(&wrapper_method_key) {
+            continue;
+        }
+
+        if !profiler::profiler_assembly_loaded_in_app_domain(module_metadata.app_domain_id) {
+            log::warn!(
+                "process_insertion_calls: skipping method as insertion found but managed profiler \
+                    has not been loaded into AppDomain id={}, function_id={}, function_token={}, caller_name={}()",
+                module_metadata.app_domain_id,
+                function_id,
+                function_token,
+                caller.full_name()
+            );
+            continue;
+        }
+
+        let wrapper_method_ref = match get_wrapper_method_ref(
+            profiler_info,
+            module_metadata,
+            module_wrapper_tokens,
+            module_id,
+            wrapper,
+            &wrapper_method_key,
+        ) {
+            Ok(w) => w,
+            Err(_) => {
+                log::warn!(
+                    "process_insertion_calls: failed to obtain wrapper method ref for {}(). function_id={}, function_token={}, name={}()",
+                    wrapper.full_name(),
+                    function_id,
+                    function_token,
+                    caller.full_name()
+                );
+                continue;
+            }
+        };
+
+        prelude.extend(arguments);
+        prelude.push(Instruction::call(helpers::operand_token(
+            wrapper_method_ref.method_ref,
+        )?));
+
+        let return_type = wrapper_method_signature
+            .bytes()
+            .get(wrapper_method_signature.index_of_return_type())
+            .and_then(|b| CorElementType::from_u8(*b));
+        if return_type != Some(CorElementType::ELEMENT_TYPE_VOID) {
+            prelude.push(Instruction::pop());
+        }
+
+        log::info!(
+            "process_insertion_calls: inserted call to {}() at the start of {}()",
+            wrapper.full_name(),
+            caller.full_name()
+        );
+    }
+
+    if prelude.is_empty() {
+        return Ok(false);
+    }
+
+    // the prelude is inserted into a copy of the method, which replaces it once verified
+    let mut modified_method = method.clone();
+    modified_method.insert_prelude(prelude).map_err(|e| {
+        log::warn!("process_insertion_calls: error inserting prelude. {:?}", e);
+        E_FAIL
+    })?;
+
+    let returns_value = caller
+        .method_signature
+        .try_parse()
+        .map(|s| {
+            let (_, flags) = s.return_type().get_type_flags();
+            !flags.contains(MethodArgumentTypeFlag::VOID)
+        })
+        .ok_or(E_FAIL)?;
+    let diagnostics = Verifier::new(module_metadata, returns_value).verify(&modified_method);
+    if !diagnostics.is_empty() {
+        log::warn!(
+            "process_insertion_calls: not inserting calls into {}() as the modified IL is invalid",
+            caller.full_name()
+        );
+        for diagnostic in diagnostics {
+            log::warn!("process_insertion_calls: {}", diagnostic);
+        }
+        return Err(E_FAIL);
+    }
+
+    *method = modified_method;
+    Ok(true)
+}
+
+/// Gets the instructions that load the caller's arguments passed to an InsertFirst wrapper,
+/// in order, starting with the this argument of an instance method. The type of each wrapper
+/// parameter is compared with the type of the caller argument by name. A value type argument
+/// passed to an object parameter is boxed, as is the instance of a value type, which is a
+/// managed pointer. Returns `None`, logging the mismatched parameter, when any other argument
+/// type differs from the parameter type
+fn load_insertion_arguments(
+    module_metadata: &ModuleMetadata,
+    caller: &FunctionInfo,
+    wrapper_params: &[TypeSig],
+) -> Result<Option<Vec<Instruction>>, HRESULT> {
+    let caller_sig = MethodSig::from_bytes(caller.signature.bytes()).map_err(|e| {
+        log::warn!(
+            "process_insertion_calls: could not parse signature of {}(). {:?}",
+            caller.full_name(),
+            e
+        );
+        E_FAIL
+    })?;
+
+    let mut argument_types = Vec::with_capacity(caller_sig.params.len() + 1);
+    let mut instance_token = None;
+    if caller.signature.is_instance_method() {
+        let type_info = caller.type_info.as_ref().ok_or(E_FAIL)?;
+        if type_info.is_value_type {
+            let (instance_type, token) = helpers::value_type_instance(module_metadata, type_info)?;
+            instance_token = Some(helpers::operand_token(token)?);
+            argument_types.push(TypeSig::ByRef(Box::new(instance_type)));
+        } else {
+            argument_types.push(TypeSig::Class(type_info.id));
+        }
+    }
+    argument_types.extend(caller_sig.params);
+
+    let mut instructions = Vec::with_capacity(wrapper_params.len());
+    for (i, (param, argument)) in wrapper_params.iter().zip(&argument_types).enumerate() {
+        instructions.push(Instruction::load_argument(i as u16));
+
+        // the tokens of the wrapper signature are not tokens of the module,
+        // so only wrapper parameters of built-in types have a name
+        let param_name = render_type_name(param, &|_| None);
+        if param_name.is_some() && param_name == get_type_name(module_metadata, argument) {
+            continue;
+        }
+
+        if *param != TypeSig::Object {
+            warn_parameter_mismatch(module_metadata, caller, i, param, argument);
+            return Ok(None);
+        }
+
+        match argument {
+            TypeSig::String
+            | TypeSig::Object
+            | TypeSig::Class(_)
+            | TypeSig::SzArray(_)
+            | TypeSig::Array(..)
+            | TypeSig::GenericInst {
+                value_type: false, ..
+            } => {}
+            TypeSig::ByRef(_) => match instance_token {
+                Some(instance_token) if i == 0 => {
+                    instructions.push(Instruction::ldobj(instance_token));
+                    instructions.push(Instruction::box_(instance_token));
+                }
+                _ => {
+                    warn_parameter_mismatch(module_metadata, caller, i, param, argument);
+                    return Ok(None);
+                }
+            },
+            TypeSig::Void
+            | TypeSig::TypedByRef
+            | TypeSig::Ptr(_)
+            | TypeSig::FnPtr(_)
+            | TypeSig::Modified(..)
+            | TypeSig::Pinned(_) => {
+                warn_parameter_mismatch(module_metadata, caller, i, param, argument);
+                return Ok(None);
+            }
+            TypeSig::ValueType(token) => {
+                instructions.push(Instruction::box_(helpers::operand_token(*token)?));
+            }
+            // built-in value types, generic value types and generic parameters
+            // are boxed with a TypeSpec of their signature
+            _ => {
+                let signature = argument.to_bytes().map_err(|e| {
+                    log::warn!(
+                        "process_insertion_calls: could not encode signature. {:?}",
+                        e
+                    );
+                    E_FAIL
+                })?;
+                let type_spec = module_metadata.emit.get_token_from_type_spec(&signature)?;
+                instructions.push(Instruction::box_(helpers::operand_token(type_spec)?));
+            }
+        }
+    }
+
+    Ok(Some(instructions))
 }
 
+/// Logs a wrapper parameter that cannot be passed the caller argument at the same position.
+/// Tokens of the wrapper signature belong to the wrapper module, so they are logged as is
+fn warn_parameter_mismatch(
+    module_metadata: &ModuleMetadata,
+    caller: &FunctionInfo,
+    index: usize,
+    param: &TypeSig,
+    argument: &TypeSig,
+) {
+    log::warn!(
+        "process_insertion_calls: parameter {} of type {} of the wrapper does not match argument of type {} of {}()",
+        index,
+        render_type_name(param, &|token| Some(format!("<token 0x{:08x}>", token)))
+            .unwrap_or_default(),
+        get_type_name(module_metadata, argument).unwrap_or_default(),
+        caller.full_name()
+    );
+}
+
+/// Replaces calls to the targets of method replacements with the
+/// [WrapperMethodAction::ReplaceTargetMethod] action with calls to the wrapper methods.
+/// Returns whether calls were replaced.
+#[allow(clippy::too_many_arguments)]
 pub fn process_replacement_calls(
     profiler_info: &ICorProfilerInfo4,
     module_metadata: &ModuleMetadata,
@@ -52,14 +384,8 @@ pub fn process_replacement_calls(
     function_token: mdToken,
     caller: &FunctionInfo,
     method_replacements: &[MethodReplacement],
-) -> Result<(), HRESULT> {
-    let il_body = profiler_info.get_il_function_body(module_id, function_token)?;
-    let mut method = Method::new(il_body.into()).map_err(|e| {
-        log::warn!("process_replacement_calls: error decoding il. {:?}", e);
-        E_FAIL
-    })?;
-
-    let mut original_il = None;
+    method: &mut Method,
+) -> Result<bool, HRESULT> {
     let mut modified = false;
     for method_replacement in method_replacements {
         if method_replacement.wrapper().is_none() {
@@ -75,16 +401,16 @@ pub fn process_replacement_calls(
             continue;
         }
 
-        let instructions = method.instructions.clone();
-        let mut index_mod = 0;
-        for (mut instr_index, instruction) in instructions.iter().enumerate() {
+        let mut edits = MethodEdits::new();
+        let instructions = &method.instructions;
+        for (instr_index, instruction) in instructions.iter().enumerate() {
             if instruction.opcode != CALL && instruction.opcode != CALLVIRT {
                 continue;
             }
 
             let original_argument;
             if let InlineMethod(token) = &instruction.operand {
-                original_argument = *token;
+                original_argument = token.token();
             } else {
                 continue;
             }
@@ -219,7 +545,7 @@ pub fn process_replacement_calls(
 
             let mut mismatch = false;
             for (idx, expected) in expected_sig.iter().enumerate() {
-                if expected != IGNORE && expected != &actual_sig[idx] {
+                if expected != IGNORE && !signature_type_matches(expected, &actual_sig[idx]) {
                     if log::log_enabled!(log::Level::Debug) {
                         log::debug!("JITCompilationStarted: skipping function call, types don't match. function_id={}, function_token={}, name={}(), expected_sig[{}]={}, actual_sig[{}]={}",
                                     function_id,
@@ -268,31 +594,10 @@ pub fn process_replacement_calls(
                 continue;
             }
 
-            if *env::ELASTIC_APM_PROFILER_LOG_IL {
-                original_il = Some(helpers::get_il_codes(
-                    "IL original code for caller: ",
-                    &method,
-                    caller,
-                    module_metadata,
-                ));
-            }
-
-            // We're going to push instructions onto the stack which may exceed tiny format max stack,
-            // so expand tiny format to fat format to ensure the stack will be big enough
-            method.expand_tiny_to_fat();
-
+            // the original call instruction is replaced with a call to the wrapper,
+            // along with the additional arguments the wrapper takes
             let original_opcode = instruction.opcode;
-
-            // Replace the original call instruction with a nop
-            method
-                .replace(instr_index + index_mod, Instruction::nop())
-                .map_err(|e| {
-                    log::warn!("unable to replace instruction {:?}", e);
-                    E_FAIL
-                })?;
-
-            // insert instructions after the original instruction
-            instr_index += 1;
+            let mut replacement = Vec::new();
 
             let original_method_def = target.id;
             let argument_len = target_arg_count;
@@ -310,8 +615,6 @@ pub fn process_replacement_calls(
                 }
             }
 
-            let mut this_index_mod = 0;
-
             if signature_read_success {
                 // handle CancellationToken in the last argument position
                 if let Some(CorElementType::ELEMENT_TYPE_VALUETYPE) =
@@ -324,22 +627,9 @@ pub fn process_replacement_calls(
                             module_metadata.import.get_type_info(value_type_token)
                         {
                             if &type_info.name == "System.Threading.CancellationToken" {
-                                method
-                                    .insert(
-                                        instr_index + index_mod,
-                                        Instruction::box_(value_type_token),
-                                    )
-                                    .map_err(|e| {
-                                        log::warn!(
-                                            "error inserting box({}) instruction, {:?}",
-                                            value_type_token,
-                                            e
-                                        );
-                                        E_FAIL
-                                    })?;
-
-                                instr_index += 1;
-                                this_index_mod += 1;
+                                replacement.push(Instruction::box_(helpers::operand_token(
+                                    value_type_token,
+                                )?));
                             }
                         }
                     }
@@ -371,22 +661,9 @@ pub fn process_replacement_calls(
                                                 &return_type_bytes[start_idx..end_idx],
                                             )
                                         {
-                                            method
-                                                .insert(
-                                                    instr_index + index_mod,
-                                                    Instruction::box_(type_token),
-                                                )
-                                                .map_err(|e| {
-                                                    log::warn!(
-                                                        "error inserting box({}) instruction, {:?}",
-                                                        type_token,
-                                                        e
-                                                    );
-                                                    E_FAIL
-                                                })?;
-
-                                            instr_index += 1;
-                                            this_index_mod += 1;
+                                            replacement.push(Instruction::box_(
+                                                helpers::operand_token(type_token)?,
+                                            ));
                                         }
                                     }
                                 }
@@ -396,62 +673,13 @@ pub fn process_replacement_calls(
                 }
             }
 
-            // TODO: add a fn to method insert all instructions in one go
-            method
-                .insert(
-                    instr_index + index_mod,
-                    Instruction::call(generated_wrapper_method_ref.method_ref),
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "error inserting call({}) instruction, {:?}",
-                        generated_wrapper_method_ref.method_ref,
-                        e
-                    );
-                    E_FAIL
-                })?;
-
-            method
-                .insert(
-                    instr_index + index_mod,
-                    Instruction::load_int32(original_opcode.byte_2 as i32),
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "error inserting load_int32({}) instruction, {:?}",
-                        original_opcode.byte_2,
-                        e
-                    );
-                    E_FAIL
-                })?;
-
-            method
-                .insert(
-                    instr_index + 1 + index_mod,
-                    Instruction::load_int32(method_def_md_token as i32),
-                )
-                .map_err(|e| {
-                    log::warn!(
-                        "error inserting load_int32({}) instruction, {:?}",
-                        method_def_md_token,
-                        e
-                    );
-                    E_FAIL
-                })?;
-
             let module_ptr: i64 = unsafe { transmute(&module_metadata.module_version_id) };
-            method
-                .insert(instr_index + 2 + index_mod, Instruction::ldc_i8(module_ptr))
-                .map_err(|e| {
-                    log::warn!(
-                        "error inserting ldc_i8({}) instruction, {:?}",
-                        module_ptr,
-                        e
-                    );
-                    E_FAIL
-                })?;
-
-            this_index_mod += 4;
+            replacement.push(Instruction::load_int32(original_opcode.byte_2 as i32));
+            replacement.push(Instruction::load_int32(method_def_md_token as i32));
+            replacement.push(Instruction::ldc_i8(module_ptr));
+            replacement.push(Instruction::call(helpers::operand_token(
+                generated_wrapper_method_ref.method_ref,
+            )?));
 
             if wrapper_method_signature.return_type_is_object() {
                 if let Some(type_token) = return_type_is_value_type_or_generic(
@@ -470,22 +698,11 @@ pub fn process_replacement_calls(
                         );
                     }
 
-                    method
-                        .insert(
-                            instr_index + 4 + index_mod,
-                            Instruction::unbox_any(type_token),
-                        )
-                        .map_err(|e| {
-                            log::warn!("error inserting unbox.any instruction, {:?}", e);
-                            E_FAIL
-                        })?;
-
-                    this_index_mod += 1;
+                    replacement.push(Instruction::unbox_any(helpers::operand_token(type_token)?));
                 }
             }
 
-            index_mod += this_index_mod;
-
+            edits.replace(instr_index, replacement);
             modified = true;
             log::info!(
                 "JITCompilationStarted: replaced calls from {}() to {}() with calls to {}() {}",
@@ -495,40 +712,80 @@ pub fn process_replacement_calls(
                 generated_wrapper_method_ref.method_ref
             );
         }
+
+        method.apply_edits(edits).map_err(|e| {
+            log::warn!("process_replacement_calls: error applying edits. {:?}", e);
+            E_FAIL
+        })?;
     }
 
-    if modified {
-        if *env::ELASTIC_APM_PROFILER_LOG_IL {
-            let modified_il = helpers::get_il_codes(
-                "IL modification for caller: ",
-                &method,
-                caller,
-                module_metadata,
+    Ok(modified)
+}
+
+/// Sets the method body of a caller modified by [process_insertion_calls] and
+/// [process_replacement_calls], along with the map from the original IL offsets
+#[allow(clippy::too_many_arguments)]
+pub fn set_method_body(
+    profiler_info: &ICorProfilerInfo4,
+    module_metadata: &ModuleMetadata,
+    function_id: FunctionID,
+    module_id: ModuleID,
+    function_token: mdToken,
+    caller: &FunctionInfo,
+    mut method: Method,
+    original_il: Option<String>,
+) -> Result<(), HRESULT> {
+    if *env::ELASTIC_APM_PROFILER_OPTIMIZE_IL {
+        if let Err(e) = method.optimize() {
+            log::debug!(
+                "set_method_body: could not optimize IL, using unoptimized IL. {:?}",
+                e
             );
-            log::debug!("{}\n{}", original_il.unwrap_or_default(), modified_il);
         }
+    }
 
-        let method_bytes = method.into_bytes();
-        let allocator = profiler_info.get_il_function_body_allocator(module_id)?;
-        let allocated_bytes = allocator.alloc(method_bytes.len() as ULONG).map_err(|e| {
-            log::warn!("process_replacement_calls: failed to allocate memory for replacement il");
+    if *env::ELASTIC_APM_PROFILER_LOG_IL {
+        let modified_il = helpers::get_il_codes(
+            "IL modification for caller: ",
+            &method,
+            caller,
+            module_metadata,
+        );
+        log::debug!("{}\n{}", original_il.unwrap_or_default(), modified_il);
+    }
+
+    let method_bytes = method.into_bytes();
+    let allocator = profiler_info.get_il_function_body_allocator(module_id)?;
+    let allocated_bytes = allocator.alloc(method_bytes.len() as ULONG).map_err(|e| {
+        log::warn!("set_method_body: failed to allocate memory for modified il");
+        e
+    })?;
+
+    let address = unsafe { allocated_bytes.into_inner() };
+    unsafe {
+        std::ptr::copy(method_bytes.as_ptr(), address, method_bytes.len());
+    }
+    profiler_info
+        .set_il_function_body(module_id, function_token, address as *const _)
+        .map_err(|e| {
+            log::warn!(
+                "set_method_body: failed to set il for module_id={} {}",
+                module_id,
+                function_token
+            );
             e
         })?;
 
-        let address = unsafe { allocated_bytes.into_inner() };
-        unsafe {
-            std::ptr::copy(method_bytes.as_ptr(), address, method_bytes.len());
-        }
-        profiler_info
-            .set_il_function_body(module_id, function_token, address as *const _)
-            .map_err(|e| {
-                log::warn!(
-                    "process_replacement_calls: failed to set il for module_id={} {}",
-                    module_id,
-                    function_token
-                );
+    let il_map = method.il_map();
+    if !il_map.is_empty() {
+        if let Err(e) = profiler_info.set_il_instrumented_code_map(function_id, true, &il_map) {
+            log::warn!(
+                "set_method_body: failed to set il instrumented code map for module_id={} {}. {:X}",
+                module_id,
+                function_token,
                 e
-            })?;
+            );
+        }
     }
 
     Ok(())
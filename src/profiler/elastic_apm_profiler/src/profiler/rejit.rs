@@ -5,18 +5,23 @@
 
 use crate::{
     cil::{
-        CorExceptionFlag, FatSectionClause, Instruction, Method, Operand::ShortInlineBrTarget, RET,
+        ClauseKind, ExceptionClause, HandlerKind, Instruction, Label, Method, MethodBuilder,
+        MethodToken, Operand::InlineMethod, TypeToken, Verifier, BRFALSE_S, CALL, CALLVIRT,
+        CONSTRAINED, LEAVE_S, TAILCALL,
     },
     ffi::{
-        mdMethodDef, mdTokenNil, mdTypeSpecNil, CorCallingConvention, FunctionID, ModuleID, ReJITID,
+        is_nil_token, mdMethodDef, mdToken, mdTokenNil, mdTypeSpecNil, CorCallingConvention,
+        FunctionID, ModuleID, ReJITID,
     },
-    interfaces::{ICorProfilerFunctionControl, ICorProfilerInfo4},
+    interfaces::{ICorProfilerFunctionControl, ICorProfilerInfo4, IMetaDataEmit2},
     profiler::{
-        calltarget_tokens::CallTargetTokens,
-        env, helpers, process,
+        calltarget_tokens::{is_by_ref, CallTargetTokens, LocalSig},
+        env, helpers,
+        local_sig::LocalSigEditor,
+        process,
         types::{
-            FunctionInfo, MethodArgumentTypeFlag, MethodReplacement, ModuleMetadata,
-            ModuleWrapperTokens, TypeInfo,
+            FunctionInfo, FunctionMethodArgument, MethodArgumentTypeFlag, MethodReplacement,
+            ModuleMetadata, ModuleWrapperTokens, TypeInfo,
         },
     },
 };
@@ -58,7 +63,8 @@ impl RejitHandlerModule {
 pub struct RejitHandlerModuleMethod {
     method_def: mdMethodDef,
     function_info: Option<FunctionInfo>,
-    method_replacement: Option<MethodReplacement>,
+    method_replacements: Vec<MethodReplacement>,
+    call_site_replacements: Vec<MethodReplacement>,
 }
 
 impl RejitHandlerModuleMethod {
@@ -66,7 +72,8 @@ impl RejitHandlerModuleMethod {
         Self {
             method_def,
             function_info: None,
-            method_replacement: None,
+            method_replacements: Vec::new(),
+            call_site_replacements: Vec::new(),
         }
     }
 
@@ -74,8 +81,18 @@ impl RejitHandlerModuleMethod {
         self.function_info = Some(function_info);
     }
 
-    pub fn set_method_replacement(&mut self, method_replacement: MethodReplacement) {
-        self.method_replacement = Some(method_replacement);
+    /// Adds a method replacement whose wrapper instruments the method. Wrappers are
+    /// ordered by descending priority, then by the order in which they are added,
+    /// and the first wrapper is the outermost
+    pub fn add_method_replacement(&mut self, method_replacement: MethodReplacement) {
+        add_by_priority(&mut self.method_replacements, method_replacement);
+    }
+
+    /// Adds a method replacement whose wrapper instruments the calls to its target
+    /// from the method. Wrappers of the same target are ordered as with
+    /// [RejitHandlerModuleMethod::add_method_replacement]
+    pub fn add_call_site_replacement(&mut self, method_replacement: MethodReplacement) {
+        add_by_priority(&mut self.call_site_replacements, method_replacement);
     }
 
     pub fn function_info(&self) -> Option<&FunctionInfo> {
@@ -83,6 +100,16 @@ impl RejitHandlerModuleMethod {
     }
 }
 
+fn add_by_priority(
+    method_replacements: &mut Vec<MethodReplacement>,
+    method_replacement: MethodReplacement,
+) {
+    if !method_replacements.contains(&method_replacement) {
+        method_replacements.push(method_replacement);
+        method_replacements.sort_by_key(|m| std::cmp::Reverse(m.priority()));
+    }
+}
+
 #[derive(Debug)]
 struct RejitItem {
     module_ids: Vec<ModuleID>,
@@ -198,7 +225,9 @@ impl RejitHandler {
             return Err(S_FALSE);
         }
 
-        if rejit_method.method_replacement.is_none() {
+        if rejit_method.method_replacements.is_empty()
+            && rejit_method.call_site_replacements.is_empty()
+        {
             log::warn!(
                 "notify_rejit_parameters: method_replacement is missing for method_def={}",
                 method_id
@@ -243,10 +272,8 @@ pub fn calltarget_rewriter_callback(
     let function_token = caller.id;
     let parsed_function_method_signature = caller.method_signature.try_parse().unwrap();
     let ret_func_arg = parsed_function_method_signature.return_type();
-    let method_replacement = rejit_handler_module_method
-        .method_replacement
-        .as_ref()
-        .unwrap();
+    let method_replacements = &rejit_handler_module_method.method_replacements;
+    let call_site_replacements = &rejit_handler_module_method.call_site_replacements;
 
     let (_, ret_type_flags) = ret_func_arg.get_type_flags();
     let is_void = ret_type_flags.contains(MethodArgumentTypeFlag::VOID);
@@ -256,28 +283,20 @@ pub fn calltarget_rewriter_callback(
         .unwrap()
         .contains(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_HASTHIS);
 
-    let method_arguments = parsed_function_method_signature.arguments();
     let num_args = parsed_function_method_signature.arg_len;
-    let wrapper = method_replacement.wrapper().unwrap();
-    let wrapper_method_key = wrapper.get_method_cache_key();
-
-    let wrapper_method_ref = process::get_wrapper_method_ref(
-        profiler_info,
-        module_metadata,
-        module_wrapper_tokens,
-        module_id,
-        wrapper,
-        &wrapper_method_key,
-    )?;
 
-    let meta_emit = &module_metadata.emit;
-    let meta_import = &module_metadata.import;
+    let integration_types = method_replacements
+        .iter()
+        .chain(call_site_replacements)
+        .map(|m| m.wrapper().unwrap().type_name.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
 
     log::debug!("calltarget_rewriter_callback: start {}() [is_void={}, is_static={}, integration_type={}, arguments={}]",
         caller.full_name(),
         is_void,
         is_static,
-        &wrapper.type_name,
+        &integration_types,
         num_args
     );
 
@@ -308,541 +327,1197 @@ pub fn calltarget_rewriter_callback(
         None
     };
 
-    // expand to a fat method with fat sections to work with
-    method.expand_tiny_to_fat();
-    method.expand_small_sections_to_fat();
+    // the method is instrumented as a target first, so that calls from the original
+    // method body are instrumented within it
+    if !method_replacements.is_empty() {
+        method = rewrite_target_method(
+            module_metadata,
+            module_wrapper_tokens,
+            module_id,
+            method,
+            caller,
+            method_replacements,
+            profiler_info,
+            call_target_tokens,
+        )?;
+    }
 
-    let (local_sig, instructions) =
-        call_target_tokens.modify_local_sig_and_initialize(&method, caller, module_metadata)?;
+    if !call_site_replacements.is_empty() {
+        method = rewrite_call_sites(
+            module_metadata,
+            module_wrapper_tokens,
+            module_id,
+            method,
+            caller,
+            call_site_replacements,
+            profiler_info,
+            call_target_tokens,
+        )?;
+    }
 
-    method
-        .header
-        .set_local_var_sig_tok(local_sig.new_local_var_sig);
+    // replace the max stack accumulated from the inserted instructions with
+    // the exact max stack, if it can be computed
+    match method.compute_max_stack(module_metadata, !is_void) {
+        Ok(max_stack) => method.header.set_max_stack(max_stack),
+        Err(e) => log::debug!(
+            "calltarget_rewriter_callback: could not compute max stack, using {}. {:?}",
+            method.header.max_stack(),
+            e
+        ),
+    }
 
-    let mut idx: usize = instructions.len();
-    method.insert_prelude(instructions).map_err(|_| S_FALSE)?;
+    if *env::ELASTIC_APM_PROFILER_OPTIMIZE_IL {
+        if let Err(e) = method.optimize() {
+            log::debug!(
+                "calltarget_rewriter_callback: could not optimize IL, using unoptimized IL. {:?}",
+                e
+            );
+        }
+    }
 
-    let type_info = caller.type_info.as_ref().unwrap();
+    if *env::ELASTIC_APM_PROFILER_LOG_IL {
+        let modified_il = helpers::get_il_codes(
+            "IL modification for caller: ",
+            &method,
+            caller,
+            module_metadata,
+        );
+        log::debug!("{}\n{}", original_il.unwrap_or_default(), modified_il);
+    }
 
-    if is_static {
-        if type_info.is_value_type {
-            log::warn!("calltarget_rewriter_callback: static methods on value types cannot be instrumented");
-            return Err(S_FALSE);
+    // refuse to hand the runtime IL that it would reject, or that would fail at run time
+    let diagnostics = Verifier::new(module_metadata, !is_void).verify(&method);
+    if !diagnostics.is_empty() {
+        log::warn!(
+            "calltarget_rewriter_callback: not rewriting {}.{}() as the modified IL is invalid",
+            caller.type_info.as_ref().map_or("", |t| t.name.as_str()),
+            &caller.name
+        );
+        for diagnostic in diagnostics {
+            log::warn!("calltarget_rewriter_callback: {}", diagnostic);
         }
-        method
-            .insert(idx, Instruction::ldnull())
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
-    } else {
-        method
-            .insert(idx, Instruction::ldarg_0())
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
+        return Err(S_FALSE);
+    }
 
-        if type_info.is_value_type {
-            if type_info.type_spec != mdTypeSpecNil {
-                method
-                    .insert(idx, Instruction::ldobj(type_info.type_spec))
-                    .map_err(|_| S_FALSE)?;
-                idx += 1;
-            } else if !type_info.is_generic {
-                method
-                    .insert(idx, Instruction::ldobj(type_info.id))
-                    .map_err(|_| S_FALSE)?;
-                idx += 1;
-            } else {
-                // Generic struct instrumentation is not supported
-                // IMetaDataImport::GetMemberProps and IMetaDataImport::GetMemberRefProps returns
-                // The parent token as mdTypeDef and not as a mdTypeSpec
-                // that's because the method definition is stored in the mdTypeDef
-                // The problem is that we don't have the exact Spec of that generic
-                // We can't emit LoadObj or Box because that would result in an invalid IL.
-                // This problem doesn't occur on a class type because we can always relay in the
-                // object type.
-                return Err(S_FALSE);
-            }
+    let method_bytes = method.into_bytes();
+
+    // write the new IL
+    function_control
+        .set_il_function_body(&method_bytes)
+        .map_err(|e| {
+            log::warn!(
+                "calltarget_rewriter_callback: failed to set il function body for \
+            module_id={} function_token={}",
+                module_id,
+                function_token
+            );
+            e
+        })?;
+
+    // map the offsets of the original IL so that debuggers and stack traces stay accurate
+    let il_map = method.il_map();
+    if !il_map.is_empty() {
+        if let Err(e) = function_control.set_il_instrumented_code_map(&il_map) {
+            log::warn!(
+                "calltarget_rewriter_callback: failed to set il instrumented code map for \
+            module_id={} function_token={}. {:X}",
+                module_id,
+                function_token,
+                e
+            );
         }
     }
 
-    // insert instructions for loading arguments
-    if num_args < CallTargetTokens::FAST_PATH_COUNT as u8 {
-        // load arguments directly
-        for (i, method_argument) in method_arguments.iter().enumerate() {
-            let arg = if is_static { i } else { i + 1 };
-            method
-                .insert(idx, Instruction::load_argument(arg as u16))
-                .map_err(|_| S_FALSE)?;
-            idx += 1;
-            let (_, flags) = method_argument.get_type_flags();
-            if flags.contains(MethodArgumentTypeFlag::BY_REF) {
-                log::warn!("calltarget_rewriter_callback: methods with ref parameters cannot be instrumented");
+    log::info!("calltarget_rewriter_callback: finished {}() [is_void={}, is_static={}, integration_type={}, arguments={}]",
+        caller.full_name(),
+        is_void,
+        is_static,
+        &integration_types,
+        num_args
+    );
+
+    Ok(())
+}
+
+/// Instruments a target method, calling BeginMethod of each wrapper before the
+/// original method body and EndMethod of each wrapper in a finally block after it
+#[allow(clippy::too_many_arguments)]
+fn rewrite_target_method(
+    module_metadata: &ModuleMetadata,
+    module_wrapper_tokens: &mut ModuleWrapperTokens,
+    module_id: ModuleID,
+    method: Method,
+    caller: &FunctionInfo,
+    method_replacements: &[MethodReplacement],
+    profiler_info: &ICorProfilerInfo4,
+    call_target_tokens: &mut CallTargetTokens,
+) -> Result<Method, HRESULT> {
+    let parsed_function_method_signature = caller.method_signature.try_parse().unwrap();
+    let ret_func_arg = parsed_function_method_signature.return_type();
+    let (_, ret_type_flags) = ret_func_arg.get_type_flags();
+    let is_void = ret_type_flags.contains(MethodArgumentTypeFlag::VOID);
+    let is_static = !caller.signature.is_instance_method();
+    let method_arguments = parsed_function_method_signature.arguments();
+
+    let integration_type_refs = get_integration_type_refs(
+        module_metadata,
+        module_wrapper_tokens,
+        module_id,
+        method_replacements,
+        profiler_info,
+    )?;
+
+    // the instance of a method on a value type is loaded with its exact type, which
+    // is instantiated over the type's generic parameters for a generic value type.
+    // A static method on a value type is passed a default value of the type
+    let mut type_info = caller.type_info.clone().unwrap();
+    let mut static_instance = None;
+    if type_info.is_value_type {
+        let (instance_type, instance_token) =
+            helpers::value_type_instance(module_metadata, &type_info)?;
+        if instance_token != type_info.id {
+            type_info.type_spec = instance_token;
+        }
+        if is_static {
+            static_instance = Some((instance_type, helpers::operand_token(instance_token)?));
+        }
+    }
+    let type_info = &type_info;
+
+    let modify_arguments = get_modify_arguments(method_replacements);
+    let (local_sig, instructions) = call_target_tokens.modify_local_sig_and_initialize(
+        &method,
+        caller,
+        static_instance
+            .as_ref()
+            .map(|(instance_type, _)| instance_type),
+        integration_type_refs.len(),
+        modify_arguments.contains(&true),
+        module_metadata,
+    )?;
+
+    let cfg = method.control_flow_graph().map_err(|e| {
+        log::warn!(
+            "calltarget_rewriter_callback: could not build control flow graph. {:?}",
+            e
+        );
+        S_FALSE
+    })?;
+
+    // the instance of a reference type cannot be passed to BeginMethod in an instance
+    // constructor until a base constructor, or another constructor of the type, has been
+    // called on it, so BeginMethod is called after that call instead. The try block
+    // starts after the call, so the stack must be empty there
+    let base_constructor_call = if caller.name == ".ctor" && !is_static && !type_info.is_value_type
+    {
+        let stack_empty_after = |index: usize| {
+            matches!(
+                method.stack_depths(module_metadata, false),
+                Ok(stack_depths) if stack_depths.depths.get(index + 1) == Some(&Some(0))
+            )
+        };
+        match find_base_constructor_call(&method, type_info, module_metadata) {
+            Some(index) if cfg.falls_through_to(index + 1) && stack_empty_after(index) => {
+                Some(index)
+            }
+            _ => {
+                log::warn!(
+                    "calltarget_rewriter_callback: could not find a base constructor call that \
+                    all paths through {}.{} pass through with an empty stack",
+                    &type_info.name,
+                    &caller.name
+                );
                 return Err(S_FALSE);
             }
         }
     } else {
-        // load into an object array
-        method
-            .insert(idx, Instruction::load_int32(num_args as i32))
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
-        method
-            .insert(
-                idx,
-                Instruction::newarr(call_target_tokens.get_object_type_ref()),
-            )
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
-        for (i, method_argument) in method_arguments.iter().enumerate() {
-            method
-                .insert(idx, Instruction::dup())
-                .map_err(|_| S_FALSE)?;
-            idx += 1;
-            method
-                .insert(idx, Instruction::load_int32(i as i32))
-                .map_err(|_| S_FALSE)?;
-            idx += 1;
-
-            let arg = if is_static { i } else { i + 1 };
-            method
-                .insert(idx, Instruction::load_argument(arg as u16))
-                .map_err(|_| S_FALSE)?;
-            idx += 1;
+        None
+    };
 
-            let (_, flags) = method_argument.get_type_flags();
-            if flags.contains(MethodArgumentTypeFlag::BY_REF) {
-                log::warn!("calltarget_rewriter_callback: methods with ref parameters cannot be instrumented");
-                return Err(S_FALSE);
-            }
+    let mut builder = MethodBuilder::new();
+    builder.set_max_stack(method.header.max_stack());
+    // a method without locals has no init locals flag, but the new locals must
+    // be zero initialized
+    builder.set_init_locals(
+        method.header.init_locals() || is_nil_token(method.header.local_var_sig_tok()),
+    );
+    builder.set_local_var_sig_tok(local_sig.new_local_var_sig);
 
-            if flags.contains(MethodArgumentTypeFlag::BOXED_TYPE) {
-                let tok = method_argument
-                    .get_type_tok(meta_emit, call_target_tokens.get_cor_lib_assembly_ref())?;
-                if tok == mdTokenNil {
-                    return Err(S_FALSE);
-                }
-                method
-                    .insert(idx, Instruction::box_(tok))
-                    .map_err(|_| S_FALSE)?;
-                idx += 1;
-            }
+    let method_start = builder.define_label();
+    if base_constructor_call.is_none() {
+        builder.mark_label(method_start);
+    }
+    builder.emit_all(instructions);
 
-            method
-                .insert(idx, Instruction::stelem_ref())
-                .map_err(|_| S_FALSE)?;
-            idx += 1;
-        }
+    if let (Some(instance_index), Some((_, instance_token))) =
+        (local_sig.instance_index, &static_instance)
+    {
+        builder.emit(Instruction::load_local_address(instance_index));
+        builder.emit(Instruction::initobj(*instance_token));
     }
 
     if log::log_enabled!(Level::Trace) {
         log_caller_type_info(caller, type_info);
     }
 
-    let begin_method = call_target_tokens.write_begin_method(
-        wrapper_method_ref.type_ref,
-        type_info,
-        &method_arguments,
-        module_metadata,
-    )?;
-
-    method.insert(idx, begin_method).map_err(|_| S_FALSE)?;
-    idx += 1;
-
-    method
-        .insert(
-            idx,
-            Instruction::store_local(local_sig.call_target_state_index as u16),
+    let arguments = ArgumentSource::Arguments { is_static };
+    let ex_type_ref = call_target_tokens.get_ex_type_ref()?;
+    // only the states of wrappers that opt in are checked for skipping the method body
+    let skip_state_indexes = get_skip_method_body(method_replacements)
+        .into_iter()
+        .zip(&local_sig.call_target_state_indexes)
+        .filter(|(skip_method_body, _)| *skip_method_body)
+        .map(|(_, state_index)| *state_index)
+        .collect::<Vec<_>>();
+    let skip_method_body = if skip_state_indexes.is_empty() {
+        None
+    } else {
+        Some(
+            call_target_tokens
+                .get_call_target_state_skip_method_body_member_ref(module_metadata)?,
         )
-        .map_err(|_| S_FALSE)?;
-    idx += 1;
-
-    // Capture the idx so that we can update the instruction offset later
-    method
-        .insert(idx, Instruction::leave_s(-1))
-        .map_err(|_| S_FALSE)?;
-    let state_leave_to_begin_original_method_idx = idx;
-    idx += 1;
-
-    let log_exception = call_target_tokens.write_log_exception(
-        wrapper_method_ref.type_ref,
-        type_info,
-        module_metadata,
-    )?;
-
-    // clone the log exception instruction as we'll use the original later
-    method
-        .insert(idx, log_exception.clone())
-        .map_err(|_| S_FALSE)?;
-    let begin_method_log_exception_idx = idx;
-    idx += 1;
-
-    method
-        .insert(idx, Instruction::leave_s(0))
-        .map_err(|_| S_FALSE)?;
-    let begin_method_catch_leave_idx = idx;
-    idx += 1;
-
-    let offsets = method.get_instruction_offsets();
-
-    let begin_method_ex_clause = FatSectionClause {
-        flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
-        try_offset: 0,
-        try_length: offsets[begin_method_log_exception_idx] as u32,
-        handler_offset: offsets[begin_method_log_exception_idx] as u32,
-        handler_length: (offsets[begin_method_catch_leave_idx + 1]
-            - offsets[begin_method_log_exception_idx]) as u32,
-        class_token_or_filter_offset: call_target_tokens.get_ex_type_ref(),
     };
+    let method_return = builder.define_label();
+
+    // call BeginMethod of each wrapper in order, each in its own try block so
+    // that an exception thrown by one integration does not skip the others
+    let mut begin_methods = Vec::with_capacity(integration_type_refs.len());
+    for ((integration_type_ref, call_target_state_index), modify_arguments) in integration_type_refs
+        .iter()
+        .zip(&local_sig.call_target_state_indexes)
+        .zip(&modify_arguments)
+    {
+        let mut begin_method = Vec::new();
+        load_instance(&mut begin_method, is_static, type_info, &local_sig)?;
+        begin_method.extend(write_begin_method(
+            call_target_tokens,
+            *integration_type_ref,
+            type_info,
+            &method_arguments,
+            &arguments,
+            *modify_arguments,
+            &local_sig,
+            *call_target_state_index,
+            module_metadata,
+        )?);
+        let log_exception = call_target_tokens.write_log_exception(
+            *integration_type_ref,
+            type_info,
+            module_metadata,
+        )?;
+        begin_methods.push((begin_method, log_exception));
+    }
+
+    let mut begin_method_ex_clauses = Vec::new();
+    if base_constructor_call.is_none() {
+        begin_method_ex_clauses =
+            emit_integration_tries(&mut builder, method_start, &begin_methods, ex_type_ref);
+        if let Some(skip_method_body) = skip_method_body {
+            emit_skip_method_body(
+                &mut builder,
+                skip_method_body,
+                &skip_state_indexes,
+                method_return,
+            );
+        }
+    }
 
     // original method
 
-    // The idx of the start of the original instructions
-    let begin_original_method_idx = idx;
+    // change all original method ret instructions to leave.s or leave instructions
+    // pointing to the instruction before the ending ret instruction.
+    let return_points = cfg.return_points();
+    builder
+        .append_method_with(&method, |builder, index, instruction| {
+            if Some(index) == base_constructor_call {
+                builder.emit(instruction.clone());
+                builder.mark_label(method_start);
+                begin_method_ex_clauses =
+                    emit_integration_tries(builder, method_start, &begin_methods, ex_type_ref);
+                if let Some(skip_method_body) = skip_method_body {
+                    emit_skip_method_body(
+                        builder,
+                        skip_method_body,
+                        &skip_state_indexes,
+                        method_return,
+                    );
+                }
+                return true;
+            }
+            if !return_points.contains(&index) {
+                return false;
+            }
+            if let Some(return_value_index) = local_sig.return_value_index {
+                builder.emit(Instruction::store_local(return_value_index));
+            }
+            builder.emit_branch(LEAVE_S, method_return);
+            true
+        })
+        .map_err(|e| {
+            log::warn!(
+                "calltarget_rewriter_callback: could not append original method. {:?}",
+                e
+            );
+            S_FALSE
+        })?;
+
+    // write end
+
+    // store any original exception that might be thrown, so that we can capture it in our end method
+    let start_exception_catch = builder.mark_new_label();
+    builder.emit(Instruction::store_local(local_sig.exception_index));
 
-    // update the leave_s instruction offset to point to the original method
-    if let Some(state_leave) = method
-        .instructions
-        .get_mut(state_leave_to_begin_original_method_idx)
+    // then rethrow any original exception
+    builder.emit(Instruction::rethrow());
+
+    // call EndMethod of each wrapper in reverse order, each in its own try block
+    let mut end_methods = Vec::with_capacity(integration_type_refs.len());
+    for (integration_type_ref, call_target_state_index) in integration_type_refs
+        .iter()
+        .zip(&local_sig.call_target_state_indexes)
+        .rev()
     {
-        if let ShortInlineBrTarget(offset) = &mut state_leave.operand {
-            let state_offset = (offsets[begin_original_method_idx]
-                - offsets[state_leave_to_begin_original_method_idx + 1])
-                as u32;
-            *offset = state_offset as i8;
-        }
+        let mut end_method = Vec::new();
+        load_instance(&mut end_method, is_static, type_info, &local_sig)?;
+        end_method.extend(write_end_method(
+            call_target_tokens,
+            *integration_type_ref,
+            type_info,
+            &ret_func_arg,
+            &local_sig,
+            *call_target_state_index,
+            module_metadata,
+        )?);
+        let log_exception = call_target_tokens.write_log_exception(
+            *integration_type_ref,
+            type_info,
+            module_metadata,
+        )?;
+        end_methods.push((end_method, log_exception));
     }
 
-    // write end
+    let end_method_try_start = builder.mark_new_label();
+    let end_method_ex_clauses = emit_integration_tries(
+        &mut builder,
+        end_method_try_start,
+        &end_methods,
+        ex_type_ref,
+    );
+
+    builder.emit(Instruction::endfinally());
+
+    builder.mark_label(method_return);
+    if let Some(return_value_index) = local_sig.return_value_index {
+        builder.emit(Instruction::load_local(return_value_index));
+    }
 
     // add return instruction at the end
-    idx = method.instructions.len();
-    method
-        .insert(idx, Instruction::ret())
-        .map_err(|_| S_FALSE)?;
+    builder.emit(Instruction::ret());
 
-    // store any original exception that might be thrown, so that we can capture it in our end method
-    method
-        .insert(
-            idx,
-            Instruction::store_local(local_sig.exception_index as u16),
-        )
-        .map_err(|_| S_FALSE)?;
-    let mut start_exception_catch_idx = idx;
-    idx += 1;
+    // add the exception handling clauses to the method
+    for clause in begin_method_ex_clauses
+        .into_iter()
+        .chain(end_method_ex_clauses)
+    {
+        builder.add_clause(clause);
+    }
+    builder.add_clause(ExceptionClause {
+        kind: ClauseKind::Catch(ex_type_ref.token()),
+        try_start: method_start,
+        try_end: start_exception_catch,
+        handler_start: start_exception_catch,
+        handler_end: end_method_try_start,
+    });
+    builder.add_clause(ExceptionClause {
+        kind: ClauseKind::Finally,
+        try_start: method_start,
+        try_end: end_method_try_start,
+        handler_start: end_method_try_start,
+        handler_end: method_return,
+    });
+
+    builder.into_method().map_err(|e| {
+        log::warn!(
+            "calltarget_rewriter_callback: could not build method. {:?}",
+            e
+        );
+        S_FALSE
+    })
+}
 
-    // then rethrow any original exception
-    method
-        .insert(idx, Instruction::rethrow())
-        .map_err(|_| S_FALSE)?;
-    let mut rethrow_idx = idx;
-    idx += 1;
+/// The instrumentation of calls to a target method from a caller, shared by each
+/// call site of the target
+struct CallSite {
+    /// Stores the instance and arguments of the call, and initializes the locals
+    prologue: Vec<Instruction>,
+    /// The calls to BeginMethod of each wrapper, each with its exception logging
+    begin_methods: Vec<(Vec<Instruction>, Instruction)>,
+    /// Loads the instance and arguments for the original call
+    load_call: Vec<Instruction>,
+    return_value_index: Option<u16>,
+    exception_index: u16,
+    /// The calls to EndMethod of each wrapper in reverse order, each with its exception logging
+    end_methods: Vec<(Vec<Instruction>, Instruction)>,
+}
 
-    let mut end_method_try_start_idx = idx;
-    if is_static {
-        if type_info.is_value_type {
-            log::warn!("calltarget_rewriter_callback: static methods on value types cannot be instrumented");
-            return Err(S_FALSE);
+/// Instruments the calls from a caller to the targets of call site replacements,
+/// wrapping each call with calls to BeginMethod and EndMethod of the wrappers of
+/// its target, with the instance and arguments of the call stored in locals.
+///
+/// A call is only instrumented when the evaluation stack holds nothing but the instance
+/// and arguments of the call, so that it can be wrapped in exception handling blocks,
+/// and when it is not in a filter block or prefixed. Calls to instance methods are only
+/// instrumented when made with callvirt, which excludes calls to methods of value types.
+#[allow(clippy::too_many_arguments)]
+fn rewrite_call_sites(
+    module_metadata: &ModuleMetadata,
+    module_wrapper_tokens: &mut ModuleWrapperTokens,
+    module_id: ModuleID,
+    method: Method,
+    caller: &FunctionInfo,
+    call_site_replacements: &[MethodReplacement],
+    profiler_info: &ICorProfilerInfo4,
+    call_target_tokens: &mut CallTargetTokens,
+) -> Result<Method, HRESULT> {
+    let returns_value = {
+        let (_, flags) = caller
+            .method_signature
+            .try_parse()
+            .ok_or(S_FALSE)?
+            .return_type()
+            .get_type_flags();
+        !flags.contains(MethodArgumentTypeFlag::VOID)
+    };
+    let depths = method
+        .stack_depths(module_metadata, returns_value)
+        .map_err(|e| {
+            log::warn!(
+                "calltarget_rewriter_callback: could not compute stack depths of {}(). {:?}",
+                caller.full_name(),
+                e
+            );
+            S_FALSE
+        })?
+        .depths;
+    let filters = method
+        .exception_regions()
+        .map_err(|e| {
+            log::warn!(
+                "calltarget_rewriter_callback: could not read exception regions of {}(). {:?}",
+                caller.full_name(),
+                e
+            );
+            S_FALSE
+        })?
+        .into_iter()
+        .filter_map(|r| match r.handler {
+            HandlerKind::Filter(filter_start) => Some(filter_start..r.handler_start),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // the targets of calls, with the replacements instrumenting them
+    let mut targets: HashMap<mdToken, Option<(FunctionInfo, Vec<MethodReplacement>)>> =
+        HashMap::new();
+    let mut call_sites = Vec::new();
+    for (index, instruction) in method.instructions.iter().enumerate() {
+        if instruction.opcode != CALL && instruction.opcode != CALLVIRT {
+            continue;
         }
-        method
-            .insert(idx, Instruction::ldnull())
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
-    } else {
-        method
-            .insert(idx, Instruction::ldarg_0())
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
+        let token = match &instruction.operand {
+            InlineMethod(token) => token.token(),
+            _ => continue,
+        };
 
-        if type_info.is_value_type {
-            if type_info.type_spec != mdTypeSpecNil {
-                method
-                    .insert(idx, Instruction::ldobj(type_info.type_spec))
-                    .map_err(|_| S_FALSE)?;
-                idx += 1;
-            } else if !type_info.is_generic {
-                method
-                    .insert(idx, Instruction::ldobj(type_info.id))
-                    .map_err(|_| S_FALSE)?;
-                idx += 1;
+        let target = targets.entry(token).or_insert_with(|| {
+            let function_info = module_metadata.import.get_function_info(token).ok()?;
+            let replacements = call_site_replacements
+                .iter()
+                .filter(|m| {
+                    matches!(m.target(),
+                        Some(t) if helpers::is_call_site_target(module_metadata, t, &function_info))
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            if replacements.is_empty() {
+                None
             } else {
-                // Generic struct instrumentation is not supported
-                // IMetaDataImport::GetMemberProps and IMetaDataImport::GetMemberRefProps returns
-                // The parent token as mdTypeDef and not as a mdTypeSpec
-                // that's because the method definition is stored in the mdTypeDef
-                // The problem is that we don't have the exact Spec of that generic
-                // We can't emit LoadObj or Box because that would result in an invalid IL.
-                // This problem doesn't occur on a class type because we can always relay in the
-                // object type.
-                return Err(S_FALSE);
+                Some((function_info, replacements))
             }
+        });
+        let (function_info, _) = match target {
+            Some(t) => t,
+            None => continue,
+        };
+
+        let is_instance_method = function_info.signature.is_instance_method();
+        let stack_size =
+            function_info.signature.arguments_len() as usize + is_instance_method as usize;
+        let prefixed = index > 0 && {
+            let previous = method.instructions[index - 1].opcode;
+            previous == CONSTRAINED || previous == TAILCALL
+        };
+        if prefixed
+            || (is_instance_method && instruction.opcode != CALLVIRT)
+            || depths[index] != Some(stack_size)
+            || filters.iter().any(|f| f.contains(&index))
+        {
+            log::debug!(
+                "calltarget_rewriter_callback: skipping call to {}() at IL_{:04x} in {}(), which cannot be instrumented",
+                function_info.full_name(),
+                method.original_offset(index).unwrap_or_default(),
+                caller.full_name()
+            );
+            continue;
         }
+
+        call_sites.push((index, token));
     }
 
-    if !is_void {
-        method
-            .insert(
-                idx,
-                Instruction::load_local(local_sig.return_value_index as u16),
-            )
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
+    if call_sites.is_empty() {
+        return Ok(method);
     }
 
-    method
-        .insert(
-            idx,
-            Instruction::load_local(local_sig.exception_index as u16),
-        )
-        .map_err(|_| S_FALSE)?;
-    idx += 1;
-    method
-        .insert(
-            idx,
-            Instruction::load_local(local_sig.call_target_state_index as u16),
-        )
-        .map_err(|_| S_FALSE)?;
-    idx += 1;
+    let mut editor = LocalSigEditor::load(module_metadata, method.header.local_var_sig_tok())?;
+    let mut prepared: HashMap<mdToken, CallSite> = HashMap::new();
+    for (_, token) in &call_sites {
+        if prepared.contains_key(token) {
+            continue;
+        }
+        let (function_info, replacements) = targets[token].as_ref().unwrap();
+        let call_site = prepare_call_site(
+            module_metadata,
+            module_wrapper_tokens,
+            module_id,
+            &mut editor,
+            function_info,
+            replacements,
+            profiler_info,
+            call_target_tokens,
+        )?;
+        log::info!(
+            "calltarget_rewriter_callback: instrumenting calls to {}() from {}()",
+            function_info.full_name(),
+            caller.full_name()
+        );
+        prepared.insert(*token, call_site);
+    }
+    let call_sites: HashMap<usize, &CallSite> = call_sites
+        .into_iter()
+        .map(|(index, token)| (index, &prepared[&token]))
+        .collect();
+    let ex_type_ref = call_target_tokens.get_ex_type_ref()?;
+
+    let mut builder = MethodBuilder::new();
+    builder.set_max_stack(method.header.max_stack());
+    builder.set_init_locals(
+        method.header.init_locals() || is_nil_token(method.header.local_var_sig_tok()),
+    );
+    builder.set_local_var_sig_tok(editor.emit(module_metadata)?);
+    builder
+        .append_method_with(&method, |builder, index, instruction| {
+            match call_sites.get(&index) {
+                Some(call_site) => {
+                    emit_call_site(builder, call_site, instruction, ex_type_ref);
+                    true
+                }
+                None => false,
+            }
+        })
+        .map_err(|e| {
+            log::warn!(
+                "calltarget_rewriter_callback: could not append original method. {:?}",
+                e
+            );
+            S_FALSE
+        })?;
 
-    let end_method_call_instruction = if is_void {
-        call_target_tokens.write_end_void_return_member_ref(
-            wrapper_method_ref.type_ref,
+    builder.into_method().map_err(|e| {
+        log::warn!(
+            "calltarget_rewriter_callback: could not build method. {:?}",
+            e
+        );
+        S_FALSE
+    })
+}
+
+/// Prepares the instrumentation of calls to a target, adding its locals to the editor
+#[allow(clippy::too_many_arguments)]
+fn prepare_call_site(
+    module_metadata: &ModuleMetadata,
+    module_wrapper_tokens: &mut ModuleWrapperTokens,
+    module_id: ModuleID,
+    editor: &mut LocalSigEditor,
+    target: &FunctionInfo,
+    replacements: &[MethodReplacement],
+    profiler_info: &ICorProfilerInfo4,
+    call_target_tokens: &mut CallTargetTokens,
+) -> Result<CallSite, HRESULT> {
+    let mut replacements = replacements.to_vec();
+    replacements.sort_by_key(|m| std::cmp::Reverse(m.priority()));
+    let integration_type_refs = get_integration_type_refs(
+        module_metadata,
+        module_wrapper_tokens,
+        module_id,
+        &replacements,
+        profiler_info,
+    )?;
+
+    let modify_arguments = get_modify_arguments(&replacements);
+    let locals = call_target_tokens.create_call_site_locals(
+        editor,
+        target,
+        integration_type_refs.len(),
+        modify_arguments.contains(&true),
+        module_metadata,
+    )?;
+    let local_sig = &locals.local_sig;
+    let parsed_method = target.method_signature.try_parse().ok_or(S_FALSE)?;
+    let method_arguments = parsed_method.arguments();
+    let ret_func_arg = parsed_method.return_type();
+    let type_info = target.type_info.as_ref().ok_or(S_FALSE)?;
+    let arguments = ArgumentSource::Locals(&locals.argument_indexes);
+
+    let load_instance = match locals.instance_index {
+        Some(instance_index) => Instruction::load_local(instance_index),
+        None => Instruction::ldnull(),
+    };
+
+    // the arguments are popped in reverse order, followed by the instance
+    let mut prologue = Vec::new();
+    for argument_index in locals.argument_indexes.iter().rev() {
+        prologue.push(Instruction::store_local(*argument_index));
+    }
+    if let Some(instance_index) = locals.instance_index {
+        prologue.push(Instruction::store_local(instance_index));
+    }
+    // the locals are reused by each call, so are reset before each call
+    prologue.extend(call_target_tokens.initialize_locals(
+        local_sig,
+        &ret_func_arg,
+        module_metadata,
+    )?);
+    let call_target_state_default =
+        call_target_tokens.get_call_target_state_default_member_ref(module_metadata)?;
+    for call_target_state_index in &local_sig.call_target_state_indexes {
+        prologue.push(Instruction::call(call_target_state_default));
+        prologue.push(Instruction::store_local(*call_target_state_index));
+    }
+
+    let mut begin_methods = Vec::with_capacity(integration_type_refs.len());
+    let mut end_methods = Vec::with_capacity(integration_type_refs.len());
+    for ((integration_type_ref, call_target_state_index), modify_arguments) in integration_type_refs
+        .iter()
+        .zip(&local_sig.call_target_state_indexes)
+        .zip(&modify_arguments)
+    {
+        let mut begin_method = vec![load_instance.clone()];
+        begin_method.extend(write_begin_method(
+            call_target_tokens,
+            *integration_type_ref,
             type_info,
+            &method_arguments,
+            &arguments,
+            *modify_arguments,
+            local_sig,
+            *call_target_state_index,
             module_metadata,
-        )?
-    } else {
-        call_target_tokens.write_end_return_member_ref(
-            wrapper_method_ref.type_ref,
+        )?);
+        let mut end_method = vec![load_instance.clone()];
+        end_method.extend(write_end_method(
+            call_target_tokens,
+            *integration_type_ref,
             type_info,
             &ret_func_arg,
+            local_sig,
+            *call_target_state_index,
             module_metadata,
-        )?
-    };
+        )?);
+        let log_exception = call_target_tokens.write_log_exception(
+            *integration_type_ref,
+            type_info,
+            module_metadata,
+        )?;
+        begin_methods.push((begin_method, log_exception.clone()));
+        end_methods.push((end_method, log_exception));
+    }
+    end_methods.reverse();
 
-    method
-        .insert(idx, end_method_call_instruction)
-        .map_err(|_| S_FALSE)?;
-    idx += 1;
+    let mut load_call = Vec::with_capacity(locals.argument_indexes.len() + 1);
+    if let Some(instance_index) = locals.instance_index {
+        load_call.push(Instruction::load_local(instance_index));
+    }
+    for argument_index in &locals.argument_indexes {
+        load_call.push(Instruction::load_local(*argument_index));
+    }
 
-    method
-        .insert(
-            idx,
-            Instruction::store_local(local_sig.call_target_return_index as u16),
-        )
-        .map_err(|_| S_FALSE)?;
-    idx += 1;
-
-    if !is_void {
-        method
-            .insert(
-                idx,
-                Instruction::load_local_address(local_sig.call_target_return_index as u16),
-            )
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
+    Ok(CallSite {
+        prologue,
+        begin_methods,
+        load_call,
+        return_value_index: local_sig.return_value_index,
+        exception_index: local_sig.exception_index,
+        end_methods,
+    })
+}
 
-        let get_return_value_instruction = call_target_tokens
-            .write_call_target_return_get_return_value(
-                local_sig.call_target_return_token,
-                module_metadata,
-            )?;
-
-        method
-            .insert(idx, get_return_value_instruction)
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
-        method
-            .insert(
-                idx,
-                Instruction::store_local(local_sig.return_value_index as u16),
-            )
-            .map_err(|_| S_FALSE)?;
-        idx += 1;
-    }
-
-    method
-        .insert(idx, Instruction::leave_s(-1))
-        .map_err(|_| S_FALSE)?;
-    let mut end_method_try_leave_idx = idx;
-    idx += 1;
-
-    method.insert(idx, log_exception).map_err(|_| S_FALSE)?;
-    let mut end_method_catch_start_idx = idx;
-    idx += 1;
-
-    method
-        .insert(idx, Instruction::leave_s(0))
-        .map_err(|_| S_FALSE)?;
-    let mut end_method_catch_leave_idx = idx;
-    idx += 1;
-
-    method
-        .insert(idx, Instruction::endfinally())
-        .map_err(|_| S_FALSE)?;
-    let mut end_finally_idx = idx;
-    idx += 1;
-
-    if !is_void {
-        method
-            .insert(
-                idx,
-                Instruction::load_local(local_sig.return_value_index as u16),
-            )
-            .map_err(|_| S_FALSE)?;
+/// Emits an instrumented call in place of the original call instruction
+fn emit_call_site(
+    builder: &mut MethodBuilder,
+    call_site: &CallSite,
+    instruction: &Instruction,
+    ex_type_ref: TypeToken,
+) {
+    builder.emit_all(call_site.prologue.iter().cloned());
+
+    let begin_method_try_start = builder.mark_new_label();
+    let mut clauses = emit_integration_tries(
+        builder,
+        begin_method_try_start,
+        &call_site.begin_methods,
+        ex_type_ref,
+    );
+    let try_start = clauses
+        .last()
+        .map_or(begin_method_try_start, |clause| clause.handler_end);
+
+    // the original call, storing any exception for EndMethod before rethrowing it
+    let call_start = try_start;
+    let call_end = builder.define_label();
+    builder.emit_all(call_site.load_call.iter().cloned());
+    builder.emit(instruction.clone());
+    if let Some(return_value_index) = call_site.return_value_index {
+        builder.emit(Instruction::store_local(return_value_index));
+    }
+    builder.emit_branch(LEAVE_S, call_end);
+    let catch_start = builder.mark_new_label();
+    builder.emit(Instruction::store_local(call_site.exception_index));
+    builder.emit(Instruction::rethrow());
+
+    let finally_start = builder.mark_new_label();
+    clauses.extend(emit_integration_tries(
+        builder,
+        finally_start,
+        &call_site.end_methods,
+        ex_type_ref,
+    ));
+    builder.emit(Instruction::endfinally());
+
+    builder.mark_label(call_end);
+    if let Some(return_value_index) = call_site.return_value_index {
+        builder.emit(Instruction::load_local(return_value_index));
     }
 
-    let mut i = start_exception_catch_idx;
-    let mut added_instruction_count = 0;
+    for clause in clauses {
+        builder.add_clause(clause);
+    }
+    builder.add_clause(ExceptionClause {
+        kind: ClauseKind::Catch(ex_type_ref.token()),
+        try_start: call_start,
+        try_end: catch_start,
+        handler_start: catch_start,
+        handler_end: finally_start,
+    });
+    builder.add_clause(ExceptionClause {
+        kind: ClauseKind::Finally,
+        try_start: call_start,
+        try_end: finally_start,
+        handler_start: finally_start,
+        handler_end: call_end,
+    });
+}
 
-    // change all original method ret instructions to leave.s or leave instructions
-    // with an offset pointing to the instruction before the ending ret instruction.
-    while i > begin_original_method_idx {
-        if method.instructions[i].opcode != RET {
-            i -= 1;
-            continue;
-        }
+/// Emits each sequence of instructions in its own try block, the first starting at
+/// the label, whose handler logs any exception thrown by the integration. Returns the
+/// clauses of the try blocks, the handler end of each marking the start of the next
+fn emit_integration_tries(
+    builder: &mut MethodBuilder,
+    try_start: Label,
+    methods: &[(Vec<Instruction>, Instruction)],
+    ex_type_ref: TypeToken,
+) -> Vec<ExceptionClause> {
+    let mut clauses = Vec::with_capacity(methods.len());
+    let mut try_start = try_start;
+    for (instructions, log_exception) in methods {
+        let end = builder.define_label();
+        builder.emit_all(instructions.iter().cloned());
+        builder.emit_branch(LEAVE_S, end);
+
+        let catch_start = builder.mark_new_label();
+        builder.emit(log_exception.clone());
+        builder.emit_branch(LEAVE_S, end);
+        builder.mark_label(end);
+
+        clauses.push(ExceptionClause {
+            kind: ClauseKind::Catch(ex_type_ref.token()),
+            try_start,
+            try_end: catch_start,
+            handler_start: catch_start,
+            handler_end: end,
+        });
+        try_start = end;
+    }
+    clauses
+}
 
-        let mut current = i;
-
-        if !is_void {
-            // Since we're adding additional instructions to the original method,
-            // make a note of how many are added so that we can later increment the indices
-            // for instructions that are targets for clauses that come after the original
-            // method instructions
-            added_instruction_count += 1;
-            method
-                .insert(
-                    i,
-                    Instruction::store_local(local_sig.return_value_index as u16),
-                )
-                .map_err(|_| S_FALSE)?;
-
-            i -= 1;
-            current += 1;
+/// Emits a check of whether any of the states returned by BeginMethod skips the method
+/// body, leaving to the label when one does. The return value local keeps its default
+/// value, and EndMethod is still called by the finally block, the return value of which
+/// is the return value of the method
+fn emit_skip_method_body(
+    builder: &mut MethodBuilder,
+    skip_method_body: MethodToken,
+    state_indexes: &[u16],
+    method_return: Label,
+) {
+    for (i, state_index) in state_indexes.iter().enumerate() {
+        builder.emit(Instruction::load_local_address(*state_index));
+        builder.emit(Instruction::call(skip_method_body));
+        if i > 0 {
+            builder.emit(Instruction::or());
         }
+    }
 
-        // calculate the offset to the target instruction to determine whether to
-        // insert a leave_s or leave instruction
-        let leave_instr = {
-            let mut leave_offset = method
-                .instructions
-                .iter()
-                .skip(current + 1)
-                .map(|i| i.len())
-                .sum::<usize>()
-                - Instruction::ret().len();
-
-            if !is_void {
-                leave_offset -= Instruction::load_local(local_sig.return_value_index as u16).len();
-            }
+    let method_body = builder.define_label();
+    builder.emit_branch(BRFALSE_S, method_body);
+    builder.emit_branch(LEAVE_S, method_return);
+    builder.mark_label(method_body);
+}
 
-            if leave_offset > i8::MAX as usize {
-                Instruction::leave(leave_offset as i32)
-            } else {
-                Instruction::leave_s(leave_offset as i8)
+/// Gets the index of the call in an instance constructor to a constructor of the base
+/// type, or to another constructor of the type, which initializes the instance
+fn find_base_constructor_call(
+    method: &Method,
+    type_info: &TypeInfo,
+    module_metadata: &ModuleMetadata,
+) -> Option<usize> {
+    // the parent of the constructor is compared with the type, and its base type
+    let initializes_instance = |constructor_type: &TypeInfo| {
+        constructor_type.id == type_info.id
+            || matches!(&type_info.extends_from, Some(base_type)
+                if is_same_type(base_type, constructor_type, module_metadata))
+    };
+    method.instructions.iter().position(|instruction| {
+        instruction.opcode == CALL
+            && match &instruction.operand {
+                InlineMethod(token) => matches!(
+                    module_metadata.import.get_function_info(token.token()),
+                    Ok(FunctionInfo { name, type_info: Some(constructor_type), .. })
+                        if name == ".ctor" && initializes_instance(&constructor_type)
+                ),
+                _ => false,
             }
-        };
+    })
+}
 
-        method.replace(current, leave_instr).map_err(|_| S_FALSE)?;
-        i -= 1;
+/// Whether two resolved types are the same type. Generic instances are the same
+/// when their TypeSpec tokens or signatures are the same
+fn is_same_type(left: &TypeInfo, right: &TypeInfo, module_metadata: &ModuleMetadata) -> bool {
+    if left.id != right.id {
+        return false;
+    }
+    if left.type_spec == right.type_spec {
+        return true;
+    }
+    if is_nil_token(left.type_spec) || is_nil_token(right.type_spec) {
+        return false;
+    }
+    match (
+        module_metadata
+            .import
+            .get_type_spec_from_token(left.type_spec),
+        module_metadata
+            .import
+            .get_type_spec_from_token(right.type_spec),
+    ) {
+        (Ok(left), Ok(right)) => left.signature == right.signature,
+        _ => false,
     }
+}
 
-    if added_instruction_count > 0 {
-        start_exception_catch_idx += added_instruction_count;
-        rethrow_idx += added_instruction_count;
-        end_method_try_start_idx += added_instruction_count;
-        end_method_try_leave_idx += added_instruction_count;
-        end_method_catch_start_idx += added_instruction_count;
-        end_method_catch_leave_idx += added_instruction_count;
-        end_finally_idx += added_instruction_count;
+/// Gets the type ref of the integration type of the wrapper of each replacement
+fn get_integration_type_refs(
+    module_metadata: &ModuleMetadata,
+    module_wrapper_tokens: &mut ModuleWrapperTokens,
+    module_id: ModuleID,
+    method_replacements: &[MethodReplacement],
+    profiler_info: &ICorProfilerInfo4,
+) -> Result<Vec<TypeToken>, HRESULT> {
+    let mut integration_type_refs = Vec::with_capacity(method_replacements.len());
+    for method_replacement in method_replacements {
+        let wrapper = method_replacement.wrapper().unwrap();
+        let wrapper_method_key = wrapper.get_method_cache_key();
+        let wrapper_method_ref = process::get_wrapper_method_ref(
+            profiler_info,
+            module_metadata,
+            module_wrapper_tokens,
+            module_id,
+            wrapper,
+            &wrapper_method_key,
+        )?;
+        integration_type_refs.push(helpers::operand_token(wrapper_method_ref.type_ref)?);
     }
+    Ok(integration_type_refs)
+}
 
-    let offsets = method.get_instruction_offsets();
+/// Gets whether the wrapper of each replacement can skip the method body
+fn get_skip_method_body(method_replacements: &[MethodReplacement]) -> Vec<bool> {
+    method_replacements
+        .iter()
+        .map(|m| matches!(m.wrapper(), Some(wrapper) if wrapper.skip_method_body))
+        .collect()
+}
 
-    // update the end method leave_s instruction offset to point to the endfinally
-    if let Some(end_method_try_leave) = method.instructions.get_mut(end_method_try_leave_idx) {
-        if let ShortInlineBrTarget(offset) = &mut end_method_try_leave.operand {
-            let finally_offset =
-                (offsets[end_finally_idx] - offsets[end_method_try_leave_idx + 1]) as u32;
-            *offset = finally_offset as i8;
-        }
+/// Gets whether the wrapper of each replacement modifies arguments
+fn get_modify_arguments(method_replacements: &[MethodReplacement]) -> Vec<bool> {
+    method_replacements
+        .iter()
+        .map(|m| matches!(m.wrapper(), Some(wrapper) if wrapper.modify_arguments))
+        .collect()
+}
+
+/// Writes the call to BeginMethod of a wrapper, which follows the instance,
+/// storing the returned state. By-ref arguments passed in an object array
+/// are copied back after the call, as are all arguments when the wrapper
+/// modifies arguments
+#[allow(clippy::too_many_arguments)]
+fn write_begin_method(
+    call_target_tokens: &mut CallTargetTokens,
+    integration_type_ref: TypeToken,
+    type_info: &TypeInfo,
+    method_arguments: &[FunctionMethodArgument],
+    arguments: &ArgumentSource,
+    modify_arguments: bool,
+    local_sig: &LocalSig,
+    call_target_state_index: u16,
+    module_metadata: &ModuleMetadata,
+) -> Result<Vec<Instruction>, HRESULT> {
+    let mut instructions = Vec::new();
+    let copied_arguments = load_arguments(
+        &mut instructions,
+        method_arguments,
+        arguments,
+        modify_arguments,
+        local_sig,
+        call_target_tokens,
+        &module_metadata.emit,
+    )?;
+
+    instructions.push(call_target_tokens.write_begin_method(
+        integration_type_ref,
+        type_info,
+        method_arguments,
+        modify_arguments,
+        module_metadata,
+    )?);
+    instructions.push(Instruction::store_local(call_target_state_index));
+    if let Some(arguments_index) = local_sig.arguments_index {
+        instructions.extend(copy_back_arguments(
+            method_arguments,
+            arguments,
+            arguments_index,
+            &copied_arguments,
+        ));
     }
+    Ok(instructions)
+}
 
-    // create all the later clauses, now that all instructions are inserted
-    let end_method_ex_clause = {
-        FatSectionClause {
-            flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
-            try_offset: offsets[end_method_try_start_idx],
-            try_length: offsets[end_method_try_leave_idx + 1] - offsets[end_method_try_start_idx],
-            handler_offset: offsets[end_method_catch_start_idx],
-            handler_length: offsets[end_method_catch_leave_idx + 1]
-                - offsets[end_method_catch_start_idx],
-            class_token_or_filter_offset: call_target_tokens.get_ex_type_ref(),
+/// Copies the values of the arguments copied into the object array back to the arguments
+/// after BeginMethod, storing the values of by-ref arguments through the reference
+fn copy_back_arguments(
+    method_arguments: &[FunctionMethodArgument],
+    arguments: &ArgumentSource,
+    arguments_index: u16,
+    copied_arguments: &[(usize, TypeToken)],
+) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(copied_arguments.len() * 6);
+    for &(i, tok) in copied_arguments {
+        let by_ref = is_by_ref(&method_arguments[i]);
+        if by_ref {
+            instructions.push(arguments.load(i));
         }
-    };
+        instructions.push(Instruction::load_local(arguments_index));
+        instructions.push(Instruction::load_int32(i as i32));
+        instructions.push(Instruction::ldelem_ref());
+        instructions.push(Instruction::unbox_any(tok));
+        if by_ref {
+            instructions.push(Instruction::stobj(tok));
+        } else {
+            instructions.push(arguments.store(i));
+        }
+    }
+    instructions
+}
 
-    let ex_clause = FatSectionClause {
-        flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_NONE,
-        try_offset: 0,
-        try_length: offsets[start_exception_catch_idx],
-        handler_offset: offsets[start_exception_catch_idx],
-        handler_length: offsets[rethrow_idx + 1] - offsets[start_exception_catch_idx],
-        class_token_or_filter_offset: call_target_tokens.get_ex_type_ref(),
-    };
+/// Writes the call to EndMethod of a wrapper, which follows the instance, passing
+/// the return value, exception and state. The return value, possibly changed by
+/// the integration, is stored to be passed to the EndMethod of the next wrapper
+fn write_end_method(
+    call_target_tokens: &mut CallTargetTokens,
+    integration_type_ref: TypeToken,
+    type_info: &TypeInfo,
+    ret_func_arg: &FunctionMethodArgument,
+    local_sig: &LocalSig,
+    call_target_state_index: u16,
+    module_metadata: &ModuleMetadata,
+) -> Result<Vec<Instruction>, HRESULT> {
+    let mut instructions = Vec::new();
+    if let Some(return_value_index) = local_sig.return_value_index {
+        instructions.push(Instruction::load_local(return_value_index));
+    }
 
-    let finally_clause = FatSectionClause {
-        flag: CorExceptionFlag::COR_ILEXCEPTION_CLAUSE_FINALLY,
-        try_offset: 0,
-        try_length: offsets[rethrow_idx + 1],
-        handler_offset: offsets[rethrow_idx + 1],
-        handler_length: offsets[end_finally_idx + 1] - offsets[rethrow_idx + 1],
-        class_token_or_filter_offset: mdTokenNil,
+    instructions.push(Instruction::load_local(local_sig.exception_index));
+    instructions.push(Instruction::load_local(call_target_state_index));
+
+    let end_method_call_instruction = match local_sig.return_value_index {
+        None => call_target_tokens.write_end_void_return_member_ref(
+            integration_type_ref,
+            type_info,
+            module_metadata,
+        )?,
+        Some(_) => call_target_tokens.write_end_return_member_ref(
+            integration_type_ref,
+            type_info,
+            ret_func_arg,
+            module_metadata,
+        )?,
     };
 
-    // add the exception handling clauses to the method
-    method
-        .push_clauses(vec![
-            begin_method_ex_clause,
-            end_method_ex_clause,
-            ex_clause,
-            finally_clause,
-        ])
-        .map_err(|e| {
-            log::warn!("calltarget_rewriter_callback: could not add clauses to method");
-            S_FALSE
-        })?;
+    instructions.push(end_method_call_instruction);
+    instructions.push(Instruction::store_local(local_sig.call_target_return_index));
 
-    if *env::ELASTIC_APM_PROFILER_LOG_IL {
-        let modified_il = helpers::get_il_codes(
-            "IL modification for caller: ",
-            &method,
-            caller,
-            module_metadata,
+    if let Some(return_value_index) = local_sig.return_value_index {
+        instructions.push(Instruction::load_local_address(
+            local_sig.call_target_return_index,
+        ));
+        instructions.push(
+            call_target_tokens.write_call_target_return_get_return_value(
+                local_sig.call_target_return_token,
+                module_metadata,
+            )?,
         );
-        log::debug!("{}\n{}", original_il.unwrap_or_default(), modified_il);
+        instructions.push(Instruction::store_local(return_value_index));
     }
+    Ok(instructions)
+}
 
-    let method_bytes = method.into_bytes();
+/// Where the arguments passed to BeginMethod are loaded from
+enum ArgumentSource<'a> {
+    /// The arguments of the instrumented method
+    Arguments { is_static: bool },
+    /// The locals holding the arguments of an instrumented call
+    Locals(&'a [u16]),
+}
 
-    // write the new IL
-    function_control
-        .set_il_function_body(&method_bytes)
-        .map_err(|e| {
-            log::warn!(
-                "calltarget_rewriter_callback: failed to set il function body for \
-            module_id={} function_token={}",
-                module_id,
-                function_token
-            );
-            e
-        })?;
+impl ArgumentSource<'_> {
+    fn load(&self, i: usize) -> Instruction {
+        match self {
+            ArgumentSource::Arguments { is_static } => {
+                Instruction::load_argument((i + !is_static as usize) as u16)
+            }
+            ArgumentSource::Locals(indexes) => Instruction::load_local(indexes[i]),
+        }
+    }
 
-    log::info!("calltarget_rewriter_callback: finished {}() [is_void={}, is_static={}, integration_type={}, arguments={}]",
-        caller.full_name(),
-        is_void,
-        is_static,
-        &wrapper.type_name,
-        num_args
-    );
+    fn store(&self, i: usize) -> Instruction {
+        match self {
+            ArgumentSource::Arguments { is_static } => {
+                Instruction::store_argument((i + !is_static as usize) as u16)
+            }
+            ArgumentSource::Locals(indexes) => Instruction::store_local(indexes[i]),
+        }
+    }
+}
 
+/// Loads the arguments passed to BeginMethod, returning the index and type of the
+/// arguments copied into an object array that are copied back after BeginMethod
+fn load_arguments(
+    instructions: &mut Vec<Instruction>,
+    method_arguments: &[FunctionMethodArgument],
+    arguments: &ArgumentSource,
+    modify_arguments: bool,
+    local_sig: &LocalSig,
+    call_target_tokens: &mut CallTargetTokens,
+    meta_emit: &IMetaDataEmit2,
+) -> Result<Vec<(usize, TypeToken)>, HRESULT> {
+    let mut copied_arguments = Vec::new();
+    if !CallTargetTokens::uses_arguments_array(method_arguments, modify_arguments) {
+        // load arguments directly
+        for i in 0..method_arguments.len() {
+            instructions.push(arguments.load(i));
+        }
+    } else {
+        // load into an object array. The values of by-ref arguments are copied
+        // into the array, and copied back after BeginMethod, as are the values of
+        // all arguments when the wrapper modifies arguments
+        instructions.push(Instruction::load_int32(method_arguments.len() as i32));
+        instructions.push(Instruction::newarr(
+            call_target_tokens.get_object_type_ref()?,
+        ));
+        for (i, method_argument) in method_arguments.iter().enumerate() {
+            instructions.push(Instruction::dup());
+            instructions.push(Instruction::load_int32(i as i32));
+            instructions.push(arguments.load(i));
+
+            let (_, flags) = method_argument.get_type_flags();
+            if modify_arguments
+                || flags
+                    .intersects(MethodArgumentTypeFlag::BY_REF | MethodArgumentTypeFlag::BOXED_TYPE)
+            {
+                let tok = method_argument
+                    .get_type_tok(meta_emit, call_target_tokens.get_cor_lib_assembly_ref())?;
+                if tok == mdTokenNil {
+                    return Err(S_FALSE);
+                }
+                let tok = helpers::operand_token(tok)?;
+                if flags.contains(MethodArgumentTypeFlag::BY_REF) {
+                    instructions.push(Instruction::ldobj(tok));
+                }
+                if modify_arguments || flags.contains(MethodArgumentTypeFlag::BY_REF) {
+                    copied_arguments.push((i, tok));
+                }
+                if flags.contains(MethodArgumentTypeFlag::BOXED_TYPE) {
+                    instructions.push(Instruction::box_(tok));
+                }
+            }
+
+            instructions.push(Instruction::stelem_ref());
+        }
+
+        if let Some(arguments_index) = local_sig.arguments_index {
+            instructions.push(Instruction::dup());
+            instructions.push(Instruction::store_local(arguments_index));
+        }
+    }
+
+    Ok(copied_arguments)
+}
+
+/// Loads the instance passed to BeginMethod and EndMethod. A static method passes
+/// null, or the default value of a value type
+fn load_instance(
+    instructions: &mut Vec<Instruction>,
+    is_static: bool,
+    type_info: &TypeInfo,
+    local_sig: &LocalSig,
+) -> Result<(), HRESULT> {
+    if is_static {
+        match local_sig.instance_index {
+            Some(instance_index) => instructions.push(Instruction::load_local(instance_index)),
+            None => instructions.push(Instruction::ldnull()),
+        }
+    } else {
+        instructions.push(Instruction::ldarg_0());
+
+        if type_info.is_value_type {
+            let instance_token = if type_info.type_spec != mdTypeSpecNil {
+                type_info.type_spec
+            } else {
+                type_info.id
+            };
+            instructions.push(Instruction::ldobj(helpers::operand_token(instance_token)?));
+        }
+    }
     Ok(())
 }
 
@@ -903,3 +1578,119 @@ fn log_caller_type_info(caller: &FunctionInfo, type_info: &TypeInfo) {
 
     log::trace!("{}", s.join("\n"));
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{Instruction, TypeToken},
+        ffi::CorElementType,
+        profiler::{
+            calltarget_tokens::CallTargetTokens,
+            rejit::{add_by_priority, copy_back_arguments, ArgumentSource},
+            types::{FunctionMethodArgument, MethodReplacement},
+        },
+    };
+    use std::convert::TryFrom;
+
+    fn method_replacement(integration_type: &str, priority: i32) -> MethodReplacement {
+        serde_yml::from_str(&format!(
+            r#"---
+target:
+  assembly: System.Data
+  type: System.Data.Common.DbCommand
+  method: ExecuteNonQuery
+  signature_types:
+  - System.Int32
+  minimum_version: 4.0.0
+  maximum_version: 4.*.*
+wrapper:
+  assembly: Elastic.Apm.Profiler.Managed, Version=1.9.0.0, Culture=neutral, PublicKeyToken=ae7400d2c189cf22
+  type: {}
+  action: CallTargetModification
+priority: {}"#,
+            integration_type, priority
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn add_by_priority_orders_by_priority_then_insertion() {
+        let mut method_replacements = Vec::new();
+        for (integration_type, priority) in [("A", 0), ("B", 10), ("C", 0), ("A", 0), ("D", 10)] {
+            add_by_priority(
+                &mut method_replacements,
+                method_replacement(integration_type, priority),
+            );
+        }
+
+        let integration_types = method_replacements
+            .iter()
+            .map(|m| m.wrapper().unwrap().type_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(integration_types, vec!["B", "D", "A", "C"]);
+    }
+
+    #[test]
+    fn modified_arguments_are_copied_back_from_arguments_array() {
+        let int32 = [CorElementType::ELEMENT_TYPE_I4 as u8];
+        let int32_by_ref = [
+            CorElementType::ELEMENT_TYPE_BYREF as u8,
+            CorElementType::ELEMENT_TYPE_I4 as u8,
+        ];
+        let method_arguments = vec![
+            FunctionMethodArgument::new(&int32),
+            FunctionMethodArgument::new(&int32_by_ref),
+        ];
+
+        // a wrapper that modifies arguments, and a method with by-ref arguments, pass
+        // arguments in the object array, even with fewer than the fast path supports
+        assert!(CallTargetTokens::uses_arguments_array(
+            &method_arguments[..1],
+            true
+        ));
+        assert!(!CallTargetTokens::uses_arguments_array(
+            &method_arguments[..1],
+            false
+        ));
+        assert!(CallTargetTokens::uses_arguments_array(
+            &method_arguments,
+            false
+        ));
+        assert!(CallTargetTokens::uses_arguments_array(
+            &(0..CallTargetTokens::FAST_PATH_COUNT)
+                .map(|_| FunctionMethodArgument::new(&int32))
+                .collect::<Vec<_>>(),
+            false
+        ));
+        let tok = TypeToken::try_from(0x01000001).unwrap();
+
+        // the values in the array, changed by BeginMethod, are stored to the arguments
+        // of an instance method, and through the reference of a by-ref argument
+        let instructions = copy_back_arguments(
+            &method_arguments,
+            &ArgumentSource::Arguments { is_static: false },
+            3,
+            &[(0, tok), (1, tok)],
+        );
+        let expected = vec![
+            Instruction::ldloc_3(),
+            Instruction::ldc_i4_0(),
+            Instruction::ldelem_ref(),
+            Instruction::unbox_any(tok),
+            Instruction::starg_s(1),
+            Instruction::ldarg_2(),
+            Instruction::ldloc_3(),
+            Instruction::ldc_i4_1(),
+            Instruction::ldelem_ref(),
+            Instruction::unbox_any(tok),
+            Instruction::stobj(tok),
+        ];
+        let bytes = |instructions: &[Instruction]| {
+            instructions
+                .iter()
+                .flat_map(|i| i.into_bytes())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bytes(&instructions), bytes(&expected));
+    }
+}
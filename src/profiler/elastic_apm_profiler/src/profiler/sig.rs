@@ -4,56 +4,11 @@
 // See the LICENSE file in the project root for more information
 
 use crate::{
-    cil::{uncompress_data, uncompress_token},
-    ffi::{CorCallingConvention, CorElementType, E_FAIL, ULONG},
+    cil::{MethodSig, TypeSig},
+    ffi::{mdToken, type_from_token, CorTokenType, ULONG},
     interfaces::IMetaDataImport2,
-    profiler::types::{FunctionInfo, ModuleMetadata, TypeInfo},
+    profiler::types::{FunctionInfo, ModuleMetadata},
 };
-use com::sys::HRESULT;
-use num_traits::FromPrimitive;
-
-fn parse_type_def_or_ref_encoded(signature: &[u8]) -> Option<(ULONG, usize)> {
-    parse_number(signature)
-}
-
-fn parse_custom_mod(signature: &[u8]) -> Option<usize> {
-    if let Some(cor_element_type) = CorElementType::from_u8(signature[0]) {
-        match cor_element_type {
-            CorElementType::ELEMENT_TYPE_CMOD_OPT | CorElementType::ELEMENT_TYPE_CMOD_REQD => {
-                let idx = 1_usize;
-                if let Some((_, token_idx)) = parse_type_def_or_ref_encoded(&signature[idx..]) {
-                    Some(idx + token_idx)
-                } else {
-                    Some(idx)
-                }
-            }
-            _ => None,
-        }
-    } else {
-        None
-    }
-}
-
-fn parse_optional_custom_mods(signature: &[u8]) -> Option<usize> {
-    let mut idx = 0;
-
-    loop {
-        if let Some(cor_element_type) = CorElementType::from_u8(signature[idx]) {
-            match cor_element_type {
-                CorElementType::ELEMENT_TYPE_CMOD_OPT | CorElementType::ELEMENT_TYPE_CMOD_REQD => {
-                    if let Some(mod_idx) = parse_custom_mod(signature) {
-                        idx += mod_idx;
-                    } else {
-                        return None;
-                    }
-                }
-                _ => return Some(idx),
-            }
-        } else {
-            return Some(idx);
-        }
-    }
-}
 
 /// parses a number from a bytes slice.
 /// first value is the number
@@ -102,547 +57,406 @@ pub fn parse_number(signature: &[u8]) -> Option<(ULONG, usize)> {
     Some((out, 4_usize))
 }
 
-fn parse_return_type(signature: &[u8]) -> Option<usize> {
-    if let Some(mut idx) = parse_optional_custom_mods(signature) {
-        if let Some(cor_element_type) = CorElementType::from_u8(signature[idx]) {
-            match cor_element_type {
-                CorElementType::ELEMENT_TYPE_TYPEDBYREF | CorElementType::ELEMENT_TYPE_VOID => {
-                    idx += 1;
-                    return Some(idx);
-                }
-                CorElementType::ELEMENT_TYPE_BYREF => {
-                    idx += 1;
-                }
-                _ => {}
-            }
-        }
-
-        parse_type(&signature[idx..]).map(|i| idx + i)
-    } else {
-        None
-    }
+/// Gets the length of the type at the start of the signature
+pub fn parse_type(signature: &[u8]) -> Option<usize> {
+    TypeSig::parse(signature).ok().map(|(_, len)| len)
 }
 
-fn parse_method(signature: &[u8]) -> Option<usize> {
-    let mut idx = 0;
-    if let Some(CorCallingConvention::IMAGE_CEE_CS_CALLCONV_GENERIC) =
-        CorCallingConvention::from_bits(signature[idx])
-    {
-        idx += 1;
-        if let Some((_, gen_idx)) = parse_number(&signature[idx..]) {
-            idx += gen_idx;
-        } else {
+/// Gets the names of the return type and parameter types of a function, in the
+/// form matched against the `signature_types` of an integration target. The
+/// return type is the first name. See [render_type_name] for the form of the names.
+pub fn parse_signature_types(
+    module_metadata: &ModuleMetadata,
+    function_info: &FunctionInfo,
+) -> Option<Vec<String>> {
+    let method_sig = match MethodSig::from_bytes(function_info.signature.bytes()) {
+        Ok(m) => m,
+        Err(e) => {
+            log::warn!(
+                "Could not parse signature {:?}, {:?}",
+                function_info.signature.bytes(),
+                e
+            );
             return None;
         }
-    }
-
-    let params;
-    if let Some((params_len, param_idx)) = parse_number(&signature[idx..]) {
-        idx += param_idx;
-        params = params_len;
-    } else {
-        return None;
-    }
-
-    if let Some(ret_idx) = parse_return_type(&signature[idx..]) {
-        idx += ret_idx;
-    } else {
-        return None;
-    }
-
-    let mut sentinel_found = false;
-    for _ in 0..params {
-        if let Some(CorElementType::ELEMENT_TYPE_SENTINEL) = CorElementType::from_u8(signature[idx])
-        {
-            if sentinel_found {
-                return None;
-            }
-
-            sentinel_found = true;
-            idx += 1;
-        }
+    };
 
-        if let Some(param_idx) = parse_param(&signature[idx..]) {
-            idx += param_idx;
-        } else {
-            return None;
-        }
-    }
+    let resolve = |token| type_token_name(&module_metadata.import, token);
+    std::iter::once(&method_sig.return_type)
+        .chain(method_sig.params.iter())
+        .map(|type_sig| render_type_name(type_sig, &resolve))
+        .collect()
+}
 
-    Some(idx)
+/// Gets the name of a type in a signature of the module. See [render_type_name]
+/// for the form of the name.
+pub fn get_type_name(module_metadata: &ModuleMetadata, type_sig: &TypeSig) -> Option<String> {
+    render_type_name(type_sig, &|token| {
+        type_token_name(&module_metadata.import, token)
+    })
 }
 
-fn parse_param(signature: &[u8]) -> Option<usize> {
-    if let Some(mut idx) = parse_optional_custom_mods(signature) {
-        if let Some(cor_element_type) = CorElementType::from_u8(signature[idx]) {
-            match cor_element_type {
-                CorElementType::ELEMENT_TYPE_TYPEDBYREF => {
-                    idx += 1;
-                    return Some(idx);
-                }
-                CorElementType::ELEMENT_TYPE_BYREF => {
-                    idx += 1;
-                }
-                _ => {}
-            }
+/// Gets the name of the type at the start of the signature, and the length of
+/// the type in bytes. The name is empty when the type cannot be parsed or rendered.
+/// See [render_type_name] for the form of the name.
+pub fn get_sig_type_token_name(
+    signature: &[u8],
+    metadata_import: &IMetaDataImport2,
+) -> (String, usize) {
+    match TypeSig::parse(signature) {
+        Ok((type_sig, len)) => {
+            let resolve = |token| type_token_name(metadata_import, token);
+            (
+                render_type_name(&type_sig, &resolve).unwrap_or_default(),
+                len,
+            )
         }
-
-        parse_type(&signature[idx..]).map(|i| idx + i)
-    } else {
-        None
+        Err(_) => (String::new(), 0),
     }
 }
 
-fn parse_array_shape(signature: &[u8]) -> Option<usize> {
-    let mut idx = 0;
-
-    if let Some((_, rank_idx)) = parse_number(&signature[idx..]) {
-        idx += rank_idx as usize;
-    } else {
-        return None;
-    }
-
-    let num_sizes;
-    if let Some((num, numsize_idx)) = parse_number(&signature[idx..]) {
-        idx += numsize_idx;
-        num_sizes = num;
-    } else {
-        return None;
+/// Gets the full name of a TypeDef, TypeRef or TypeSpec token. Nested types are
+/// separated from their enclosing type with `+`.
+fn type_token_name(metadata_import: &IMetaDataImport2, token: mdToken) -> Option<String> {
+    let token_type = CorTokenType::from_bits(type_from_token(token))?;
+    if token_type == CorTokenType::mdtTypeSpec {
+        let type_spec = metadata_import.get_type_spec_from_token(token).ok()?;
+        let type_sig = TypeSig::from_bytes(&type_spec.signature).ok()?;
+        return render_type_name(&type_sig, &|t| type_token_name(metadata_import, t));
     }
 
-    for _ in 0..num_sizes {
-        if let Some((_, size_idx)) = parse_number(&signature[idx..]) {
-            idx += size_idx;
-        } else {
+    let mut name = match metadata_import.get_type_info(token) {
+        Ok(Some(type_info)) => type_info.name,
+        Ok(None) => {
+            log::warn!("None type info from token={}", token);
             return None;
         }
-    }
-
-    if let Some((_, size_idx)) = parse_number(&signature[idx..]) {
-        idx += size_idx;
-    } else {
-        return None;
-    }
-
-    for _ in 0..num_sizes {
-        if let Some((_, size_idx)) = parse_number(&signature[idx..]) {
-            idx += size_idx;
-        } else {
+        Err(e) => {
+            log::warn!("Could not get type info from token={}, {}", token, e);
             return None;
         }
+    };
+
+    if token_type == CorTokenType::mdtTypeDef {
+        let mut nested_token = token;
+        while let Ok(parent_token) = metadata_import.get_nested_class_props(nested_token) {
+            let parent = metadata_import.get_type_def_props(parent_token).ok()?;
+            name = format!("{}+{}", parent.name, name);
+            nested_token = parent_token;
+        }
+    } else if token_type == CorTokenType::mdtTypeRef {
+        // the resolution scope of a nested type reference is the enclosing type reference
+        let mut scope = metadata_import.get_type_ref_props(token).ok()?.parent_token;
+        while type_from_token(scope) == CorTokenType::mdtTypeRef.bits() {
+            let parent = metadata_import.get_type_ref_props(scope).ok()?;
+            name = format!("{}+{}", parent.name, name);
+            scope = parent.parent_token;
+        }
     }
 
-    Some(idx)
+    Some(name)
 }
 
-pub fn parse_type(signature: &[u8]) -> Option<usize> {
-    let mut idx = 0;
-    if let Some(cor_element_type) = CorElementType::from_u8(signature[idx]) {
-        idx += 1;
-
-        match cor_element_type {
-            CorElementType::ELEMENT_TYPE_VOID
-            | CorElementType::ELEMENT_TYPE_BOOLEAN
-            | CorElementType::ELEMENT_TYPE_CHAR
-            | CorElementType::ELEMENT_TYPE_I1
-            | CorElementType::ELEMENT_TYPE_U1
-            | CorElementType::ELEMENT_TYPE_I2
-            | CorElementType::ELEMENT_TYPE_U2
-            | CorElementType::ELEMENT_TYPE_I4
-            | CorElementType::ELEMENT_TYPE_U4
-            | CorElementType::ELEMENT_TYPE_I8
-            | CorElementType::ELEMENT_TYPE_U8
-            | CorElementType::ELEMENT_TYPE_R4
-            | CorElementType::ELEMENT_TYPE_R8
-            | CorElementType::ELEMENT_TYPE_STRING
-            | CorElementType::ELEMENT_TYPE_OBJECT => Some(idx),
-            CorElementType::ELEMENT_TYPE_PTR => {
-                if let Some(mods_idx) = parse_optional_custom_mods(&signature[idx..]) {
-                    idx += mods_idx;
-                    if let Some(CorElementType::ELEMENT_TYPE_VOID) =
-                        CorElementType::from_u8(signature[idx])
-                    {
-                        idx += 1;
-                        Some(idx)
-                    } else {
-                        parse_type(&signature[idx..]).map(|i| idx + i)
-                    }
-                } else {
-                    None
-                }
-            }
-            CorElementType::ELEMENT_TYPE_VALUETYPE | CorElementType::ELEMENT_TYPE_CLASS => {
-                parse_type_def_or_ref_encoded(&signature[idx..]).map(|(_, i)| idx + i)
-            }
-            CorElementType::ELEMENT_TYPE_FNPTR => parse_method(&signature[idx..]).map(|i| idx + i),
-            CorElementType::ELEMENT_TYPE_ARRAY => {
-                if let Some(type_idx) = parse_type(&signature[idx..]) {
-                    idx += type_idx;
-                } else {
-                    return None;
-                }
+/// Renders the name of a type in a signature, using `resolve` to get the full
+/// name of TypeDef, TypeRef and TypeSpec tokens. This is the form in which
+/// `signature_types` of an integration target are written:
+///
+/// - built-in types by their full name, such as `System.Int32`, `System.IntPtr`
+///   and `System.TypedReference`
+/// - classes and value types by their full name, with nested types separated
+///   from their enclosing type with `+`, such as `Namespace.Outer+Inner`
+/// - generic instances as the generic type followed by the type arguments in angle
+///   brackets, separated with `, `, such as ``System.Func`2<System.Int32, !!0>``
+/// - generic parameters by position, `!n` for the nth parameter of the type and
+///   `!!n` for the nth parameter of the method
+/// - single dimension arrays with `[]`, and multi-dimensional arrays with a comma
+///   between dimensions. A dimension with a lower bound and size is written as
+///   `lower...upper`, with only a lower bound as `lower...`, and with only a size
+///   as the size, such as `System.Int32[,]` or `System.Int32[0...3,5]`
+/// - unmanaged pointers with `*`, and managed pointers (ref, out and in) with a
+///   `ref ` prefix, such as `ref System.Int32`
+/// - pinned locals followed by ` pinned`, and custom modifiers followed by
+///   ` modreq(Type)` or ` modopt(Type)`
+/// - function pointers as `method ReturnType *(ParameterTypes)`
+///
+/// Returns `None` when a token cannot be resolved. See [signature_type_matches] for
+/// the other forms accepted in `signature_types`.
+pub fn render_type_name(
+    type_sig: &TypeSig,
+    resolve: &dyn Fn(mdToken) -> Option<String>,
+) -> Option<String> {
+    let mut name = String::new();
+    write_type_name(type_sig, resolve, &mut name)?;
+    Some(name)
+}
 
-                parse_array_shape(&signature[idx..]).map(|i| idx + i)
-            }
-            CorElementType::ELEMENT_TYPE_SZARRAY => {
-                if let Some(mods_idx) = parse_optional_custom_mods(&signature[idx..]) {
-                    idx += mods_idx;
-                    parse_type(&signature[idx..]).map(|i| idx + i)
-                } else {
-                    None
+fn write_type_name(
+    type_sig: &TypeSig,
+    resolve: &dyn Fn(mdToken) -> Option<String>,
+    name: &mut String,
+) -> Option<()> {
+    match type_sig {
+        TypeSig::Void => name.push_str("System.Void"),
+        TypeSig::Boolean => name.push_str("System.Boolean"),
+        TypeSig::Char => name.push_str("System.Char"),
+        TypeSig::I1 => name.push_str("System.SByte"),
+        TypeSig::U1 => name.push_str("System.Byte"),
+        TypeSig::I2 => name.push_str("System.Int16"),
+        TypeSig::U2 => name.push_str("System.UInt16"),
+        TypeSig::I4 => name.push_str("System.Int32"),
+        TypeSig::U4 => name.push_str("System.UInt32"),
+        TypeSig::I8 => name.push_str("System.Int64"),
+        TypeSig::U8 => name.push_str("System.UInt64"),
+        TypeSig::R4 => name.push_str("System.Single"),
+        TypeSig::R8 => name.push_str("System.Double"),
+        TypeSig::String => name.push_str("System.String"),
+        TypeSig::Object => name.push_str("System.Object"),
+        TypeSig::I => name.push_str("System.IntPtr"),
+        TypeSig::U => name.push_str("System.UIntPtr"),
+        TypeSig::TypedByRef => name.push_str("System.TypedReference"),
+        TypeSig::ValueType(token) | TypeSig::Class(token) => name.push_str(&resolve(*token)?),
+        TypeSig::GenericInst { token, args, .. } => {
+            name.push_str(&resolve(*token)?);
+            name.push('<');
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    name.push_str(", ");
                 }
+                write_type_name(arg, resolve, name)?;
             }
-            CorElementType::ELEMENT_TYPE_GENERICINST => {
-                if let Some(elem_type) = CorElementType::from_u8(signature[idx]) {
-                    match elem_type {
-                        CorElementType::ELEMENT_TYPE_VALUETYPE
-                        | CorElementType::ELEMENT_TYPE_CLASS => {
-                            idx += 1;
-
-                            if let Some((_, type_idx)) =
-                                parse_type_def_or_ref_encoded(&signature[idx..])
-                            {
-                                idx += type_idx;
-                            } else {
-                                return None;
-                            }
-
-                            let num;
-                            if let Some((num_size, num_idx)) = parse_number(&signature[idx..]) {
-                                idx += num_idx as usize;
-                                num = num_size;
-                            } else {
-                                return None;
-                            }
-
-                            for _ in 0..num {
-                                if let Some(type_idx) = parse_type(&signature[idx..]) {
-                                    idx += type_idx;
-                                } else {
-                                    return None;
-                                }
-                            }
-
-                            Some(idx)
-                        }
-                        _ => None,
-                    }
-                } else {
-                    None
-                }
-            }
-            CorElementType::ELEMENT_TYPE_VAR | CorElementType::ELEMENT_TYPE_MVAR => {
-                parse_number(&signature[idx..]).map(|(_, i)| idx + i)
-            }
-            _ => None,
+            name.push('>');
         }
-    } else {
-        None
-    }
-}
-
-fn retrieve_type_for_signature(
-    metadata_import: &IMetaDataImport2,
-    signature: &[u8],
-) -> Result<(TypeInfo, usize), HRESULT> {
-    let (token, len) = uncompress_token(signature);
-    match metadata_import.get_type_info(token) {
-        Ok(Some(type_info)) => Ok((type_info, len)),
-        Ok(None) => {
-            log::warn!(
-                "None type info from token={} in signature={:?}",
-                token,
-                signature
-            );
-            Err(E_FAIL)
+        TypeSig::Var(index) => name.push_str(&format!("!{}", index)),
+        TypeSig::MVar(index) => name.push_str(&format!("!!{}", index)),
+        TypeSig::Ptr(elem) => {
+            write_type_name(elem, resolve, name)?;
+            name.push('*');
         }
-        Err(e) => {
-            log::warn!(
-                "Could not get type info from token={} in signature={:?}, {}",
-                token,
-                signature,
-                e
-            );
-            Err(e)
+        TypeSig::ByRef(elem) => {
+            name.push_str("ref ");
+            write_type_name(elem, resolve, name)?;
         }
-    }
-}
-
-pub fn parse_signature_types(
-    module_metadata: &ModuleMetadata,
-    function_info: &FunctionInfo,
-) -> Option<Vec<String>> {
-    let signature = &function_info.signature;
-    let signature_size = signature.len();
-    let generic_count = signature.type_arguments_len();
-    let param_count = signature.arguments_len();
-    let start_index = if generic_count > 0 { 3 } else { 2 };
-
-    let expected_number_of_types = param_count + 1;
-    let mut current_type_index = 0;
-    let mut type_names = Vec::with_capacity(expected_number_of_types as usize);
-
-    let mut generic_arg_stack = Vec::new();
-    let mut append_to_type = String::new();
-    let mut current_type_name = String::new();
-
-    let signature = signature.bytes();
-    let params = &signature[start_index..];
-    let mut enumerator = params.iter().enumerate().peekable();
-
-    while let Some((i, param_piece)) = enumerator.next() {
-        let cor_element_type: CorElementType = CorElementType::from_u8(*param_piece)?;
-        match cor_element_type {
-            CorElementType::ELEMENT_TYPE_END => {
-                continue;
-            }
-            CorElementType::ELEMENT_TYPE_VOID => current_type_name.push_str("System.Void"),
-            CorElementType::ELEMENT_TYPE_BOOLEAN => current_type_name.push_str("System.Boolean"),
-            CorElementType::ELEMENT_TYPE_CHAR => current_type_name.push_str("System.Char"),
-            CorElementType::ELEMENT_TYPE_I1 => current_type_name.push_str("System.SByte"),
-            CorElementType::ELEMENT_TYPE_U1 => current_type_name.push_str("System.Byte"),
-            CorElementType::ELEMENT_TYPE_I2 => current_type_name.push_str("System.Int16"),
-            CorElementType::ELEMENT_TYPE_U2 => current_type_name.push_str("System.UInt16"),
-            CorElementType::ELEMENT_TYPE_I4 => current_type_name.push_str("System.Int32"),
-            CorElementType::ELEMENT_TYPE_U4 => current_type_name.push_str("System.UInt32"),
-            CorElementType::ELEMENT_TYPE_I8 => current_type_name.push_str("System.Int64"),
-            CorElementType::ELEMENT_TYPE_U8 => current_type_name.push_str("System.UInt64"),
-            CorElementType::ELEMENT_TYPE_R4 => current_type_name.push_str("System.Single"),
-            CorElementType::ELEMENT_TYPE_R8 => current_type_name.push_str("System.Double"),
-            CorElementType::ELEMENT_TYPE_STRING => current_type_name.push_str("System.String"),
-            CorElementType::ELEMENT_TYPE_VALUETYPE | CorElementType::ELEMENT_TYPE_CLASS => {
-                let (j, next) = enumerator.next().unwrap();
-                let type_info = retrieve_type_for_signature(&module_metadata.import, &params[j..]);
-                if type_info.is_err() {
-                    return None;
+        TypeSig::SzArray(elem) => {
+            write_type_name(elem, resolve, name)?;
+            name.push_str("[]");
+        }
+        TypeSig::Array(elem, shape) => {
+            write_type_name(elem, resolve, name)?;
+            name.push('[');
+            for i in 0..shape.rank as usize {
+                if i > 0 {
+                    name.push(',');
                 }
-                let (type_data, len) = type_info.unwrap();
-                let mut examined_type_token = type_data.id;
-                let mut examined_type_name = type_data.name;
-                let mut ongoing_type_name = examined_type_name.to_string();
-
-                // check for nested class
-                while examined_type_name.contains('.') {
-                    let parent_token = module_metadata
-                        .import
-                        .get_nested_class_props(examined_type_token);
-                    if parent_token.is_err() {
-                        break;
-                    }
-                    let parent_token = parent_token.unwrap();
-                    let nesting_type = module_metadata.import.get_type_info(parent_token);
-                    if nesting_type.is_err() {
-                        log::warn!(
-                            "Could not retrieve type info for parent token {}",
-                            parent_token
-                        );
-                        return None;
+                match (shape.lower_bounds.get(i), shape.sizes.get(i)) {
+                    (Some(lower), Some(size)) => {
+                        name.push_str(&format!("{}...{}", lower, *lower as i64 + *size as i64 - 1))
                     }
-                    let nesting_type = match nesting_type.unwrap() {
-                        Some(n) => n,
-                        None => {
-                            return None;
-                        }
-                    };
-
-                    examined_type_token = nesting_type.id;
-                    examined_type_name = nesting_type.name;
-                    ongoing_type_name = format!("{}+{}", &examined_type_name, &ongoing_type_name);
-                }
-
-                // skip len number of items
-                for _ in 0..(len - 1) {
-                    enumerator.next();
+                    (Some(lower), None) => name.push_str(&format!("{}...", lower)),
+                    (None, Some(size)) => name.push_str(&size.to_string()),
+                    (None, None) => {}
                 }
-                current_type_name.push_str(&ongoing_type_name);
-            }
-            CorElementType::ELEMENT_TYPE_BYREF => current_type_name.push_str("ref"),
-            CorElementType::ELEMENT_TYPE_GENERICINST => {
-                // skip generic type indicator token
-                let _ = enumerator.next();
-                // skip generic type token
-                let (j, next) = enumerator.next().unwrap();
-                let generic_type_info =
-                    retrieve_type_for_signature(&module_metadata.import, &params[j..]);
-                if generic_type_info.is_err() {
-                    return None;
-                }
-                let (generic_type_info, len) = generic_type_info.unwrap();
-                let type_name = &generic_type_info.name;
-                current_type_name.push_str(type_name);
-                current_type_name.push('<');
-
-                if !generic_arg_stack.is_empty() {
-                    generic_arg_stack[0] -= 1;
-                }
-
-                let arity_index = type_name.rfind('`').unwrap();
-                let actual_args = type_name[(arity_index + 1)..].parse::<i32>().unwrap();
-                generic_arg_stack.insert(0, actual_args);
-
-                for _ in 0..len {
-                    enumerator.next();
-                }
-                continue;
-            }
-            CorElementType::ELEMENT_TYPE_OBJECT => current_type_name.push_str("System.Object"),
-            CorElementType::ELEMENT_TYPE_SZARRAY => {
-                append_to_type.push_str("[]");
-                while let Some((j, next)) = enumerator.peek() {
-                    // check it's an array element type
-                    if let Some(CorElementType::ELEMENT_TYPE_SZARRAY) =
-                        CorElementType::from_u8(**next)
-                    {
-                        append_to_type.push_str("[]");
-                        enumerator.next();
-                    } else {
-                        break;
-                    }
-                }
-
-                continue;
-            }
-            CorElementType::ELEMENT_TYPE_MVAR | CorElementType::ELEMENT_TYPE_VAR => {
-                let (token, len) = uncompress_token(&params[i..]);
-                current_type_name.push('T');
-                // skip len number of items
-                for _ in 0..len {
-                    enumerator.next();
-                }
-                // TODO: implement conventions for generics (eg., TC1, TC2, TM1, TM2)
-                // current_type_name.push(type_token.to_string());
-            }
-            _ => {
-                log::warn!("Unexpected element type: {:?}", cor_element_type);
-                current_type_name.push_str(&format!("{}", cor_element_type as u32));
-            }
-        }
-
-        if !append_to_type.is_empty() {
-            current_type_name.push_str(&append_to_type);
-            append_to_type = String::new();
-        }
-
-        if !generic_arg_stack.is_empty() {
-            generic_arg_stack[0] -= 1;
-
-            if generic_arg_stack[0] > 0 {
-                current_type_name.push_str(", ");
             }
+            name.push(']');
         }
-
-        while !generic_arg_stack.is_empty() && generic_arg_stack[0] == 0 {
-            generic_arg_stack.remove(0);
-            current_type_name.push('>');
-
-            if !generic_arg_stack.is_empty() && generic_arg_stack[0] > 0 {
-                current_type_name.push_str(", ");
+        TypeSig::FnPtr(method_sig) => {
+            name.push_str("method ");
+            write_type_name(&method_sig.return_type, resolve, name)?;
+            name.push_str(" *(");
+            for (i, param) in method_sig.params.iter().enumerate() {
+                if i > 0 {
+                    name.push(',');
+                }
+                write_type_name(param, resolve, name)?;
             }
+            name.push(')');
         }
-
-        if !generic_arg_stack.is_empty() {
-            continue;
+        TypeSig::Modified(modifier, elem) => {
+            write_type_name(elem, resolve, name)?;
+            name.push_str(if modifier.required {
+                " modreq("
+            } else {
+                " modopt("
+            });
+            name.push_str(&resolve(modifier.token)?);
+            name.push(')');
         }
-
-        if current_type_index >= expected_number_of_types {
-            return None;
+        TypeSig::Pinned(elem) => {
+            write_type_name(elem, resolve, name)?;
+            name.push_str(" pinned");
         }
-
-        type_names.push(current_type_name);
-        current_type_name = String::new();
-        current_type_index += 1;
     }
 
-    Some(type_names)
+    Some(())
 }
 
-pub fn get_sig_type_token_name(
-    signature: &[u8],
-    metadata_import: &IMetaDataImport2,
-) -> (String, usize) {
-    let mut token_name = String::new();
-    let mut ref_flag = false;
-    let start_idx = 0;
-    let mut idx = start_idx;
-
-    if signature[idx] == CorElementType::ELEMENT_TYPE_BYREF as u8 {
-        idx += 1;
-        ref_flag = true;
-    }
+/// Whether a type name in the `signature_types` of an integration target matches the
+/// name of a type rendered by [render_type_name]. Besides the rendered form, the name
+/// can be written
+///
+/// - with the type arguments of generic instances in square brackets, separated with
+///   `,`, such as ``System.Func`2[System.Int32,!!0]``
+/// - with a `&` suffix for managed pointers, such as `System.Int32&`
+/// - with `T` for any generic parameter, such as ``System.Threading.Tasks.Task`1<T>``
+pub fn signature_type_matches(expected: &str, actual: &str) -> bool {
+    let expected = normalize_type_name(expected);
+    expected == actual || expected == any_generic_parameter(actual)
+}
 
-    if let Some(elem_type) = CorElementType::from_u8(signature[idx]) {
-        idx += 1;
-        match elem_type {
-            CorElementType::ELEMENT_TYPE_BOOLEAN => token_name.push_str("System.Boolean"),
-            CorElementType::ELEMENT_TYPE_CHAR => token_name.push_str("System.Char"),
-            CorElementType::ELEMENT_TYPE_I1 => token_name.push_str("System.SByte"),
-            CorElementType::ELEMENT_TYPE_U1 => token_name.push_str("System.Byte"),
-            CorElementType::ELEMENT_TYPE_I2 => token_name.push_str("System.Int16"),
-            CorElementType::ELEMENT_TYPE_U2 => token_name.push_str("System.UInt16"),
-            CorElementType::ELEMENT_TYPE_I4 => token_name.push_str("System.Int32"),
-            CorElementType::ELEMENT_TYPE_U4 => token_name.push_str("System.UInt32"),
-            CorElementType::ELEMENT_TYPE_I8 => token_name.push_str("System.Int64"),
-            CorElementType::ELEMENT_TYPE_U8 => token_name.push_str("System.UInt64"),
-            CorElementType::ELEMENT_TYPE_R4 => token_name.push_str("System.Single"),
-            CorElementType::ELEMENT_TYPE_R8 => token_name.push_str("System.Double"),
-            CorElementType::ELEMENT_TYPE_STRING => token_name.push_str("System.String"),
-            CorElementType::ELEMENT_TYPE_OBJECT => token_name.push_str("System.Object"),
-            CorElementType::ELEMENT_TYPE_CLASS | CorElementType::ELEMENT_TYPE_VALUETYPE => {
-                let (token, len) = uncompress_token(&signature[idx..]);
-                idx += len;
-                if let Ok(Some(type_info)) = metadata_import.get_type_info(token) {
-                    token_name.push_str(&type_info.name);
-                }
-            }
-            CorElementType::ELEMENT_TYPE_SZARRAY => {
-                let (elem, len) = get_sig_type_token_name(&signature[idx..], metadata_import);
-                idx += len;
-                token_name.push_str(&elem);
-                token_name.push_str("[]");
+/// Normalizes a type name written with square brackets for type arguments or a `&`
+/// suffix for managed pointers to the form rendered by [render_type_name]
+fn normalize_type_name(name: &str) -> String {
+    let name = name.trim();
+    let mut normalized = String::with_capacity(name.len() + 4);
+    let name = match name.strip_suffix('&') {
+        Some(element) => {
+            normalized.push_str("ref ");
+            element.trim_end()
+        }
+        None => name,
+    };
+
+    // whether each open bracket encloses type arguments, rather than array dimensions
+    let mut brackets = Vec::new();
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '<' | '[' => {
+                // type arguments follow the arity of a generic type, such as Func`2
+                let arity = normalized.trim_end_matches(|c: char| c.is_ascii_digit());
+                let type_arguments =
+                    c == '<' || (arity.len() < normalized.len() && arity.ends_with('`'));
+                brackets.push(type_arguments);
+                normalized.push(if type_arguments { '<' } else { '[' });
             }
-            CorElementType::ELEMENT_TYPE_GENERICINST => {
-                let (elem, len) = get_sig_type_token_name(&signature[idx..], metadata_import);
-                idx += len;
-                token_name.push_str(&elem);
-                token_name.push('[');
-
-                if let Some((data, len)) = uncompress_data(&signature[idx..]) {
-                    idx += len;
-                    for i in 0..data {
-                        let (elem, end_idx) =
-                            get_sig_type_token_name(&signature[idx..], metadata_import);
-                        idx += end_idx;
-                        token_name.push_str(&elem);
-                        if i != (data - 1) {
-                            token_name.push(',');
-                        }
-                    }
-                }
-                token_name.push(']');
+            '>' | ']' => {
+                let type_arguments = brackets.pop().unwrap_or(c == '>');
+                normalized.push(if type_arguments { '>' } else { ']' });
             }
-            CorElementType::ELEMENT_TYPE_MVAR => {
-                if let Some((data, len)) = uncompress_data(&signature[idx..]) {
-                    idx += len;
-                    token_name.push_str("!!");
-                    token_name.push_str(&format!("{}", data));
+            ',' if brackets.last() == Some(&true) => {
+                normalized.push_str(", ");
+                while chars.peek() == Some(&' ') {
+                    chars.next();
                 }
             }
-            CorElementType::ELEMENT_TYPE_VAR => {
-                if let Some((data, len)) = uncompress_data(&signature[idx..]) {
-                    idx += len;
-                    token_name.push('!');
-                    token_name.push_str(&format!("{}", data));
-                }
+            c => normalized.push(c),
+        }
+    }
+
+    normalized
+}
+
+/// Replaces the generic parameters of a rendered type name with `T`
+fn any_generic_parameter(name: &str) -> String {
+    let mut replaced = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '!' {
+            while matches!(chars.peek(), Some(c) if *c == '!' || c.is_ascii_digit()) {
+                chars.next();
             }
-            _ => {}
+            replaced.push('T');
+        } else {
+            replaced.push(c);
         }
-    } else {
-        return (token_name, idx);
     }
+    replaced
+}
 
-    if ref_flag {
-        token_name.push('&');
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cil::{ArrayShape, CustomMod, MethodSig, TypeSig},
+        ffi::CorCallingConvention,
+        profiler::sig::{render_type_name, signature_type_matches},
+    };
+
+    #[test]
+    fn render_type_names() {
+        let resolve = |token| match token {
+            0x01000001 => Some("System.Collections.Generic.Dictionary`2".to_string()),
+            0x02000002 => Some("Namespace.Outer+Inner".to_string()),
+            0x01000003 => Some("System.Runtime.CompilerServices.IsVolatile".to_string()),
+            _ => None,
+        };
+        let render = |type_sig: TypeSig| render_type_name(&type_sig, &resolve).unwrap();
+
+        assert_eq!(
+            render(TypeSig::ByRef(Box::new(TypeSig::generic_inst(
+                false,
+                0x01000001,
+                vec![
+                    TypeSig::Var(0),
+                    TypeSig::SzArray(Box::new(TypeSig::generic_inst(
+                        false,
+                        0x01000001,
+                        vec![TypeSig::MVar(1), TypeSig::type_token(true, 0x02000002)],
+                    ))),
+                ],
+            )))),
+            "ref System.Collections.Generic.Dictionary`2<!0, System.Collections.Generic.Dictionary`2<!!1, Namespace.Outer+Inner>[]>"
+        );
+        assert_eq!(
+            render(TypeSig::Array(
+                Box::new(TypeSig::I4),
+                ArrayShape {
+                    rank: 3,
+                    sizes: vec![4],
+                    lower_bounds: vec![0, -1],
+                }
+            )),
+            "System.Int32[0...3,-1...,]"
+        );
+        assert_eq!(
+            render(TypeSig::Pinned(Box::new(TypeSig::Ptr(Box::new(
+                TypeSig::Modified(
+                    CustomMod {
+                        required: true,
+                        token: 0x01000003
+                    },
+                    Box::new(TypeSig::U1)
+                )
+            ))))),
+            "System.Byte modreq(System.Runtime.CompilerServices.IsVolatile)* pinned"
+        );
+        assert_eq!(
+            render(TypeSig::FnPtr(Box::new(MethodSig::new(
+                CorCallingConvention::IMAGE_CEE_CS_CALLCONV_DEFAULT,
+                TypeSig::Void,
+                vec![TypeSig::I, TypeSig::String],
+            )))),
+            "method System.Void *(System.IntPtr,System.String)"
+        );
+        assert!(render_type_name(&TypeSig::Class(0x01000004), &resolve).is_none());
     }
 
-    (token_name, idx)
+    #[test]
+    fn match_signature_types() {
+        let actual = "ref System.Collections.Generic.Dictionary`2<!0, System.Action`1<!!1>[]>";
+        assert!(signature_type_matches(actual, actual));
+        assert!(signature_type_matches(
+            "System.Collections.Generic.Dictionary`2[!0,System.Action`1[!!1][]]&",
+            actual
+        ));
+        assert!(signature_type_matches(
+            "ref System.Collections.Generic.Dictionary`2<T, System.Action`1<T>[]>",
+            actual
+        ));
+        assert!(!signature_type_matches(
+            "System.Collections.Generic.Dictionary`2<!0, System.Action`1<!!1>[]>",
+            actual
+        ));
+        assert!(!signature_type_matches(
+            "ref System.Collections.Generic.Dictionary`2<!1, System.Action`1<!!1>[]>",
+            actual
+        ));
+
+        assert!(signature_type_matches(
+            "System.Threading.Tasks.Task`1<System.Int32>",
+            "System.Threading.Tasks.Task`1<System.Int32>"
+        ));
+        assert!(signature_type_matches(
+            "System.Int32[0...3,-1...,]",
+            "System.Int32[0...3,-1...,]"
+        ));
+    }
 }
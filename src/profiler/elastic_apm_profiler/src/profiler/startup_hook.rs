@@ -4,13 +4,17 @@
 // See the LICENSE file in the project root for more information
 
 use crate::{
-    cil::{compress_token, Instruction, Method, MethodHeader},
+    cil::{compress_token, Instruction, Method, MethodBuilder, Verifier, BRFALSE_S},
     ffi::{
         mdMethodDef, mdToken, CorCallingConvention, CorElementType, CorFieldAttr, CorMethodAttr,
-        CorMethodImpl, CorPinvokeMap, CorTypeAttr, ModuleID, COR_SIGNATURE, E_FAIL, ULONG,
+        CorMethodImpl, CorPinvokeMap, CorTypeAttr, FunctionID, ModuleID, COR_SIGNATURE, E_FAIL,
+        ULONG,
     },
     interfaces::ICorProfilerInfo4,
-    profiler::{env, helpers, managed, types::ModuleMetadata},
+    profiler::{
+        env, helpers, managed,
+        types::{MethodArgumentTypeFlag, ModuleMetadata},
+    },
 };
 use com::sys::HRESULT;
 
@@ -20,6 +24,7 @@ use com::sys::HRESULT;
 pub fn run_il_startup_hook(
     profiler_info: &ICorProfilerInfo4,
     module_metadata: &ModuleMetadata,
+    function_id: FunctionID,
     module_id: ModuleID,
     function_token: mdToken,
 ) -> Result<(), HRESULT> {
@@ -32,12 +37,32 @@ pub fn run_il_startup_hook(
     })?;
 
     method
-        .insert_prelude(vec![Instruction::call(startup_method_def)])
+        .insert_prelude(vec![Instruction::call(helpers::operand_token(
+            startup_method_def,
+        )?)])
         .map_err(|e| {
             log::warn!("run_il_startup_hook: error inserting prelude. {:?}", e);
             E_FAIL
         })?;
 
+    let function_info = module_metadata.import.get_function_info(function_token)?;
+    let returns_value = function_info
+        .method_signature
+        .try_parse()
+        .map(|s| {
+            let (_, flags) = s.return_type().get_type_flags();
+            !flags.contains(MethodArgumentTypeFlag::VOID)
+        })
+        .ok_or(E_FAIL)?;
+    let diagnostics = Verifier::new(module_metadata, returns_value).verify(&method);
+    if !diagnostics.is_empty() {
+        log::warn!("run_il_startup_hook: not inserting startup hook as the modified IL is invalid");
+        for diagnostic in diagnostics {
+            log::warn!("run_il_startup_hook: {}", diagnostic);
+        }
+        return Err(E_FAIL);
+    }
+
     let method_bytes = method.into_bytes();
     let allocator = profiler_info.get_il_function_body_allocator(module_id)?;
     let allocated_bytes = allocator.alloc(method_bytes.len() as ULONG)?;
@@ -52,6 +77,16 @@ pub fn run_il_startup_hook(
             e
         })?;
 
+    let il_map = method.il_map();
+    if !il_map.is_empty() {
+        if let Err(e) = profiler_info.set_il_instrumented_code_map(function_id, true, &il_map) {
+            log::warn!(
+                "run_il_startup_hook: failed to set il instrumented code map for startup hook. {:X}",
+                e
+            );
+        }
+    }
+
     Ok(())
 }
 
@@ -193,10 +228,10 @@ fn generate_void_il_startup_method(
 
     // Write the instructions for the IsAlreadyLoaded method
     let instructions = vec![
-        Instruction::ldsflda(is_assembly_loaded_field_def),
+        Instruction::ldsflda(helpers::operand_token(is_assembly_loaded_field_def)?),
         Instruction::ldc_i4_1(),
         Instruction::ldc_i4_0(),
-        Instruction::call(interlocked_compare_member_ref),
+        Instruction::call(helpers::operand_token(interlocked_compare_member_ref)?),
         Instruction::ldc_i4_1(),
         Instruction::ceq(),
         Instruction::ret(),
@@ -373,66 +408,66 @@ fn generate_void_il_startup_method(
 
     let locals_signature_token = module_metadata.emit.get_token_from_sig(&locals_signature)?;
 
-    let instructions = vec![
-        // Step 0) Check if the assembly was already loaded
-        Instruction::call(already_loaded_method_token),
-        // val is the offset of the instruction to go to when false
-        Instruction::brfalse_s(Instruction::ret().len() as i8),
-        Instruction::ret(),
+    let mut builder = MethodBuilder::new();
+    builder.set_local_var_sig_tok(locals_signature_token);
+    let load_assembly = builder.define_label();
+
+    // Step 0) Check if the assembly was already loaded
+    builder.emit(Instruction::call(helpers::operand_token(
+        already_loaded_method_token,
+    )?));
+    builder.emit_branch(BRFALSE_S, load_assembly);
+    builder.emit(Instruction::ret());
+    builder.mark_label(load_assembly);
+
+    builder.emit_all(vec![
         // Step 1) Call void GetAssemblyAndSymbolsBytes(out IntPtr assemblyPtr,
         // out int assemblySize, out IntPtr symbolsPtr, out int symbolsSize)
         Instruction::ldloca_s(0),
         Instruction::ldloca_s(1),
         Instruction::ldloca_s(2),
         Instruction::ldloca_s(3),
-        Instruction::call(pinvoke_method_def),
+        Instruction::call(helpers::operand_token(pinvoke_method_def)?),
         // Step 2) Call void Marshal.Copy(IntPtr source, byte[] destination,
         // int startIndex, int length) to populate the managed assembly bytes
         Instruction::ldloc_1(),
-        Instruction::newarr(byte_type_ref),
+        Instruction::newarr(helpers::operand_token(byte_type_ref)?),
         Instruction::stloc_s(4),
         Instruction::ldloc_0(),
         Instruction::ldloc_s(4),
         Instruction::ldc_i4_0(),
         Instruction::ldloc_1(),
-        Instruction::call(marshal_copy_member_ref),
+        Instruction::call(helpers::operand_token(marshal_copy_member_ref)?),
         // Step 3) Call void Marshal.Copy(IntPtr source, byte[] destination,
         // int startIndex, int length) to populate the symbols bytes
         Instruction::ldloc_3(),
-        Instruction::newarr(byte_type_ref),
+        Instruction::newarr(helpers::operand_token(byte_type_ref)?),
         Instruction::stloc_s(5),
         Instruction::ldloc_2(),
         Instruction::ldloc_s(5),
         Instruction::ldc_i4_0(),
         Instruction::ldloc_3(),
-        Instruction::call(marshal_copy_member_ref),
+        Instruction::call(helpers::operand_token(marshal_copy_member_ref)?),
         // Step 4) Call System.Reflection.Assembly System.Reflection.Assembly.Load(byte[], byte[]))
         Instruction::ldloc_s(4),
         Instruction::ldloc_s(5),
-        Instruction::call(assembly_load_member_ref),
+        Instruction::call(helpers::operand_token(assembly_load_member_ref)?),
         Instruction::stloc_s(6),
         // Step 5) Call instance method Assembly.CreateInstance("Elastic.Apm.Profiler.Managed.Loader.Startup")
         Instruction::ldloc_s(6),
-        Instruction::ldstr(load_helper_token),
-        Instruction::callvirt(assembly_create_instance_member_ref),
+        Instruction::ldstr(helpers::operand_token(load_helper_token)?),
+        Instruction::callvirt(helpers::operand_token(assembly_create_instance_member_ref)?),
         Instruction::pop(),
         Instruction::ret(),
-    ];
-
-    let method = Method {
-        address: 0,
-        header: MethodHeader::fat(
-            false,
-            false,
-            instructions.iter().map(|i| i.opcode.len as u16).sum(),
-            instructions.iter().map(|i| i.len() as u32).sum(),
-            locals_signature_token,
-        ),
-        instructions,
-        sections: vec![],
-    };
+    ]);
 
-    let method_bytes = method.into_bytes();
+    let method_bytes = builder.into_bytes().map_err(|e| {
+        log::warn!(
+            "generate_void_il_startup_method: failed to build __ElasticVoidMethodCall__. {:?}",
+            e
+        );
+        E_FAIL
+    })?;
     let allocated_bytes = allocator.alloc(method_bytes.len() as ULONG).map_err(|e| {
         log::warn!("generate_void_il_startup_method: failed to allocate memory for __ElasticVoidMethodCall__");
         e
@@ -286,15 +286,31 @@ impl PublicKeyToken {
     }
 }
 
+/// A caller of the target method. An empty type or method matches any type or method
 #[derive(Debug, Eq, PartialEq, Deserialize, Clone)]
 pub struct CallerMethodReference {
     pub(crate) assembly: String,
+    #[serde(default)]
     #[serde(rename = "type")]
     pub(crate) type_name: String,
+    #[serde(default)]
     #[serde(rename = "method")]
     pub(crate) method_name: String,
 }
 
+impl CallerMethodReference {
+    pub fn matches_assembly(&self, assembly_name: &str) -> bool {
+        self.assembly.is_empty() || self.assembly == assembly_name
+    }
+
+    /// Gets whether the method is a caller, by type and method name
+    pub fn matches_method(&self, caller: &FunctionInfo) -> bool {
+        (self.type_name.is_empty()
+            || caller.type_info.as_ref().map(|t| &t.name) == Some(&self.type_name))
+            && (self.method_name.is_empty() || caller.name == self.method_name)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Deserialize, Clone)]
 pub struct WrapperMethodReference {
     pub(crate) assembly: AssemblyReference,
@@ -305,12 +321,26 @@ pub struct WrapperMethodReference {
     pub(crate) action: WrapperMethodAction,
     #[serde(rename = "signature")]
     pub(crate) method_signature: Option<MethodSignature>,
+    /// Whether arguments are passed to BeginMethod in an object array that is copied back
+    /// after the call, so that changes the integration makes to its ref parameters are
+    /// seen by the target method
+    #[serde(default)]
+    pub(crate) modify_arguments: bool,
+    /// Whether the state returned by BeginMethod can skip the body of the target method.
+    /// The return value of a skipped method is the value returned by EndMethod
+    #[serde(default)]
+    pub(crate) skip_method_body: bool,
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Clone)]
 pub enum WrapperMethodAction {
     CallTargetModification,
     ReplaceTargetMethod,
+    /// Inserts a call to the wrapper at the start of the target method body, when CallTarget
+    /// is disabled. The tokens of the wrapper signature are not resolved, so each wrapper
+    /// parameter must be a built-in type with the same type as the caller argument, or `object`.
+    /// A parameter of any other type does not match, and the call is not inserted
+    InsertFirst,
 }
 
 impl WrapperMethodReference {
@@ -420,6 +450,10 @@ pub struct MethodReplacement {
     target: Option<TargetMethodReference>,
     /// The wrapper providing the instrumentation
     wrapper: Option<WrapperMethodReference>,
+    /// The priority of the wrapper when several wrappers instrument the same target.
+    /// Wrappers with a higher priority are called first on entry and last on exit
+    #[serde(default)]
+    priority: i32,
 }
 
 impl MethodReplacement {
@@ -434,6 +468,10 @@ impl MethodReplacement {
     pub fn wrapper(&self) -> Option<&WrapperMethodReference> {
         self.wrapper.as_ref()
     }
+
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
 }
 
 /// Deserializes a T to Option::Some(T) and an empty struct to Option::None
@@ -556,24 +594,11 @@ impl ModuleMetadata {
     ) -> Vec<MethodReplacement> {
         self.integrations
             .iter()
-            .filter_map(|i| {
-                if let Some(caller_ref) = &i.method_replacement.caller {
-                    if caller_ref.type_name.is_empty()
-                        || caller
-                            .type_info
-                            .as_ref()
-                            .map_or(false, |t| t.name == caller_ref.type_name)
-                            && caller_ref.method_name.is_empty()
-                        || caller.name == caller_ref.method_name
-                    {
-                        Some(&i.method_replacement)
-                    } else {
-                        None
-                    }
-                } else {
-                    Some(&i.method_replacement)
-                }
+            .filter(|i| match i.method_replacement.caller() {
+                Some(caller_ref) => caller_ref.matches_method(caller),
+                None => true,
             })
+            .map(|i| &i.method_replacement)
             .cloned()
             .collect()
     }
@@ -1132,7 +1157,7 @@ impl<'a> FunctionMethodArgument<'a> {
                 metadata_emit.define_type_ref_by_name(cor_lib_assembly_ref, "System.UInt16")
             }
             CorElementType::ELEMENT_TYPE_I4 => {
-                metadata_emit.define_type_ref_by_name(cor_lib_assembly_ref, "System.In32")
+                metadata_emit.define_type_ref_by_name(cor_lib_assembly_ref, "System.Int32")
             }
             CorElementType::ELEMENT_TYPE_U4 => {
                 metadata_emit.define_type_ref_by_name(cor_lib_assembly_ref, "System.UInt32")
@@ -1445,8 +1470,8 @@ bitflags! {
 #[cfg(test)]
 pub mod tests {
     use crate::profiler::types::{
-        AssemblyReference, Integration, MethodSignature, PublicKeyToken, Version,
-        WrapperMethodAction,
+        AssemblyReference, CallerMethodReference, Integration, MethodSignature, PublicKeyToken,
+        Version, WrapperMethodAction, WrapperMethodReference,
     };
     use std::{error::Error, fs::File, io::BufReader, path::PathBuf};
 
@@ -1539,6 +1564,7 @@ method_replacements:
         let method_replacement = &integration.method_replacements[0];
 
         assert!(method_replacement.caller.is_none());
+        assert_eq!(method_replacement.priority(), 0);
 
         assert!(method_replacement.target.is_some());
         let target = method_replacement.target.as_ref().unwrap();
@@ -1571,7 +1597,59 @@ method_replacements:
             "Elastic.Apm.Profiler.Integrations.AdoNet.CommandExecuteNonQueryAsyncIntegration"
         );
         assert_eq!(wrapper.action, WrapperMethodAction::CallTargetModification);
+        assert!(!wrapper.modify_arguments);
+
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_integration_from_json() -> Result<(), Box<dyn Error>> {
+        let json = r#"{
+  "name": "AdoNet",
+  "method_replacements": [
+    {
+      "caller": {},
+      "target": {
+        "assembly": "System.Data",
+        "type": "System.Data.Common.DbCommand",
+        "method": "ExecuteNonQueryAsync",
+        "signature_types": [
+          "System.Threading.Tasks.Task`1<System.Int32>",
+          "System.Threading.CancellationToken"
+        ],
+        "minimum_version": "4.0.0",
+        "maximum_version": "4.*.*"
+      },
+      "wrapper": {
+        "assembly": "Elastic.Apm.Profiler.Managed, Version=1.9.0.0, Culture=neutral, PublicKeyToken=ae7400d2c189cf22",
+        "type": "Elastic.Apm.Profiler.Integrations.AdoNet.CommandExecuteNonQueryAsyncIntegration",
+        "action": "CallTargetModification"
+      }
+    }
+  ]
+}"#;
+        let yml = r#"---
+name: AdoNet
+method_replacements:
+- caller: {}
+  target:
+    assembly: System.Data
+    type: System.Data.Common.DbCommand
+    method: ExecuteNonQueryAsync
+    signature_types:
+    - System.Threading.Tasks.Task`1<System.Int32>
+    - System.Threading.CancellationToken
+    minimum_version: 4.0.0
+    maximum_version: 4.*.*
+  wrapper:
+    assembly: Elastic.Apm.Profiler.Managed, Version=1.9.0.0, Culture=neutral, PublicKeyToken=ae7400d2c189cf22
+    type: Elastic.Apm.Profiler.Integrations.AdoNet.CommandExecuteNonQueryAsyncIntegration
+    action: CallTargetModification"#;
 
+        let integration: Integration = serde_json::from_str(json)?;
+        let expected: Integration = serde_yml::from_str(yml)?;
+        assert_eq!(integration, expected);
+        assert!(integration.method_replacements[0].caller.is_none());
         Ok(())
     }
 
@@ -1586,6 +1664,54 @@ method_replacements:
         Ok(())
     }
 
+    #[test]
+    fn deserialize_caller_with_assembly_only() -> Result<(), Box<dyn Error>> {
+        let caller: CallerMethodReference = serde_yml::from_str("assembly: My.App")?;
+        assert_eq!(&caller.assembly, "My.App");
+        assert!(caller.type_name.is_empty());
+        assert!(caller.method_name.is_empty());
+        assert!(caller.matches_assembly("My.App"));
+        assert!(!caller.matches_assembly("Other.App"));
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_insert_first_action() -> Result<(), Box<dyn Error>> {
+        let action: WrapperMethodAction = serde_yml::from_str("InsertFirst")?;
+        assert_eq!(action, WrapperMethodAction::InsertFirst);
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_wrapper_that_modifies_arguments() -> Result<(), Box<dyn Error>> {
+        let wrapper: WrapperMethodReference = serde_yml::from_str(
+            r#"
+assembly: Elastic.Apm.Profiler.Managed, Version=1.9.0.0, Culture=neutral, PublicKeyToken=ae7400d2c189cf22
+type: Elastic.Apm.Profiler.Integrations.AdoNet.CommandExecuteNonQueryIntegration
+action: CallTargetModification
+modify_arguments: true
+"#,
+        )?;
+        assert!(wrapper.modify_arguments);
+        assert!(!wrapper.skip_method_body);
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_wrapper_that_skips_method_body() -> Result<(), Box<dyn Error>> {
+        let wrapper: WrapperMethodReference = serde_yml::from_str(
+            r#"
+assembly: Elastic.Apm.Profiler.Managed, Version=1.9.0.0, Culture=neutral, PublicKeyToken=ae7400d2c189cf22
+type: Elastic.Apm.Profiler.Integrations.AdoNet.CommandExecuteNonQueryIntegration
+action: CallTargetModification
+skip_method_body: true
+"#,
+        )?;
+        assert!(wrapper.skip_method_body);
+        assert!(!wrapper.modify_arguments);
+        Ok(())
+    }
+
     #[test]
     fn public_key_token_into_bytes() {
         let public_key_token = PublicKeyToken::new("ae7400d2c189cf22");
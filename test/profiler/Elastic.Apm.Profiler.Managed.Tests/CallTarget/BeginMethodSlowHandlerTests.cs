@@ -0,0 +1,34 @@
+// Licensed to Elasticsearch B.V under
+// one or more agreements.
+// Elasticsearch B.V licenses this file to you under the Apache 2.0 License.
+// See the LICENSE file in the project root for more information
+
+using Elastic.Apm.Profiler.Managed.CallTarget;
+using FluentAssertions;
+using Xunit;
+
+namespace Elastic.Apm.Profiler.Managed.Tests.CallTarget;
+
+public class BeginMethodSlowHandlerTests
+{
+	[Fact]
+	public void RefArgumentsAreCopiedBackToArgumentsArray()
+	{
+		var arguments = new object[] { "SELECT 1", 30, "unchanged" };
+
+		CallTargetInvoker.BeginMethod<ModifyArgumentsIntegration, BeginMethodSlowHandlerTests>(this, arguments);
+
+		// the rewritten method copies the values in the array back to its arguments
+		arguments.Should().Equal("/* traceparent */ SELECT 1", 60, "unchanged");
+	}
+
+	private static class ModifyArgumentsIntegration
+	{
+		internal static CallTargetState OnMethodBegin<TTarget>(TTarget instance, ref string commandText, ref int timeout, string name)
+		{
+			commandText = "/* traceparent */ " + commandText;
+			timeout *= 2;
+			return CallTargetState.GetDefault();
+		}
+	}
+}